@@ -0,0 +1,247 @@
+use crate::{Group, Message, SessionId, UserId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Namespace of the name-based UUIDs used as one-to-one session ids
+const DIRECT_SESSION_NAMESPACE: Uuid = Uuid::from_u128(0xe70c4e30_33cc_427e_8273_4950def9684b);
+
+/// Session id of the one-to-one conversation between two users
+///
+/// Both sides derive the same id, whichever of them asks.
+pub fn direct_session_id(a: &UserId, b: &UserId) -> SessionId {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut name = [0u8; 32];
+    name[..16].copy_from_slice(first.as_bytes());
+    name[16..].copy_from_slice(second.as_bytes());
+    Uuid::new_v5(&DIRECT_SESSION_NAMESPACE, &name)
+}
+
+/// Who a conversation is with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConversationKind {
+    Direct { peer_id: UserId },
+    /// The group with the conversation's session id
+    Group,
+}
+
+/// Summary of one conversation, for the conversation list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub session_id: SessionId,
+    pub kind: ConversationKind,
+    pub last_message: Option<Message>,
+    /// Messages received since the conversation was last read
+    pub unread_count: usize,
+    pub muted: bool,
+    pub last_activity: DateTime<Utc>,
+}
+
+impl Conversation {
+    fn new(session_id: SessionId, kind: ConversationKind, last_activity: DateTime<Utc>) -> Self {
+        Self {
+            session_id,
+            kind,
+            last_message: None,
+            unread_count: 0,
+            muted: false,
+            last_activity,
+        }
+    }
+}
+
+/// Conversations of the local user, keyed by session id
+#[derive(Debug, Clone, Default)]
+pub struct ConversationList {
+    conversations: HashMap<SessionId, Conversation>,
+}
+
+impl ConversationList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a conversation for a group we're in, if there isn't one yet
+    pub fn add_group(&mut self, group: &Group) {
+        self.conversations
+            .entry(group.session_id)
+            .or_insert_with(|| {
+                Conversation::new(group.session_id, ConversationKind::Group, group.created_at)
+            });
+    }
+
+    /// Add a message we sent or received to its conversation
+    ///
+    /// Messages outside a known group belong to the one-to-one conversation
+    /// with the other party, and get its session id. Sending a message
+    /// counts as having read the conversation.
+    pub fn record(&mut self, message: &mut Message, own_id: &UserId) -> &Conversation {
+        let is_group = self
+            .conversations
+            .get(&message.session_id)
+            .is_some_and(|c| c.kind == ConversationKind::Group);
+        let outgoing = message.sender_id == *own_id;
+        let peer_id = if outgoing {
+            message.recipient_id
+        } else {
+            message.sender_id
+        };
+
+        if !is_group {
+            message.session_id = direct_session_id(own_id, &peer_id);
+        }
+
+        let conversation = self
+            .conversations
+            .entry(message.session_id)
+            .or_insert_with(|| {
+                Conversation::new(
+                    message.session_id,
+                    ConversationKind::Direct { peer_id },
+                    message.timestamp,
+                )
+            });
+
+        if outgoing {
+            conversation.unread_count = 0;
+        } else {
+            conversation.unread_count += 1;
+        }
+        // Late arrivals don't replace a newer last message
+        if conversation
+            .last_message
+            .as_ref()
+            .is_none_or(|last| last.timestamp <= message.timestamp)
+        {
+            conversation.last_message = Some(message.clone());
+        }
+        conversation.last_activity = conversation.last_activity.max(message.timestamp);
+        conversation
+    }
+
+    /// Show an edited or deleted message if it's a conversation's last one
+    pub fn update_message(&mut self, message: &Message) {
+        if let Some(last) = self
+            .conversations
+            .get_mut(&message.session_id)
+            .and_then(|c| c.last_message.as_mut())
+            .filter(|last| last.id == message.id)
+        {
+            *last = message.clone();
+        }
+    }
+
+    /// Reset the unread count; `false` if there's no such conversation
+    pub fn mark_read(&mut self, session_id: &SessionId) -> bool {
+        match self.conversations.get_mut(session_id) {
+            Some(conversation) => {
+                conversation.unread_count = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Mute or unmute a conversation; `false` if there's no such conversation
+    pub fn set_muted(&mut self, session_id: &SessionId, muted: bool) -> bool {
+        match self.conversations.get_mut(session_id) {
+            Some(conversation) => {
+                conversation.muted = muted;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, session_id: &SessionId) -> Option<&Conversation> {
+        self.conversations.get(session_id)
+    }
+
+    /// All conversations, most recently active first
+    pub fn by_activity(&self) -> Vec<Conversation> {
+        let mut conversations: Vec<_> = self.conversations.values().cloned().collect();
+        conversations.sort_by_key(|c| std::cmp::Reverse(c.last_activity));
+        conversations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn text(sender_id: UserId, recipient_id: UserId, minutes: i64) -> Message {
+        let mut message = Message::new_text(Uuid::new_v4(), sender_id, recipient_id, "hi".into());
+        message.timestamp = DateTime::UNIX_EPOCH + Duration::minutes(minutes);
+        message
+    }
+
+    #[test]
+    fn test_direct_session_id_is_symmetric() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(direct_session_id(&a, &b), direct_session_id(&b, &a));
+        assert_ne!(
+            direct_session_id(&a, &b),
+            direct_session_id(&a, &Uuid::new_v4())
+        );
+    }
+
+    #[test]
+    fn test_record_direct_messages() {
+        let (me, peer) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut list = ConversationList::new();
+
+        // Both directions land in the same conversation
+        let mut received = text(peer, me, 2);
+        let mut sent = text(me, peer, 1);
+        list.record(&mut received, &me);
+        let conversation = list.record(&mut sent, &me);
+
+        assert_eq!(received.session_id, direct_session_id(&me, &peer));
+        assert_eq!(sent.session_id, received.session_id);
+        assert_eq!(
+            conversation.kind,
+            ConversationKind::Direct { peer_id: peer }
+        );
+        assert_eq!(conversation.unread_count, 0);
+        // The earlier message arrived late and doesn't replace the newer one
+        assert_eq!(conversation.last_message.as_ref().unwrap().id, received.id);
+    }
+
+    #[test]
+    fn test_group_sessions_are_kept() {
+        let (me, member) = (Uuid::new_v4(), Uuid::new_v4());
+        let group = Group::new("Team".into(), member, [me]);
+        let mut list = ConversationList::new();
+        list.add_group(&group);
+
+        let mut message = text(member, me, 1);
+        message.session_id = group.session_id;
+        let conversation = list.record(&mut message, &me);
+
+        assert_eq!(message.session_id, group.session_id);
+        assert_eq!(conversation.kind, ConversationKind::Group);
+        assert_eq!(conversation.unread_count, 1);
+    }
+
+    #[test]
+    fn test_by_activity() {
+        let me = Uuid::new_v4();
+        let (quiet, busy) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut list = ConversationList::new();
+
+        list.record(&mut text(busy, me, 1), &me);
+        list.record(&mut text(quiet, me, 2), &me);
+        list.record(&mut text(me, busy, 3), &me);
+
+        let order: Vec<_> = list.by_activity().into_iter().map(|c| c.kind).collect();
+        assert_eq!(
+            order,
+            vec![
+                ConversationKind::Direct { peer_id: busy },
+                ConversationKind::Direct { peer_id: quiet },
+            ]
+        );
+    }
+}
@@ -14,6 +14,9 @@ pub enum ChatError {
     #[error("Peer not found: {0}")]
     PeerNotFound(String),
 
+    #[error("Not authorized: {0}")]
+    NotAuthorized(String),
+
     #[error("File transfer error: {0}")]
     FileTransfer(String),
 
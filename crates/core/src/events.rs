@@ -1,4 +1,5 @@
-use crate::{Message, Peer, ReadReceipt, TypingIndicator, UserId, UserStatus};
+use crate::{Group, Message, Peer, ReadReceipt, SessionId, TypingIndicator, UserId, UserStatus};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 /// Events that can occur in the chat system
@@ -15,24 +16,91 @@ pub enum ChatEvent {
     MessageSent(Message),
     MessageDelivered { message_id: uuid::Uuid },
     MessageRead(ReadReceipt),
+    /// A peer sent new content for a message; it only applies if they sent
+    /// the original
+    MessageEdited {
+        message_id: uuid::Uuid,
+        edited_by: UserId,
+        content: String,
+        edited_at: DateTime<Utc>,
+    },
+    /// A peer deleted a message for everyone; it only applies if they sent
+    /// the original
+    MessageDeleted {
+        message_id: uuid::Uuid,
+        deleted_by: UserId,
+    },
 
     // Typing events
     TypingIndicator(TypingIndicator),
 
+    // Group events
+    /// A group we're in was created, joined or changed
+    GroupUpdated(Group),
+    /// We left a group, or an admin removed us
+    GroupRemoved { session_id: SessionId },
+
     // File transfer events
     FileTransferRequested {
         transfer_id: uuid::Uuid,
         from: UserId,
         file_name: String,
         file_size: u64,
+        file_count: usize,
     },
     FileTransferAccepted {
         transfer_id: uuid::Uuid,
     },
+    FileTransferRejected {
+        transfer_id: uuid::Uuid,
+        reason: String,
+    },
+    /// An incoming transfer was accepted by the accept policy, without asking
+    FileTransferAutoAccepted {
+        transfer_id: uuid::Uuid,
+        from: UserId,
+        file_name: String,
+        reason: String,
+    },
+    /// An incoming transfer was rejected without asking, by the accept policy
+    /// or because it's too large
+    FileTransferAutoRejected {
+        transfer_id: uuid::Uuid,
+        from: UserId,
+        file_name: String,
+        reason: String,
+    },
+    /// Accepted, but waiting for other outgoing transfers to finish
+    FileTransferQueued {
+        transfer_id: uuid::Uuid,
+    },
+    /// Sent at most every so often while a transfer is busy, and once more
+    /// when a phase finishes
     FileTransferProgress {
         transfer_id: uuid::Uuid,
+        phase: TransferPhase,
         bytes_transferred: u64,
         total_bytes: u64,
+        /// Current speed, measured over the last few seconds
+        bytes_per_second: u64,
+        /// Seconds left at the current speed, once there is one
+        eta_seconds: Option<u64>,
+    },
+    /// Progress of one file within a multi-file transfer
+    FileTransferItemProgress {
+        transfer_id: uuid::Uuid,
+        path: String,
+        bytes_transferred: u64,
+        total_bytes: u64,
+    },
+    FileTransferPaused {
+        transfer_id: uuid::Uuid,
+    },
+    FileTransferResumed {
+        transfer_id: uuid::Uuid,
+    },
+    FileTransferCancelled {
+        transfer_id: uuid::Uuid,
     },
     FileTransferCompleted {
         transfer_id: uuid::Uuid,
@@ -46,3 +114,15 @@ pub enum ChatEvent {
     Error(String),
     NetworkStatusChanged { connected: bool },
 }
+
+/// What a transfer is busy with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferPhase {
+    /// Sender is hashing the files before asking the recipient
+    Hashing,
+    /// Request sent; the recipient hasn't answered yet
+    WaitingForAccept,
+    Transferring,
+    /// Recipient is checking the received files against their hashes
+    Verifying,
+}
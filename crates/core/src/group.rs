@@ -0,0 +1,206 @@
+use crate::{SessionId, UserId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// A group chat: one session whose messages go to every member
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub session_id: SessionId,
+    pub name: String,
+    /// Everyone in the group, admins included, in the order they joined
+    pub members: Vec<UserId>,
+    /// Members who may invite, remove and rename
+    pub admins: Vec<UserId>,
+    pub created_by: UserId,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Group {
+    /// New group with `creator` as its only admin
+    pub fn new(name: String, creator: UserId, members: impl IntoIterator<Item = UserId>) -> Self {
+        let mut group = Self {
+            session_id: Uuid::new_v4(),
+            name,
+            members: vec![creator],
+            admins: vec![creator],
+            created_by: creator,
+            created_at: Utc::now(),
+        };
+        for member in members {
+            group.add_member(member);
+        }
+        group
+    }
+
+    pub fn is_member(&self, user_id: &UserId) -> bool {
+        self.members.contains(user_id)
+    }
+
+    pub fn is_admin(&self, user_id: &UserId) -> bool {
+        self.admins.contains(user_id)
+    }
+
+    /// Returns `false` if they were already a member
+    pub fn add_member(&mut self, user_id: UserId) -> bool {
+        if self.is_member(&user_id) {
+            return false;
+        }
+        self.members.push(user_id);
+        true
+    }
+
+    /// Remove a member, and their admin rights with them
+    ///
+    /// When the last admin goes, the longest-standing member takes over, so
+    /// every copy of the group picks the same one.
+    pub fn remove_member(&mut self, user_id: &UserId) -> bool {
+        let len = self.members.len();
+        self.members.retain(|m| m != user_id);
+        self.admins.retain(|a| a != user_id);
+        if self.admins.is_empty() {
+            self.admins.extend(self.members.first().copied());
+        }
+        self.members.len() != len
+    }
+
+    /// Members other than `user_id`, i.e. who their group messages go to
+    pub fn other_members<'a>(&'a self, user_id: &'a UserId) -> impl Iterator<Item = &'a UserId> {
+        self.members.iter().filter(move |m| *m != user_id)
+    }
+}
+
+/// Thread-safe set of the groups we're in
+#[derive(Debug, Clone, Default)]
+pub struct GroupRegistry {
+    groups: Arc<RwLock<HashMap<SessionId, Group>>>,
+}
+
+impl GroupRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a group, or replace it with a newer copy
+    pub async fn insert(&self, group: Group) {
+        let mut groups = self.groups.write().await;
+        groups.insert(group.session_id, group);
+    }
+
+    pub async fn remove(&self, session_id: &SessionId) -> Option<Group> {
+        let mut groups = self.groups.write().await;
+        groups.remove(session_id)
+    }
+
+    pub async fn get(&self, session_id: &SessionId) -> Option<Group> {
+        let groups = self.groups.read().await;
+        groups.get(session_id).cloned()
+    }
+
+    /// All groups, oldest first
+    pub async fn get_all(&self) -> Vec<Group> {
+        let groups = self.groups.read().await;
+        let mut groups: Vec<_> = groups.values().cloned().collect();
+        groups.sort_by_key(|g| g.created_at);
+        groups
+    }
+}
+
+/// Persisted groups, so they survive a restart
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GroupList {
+    pub groups: Vec<Group>,
+}
+
+impl GroupList {
+    /// Load the list from a JSON file, returning an empty list if it doesn't exist
+    pub async fn load(path: &Path) -> crate::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the list as JSON, creating the parent directory if needed
+    pub async fn save(&self, path: &Path) -> crate::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_vec_pretty(self)?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    /// Add every group to the registry
+    pub async fn apply_to(&self, registry: &GroupRegistry) {
+        for group in &self.groups {
+            registry.insert(group.clone()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_rights_go_to_the_oldest_member() {
+        let (creator, first, second) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut group = Group::new("Team".into(), creator, [first, second]);
+
+        assert!(group.remove_member(&creator));
+        assert_eq!(group.members, vec![first, second]);
+        assert_eq!(group.admins, vec![first]);
+
+        // Not a member anymore
+        assert!(!group.remove_member(&creator));
+    }
+
+    #[test]
+    fn test_remaining_admins_keep_the_group() {
+        let (creator, first, second) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut group = Group::new("Team".into(), creator, [first, second]);
+        group.admins.push(second);
+
+        assert!(group.remove_member(&creator));
+        assert_eq!(group.admins, vec![second]);
+
+        // The last member leaving takes the admin rights with them
+        group.remove_member(&first);
+        group.remove_member(&second);
+        assert!(group.members.is_empty());
+        assert!(group.admins.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_group_list_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lan-chat-groups-{}", Uuid::new_v4()));
+        let path = dir.join("nested").join("groups.json");
+
+        // Nothing saved yet
+        assert!(GroupList::load(&path).await.unwrap().groups.is_empty());
+
+        let creator = Uuid::new_v4();
+        let list = GroupList {
+            groups: vec![
+                Group::new("Team".into(), creator, [Uuid::new_v4()]),
+                Group::new("Lunch".into(), creator, []),
+            ],
+        };
+        list.save(&path).await.unwrap();
+        let loaded = GroupList::load(&path).await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        assert_eq!(loaded.groups, list.groups);
+
+        let registry = GroupRegistry::new();
+        loaded.apply_to(&registry).await;
+        for group in &list.groups {
+            assert_eq!(registry.get(&group.session_id).await.as_ref(), Some(group));
+        }
+    }
+}
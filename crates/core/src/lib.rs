@@ -1,11 +1,15 @@
 pub mod types;
 pub mod peer;
 pub mod message;
+pub mod group;
+pub mod conversation;
 pub mod events;
 pub mod error;
 
 pub use types::*;
 pub use peer::*;
 pub use message::*;
+pub use group::*;
+pub use conversation::*;
 pub use events::*;
 pub use error::*;
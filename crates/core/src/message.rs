@@ -37,6 +37,21 @@ pub struct Message {
     pub timestamp: DateTime<Utc>,
     pub status: MessageStatus,
     pub encrypted: bool,
+    /// Message this one replies to, quoting it
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    /// First message of the thread this reply belongs to
+    #[serde(default)]
+    pub thread_root: Option<Uuid>,
+    /// When the sender last edited the content
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Earlier versions of the content, oldest first; kept locally only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edit_history: Vec<MessageEdit>,
+    /// Deleted for everyone; the content is gone and only this marker remains
+    #[serde(default)]
+    pub deleted: bool,
 }
 
 impl Message {
@@ -57,6 +72,11 @@ impl Message {
             timestamp: Utc::now(),
             status: MessageStatus::Sending,
             encrypted: false,
+            reply_to: None,
+            thread_root: None,
+            edited_at: None,
+            edit_history: Vec::new(),
+            deleted: false,
         }
     }
 
@@ -83,8 +103,221 @@ impl Message {
             timestamp: Utc::now(),
             status: MessageStatus::Sending,
             encrypted: false,
+            reply_to: None,
+            thread_root: None,
+            edited_at: None,
+            edit_history: Vec::new(),
+            deleted: false,
         }
     }
+
+    /// Image message; `thumbnail` is shown inline until the full file is fetched
+    pub fn new_image(
+        session_id: SessionId,
+        sender_id: UserId,
+        recipient_id: UserId,
+        file: MediaFile,
+        width: u32,
+        height: u32,
+        thumbnail: Option<String>,
+    ) -> Self {
+        Self::new_media(
+            session_id,
+            sender_id,
+            recipient_id,
+            MessageType::Image,
+            file.file_name.clone(),
+            MessageMetadata::Image {
+                width,
+                height,
+                thumbnail,
+                file: Some(file),
+            },
+        )
+    }
+
+    /// Audio message; `duration` is in seconds, 0 if unknown
+    pub fn new_audio(
+        session_id: SessionId,
+        sender_id: UserId,
+        recipient_id: UserId,
+        file: MediaFile,
+        duration: u32,
+    ) -> Self {
+        Self::new_media(
+            session_id,
+            sender_id,
+            recipient_id,
+            MessageType::Audio,
+            file.file_name.clone(),
+            MessageMetadata::Audio {
+                duration,
+                file: Some(file),
+            },
+        )
+    }
+
+    /// Video message; `duration` is in seconds, 0 if unknown
+    pub fn new_video(
+        session_id: SessionId,
+        sender_id: UserId,
+        recipient_id: UserId,
+        file: MediaFile,
+        duration: u32,
+        thumbnail: Option<String>,
+    ) -> Self {
+        Self::new_media(
+            session_id,
+            sender_id,
+            recipient_id,
+            MessageType::Video,
+            file.file_name.clone(),
+            MessageMetadata::Video {
+                duration,
+                thumbnail,
+                file: Some(file),
+            },
+        )
+    }
+
+    fn new_media(
+        session_id: SessionId,
+        sender_id: UserId,
+        recipient_id: UserId,
+        message_type: MessageType,
+        content: String,
+        metadata: MessageMetadata,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            session_id,
+            sender_id,
+            recipient_id,
+            message_type,
+            content,
+            metadata: Some(metadata),
+            timestamp: Utc::now(),
+            status: MessageStatus::Sending,
+            encrypted: false,
+            reply_to: None,
+            thread_root: None,
+            edited_at: None,
+            edit_history: Vec::new(),
+            deleted: false,
+        }
+    }
+
+    /// The file behind an image, audio or video message, which the recipient
+    /// fetches from the sender when it's opened
+    pub fn media_file(&self) -> Option<&MediaFile> {
+        match self.metadata.as_ref()? {
+            MessageMetadata::Image { file, .. }
+            | MessageMetadata::Audio { file, .. }
+            | MessageMetadata::Video { file, .. } => file.as_ref(),
+            MessageMetadata::File { .. } => None,
+        }
+    }
+
+    /// Make this a reply to `parent`, in the parent's thread or starting one
+    pub fn with_reply_to(mut self, parent: &Message) -> Self {
+        self.reply_to = Some(parent.id);
+        self.thread_root = Some(parent.thread_root.unwrap_or(parent.id));
+        self
+    }
+
+    /// Id of the thread this message starts or belongs to
+    pub fn thread_id(&self) -> Uuid {
+        self.thread_root.unwrap_or(self.id)
+    }
+
+    /// Replace the content of a text message, keeping the old version
+    ///
+    /// `editor` is whoever asked for the edit; only the sender may. Returns
+    /// `false` if the edit is older than the current content.
+    pub fn edit(
+        &mut self,
+        editor: &UserId,
+        content: String,
+        edited_at: DateTime<Utc>,
+    ) -> crate::Result<bool> {
+        self.check_sender(editor)?;
+        if self.deleted || self.message_type != MessageType::Text {
+            return Err(crate::ChatError::InvalidData(
+                "Only text messages can be edited".into(),
+            ));
+        }
+        if self.edited_at.is_some_and(|last| last >= edited_at) {
+            return Ok(false);
+        }
+
+        let previous = std::mem::replace(&mut self.content, content);
+        self.edit_history.push(MessageEdit {
+            content: previous,
+            edited_at: self.edited_at.unwrap_or(self.timestamp),
+        });
+        self.edited_at = Some(edited_at);
+        Ok(true)
+    }
+
+    /// Delete the message for everyone, erasing its content, attachments and
+    /// edit history
+    ///
+    /// `deleter` is whoever asked for the deletion; only the sender may.
+    pub fn delete(&mut self, deleter: &UserId) -> crate::Result<()> {
+        self.check_sender(deleter)?;
+        self.content.clear();
+        self.metadata = None;
+        self.edit_history.clear();
+        self.deleted = true;
+        Ok(())
+    }
+
+    fn check_sender(&self, user_id: &UserId) -> crate::Result<()> {
+        if *user_id != self.sender_id {
+            return Err(crate::ChatError::NotAuthorized(format!(
+                "{} did not send message {}",
+                user_id, self.id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The thread `message_id` starts or belongs to: its first message and every
+/// reply in the same session, oldest first
+///
+/// Replies carry their thread's id, so they're found even when the messages
+/// they reply to aren't among `messages`. `None` if `message_id` isn't.
+pub fn thread(messages: &[Message], message_id: &Uuid) -> Option<Vec<Message>> {
+    let message = messages.iter().find(|m| m.id == *message_id)?;
+    let (session_id, thread_id) = (message.session_id, message.thread_id());
+
+    let mut thread: Vec<_> = messages
+        .iter()
+        .filter(|m| m.session_id == session_id && m.thread_id() == thread_id)
+        .cloned()
+        .collect();
+    thread.sort_by_key(|m| m.timestamp);
+    Some(thread)
+}
+
+/// An earlier version of an edited message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub content: String,
+    /// When this version was written
+    pub edited_at: DateTime<Utc>,
+}
+
+/// File sent with a media message, kept by the sender until asked for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaFile {
+    pub file_name: String,
+    pub file_size: u64,
+    /// Content hash of the file's transfer manifest, which identifies it when
+    /// it's fetched: the file's own SHA-256 for a single file, otherwise a hash
+    /// over every file's hash
+    pub file_hash: String,
 }
 
 /// Additional message metadata
@@ -98,14 +331,22 @@ pub enum MessageMetadata {
     Image {
         width: u32,
         height: u32,
+        /// Small JPEG as a `data:` URL
         thumbnail: Option<String>,
+        #[serde(default)]
+        file: Option<MediaFile>,
     },
     Audio {
         duration: u32,
+        #[serde(default)]
+        file: Option<MediaFile>,
     },
     Video {
         duration: u32,
+        /// Small JPEG as a `data:` URL
         thumbnail: Option<String>,
+        #[serde(default)]
+        file: Option<MediaFile>,
     },
 }
 
@@ -124,3 +365,150 @@ pub struct ReadReceipt {
     pub user_id: UserId,
     pub timestamp: DateTime<Utc>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn text(sender_id: UserId) -> Message {
+        Message::new_text(Uuid::new_v4(), sender_id, Uuid::new_v4(), "first".into())
+    }
+
+    #[test]
+    fn test_edit_keeps_history() {
+        let sender = Uuid::new_v4();
+        let mut message = text(sender);
+        let edited_at = message.timestamp + Duration::seconds(10);
+
+        assert!(message.edit(&sender, "second".into(), edited_at).unwrap());
+        assert_eq!(message.content, "second");
+        assert_eq!(message.edited_at, Some(edited_at));
+        assert_eq!(
+            message.edit_history,
+            vec![MessageEdit {
+                content: "first".into(),
+                edited_at: message.timestamp,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stale_edit_is_ignored() {
+        let sender = Uuid::new_v4();
+        let mut message = text(sender);
+        let edited_at = message.timestamp + Duration::seconds(10);
+        message.edit(&sender, "newer".into(), edited_at).unwrap();
+
+        // An edit that arrives late, or twice, doesn't undo a newer one
+        let older = edited_at - Duration::seconds(5);
+        assert!(!message.edit(&sender, "older".into(), older).unwrap());
+        assert!(!message.edit(&sender, "newer".into(), edited_at).unwrap());
+        assert_eq!(message.content, "newer");
+        assert_eq!(message.edit_history.len(), 1);
+    }
+
+    #[test]
+    fn test_only_sender_can_edit_or_delete() {
+        let mut message = text(Uuid::new_v4());
+        let other = Uuid::new_v4();
+
+        let edited = message.edit(&other, "mine now".into(), Utc::now());
+        assert!(matches!(edited, Err(crate::ChatError::NotAuthorized(_))));
+        let deleted = message.delete(&other);
+        assert!(matches!(deleted, Err(crate::ChatError::NotAuthorized(_))));
+        assert_eq!(message.content, "first");
+        assert!(!message.deleted);
+    }
+
+    #[test]
+    fn test_delete_erases_everything() {
+        let sender = Uuid::new_v4();
+        let file = MediaFile {
+            file_name: "photo.png".into(),
+            file_size: 1024,
+            file_hash: "abc".into(),
+        };
+        let mut media =
+            Message::new_image(Uuid::new_v4(), sender, Uuid::new_v4(), file, 640, 480, None);
+        media.delete(&sender).unwrap();
+        assert!(media.deleted);
+        assert!(media.metadata.is_none());
+
+        let mut message = text(sender);
+        let edited_at = message.timestamp + Duration::seconds(10);
+        message.edit(&sender, "second".into(), edited_at).unwrap();
+        message.delete(&sender).unwrap();
+        assert!(message.content.is_empty());
+        assert!(message.edit_history.is_empty());
+
+        // Nothing to edit any more
+        let later = edited_at + Duration::seconds(10);
+        assert!(message.edit(&sender, "back".into(), later).is_err());
+    }
+
+    #[test]
+    fn test_replies_join_the_first_message_thread() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let root = text(a);
+        let reply = Message::new_text(root.session_id, b, a, "re".into()).with_reply_to(&root);
+        let nested =
+            Message::new_text(root.session_id, a, b, "re: re".into()).with_reply_to(&reply);
+
+        assert_eq!(root.thread_id(), root.id);
+        assert_eq!(reply.reply_to, Some(root.id));
+        assert_eq!(reply.thread_id(), root.id);
+        // A reply to a reply quotes its parent but stays in the root's thread
+        assert_eq!(nested.reply_to, Some(reply.id));
+        assert_eq!(nested.thread_id(), root.id);
+    }
+
+    #[test]
+    fn test_thread() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut root = text(a);
+        root.timestamp -= Duration::minutes(5);
+        let mut reply = Message::new_text(root.session_id, b, a, "re".into()).with_reply_to(&root);
+        reply.timestamp -= Duration::minutes(2);
+        let nested =
+            Message::new_text(root.session_id, a, b, "re: re".into()).with_reply_to(&reply);
+        let unrelated = Message::new_text(root.session_id, b, a, "other".into());
+        // Same thread id, but from another conversation
+        let mut elsewhere = nested.clone();
+        elsewhere.id = Uuid::new_v4();
+        elsewhere.session_id = Uuid::new_v4();
+
+        let messages = vec![
+            nested.clone(),
+            unrelated,
+            elsewhere,
+            root.clone(),
+            reply.clone(),
+        ];
+        let ids = |thread: Vec<Message>| thread.into_iter().map(|m| m.id).collect::<Vec<_>>();
+        let expected = vec![root.id, reply.id, nested.id];
+        assert_eq!(ids(thread(&messages, &root.id).unwrap()), expected);
+        assert_eq!(ids(thread(&messages, &nested.id).unwrap()), expected);
+        assert!(thread(&messages, &Uuid::new_v4()).is_none());
+    }
+
+    #[test]
+    fn test_thread_with_missing_parent() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        // We never got the first message, only the replies
+        let root = text(a);
+        let mut reply = Message::new_text(root.session_id, b, a, "re".into()).with_reply_to(&root);
+        reply.timestamp -= Duration::minutes(1);
+        let nested =
+            Message::new_text(root.session_id, a, b, "re: re".into()).with_reply_to(&reply);
+
+        let messages = vec![nested.clone(), reply.clone()];
+        let found: Vec<_> = thread(&messages, &nested.id)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(found, vec![reply.id, nested.id]);
+        assert!(thread(&messages, &root.id).is_none());
+    }
+}
@@ -1,7 +1,8 @@
 use crate::{NetworkAddress, UserProfile, UserId, UserStatus};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -41,18 +42,48 @@ impl Peer {
 #[derive(Debug, Clone)]
 pub struct PeerRegistry {
     peers: Arc<RwLock<HashMap<UserId, Peer>>>,
+    static_addresses: Arc<RwLock<HashSet<NetworkAddress>>>,
+    /// First public key seen for each user, kept after the peer is removed
+    pinned_keys: Arc<RwLock<HashMap<UserId, Vec<u8>>>>,
 }
 
 impl PeerRegistry {
     pub fn new() -> Self {
         Self {
             peers: Arc::new(RwLock::new(HashMap::new())),
+            static_addresses: Arc::new(RwLock::new(HashSet::new())),
+            pinned_keys: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    pub async fn add_peer(&self, peer: Peer) {
+    /// Add or update a peer, pinning its public key the first time one is seen
+    ///
+    /// Returns `false`, leaving the registry unchanged, if the peer comes with
+    /// a different key than the one on record. A peer without a key keeps the
+    /// pinned one.
+    pub async fn add_peer(&self, mut peer: Peer) -> bool {
+        let user_id = peer.profile.user_id;
+        {
+            let mut pinned_keys = self.pinned_keys.write().await;
+            match (pinned_keys.get(&user_id), &peer.public_key) {
+                (Some(pinned), Some(key)) if pinned != key => return false,
+                (Some(pinned), None) => peer.public_key = Some(pinned.clone()),
+                (None, Some(key)) => {
+                    pinned_keys.insert(user_id, key.clone());
+                }
+                _ => {}
+            }
+        }
+
         let mut peers = self.peers.write().await;
-        peers.insert(peer.profile.user_id, peer);
+        peers.insert(user_id, peer);
+        true
+    }
+
+    /// Public key on record for a user, even if they've since gone offline
+    pub async fn pinned_key(&self, user_id: &UserId) -> Option<Vec<u8>> {
+        let pinned_keys = self.pinned_keys.read().await;
+        pinned_keys.get(user_id).cloned()
     }
 
     pub async fn remove_peer(&self, user_id: &UserId) -> Option<Peer> {
@@ -87,13 +118,72 @@ impl PeerRegistry {
             .collect()
     }
 
+    /// Remove peers not seen within the timeout, keeping static peers
     pub async fn cleanup_offline_peers(&self, timeout_seconds: i64) {
+        let static_addresses = self.static_addresses.read().await;
         let mut peers = self.peers.write().await;
         let now = Utc::now();
         peers.retain(|_, peer| {
-            now.signed_duration_since(peer.last_seen).num_seconds() < timeout_seconds
+            static_addresses.contains(&peer.address)
+                || now.signed_duration_since(peer.last_seen).num_seconds() < timeout_seconds
         });
     }
+
+    /// Add a manually configured peer address
+    pub async fn add_static_address(&self, address: NetworkAddress) -> bool {
+        let mut static_addresses = self.static_addresses.write().await;
+        static_addresses.insert(address)
+    }
+
+    /// Remove a manually configured peer address
+    pub async fn remove_static_address(&self, address: &NetworkAddress) -> bool {
+        let mut static_addresses = self.static_addresses.write().await;
+        static_addresses.remove(address)
+    }
+
+    pub async fn get_static_addresses(&self) -> Vec<NetworkAddress> {
+        let static_addresses = self.static_addresses.read().await;
+        static_addresses.iter().cloned().collect()
+    }
+
+    pub async fn is_static(&self, address: &NetworkAddress) -> bool {
+        let static_addresses = self.static_addresses.read().await;
+        static_addresses.contains(address)
+    }
+}
+
+/// Persisted list of manually added peer addresses
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StaticPeerList {
+    pub addresses: Vec<NetworkAddress>,
+}
+
+impl StaticPeerList {
+    /// Load the list from a JSON file, returning an empty list if it doesn't exist
+    pub async fn load(path: &Path) -> crate::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the list as JSON, creating the parent directory if needed
+    pub async fn save(&self, path: &Path) -> crate::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_vec_pretty(self)?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    /// Register every address with the peer registry
+    pub async fn apply_to(&self, registry: &PeerRegistry) {
+        for address in &self.addresses {
+            registry.add_static_address(address.clone()).await;
+        }
+    }
 }
 
 impl Default for PeerRegistry {
@@ -101,3 +191,82 @@ impl Default for PeerRegistry {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn address(last: u8) -> NetworkAddress {
+        NetworkAddress::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, last)), 37843)
+    }
+
+    /// A peer last seen `seconds_ago`
+    fn peer_at(address: NetworkAddress, seconds_ago: i64) -> Peer {
+        let mut peer = Peer::new(UserProfile::new("peer".into(), "Peer".into()), address);
+        peer.last_seen = Utc::now() - chrono::Duration::seconds(seconds_ago);
+        peer
+    }
+
+    #[tokio::test]
+    async fn test_static_peer_list_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lan-chat-peers-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("nested").join("static_peers.json");
+
+        // Nothing saved yet
+        assert!(StaticPeerList::load(&path)
+            .await
+            .unwrap()
+            .addresses
+            .is_empty());
+
+        let list = StaticPeerList {
+            addresses: vec![address(10), address(11)],
+        };
+        list.save(&path).await.unwrap();
+        let loaded = StaticPeerList::load(&path).await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        assert_eq!(loaded.addresses, list.addresses);
+    }
+
+    #[tokio::test]
+    async fn test_apply_static_peers() {
+        let registry = PeerRegistry::new();
+        let list = StaticPeerList {
+            addresses: vec![address(10), address(11)],
+        };
+        list.apply_to(&registry).await;
+
+        let mut addresses = registry.get_static_addresses().await;
+        addresses.sort_by_key(|a| a.ip);
+        assert_eq!(addresses, list.addresses);
+        assert!(registry.is_static(&address(10)).await);
+        assert!(!registry.is_static(&address(12)).await);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_keeps_static_peers() {
+        let registry = PeerRegistry::new();
+        let fixed = peer_at(address(10), 600);
+        let discovered = peer_at(address(11), 600);
+        let recent = peer_at(address(12), 1);
+        for peer in [&fixed, &discovered, &recent] {
+            registry.add_peer(peer.clone()).await;
+        }
+        registry.add_static_address(address(10)).await;
+
+        registry.cleanup_offline_peers(45).await;
+        assert!(registry.get_peer(&fixed.profile.user_id).await.is_some());
+        assert!(registry
+            .get_peer(&discovered.profile.user_id)
+            .await
+            .is_none());
+        assert!(registry.get_peer(&recent.profile.user_id).await.is_some());
+
+        // Once it's no longer static, it goes like any other
+        registry.remove_static_address(&address(10)).await;
+        registry.cleanup_offline_peers(45).await;
+        assert!(registry.get_peer(&fixed.profile.user_id).await.is_none());
+    }
+}
@@ -26,6 +26,17 @@ impl NetworkAddress {
     pub fn to_socket_addr(&self) -> std::net::SocketAddr {
         std::net::SocketAddr::new(self.ip, self.port)
     }
+
+    /// Parse `ip:port`, or a bare IP using `default_port`
+    pub fn parse(s: &str, default_port: u16) -> Option<Self> {
+        let s = s.trim();
+        if let Ok(addr) = s.parse::<std::net::SocketAddr>() {
+            return Some(Self::new(addr.ip(), addr.port()));
+        }
+        s.parse::<IpAddr>()
+            .ok()
+            .map(|ip| Self::new(ip, default_port))
+    }
 }
 
 /// User status
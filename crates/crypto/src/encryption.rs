@@ -1,6 +1,6 @@
 use crate::{CryptoError, Result};
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Key, Nonce,
 };
 use rand::RngCore;
@@ -49,6 +49,16 @@ impl AesEncryption {
 
     /// Encrypt data using AES-256-GCM
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<EncryptedData> {
+        self.encrypt_with_aad(plaintext, &[])
+    }
+
+    /// Decrypt data using AES-256-GCM
+    pub fn decrypt(&self, encrypted: &EncryptedData) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(encrypted, &[])
+    }
+
+    /// Encrypt data, binding it to associated data that is authenticated but not encrypted
+    pub fn encrypt_with_aad(&self, plaintext: &[u8], aad: &[u8]) -> Result<EncryptedData> {
         let key = Key::<Aes256Gcm>::from(self.key);
         let cipher = Aes256Gcm::new(&key);
 
@@ -59,7 +69,7 @@ impl AesEncryption {
 
         // Encrypt
         let ciphertext = cipher
-            .encrypt(&nonce, plaintext)
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
             .map_err(|e| CryptoError::EncryptionFailed(e.to_string()))?;
 
         Ok(EncryptedData {
@@ -68,8 +78,8 @@ impl AesEncryption {
         })
     }
 
-    /// Decrypt data using AES-256-GCM
-    pub fn decrypt(&self, encrypted: &EncryptedData) -> Result<Vec<u8>> {
+    /// Decrypt data; fails unless `aad` matches what it was encrypted with
+    pub fn decrypt_with_aad(&self, encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
         let key = Key::<Aes256Gcm>::from(self.key);
         let cipher = Aes256Gcm::new(&key);
 
@@ -81,8 +91,12 @@ impl AesEncryption {
         nonce_array.copy_from_slice(&encrypted.nonce);
         let nonce = Nonce::from(nonce_array);
 
+        let payload = Payload {
+            msg: encrypted.ciphertext.as_ref(),
+            aad,
+        };
         cipher
-            .decrypt(&nonce, encrypted.ciphertext.as_ref())
+            .decrypt(&nonce, payload)
             .map_err(|e| CryptoError::DecryptionFailed(e.to_string()))
     }
 }
@@ -187,6 +201,15 @@ mod tests {
         assert_eq!(plaintext, &decrypted[..]);
     }
 
+    #[test]
+    fn test_aes_associated_data() {
+        let aes = AesEncryption::new();
+
+        let encrypted = aes.encrypt_with_aad(b"chunk", b"index 1").unwrap();
+        assert_eq!(aes.decrypt_with_aad(&encrypted, b"index 1").unwrap(), b"chunk");
+        assert!(aes.decrypt_with_aad(&encrypted, b"index 2").is_err());
+    }
+
     #[test]
     fn test_hybrid_encryption() {
         let keypair = KeyPair::generate().unwrap();
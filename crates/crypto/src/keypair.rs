@@ -28,6 +28,18 @@ impl KeyPair {
         })
     }
 
+    /// Rebuild a key pair from its private key, as exported by
+    /// `export_private_key_pem`
+    pub fn from_private_key_pem(pem: &str) -> Result<Self> {
+        let private_key = Self::import_private_key_pem(pem)?;
+        let public_key = RsaPublicKey::from(&private_key);
+
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
     /// Get the public key
     pub fn public_key(&self) -> &RsaPublicKey {
         &self.public_key
@@ -96,3 +108,18 @@ impl PublicKeyData {
         KeyPair::import_public_key_pem(&self.key_pem)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_private_key_round_trip() {
+        let keypair = KeyPair::generate().unwrap();
+        let pem = keypair.export_private_key_pem().unwrap();
+
+        let restored = KeyPair::from_private_key_pem(&pem).unwrap();
+        assert_eq!(restored.public_key(), keypair.public_key());
+        assert!(KeyPair::from_private_key_pem("not a key").is_err());
+    }
+}
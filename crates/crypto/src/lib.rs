@@ -22,6 +22,9 @@ pub enum CryptoError {
     #[error("Invalid key: {0}")]
     InvalidKey(String),
 
+    #[error("Signing failed: {0}")]
+    SigningFailed(String),
+
     #[error("Signature verification failed")]
     SignatureVerificationFailed,
 
@@ -1,6 +1,8 @@
 use crate::{CryptoError, Result};
 use ring::signature::{self, KeyPair as RingKeyPair};
+use rsa::{Pss, RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 /// Digital signature for message authentication
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,3 +51,42 @@ impl MessageSigner {
             .map_err(|_| CryptoError::SignatureVerificationFailed)
     }
 }
+
+/// RSA-PSS signature utilities for the identity key pair
+pub struct RsaSigner;
+
+impl RsaSigner {
+    /// Sign a message with an RSA private key
+    pub fn sign(private_key: &RsaPrivateKey, message: &[u8]) -> Result<Signature> {
+        let digest = Sha256::digest(message);
+        let signature = private_key
+            .sign_with_rng(&mut rand::thread_rng(), Pss::new::<Sha256>(), &digest)
+            .map_err(|e| CryptoError::SigningFailed(e.to_string()))?;
+
+        Ok(Signature { signature })
+    }
+
+    /// Verify a signature with an RSA public key
+    pub fn verify(public_key: &RsaPublicKey, message: &[u8], signature: &Signature) -> Result<()> {
+        let digest = Sha256::digest(message);
+        public_key
+            .verify(Pss::new::<Sha256>(), &digest, &signature.signature)
+            .map_err(|_| CryptoError::SignatureVerificationFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyPair;
+
+    #[test]
+    fn test_rsa_signature() {
+        let keypair = KeyPair::generate().unwrap();
+        let message = b"Announce";
+
+        let signature = RsaSigner::sign(keypair.private_key(), message).unwrap();
+        assert!(RsaSigner::verify(keypair.public_key(), message, &signature).is_ok());
+        assert!(RsaSigner::verify(keypair.public_key(), b"Goodbye", &signature).is_err());
+    }
+}
@@ -1,5 +1,9 @@
 pub mod service;
 pub mod protocol;
+pub mod network;
+pub mod scanner;
 
 pub use service::*;
 pub use protocol::*;
+pub use network::*;
+pub use scanner::*;
@@ -0,0 +1,96 @@
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::debug;
+
+/// IPv4 subnet of a local network interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalSubnet {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl LocalSubnet {
+    pub fn new(ip: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        Self { ip, netmask }
+    }
+
+    /// Subnet-directed broadcast address (e.g. 192.168.1.255 for 192.168.1.0/24)
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.ip) | !u32::from(self.netmask))
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(ip) & mask == u32::from(self.ip) & mask
+    }
+
+    /// Number of usable host addresses in the subnet
+    pub fn host_count(&self) -> u32 {
+        (!u32::from(self.netmask)).saturating_sub(1)
+    }
+
+    /// Usable host addresses, excluding the network and broadcast addresses
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let network = u32::from(self.ip) & u32::from(self.netmask);
+        let broadcast = u32::from(self.broadcast());
+        (network.saturating_add(1)..broadcast).map(Ipv4Addr::from)
+    }
+}
+
+/// List the IPv4 subnets of all non-loopback interfaces
+pub fn local_subnets() -> Vec<LocalSubnet> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            debug!("Failed to enumerate network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    interfaces
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(addr) => Some(LocalSubnet::new(addr.ip, addr.netmask)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Find the subnet the given local address belongs to
+///
+/// Falls back to a /24 around the address when the interface cannot be found,
+/// which matches the vast majority of home and office networks.
+pub fn subnet_for(ip: IpAddr) -> Option<LocalSubnet> {
+    let IpAddr::V4(ip) = ip else {
+        return None;
+    };
+
+    local_subnets()
+        .into_iter()
+        .find(|subnet| subnet.ip == ip)
+        .or_else(|| Some(LocalSubnet::new(ip, Ipv4Addr::new(255, 255, 255, 0))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet_broadcast() {
+        let subnet = LocalSubnet::new(Ipv4Addr::new(192, 168, 1, 42), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(subnet.broadcast(), Ipv4Addr::new(192, 168, 1, 255));
+
+        let subnet = LocalSubnet::new(Ipv4Addr::new(10, 0, 5, 7), Ipv4Addr::new(255, 255, 252, 0));
+        assert_eq!(subnet.broadcast(), Ipv4Addr::new(10, 0, 7, 255));
+        assert!(subnet.contains(Ipv4Addr::new(10, 0, 4, 1)));
+        assert!(!subnet.contains(Ipv4Addr::new(10, 0, 8, 1)));
+    }
+
+    #[test]
+    fn test_subnet_hosts() {
+        let subnet = LocalSubnet::new(Ipv4Addr::new(192, 168, 1, 42), Ipv4Addr::new(255, 255, 255, 252));
+        let hosts: Vec<_> = subnet.hosts().collect();
+        assert_eq!(hosts, vec![Ipv4Addr::new(192, 168, 1, 41), Ipv4Addr::new(192, 168, 1, 42)]);
+        assert_eq!(subnet.host_count(), 2);
+    }
+}
@@ -10,6 +10,16 @@ pub const MULTICAST_ADDR_V4: &str = "239.255.42.99";
 /// Multicast address for IPv6
 pub const MULTICAST_ADDR_V6: &str = "ff02::1";
 
+/// Sender signature over a discovery message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoverySignature {
+    /// Unix time in milliseconds when the message was signed
+    pub timestamp: u64,
+    /// Per-sender sequence number, strictly increasing
+    pub sequence: u64,
+    pub signature: Vec<u8>,
+}
+
 /// Discovery protocol messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DiscoveryMessage {
@@ -18,6 +28,8 @@ pub enum DiscoveryMessage {
         profile: UserProfile,
         address: NetworkAddress,
         public_key: Option<Vec<u8>>,
+        #[serde(default)]
+        signature: Option<DiscoverySignature>,
     },
 
     /// Request all peers to announce themselves
@@ -28,17 +40,42 @@ pub enum DiscoveryMessage {
         profile: UserProfile,
         address: NetworkAddress,
         public_key: Option<Vec<u8>>,
+        #[serde(default)]
+        signature: Option<DiscoverySignature>,
     },
 
     /// Announce going offline
     Goodbye {
         user_id: uuid::Uuid,
+        #[serde(default)]
+        signature: Option<DiscoverySignature>,
     },
 
     /// Heartbeat to maintain presence
     Heartbeat {
         user_id: uuid::Uuid,
         status: lan_chat_core::UserStatus,
+        #[serde(default)]
+        signature: Option<DiscoverySignature>,
+    },
+
+    /// Probe used to check whether multicast delivery works
+    Probe {
+        nonce: uuid::Uuid,
+        user_id: uuid::Uuid,
+        public_key: Option<Vec<u8>>,
+        #[serde(default)]
+        signature: Option<DiscoverySignature>,
+    },
+
+    /// Answer to another host's probe, sent to the multicast group
+    ProbeReply {
+        /// Nonce of the probe being answered
+        nonce: uuid::Uuid,
+        user_id: uuid::Uuid,
+        public_key: Option<Vec<u8>>,
+        #[serde(default)]
+        signature: Option<DiscoverySignature>,
     },
 }
 
@@ -50,4 +87,73 @@ impl DiscoveryMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(bytes)
     }
+
+    /// User the message claims to come from, for messages that must be signed
+    pub fn sender_id(&self) -> Option<uuid::Uuid> {
+        match self {
+            Self::Announce { profile, .. } | Self::DiscoveryResponse { profile, .. } => {
+                Some(profile.user_id)
+            }
+            Self::Goodbye { user_id, .. }
+            | Self::Heartbeat { user_id, .. }
+            | Self::Probe { user_id, .. }
+            | Self::ProbeReply { user_id, .. } => Some(*user_id),
+            Self::DiscoveryRequest => None,
+        }
+    }
+
+    /// Public key carried by the message itself, if any
+    pub fn announced_public_key(&self) -> Option<&Vec<u8>> {
+        match self {
+            Self::Announce { public_key, .. }
+            | Self::DiscoveryResponse { public_key, .. }
+            | Self::Probe { public_key, .. }
+            | Self::ProbeReply { public_key, .. } => public_key.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn signature(&self) -> Option<&DiscoverySignature> {
+        match self {
+            Self::Announce { signature, .. }
+            | Self::DiscoveryResponse { signature, .. }
+            | Self::Goodbye { signature, .. }
+            | Self::Heartbeat { signature, .. }
+            | Self::Probe { signature, .. }
+            | Self::ProbeReply { signature, .. } => signature.as_ref(),
+            Self::DiscoveryRequest => None,
+        }
+    }
+
+    /// Attach a signature; ignored for message types that aren't signed
+    pub fn set_signature(&mut self, value: DiscoverySignature) {
+        match self {
+            Self::Announce { signature, .. }
+            | Self::DiscoveryResponse { signature, .. }
+            | Self::Goodbye { signature, .. }
+            | Self::Heartbeat { signature, .. }
+            | Self::Probe { signature, .. }
+            | Self::ProbeReply { signature, .. } => *signature = Some(value),
+            Self::DiscoveryRequest => {}
+        }
+    }
+
+    /// Bytes covered by the signature: the unsigned message plus timestamp and sequence
+    pub fn signing_payload(&self, timestamp: u64, sequence: u64) -> Result<Vec<u8>, serde_json::Error> {
+        let mut unsigned = self.clone();
+        match &mut unsigned {
+            Self::Announce { signature, .. }
+            | Self::DiscoveryResponse { signature, .. }
+            | Self::Goodbye { signature, .. }
+            | Self::Heartbeat { signature, .. }
+            | Self::Probe { signature, .. }
+            | Self::ProbeReply { signature, .. } => *signature = None,
+            Self::DiscoveryRequest => {}
+        }
+
+        let mut payload = unsigned.to_bytes()?;
+        payload.extend_from_slice(&timestamp.to_be_bytes());
+        payload.extend_from_slice(&sequence.to_be_bytes());
+        Ok(payload)
+    }
 }
@@ -0,0 +1,395 @@
+use crate::network::{self, LocalSubnet};
+use lan_chat_core::{ChatEvent, NetworkAddress, Peer, PeerRegistry, UserId};
+use lan_chat_crypto::{KeyPair, RsaSigner, Signature};
+use lan_chat_protocol::{PeerConnection, ProtocolMessage, MESSAGING_PORT, PROBE_PURPOSE};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, info, warn};
+
+const DEFAULT_CONCURRENCY: usize = 64;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(800);
+// Larger subnets are narrowed to the /24 around our own address
+const MAX_SCAN_HOSTS: u32 = 1024;
+
+/// Result of probing a single host
+#[derive(Debug)]
+enum ProbeOutcome {
+    /// Nothing listening on the messaging port
+    Closed,
+    /// Port open, but the host didn't answer the probe
+    NoResponse,
+    /// A LAN Chat peer answered, signed with the key it sent
+    Peer(Peer),
+    /// The answer wasn't signed with the key it carried
+    Unverified,
+}
+
+/// Summary of a subnet scan, useful for diagnosing missing peers
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanReport {
+    /// Subnets that were scanned, as `ip/netmask`
+    pub subnets: Vec<String>,
+    /// Number of hosts probed
+    pub hosts_probed: usize,
+    /// Hosts with the messaging port open that didn't answer the probe, or
+    /// whose answer couldn't be verified
+    pub unresponsive_hosts: Vec<IpAddr>,
+    /// Peers that answered the probe
+    pub peers: Vec<Peer>,
+}
+
+/// Opt-in scanner that probes local subnets for the messaging port
+///
+/// An alternative to multicast discovery for networks that block it.
+pub struct SubnetScanner {
+    user_id: UserId,
+    peer_registry: PeerRegistry,
+    event_tx: mpsc::UnboundedSender<ChatEvent>,
+    port: u16,
+    concurrency: usize,
+    timeout: Duration,
+}
+
+impl SubnetScanner {
+    pub fn new(
+        user_id: UserId,
+        peer_registry: PeerRegistry,
+        event_tx: mpsc::UnboundedSender<ChatEvent>,
+    ) -> Self {
+        Self {
+            user_id,
+            peer_registry,
+            event_tx,
+            port: MESSAGING_PORT,
+            concurrency: DEFAULT_CONCURRENCY,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Scan every local IPv4 subnet
+    pub async fn scan(&self) -> ScanReport {
+        let mut report = ScanReport::default();
+
+        for subnet in network::local_subnets() {
+            let subnet_report = self.scan_subnet(subnet).await;
+            report.subnets.extend(subnet_report.subnets);
+            report.hosts_probed += subnet_report.hosts_probed;
+            report.unresponsive_hosts.extend(subnet_report.unresponsive_hosts);
+            report.peers.extend(subnet_report.peers);
+        }
+
+        report
+    }
+
+    /// Scan a single subnet, registering every new peer that answers
+    pub async fn scan_subnet(&self, subnet: LocalSubnet) -> ScanReport {
+        let subnet = if subnet.host_count() > MAX_SCAN_HOSTS {
+            warn!(
+                "Subnet {}/{} is too large to scan, limiting to /24",
+                subnet.ip, subnet.netmask
+            );
+            LocalSubnet::new(subnet.ip, Ipv4Addr::new(255, 255, 255, 0))
+        } else {
+            subnet
+        };
+
+        info!("Scanning {}/{} for peers on port {}", subnet.ip, subnet.netmask, self.port);
+
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        let mut report = ScanReport {
+            subnets: vec![format!("{}/{}", subnet.ip, subnet.netmask)],
+            ..Default::default()
+        };
+
+        for host in subnet.hosts().filter(|host| *host != subnet.ip) {
+            let permit = match Arc::clone(&semaphore).acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            let addr = SocketAddr::new(IpAddr::V4(host), self.port);
+            let probe_timeout = self.timeout;
+            report.hosts_probed += 1;
+
+            tasks.spawn(async move {
+                let outcome = probe_host(addr, probe_timeout).await;
+                drop(permit);
+                (addr, outcome)
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            let Ok((addr, outcome)) = result else {
+                continue;
+            };
+
+            match outcome {
+                ProbeOutcome::Closed => {}
+                ProbeOutcome::NoResponse => {
+                    debug!("{} has port {} open but didn't answer the probe", addr.ip(), self.port);
+                    report.unresponsive_hosts.push(addr.ip());
+                }
+                ProbeOutcome::Unverified => {
+                    warn!("Ignoring unverified probe response from {}", addr.ip());
+                    report.unresponsive_hosts.push(addr.ip());
+                }
+                ProbeOutcome::Peer(peer) => {
+                    let user_id = peer.profile.user_id;
+                    if user_id == self.user_id {
+                        continue;
+                    }
+
+                    // Peers we already know keep their registry entry; the
+                    // scan only confirms they're still there
+                    let known = self.peer_registry.get_peer(&user_id).await.is_some();
+                    let accepted = if known {
+                        self.peer_registry.pinned_key(&user_id).await == peer.public_key
+                    } else {
+                        self.peer_registry.add_peer(peer.clone()).await
+                    };
+                    if !accepted {
+                        warn!("Public key mismatch for {} at {}", user_id, addr);
+                        continue;
+                    }
+                    if !known {
+                        let _ = self.event_tx.send(ChatEvent::PeerDiscovered(peer.clone()));
+                    }
+
+                    info!("✅ Peer found by scan: {} at {}", peer.profile.display_name, addr);
+                    report.peers.push(peer);
+                }
+            }
+        }
+
+        info!(
+            "Scan finished: {} hosts probed, {} peers, {} unresponsive",
+            report.hosts_probed,
+            report.peers.len(),
+            report.unresponsive_hosts.len()
+        );
+
+        report
+    }
+}
+
+/// Connect to a host and exchange a probe message
+async fn probe_host(addr: SocketAddr, probe_timeout: Duration) -> ProbeOutcome {
+    let stream = match timeout(probe_timeout, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return ProbeOutcome::Closed,
+    };
+
+    let mut conn = PeerConnection::new(stream);
+
+    let nonce = uuid::Uuid::new_v4();
+    let response = timeout(probe_timeout, async {
+        conn.send_message(&ProtocolMessage::Probe { nonce }).await?;
+        conn.receive_message().await
+    })
+    .await;
+
+    match response {
+        Ok(Ok(ProtocolMessage::ProbeResponse {
+            profile,
+            public_key,
+            signature,
+        })) => {
+            let payload = ProtocolMessage::proof_payload(PROBE_PURPOSE, &nonce, &profile.user_id);
+            let verified = KeyPair::import_public_key_bytes(&public_key).is_ok_and(|key| {
+                RsaSigner::verify(&key, &payload, &Signature { signature }).is_ok()
+            });
+            if !verified {
+                return ProbeOutcome::Unverified;
+            }
+
+            let mut peer = Peer::new(profile, NetworkAddress::new(addr.ip(), addr.port()));
+            peer.public_key = Some(public_key);
+            ProbeOutcome::Peer(peer)
+        }
+        _ => ProbeOutcome::NoResponse,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lan_chat_core::UserProfile;
+    use tokio::net::TcpListener;
+
+    /// Our own address in a /30 whose only other host is 127.0.0.1
+    fn loopback_subnet() -> LocalSubnet {
+        LocalSubnet::new(
+            Ipv4Addr::new(127, 0, 0, 2),
+            Ipv4Addr::new(255, 255, 255, 252),
+        )
+    }
+
+    fn new_scanner(
+        registry: &PeerRegistry,
+        port: u16,
+    ) -> (SubnetScanner, mpsc::UnboundedReceiver<ChatEvent>) {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let scanner = SubnetScanner::new(uuid::Uuid::new_v4(), registry.clone(), event_tx)
+            .with_port(port)
+            .with_timeout(Duration::from_secs(5));
+        (scanner, event_rx)
+    }
+
+    /// Answer one probe on 127.0.0.1 as `profile`, signing with `signer`
+    async fn responder(profile: UserProfile, public_key: Vec<u8>, signer: Option<KeyPair>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = PeerConnection::new(stream);
+            let Ok(ProtocolMessage::Probe { nonce }) = conn.receive_message().await else {
+                return;
+            };
+            let payload = ProtocolMessage::proof_payload(PROBE_PURPOSE, &nonce, &profile.user_id);
+            let signature = signer
+                .map(|key| {
+                    RsaSigner::sign(key.private_key(), &payload)
+                        .unwrap()
+                        .signature
+                })
+                .unwrap_or_default();
+            let response = ProtocolMessage::ProbeResponse {
+                profile,
+                public_key,
+                signature,
+            };
+            let _ = conn.send_message(&response).await;
+        });
+        port
+    }
+
+    fn identity() -> (UserProfile, KeyPair, Vec<u8>) {
+        let keypair = KeyPair::generate().unwrap();
+        let public_key = keypair.export_public_key_bytes().unwrap();
+        (
+            UserProfile::new("peer".into(), "Peer".into()),
+            keypair,
+            public_key,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_scan_registers_signed_responder() {
+        let (profile, keypair, public_key) = identity();
+        let user_id = profile.user_id;
+        let port = responder(profile, public_key.clone(), Some(keypair)).await;
+        let registry = PeerRegistry::new();
+        let (scanner, mut events) = new_scanner(&registry, port);
+
+        let report = scanner.scan_subnet(loopback_subnet()).await;
+        assert_eq!(report.hosts_probed, 1);
+        assert_eq!(report.peers.len(), 1);
+
+        let peer = registry.get_peer(&user_id).await.unwrap();
+        assert_eq!(peer.public_key, Some(public_key));
+        assert_eq!(peer.address.port, port);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ChatEvent::PeerDiscovered(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scan_ignores_unverified_responses() {
+        let (profile, _keypair, public_key) = identity();
+        let forger = KeyPair::generate().unwrap();
+        let registry = PeerRegistry::new();
+
+        for signer in [None, Some(forger)] {
+            let port = responder(profile.clone(), public_key.clone(), signer).await;
+            let (scanner, mut events) = new_scanner(&registry, port);
+
+            let report = scanner.scan_subnet(loopback_subnet()).await;
+            assert!(report.peers.is_empty());
+            assert_eq!(
+                report.unresponsive_hosts,
+                vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+            );
+            assert!(events.try_recv().is_err());
+        }
+        assert!(registry.get_peer(&profile.user_id).await.is_none());
+        assert!(registry.pinned_key(&profile.user_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scan_keeps_known_peers() {
+        let (profile, keypair, public_key) = identity();
+        let user_id = profile.user_id;
+        let registry = PeerRegistry::new();
+        let mut known = Peer::new(
+            profile.clone(),
+            NetworkAddress::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)), MESSAGING_PORT),
+        );
+        known.public_key = Some(public_key.clone());
+        registry.add_peer(known.clone()).await;
+
+        // The genuine peer answers under a new name: seen, but not replaced
+        let mut renamed = profile.clone();
+        renamed.display_name = "Renamed".into();
+        let port = responder(renamed, public_key, Some(keypair)).await;
+        let (scanner, mut events) = new_scanner(&registry, port);
+        let report = scanner.scan_subnet(loopback_subnet()).await;
+        assert_eq!(report.peers.len(), 1);
+        assert!(events.try_recv().is_err());
+
+        let peer = registry.get_peer(&user_id).await.unwrap();
+        assert_eq!(peer.profile.display_name, known.profile.display_name);
+        assert_eq!(peer.address, known.address);
+
+        // Someone else answering as that user with their own, validly signed key
+        let (_, impostor, impostor_key) = identity();
+        let port = responder(profile, impostor_key, Some(impostor)).await;
+        let (scanner, _events) = new_scanner(&registry, port);
+        let report = scanner.scan_subnet(loopback_subnet()).await;
+        assert!(report.peers.is_empty());
+        assert_eq!(
+            registry.get_peer(&user_id).await.unwrap().address,
+            known.address
+        );
+        assert_eq!(registry.pinned_key(&user_id).await, known.public_key);
+    }
+
+    #[tokio::test]
+    async fn test_large_subnets_are_narrowed() {
+        // A port nothing listens on, so every probe is refused right away
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let registry = PeerRegistry::new();
+        let (scanner, _events) = new_scanner(&registry, port);
+
+        let subnet = LocalSubnet::new(Ipv4Addr::new(127, 0, 5, 1), Ipv4Addr::new(255, 255, 0, 0));
+        let report = scanner.scan_subnet(subnet).await;
+        assert_eq!(report.subnets, vec!["127.0.5.1/255.255.255.0".to_string()]);
+        // The /24's 254 hosts, minus our own address
+        assert_eq!(report.hosts_probed, 253);
+        assert!(report.peers.is_empty());
+    }
+}
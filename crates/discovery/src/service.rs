@@ -1,10 +1,16 @@
-use crate::protocol::{DiscoveryMessage, DISCOVERY_PORT, MULTICAST_ADDR_V4};
-use lan_chat_core::{ChatEvent, NetworkAddress, Peer, PeerRegistry, UserProfile};
+use crate::network;
+use crate::protocol::{DiscoveryMessage, DiscoverySignature, DISCOVERY_PORT, MULTICAST_ADDR_V4};
+use lan_chat_core::{ChatEvent, NetworkAddress, Peer, PeerRegistry, UserId, UserProfile};
+use lan_chat_crypto::{KeyPair, RsaSigner, Signature};
 use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, RwLock};
 use tokio::time::{interval, Duration};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
 const BUFFER_SIZE: usize = 8192;
@@ -12,6 +18,22 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
 // Peer cleanup timeout: Remove peers after 3 missed heartbeats (45 seconds)
 // Note: Peers are marked offline after 30s (2 missed heartbeats) in peer.rs
 const PEER_TIMEOUT: i64 = 45; // seconds
+// How long to wait for another host to answer our multicast probe
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+// Signed messages older (or newer) than this are treated as stale
+const MAX_MESSAGE_AGE_MS: u64 = 5 * 60 * 1000;
+
+/// How discovery messages are delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiscoveryMode {
+    /// Multicast only
+    Multicast,
+    /// Always add subnet broadcast and unicast to known peers
+    Broadcast,
+    /// Use multicast, falling back to broadcast/unicast when it doesn't work
+    #[default]
+    Auto,
+}
 
 /// Peer discovery service using UDP multicast
 pub struct DiscoveryService {
@@ -20,6 +42,13 @@ pub struct DiscoveryService {
     peer_registry: PeerRegistry,
     event_tx: mpsc::UnboundedSender<ChatEvent>,
     public_key: Option<Vec<u8>>,
+    keypair: Option<KeyPair>,
+    mode: DiscoveryMode,
+    probe_nonce: uuid::Uuid,
+    multicast_confirmed: AtomicBool,
+    sequence: AtomicU64,
+    last_sequences: RwLock<HashMap<UserId, u64>>,
+    cancel_token: CancellationToken,
 }
 
 impl DiscoveryService {
@@ -35,6 +64,13 @@ impl DiscoveryService {
             peer_registry,
             event_tx,
             public_key: None,
+            keypair: None,
+            mode: DiscoveryMode::default(),
+            probe_nonce: uuid::Uuid::new_v4(),
+            multicast_confirmed: AtomicBool::new(false),
+            sequence: AtomicU64::new(0),
+            last_sequences: RwLock::new(HashMap::new()),
+            cancel_token: CancellationToken::new(),
         }
     }
 
@@ -43,6 +79,39 @@ impl DiscoveryService {
         self
     }
 
+    /// Use the identity key pair to sign outgoing messages
+    ///
+    /// Peers ignore unsigned announcements, heartbeats and goodbyes, so this
+    /// is required for others to see us.
+    pub fn with_keypair(mut self, keypair: KeyPair) -> Self {
+        self.public_key = keypair.export_public_key_bytes().ok();
+        self.keypair = Some(keypair);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: DiscoveryMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Whether multicast from another host has reached us
+    ///
+    /// The kernel loops our own multicast back to us, so only other hosts'
+    /// probes and replies count; on a network with client isolation they
+    /// never arrive.
+    pub fn multicast_works(&self) -> bool {
+        self.multicast_confirmed.load(Ordering::Relaxed)
+    }
+
+    /// Whether broadcast and unicast delivery are in use
+    pub fn fallback_active(&self) -> bool {
+        match self.mode {
+            DiscoveryMode::Multicast => false,
+            DiscoveryMode::Broadcast => true,
+            DiscoveryMode::Auto => !self.multicast_works(),
+        }
+    }
+
     /// Start the discovery service
     pub async fn start(self: Arc<Self>) -> lan_chat_core::Result<()> {
         info!("Starting discovery service on port {}", DISCOVERY_PORT);
@@ -52,7 +121,7 @@ impl DiscoveryService {
         let socket = Arc::new(socket);
 
         // Spawn receiver task
-        let receiver_handle = {
+        let mut receiver_handle = {
             let service = Arc::clone(&self);
             let socket = Arc::clone(&socket);
             tokio::spawn(async move {
@@ -64,6 +133,9 @@ impl DiscoveryService {
         // This prevents missing announcements due to race conditions
         tokio::time::sleep(Duration::from_millis(100)).await;
 
+        // Check whether multicast loops back before relying on it
+        self.probe_multicast(socket.as_ref()).await;
+
         // Send initial announcement
         self.announce(socket.as_ref()).await?;
 
@@ -72,7 +144,7 @@ impl DiscoveryService {
         self.send_discovery_request(socket.as_ref()).await?;
 
         // Spawn heartbeat task
-        let heartbeat_handle = {
+        let mut heartbeat_handle = {
             let service = Arc::clone(&self);
             let socket = Arc::clone(&socket);
             tokio::spawn(async move {
@@ -81,20 +153,25 @@ impl DiscoveryService {
         };
 
         // Spawn cleanup task
-        let cleanup_handle = {
+        let mut cleanup_handle = {
             let service = Arc::clone(&self);
             tokio::spawn(async move {
                 service.cleanup_loop().await;
             })
         };
 
-        // Wait for tasks
+        // Wait for tasks or shutdown
         tokio::select! {
-            _ = receiver_handle => warn!("Receiver task ended"),
-            _ = heartbeat_handle => warn!("Heartbeat task ended"),
-            _ = cleanup_handle => warn!("Cleanup task ended"),
+            _ = self.cancel_token.cancelled() => info!("Discovery service stopped"),
+            _ = &mut receiver_handle => warn!("Receiver task ended"),
+            _ = &mut heartbeat_handle => warn!("Heartbeat task ended"),
+            _ = &mut cleanup_handle => warn!("Cleanup task ended"),
         }
 
+        receiver_handle.abort();
+        heartbeat_handle.abort();
+        cleanup_handle.abort();
+
         Ok(())
     }
 
@@ -152,6 +229,11 @@ impl DiscoveryService {
             .set_multicast_loop_v4(true)
             .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
 
+        // Allow subnet-directed broadcasts for the fallback mode
+        socket
+            .set_broadcast(true)
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
         // Set the outgoing interface for multicast BEFORE joining - critical for cross-platform
         let interface_addr = if let IpAddr::V4(local_ipv4) = self.listen_address.ip {
             socket
@@ -223,40 +305,45 @@ impl DiscoveryService {
         let message = DiscoveryMessage::from_bytes(data)
             .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
 
+        if let Some(sender_id) = message.sender_id() {
+            // Ignore our own messages
+            if sender_id == self.profile.user_id {
+                return Ok(());
+            }
+
+            self.verify(&message).await?;
+        }
+
         match message {
             DiscoveryMessage::Announce {
                 profile,
                 address,
                 public_key,
+                ..
             } => {
-                // Ignore our own announcements
-                if profile.user_id == self.profile.user_id {
-                    debug!("Ignoring our own announcement");
-                    return Ok(());
-                }
-
                 info!("✅ Peer discovered: {} at {} (from {})", profile.display_name, address.ip, from);
 
                 let mut peer = Peer::new(profile, address);
                 peer.public_key = public_key;
 
-                self.peer_registry.add_peer(peer.clone()).await;
-                let _ = self.event_tx.send(ChatEvent::PeerDiscovered(peer));
+                self.register_peer(peer).await?;
             }
 
             DiscoveryMessage::DiscoveryRequest => {
                 info!("📡 Discovery request from {}, sending our info", from);
                 // Respond immediately with our full profile
                 // This is critical for fast peer discovery
-                let response = DiscoveryMessage::DiscoveryResponse {
+                let response = self.sign(DiscoveryMessage::DiscoveryResponse {
                     profile: self.profile.clone(),
                     address: self.listen_address.clone(),
                     public_key: self.public_key.clone(),
-                };
+                    signature: None,
+                })?;
 
-                // Send response back via multicast
-                // We use multicast instead of unicast so all devices benefit
-                if let Err(e) = self.send_multicast_from_handler(&response).await {
+                // Send response back via multicast so all devices benefit,
+                // and directly to the requester in case multicast is dropped
+                let requester = SocketAddr::new(from.ip(), DISCOVERY_PORT);
+                if let Err(e) = self.send_from_handler(&response, Some(requester)).await {
                     warn!("Failed to send discovery response: {}", e);
                 }
             }
@@ -265,58 +352,214 @@ impl DiscoveryService {
                 profile,
                 address,
                 public_key,
+                ..
             } => {
-                if profile.user_id == self.profile.user_id {
-                    return Ok(());
-                }
-
                 info!("✅ Peer discovered via response: {} at {} (from {})", profile.display_name, address.ip, from);
 
                 let mut peer = Peer::new(profile, address);
                 peer.public_key = public_key;
 
-                self.peer_registry.add_peer(peer.clone()).await;
-                let _ = self.event_tx.send(ChatEvent::PeerDiscovered(peer));
+                self.register_peer(peer).await?;
             }
 
-            DiscoveryMessage::Goodbye { user_id } => {
-                if user_id == self.profile.user_id {
-                    return Ok(());
-                }
-
+            DiscoveryMessage::Goodbye { user_id, .. } => {
                 debug!("Peer going offline: {}", user_id);
                 self.peer_registry.remove_peer(&user_id).await;
                 let _ = self.event_tx.send(ChatEvent::PeerDisconnected(user_id));
             }
 
-            DiscoveryMessage::Heartbeat { user_id, status } => {
-                if user_id == self.profile.user_id {
-                    return Ok(());
-                }
-
+            DiscoveryMessage::Heartbeat { user_id, status, .. } => {
                 self.peer_registry.update_peer_status(&user_id, status).await;
 
                 if let Some(_peer) = self.peer_registry.get_peer(&user_id).await {
                     let _ = self.event_tx.send(ChatEvent::PeerStatusChanged { user_id, status });
                 }
             }
+
+            DiscoveryMessage::Probe { nonce, .. } => {
+                // Our own probe, looped back by the kernel, proves nothing
+                if nonce == self.probe_nonce || from.ip() == self.listen_address.ip {
+                    return Ok(());
+                }
+
+                // Probes only go to the multicast group, so getting one means
+                // multicast from that host reaches us
+                debug!("Multicast probe from {}", from);
+                self.multicast_confirmed.store(true, Ordering::Relaxed);
+
+                let reply = self.sign(DiscoveryMessage::ProbeReply {
+                    nonce,
+                    user_id: self.profile.user_id,
+                    public_key: self.public_key.clone(),
+                    signature: None,
+                })?;
+                let socket = self.handler_socket()?;
+                self.send_datagram(&socket, &reply, multicast_dest()?)?;
+            }
+
+            // Signed like every message with a sender, so a spoofed reply
+            // can't switch the fallback off
+            DiscoveryMessage::ProbeReply { nonce, .. } => {
+                if nonce == self.probe_nonce && from.ip() != self.listen_address.ip {
+                    debug!("Multicast probe answered by {}", from);
+                    self.multicast_confirmed.store(true, Ordering::Relaxed);
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Add a peer whose message was verified, pinning its key
+    async fn register_peer(&self, peer: Peer) -> lan_chat_core::Result<()> {
+        let user_id = peer.profile.user_id;
+        if !self.peer_registry.add_peer(peer.clone()).await {
+            return Err(lan_chat_core::ChatError::Crypto(format!(
+                "Public key mismatch for {}",
+                user_id
+            )));
+        }
+        let _ = self.event_tx.send(ChatEvent::PeerDiscovered(peer));
+        Ok(())
+    }
+
+    /// Verify the signature, freshness and sequence number of a signed message
+    async fn verify(&self, message: &DiscoveryMessage) -> lan_chat_core::Result<()> {
+        let sender_id = message
+            .sender_id()
+            .ok_or_else(|| lan_chat_core::ChatError::Protocol("Message has no sender".into()))?;
+
+        let signature = message.signature().ok_or_else(|| {
+            lan_chat_core::ChatError::Protocol(format!("Unsigned discovery message from {}", sender_id))
+        })?;
+
+        if unix_millis().abs_diff(signature.timestamp) > MAX_MESSAGE_AGE_MS {
+            return Err(lan_chat_core::ChatError::Protocol(format!(
+                "Stale discovery message from {}",
+                sender_id
+            )));
+        }
+
+        // The key on record for this user wins over whatever is announced, so
+        // nobody can take over an existing registry entry with their own key,
+        // even after the peer has gone offline
+        let known_key = self.peer_registry.pinned_key(&sender_id).await;
+
+        let key = match (known_key, message.announced_public_key()) {
+            (Some(known), Some(announced)) if &known != announced => {
+                return Err(lan_chat_core::ChatError::Crypto(format!(
+                    "Public key mismatch for {}",
+                    sender_id
+                )));
+            }
+            (Some(known), _) => known,
+            (None, Some(announced)) => announced.clone(),
+            (None, None) => {
+                return Err(lan_chat_core::ChatError::Crypto(format!(
+                    "No public key to verify message from {}",
+                    sender_id
+                )));
+            }
+        };
+
+        let public_key = KeyPair::import_public_key_bytes(&key)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+
+        let payload = message
+            .signing_payload(signature.timestamp, signature.sequence)
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+
+        RsaSigner::verify(
+            &public_key,
+            &payload,
+            &Signature {
+                signature: signature.signature.clone(),
+            },
+        )
+        .map_err(|e| lan_chat_core::ChatError::Crypto(format!("{} from {}", e, sender_id)))?;
+
+        // Only accept each sequence number once
+        let mut last_sequences = self.last_sequences.write().await;
+        match last_sequences.get(&sender_id) {
+            Some(&last) if signature.sequence <= last => Err(lan_chat_core::ChatError::Protocol(
+                format!("Replayed discovery message from {}", sender_id),
+            )),
+            _ => {
+                last_sequences.insert(sender_id, signature.sequence);
+                Ok(())
+            }
+        }
+    }
+
+    /// Sign an outgoing message with our identity key
+    fn sign(&self, mut message: DiscoveryMessage) -> lan_chat_core::Result<DiscoveryMessage> {
+        let keypair = self.keypair.as_ref().ok_or_else(|| {
+            lan_chat_core::ChatError::Crypto("No identity key to sign discovery messages".into())
+        })?;
+
+        // Seeding the sequence from the clock keeps it increasing across restarts
+        let timestamp = unix_millis();
+        let previous = self
+            .sequence
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(timestamp.max(last + 1))
+            })
+            .unwrap_or_default();
+        let sequence = timestamp.max(previous + 1);
+
+        let payload = message
+            .signing_payload(timestamp, sequence)
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+
+        let signature = RsaSigner::sign(keypair.private_key(), &payload)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+
+        message.set_signature(DiscoverySignature {
+            timestamp,
+            sequence,
+            signature: signature.signature,
+        });
+
+        Ok(message)
+    }
+
     /// Send announcement to the network
     async fn announce(&self, socket: &Socket) -> lan_chat_core::Result<()> {
-        let message = DiscoveryMessage::Announce {
+        let message = self.sign(DiscoveryMessage::Announce {
             profile: self.profile.clone(),
             address: self.listen_address.clone(),
             public_key: self.public_key.clone(),
-        };
+            signature: None,
+        })?;
 
         info!("Sending announcement: {} from {}", self.profile.display_name, self.listen_address.ip);
         self.send_multicast(socket, &message).await
     }
 
+    /// Send a probe to the multicast group and wait for another host to answer
+    async fn probe_multicast(&self, socket: &Socket) {
+        let sent = self
+            .sign(DiscoveryMessage::Probe {
+                nonce: self.probe_nonce,
+                user_id: self.profile.user_id,
+                public_key: self.public_key.clone(),
+                signature: None,
+            })
+            .and_then(|message| self.send_datagram(socket, &message, multicast_dest()?));
+
+        if let Err(e) = sent {
+            warn!("Failed to send multicast probe: {}", e);
+        }
+
+        tokio::time::sleep(PROBE_TIMEOUT).await;
+
+        if self.multicast_works() {
+            info!("✓ Multicast probe answered by another host");
+        } else if self.mode == DiscoveryMode::Auto {
+            warn!("⚠ No other host answered the multicast probe, falling back to broadcast and unicast discovery");
+        }
+    }
+
     /// Heartbeat loop to maintain presence
     async fn heartbeat_loop(&self, socket: Arc<Socket>) {
         let mut ticker = interval(HEARTBEAT_INTERVAL);
@@ -324,18 +567,34 @@ impl DiscoveryService {
         loop {
             ticker.tick().await;
 
-            let message = DiscoveryMessage::Heartbeat {
-                user_id: self.profile.user_id,
-                status: self.profile.status,
-            };
-
             debug!("Sending heartbeat for {}", self.profile.display_name);
-            if let Err(e) = self.send_multicast(socket.as_ref(), &message).await {
+            if let Err(e) = self.send_heartbeat(socket.as_ref()).await {
                 error!("Failed to send heartbeat: {}", e);
             }
+
+            // Without multicast, keep asking until someone answers
+            if self.fallback_active() && self.peer_registry.get_all_peers().await.is_empty() {
+                if let Err(e) = self.send_discovery_request(socket.as_ref()).await {
+                    debug!("Failed to send discovery request: {}", e);
+                }
+            }
+
+            // Keep asking static peers we haven't heard from yet
+            self.query_static_peers(socket.as_ref()).await;
         }
     }
 
+    /// Send a signed heartbeat
+    async fn send_heartbeat(&self, socket: &Socket) -> lan_chat_core::Result<()> {
+        let message = self.sign(DiscoveryMessage::Heartbeat {
+            user_id: self.profile.user_id,
+            status: self.profile.status,
+            signature: None,
+        })?;
+
+        self.send_multicast(socket, &message).await
+    }
+
     /// Cleanup loop to remove offline peers
     async fn cleanup_loop(&self) {
         let mut ticker = interval(Duration::from_secs(30));
@@ -346,46 +605,31 @@ impl DiscoveryService {
         }
     }
 
-    /// Send a message to the multicast group
+    /// Send a message to the multicast group, plus broadcast and unicast
+    /// destinations when the fallback is active
     async fn send_multicast(
         &self,
         socket: &Socket,
         message: &DiscoveryMessage,
     ) -> lan_chat_core::Result<()> {
-        let data = message
-            .to_bytes()
-            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
-
-        let multicast_addr: Ipv4Addr = MULTICAST_ADDR_V4
-            .parse()
-            .map_err(|e| lan_chat_core::ChatError::Network(format!("Invalid multicast address: {}", e)))?;
-
-        let dest = SocketAddr::new(IpAddr::V4(multicast_addr), DISCOVERY_PORT);
-
-        socket
-            .send_to(&data, &dest.into())
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
-
-        Ok(())
+        let destinations = self.destinations(None).await?;
+        self.send_to_all(socket, message, &destinations)
     }
 
-    /// Send a message to the multicast group from a message handler
+    /// Send a message from a message handler
     /// This creates a temporary socket for sending
-    async fn send_multicast_from_handler(
+    async fn send_from_handler(
         &self,
         message: &DiscoveryMessage,
+        reply_to: Option<SocketAddr>,
     ) -> lan_chat_core::Result<()> {
-        let data = message
-            .to_bytes()
-            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
-
-        let multicast_addr: Ipv4Addr = MULTICAST_ADDR_V4
-            .parse()
-            .map_err(|e| lan_chat_core::ChatError::Network(format!("Invalid multicast address: {}", e)))?;
-
-        let dest = SocketAddr::new(IpAddr::V4(multicast_addr), DISCOVERY_PORT);
+        let socket = self.handler_socket()?;
+        let destinations = self.destinations(reply_to).await?;
+        self.send_to_all(&socket, message, &destinations)
+    }
 
-        // Create a temporary socket for sending
+    /// Temporary socket for sending from a message handler
+    fn handler_socket(&self) -> lan_chat_core::Result<Socket> {
         let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
             .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
 
@@ -396,6 +640,85 @@ impl DiscoveryService {
                 .map_err(|e| lan_chat_core::ChatError::Network(format!("Failed to set multicast interface: {}", e)))?;
         }
 
+        socket
+            .set_broadcast(true)
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        Ok(socket)
+    }
+
+    /// Collect the addresses a discovery message should go to
+    async fn destinations(
+        &self,
+        reply_to: Option<SocketAddr>,
+    ) -> lan_chat_core::Result<Vec<SocketAddr>> {
+        let mut destinations = vec![multicast_dest()?];
+
+        if let Some(addr) = reply_to {
+            destinations.push(addr);
+        }
+
+        // Static peers are usually on other subnets, so always unicast to them
+        for address in self.peer_registry.get_static_addresses().await {
+            let addr = SocketAddr::new(address.ip, DISCOVERY_PORT);
+            if !destinations.contains(&addr) {
+                destinations.push(addr);
+            }
+        }
+
+        if self.fallback_active() {
+            if let Some(subnet) = network::subnet_for(self.listen_address.ip) {
+                destinations.push(SocketAddr::new(IpAddr::V4(subnet.broadcast()), DISCOVERY_PORT));
+            }
+
+            for peer in self.peer_registry.get_all_peers().await {
+                let addr = SocketAddr::new(peer.address.ip, DISCOVERY_PORT);
+                if !destinations.contains(&addr) {
+                    destinations.push(addr);
+                }
+            }
+        }
+
+        Ok(destinations)
+    }
+
+    /// Send a message to every destination, succeeding if any send succeeds
+    fn send_to_all(
+        &self,
+        socket: &Socket,
+        message: &DiscoveryMessage,
+        destinations: &[SocketAddr],
+    ) -> lan_chat_core::Result<()> {
+        let mut last_error = None;
+        let mut sent = false;
+
+        for dest in destinations {
+            match self.send_datagram(socket, message, *dest) {
+                Ok(()) => sent = true,
+                Err(e) => {
+                    debug!("Failed to send discovery message to {}: {}", dest, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if !sent => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send a single datagram
+    fn send_datagram(
+        &self,
+        socket: &Socket,
+        message: &DiscoveryMessage,
+        dest: SocketAddr,
+    ) -> lan_chat_core::Result<()> {
+        let data = message
+            .to_bytes()
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+
         socket
             .send_to(&data, &dest.into())
             .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
@@ -410,12 +733,255 @@ impl DiscoveryService {
         self.send_multicast(socket, &message).await
     }
 
-    /// Send goodbye message before shutting down
-    pub async fn shutdown(&self, socket: &Socket) -> lan_chat_core::Result<()> {
-        let message = DiscoveryMessage::Goodbye {
+    /// Send a unicast discovery request to a specific host
+    pub async fn query_address(&self, ip: IpAddr) -> lan_chat_core::Result<()> {
+        let dest = SocketAddr::new(ip, DISCOVERY_PORT);
+        let socket = Socket::new(Domain::for_address(dest), Type::DGRAM, Some(Protocol::UDP))
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        info!("Sending discovery request to {}", dest);
+        self.send_datagram(&socket, &DiscoveryMessage::DiscoveryRequest, dest)
+    }
+
+    /// Send discovery requests to static peers that aren't in the registry
+    async fn query_static_peers(&self, socket: &Socket) {
+        let known: Vec<NetworkAddress> = self
+            .peer_registry
+            .get_all_peers()
+            .await
+            .into_iter()
+            .map(|peer| peer.address)
+            .collect();
+
+        for address in self.peer_registry.get_static_addresses().await {
+            if known.contains(&address) {
+                continue;
+            }
+
+            let dest = SocketAddr::new(address.ip, DISCOVERY_PORT);
+            if let Err(e) = self.send_datagram(socket, &DiscoveryMessage::DiscoveryRequest, dest) {
+                debug!("Failed to query static peer {}: {}", dest, e);
+            }
+        }
+    }
+
+    /// Send goodbye message and stop all discovery tasks
+    pub async fn shutdown(&self) -> lan_chat_core::Result<()> {
+        info!("Shutting down discovery service");
+
+        let result = self.send_goodbye().await;
+        self.cancel_token.cancel();
+        result
+    }
+
+    /// Tell peers we're going offline
+    async fn send_goodbye(&self) -> lan_chat_core::Result<()> {
+        let message = self.sign(DiscoveryMessage::Goodbye {
             user_id: self.profile.user_id,
+            signature: None,
+        })?;
+
+        self.send_from_handler(&message, None).await
+    }
+}
+
+/// Destination address of the multicast group
+fn multicast_dest() -> lan_chat_core::Result<SocketAddr> {
+    let multicast_addr: Ipv4Addr = MULTICAST_ADDR_V4
+        .parse()
+        .map_err(|e| lan_chat_core::ChatError::Network(format!("Invalid multicast address: {}", e)))?;
+
+    Ok(SocketAddr::new(IpAddr::V4(multicast_addr), DISCOVERY_PORT))
+}
+
+/// Current Unix time in milliseconds
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(keypair: KeyPair) -> (DiscoveryService, mpsc::UnboundedReceiver<ChatEvent>) {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let service = DiscoveryService::new(
+            UserProfile::new("local".into(), "Local".into()),
+            NetworkAddress::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 37843),
+            PeerRegistry::new(),
+            event_tx,
+        )
+        .with_keypair(keypair);
+        (service, event_rx)
+    }
+
+    #[tokio::test]
+    async fn test_signed_discovery_messages() {
+        let (local, _events) = service(KeyPair::generate().unwrap());
+        let (remote, _remote_events) = service(KeyPair::generate().unwrap());
+        let from = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DISCOVERY_PORT);
+        let remote_id = remote.profile.user_id;
+
+        let announce = remote
+            .sign(DiscoveryMessage::Announce {
+                profile: remote.profile.clone(),
+                address: remote.listen_address.clone(),
+                public_key: remote.public_key.clone(),
+                signature: None,
+            })
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+
+        local.handle_message(&announce, from).await.unwrap();
+        assert!(local.peer_registry.get_peer(&remote_id).await.is_some());
+
+        // Replaying the same packet is rejected
+        assert!(local.handle_message(&announce, from).await.is_err());
+
+        // Unsigned and forged goodbyes don't remove the peer
+        let unsigned = DiscoveryMessage::Goodbye {
+            user_id: remote_id,
+            signature: None,
         };
+        assert!(local.handle_message(&unsigned.to_bytes().unwrap(), from).await.is_err());
 
-        self.send_multicast(socket, &message).await
+        let (forger, _forger_events) = service(KeyPair::generate().unwrap());
+        let forged = forger
+            .sign(DiscoveryMessage::Goodbye {
+                user_id: remote_id,
+                signature: None,
+            })
+            .unwrap();
+        assert!(local.handle_message(&forged.to_bytes().unwrap(), from).await.is_err());
+        assert!(local.peer_registry.get_peer(&remote_id).await.is_some());
+
+        // A genuine goodbye does
+        let goodbye = remote
+            .sign(DiscoveryMessage::Goodbye {
+                user_id: remote_id,
+                signature: None,
+            })
+            .unwrap();
+        local.handle_message(&goodbye.to_bytes().unwrap(), from).await.unwrap();
+        assert!(local.peer_registry.get_peer(&remote_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_only_other_hosts_confirm_multicast() {
+        let (local, _events) = service(KeyPair::generate().unwrap());
+        let own = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DISCOVERY_PORT);
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), DISCOVERY_PORT);
+
+        let (remote, _remote_events) = service(KeyPair::generate().unwrap());
+        let reply = |nonce| {
+            remote
+                .sign(DiscoveryMessage::ProbeReply {
+                    nonce,
+                    user_id: remote.profile.user_id,
+                    public_key: remote.public_key.clone(),
+                    signature: None,
+                })
+                .unwrap()
+                .to_bytes()
+                .unwrap()
+        };
+
+        // Our own probe looped back by the kernel
+        let probe = local
+            .sign(DiscoveryMessage::Probe {
+                nonce: local.probe_nonce,
+                user_id: local.profile.user_id,
+                public_key: local.public_key.clone(),
+                signature: None,
+            })
+            .unwrap();
+        local.handle_message(&probe.to_bytes().unwrap(), own).await.unwrap();
+        assert!(!local.multicast_works());
+
+        // A reply to someone else's probe, or one from our own host
+        local.handle_message(&reply(uuid::Uuid::new_v4()), other).await.unwrap();
+        local.handle_message(&reply(local.probe_nonce), own).await.unwrap();
+        assert!(!local.multicast_works());
+
+        // An unsigned reply, or one signed with a key other than the sender's
+        let unsigned = DiscoveryMessage::ProbeReply {
+            nonce: local.probe_nonce,
+            user_id: remote.profile.user_id,
+            public_key: remote.public_key.clone(),
+            signature: None,
+        };
+        assert!(local.handle_message(&unsigned.to_bytes().unwrap(), other).await.is_err());
+        let (forger, _forger_events) = service(KeyPair::generate().unwrap());
+        let forged = forger
+            .sign(DiscoveryMessage::ProbeReply {
+                nonce: local.probe_nonce,
+                user_id: remote.profile.user_id,
+                public_key: remote.public_key.clone(),
+                signature: None,
+            })
+            .unwrap();
+        assert!(local.handle_message(&forged.to_bytes().unwrap(), other).await.is_err());
+        assert!(!local.multicast_works());
+
+        local.handle_message(&reply(local.probe_nonce), other).await.unwrap();
+        assert!(local.multicast_works());
+        assert!(!local.fallback_active());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_key_outlives_peer() {
+        let (local, _events) = service(KeyPair::generate().unwrap());
+        let (remote, _remote_events) = service(KeyPair::generate().unwrap());
+        let from = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DISCOVERY_PORT);
+        let remote_id = remote.profile.user_id;
+
+        let announce = remote
+            .sign(DiscoveryMessage::Announce {
+                profile: remote.profile.clone(),
+                address: remote.listen_address.clone(),
+                public_key: remote.public_key.clone(),
+                signature: None,
+            })
+            .unwrap();
+        local.handle_message(&announce.to_bytes().unwrap(), from).await.unwrap();
+        local.peer_registry.remove_peer(&remote_id).await;
+
+        // Someone else announcing as the removed peer with their own key
+        let (forger, _forger_events) = service(KeyPair::generate().unwrap());
+        let forged = forger
+            .sign(DiscoveryMessage::Announce {
+                profile: remote.profile.clone(),
+                address: forger.listen_address.clone(),
+                public_key: forger.public_key.clone(),
+                signature: None,
+            })
+            .unwrap();
+        assert!(local.handle_message(&forged.to_bytes().unwrap(), from).await.is_err());
+        assert!(local.peer_registry.get_peer(&remote_id).await.is_none());
+        assert_eq!(local.peer_registry.pinned_key(&remote_id).await, remote.public_key);
+
+        // Registering the forged key directly is refused too
+        let mut peer = Peer::new(remote.profile.clone(), forger.listen_address.clone());
+        peer.public_key = forger.public_key.clone();
+        assert!(!local.peer_registry.add_peer(peer).await);
+
+        // Without a key pair nothing gets sent unsigned
+        let (event_tx, _rx) = mpsc::unbounded_channel();
+        let unsigned = DiscoveryService::new(
+            UserProfile::new("plain".into(), "Plain".into()),
+            NetworkAddress::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 37843),
+            PeerRegistry::new(),
+            event_tx,
+        );
+        assert!(unsigned
+            .sign(DiscoveryMessage::Goodbye {
+                user_id: unsigned.profile.user_id,
+                signature: None,
+            })
+            .is_err());
     }
 }
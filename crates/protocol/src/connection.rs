@@ -1,8 +1,12 @@
 use crate::messages::{MessageFrame, ProtocolMessage};
 use lan_chat_core::{ChatError, Result, UserId};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 
+/// Maximum size of a single protocol message
+const MAX_MESSAGE_SIZE: u32 = 10 * 1024 * 1024; // 10 MB
+
 /// A connection to a peer
 pub struct PeerConnection {
     stream: TcpStream,
@@ -25,59 +29,65 @@ impl PeerConnection {
         self.peer_id = Some(peer_id);
     }
 
+    /// Remote address of the connection
+    pub fn peer_addr(&self) -> Result<std::net::SocketAddr> {
+        self.stream
+            .peer_addr()
+            .map_err(|e| ChatError::Network(e.to_string()))
+    }
+
     /// Send a protocol message
     pub async fn send_message(&mut self, message: &ProtocolMessage) -> Result<()> {
-        let data = message
-            .to_bytes()
-            .map_err(|e| ChatError::Protocol(e.to_string()))?;
+        write_message(&mut self.stream, message).await
+    }
 
-        let frame = MessageFrame::new(data);
-        let frame_bytes = frame.to_bytes();
+    /// Receive a protocol message
+    pub async fn receive_message(&mut self) -> Result<ProtocolMessage> {
+        read_message(&mut self.stream).await
+    }
 
-        self.stream
-            .write_all(&frame_bytes)
-            .await
-            .map_err(|e| ChatError::Network(e.to_string()))?;
+    /// Split into halves that can be used from separate tasks
+    pub fn into_split(self) -> (ConnectionReader, ConnectionWriter) {
+        let (reader, writer) = self.stream.into_split();
+        (
+            ConnectionReader { stream: reader },
+            ConnectionWriter { stream: writer },
+        )
+    }
 
+    /// Close the connection
+    pub async fn close(mut self) -> Result<()> {
         self.stream
-            .flush()
+            .shutdown()
             .await
-            .map_err(|e| ChatError::Network(e.to_string()))?;
-
-        Ok(())
+            .map_err(|e| ChatError::Network(e.to_string()))
     }
+}
 
+/// Receiving half of a peer connection
+pub struct ConnectionReader {
+    stream: OwnedReadHalf,
+}
+
+impl ConnectionReader {
     /// Receive a protocol message
     pub async fn receive_message(&mut self) -> Result<ProtocolMessage> {
-        // Read message length (4 bytes, big-endian)
-        let length = self
-            .stream
-            .read_u32()
-            .await
-            .map_err(|e| ChatError::Network(e.to_string()))?;
-
-        // Validate length
-        const MAX_MESSAGE_SIZE: u32 = 10 * 1024 * 1024; // 10 MB
-        if length > MAX_MESSAGE_SIZE {
-            return Err(ChatError::Protocol(format!(
-                "Message too large: {} bytes",
-                length
-            )));
-        }
+        read_message(&mut self.stream).await
+    }
+}
 
-        // Read message data
-        let mut data = vec![0u8; length as usize];
-        self.stream
-            .read_exact(&mut data)
-            .await
-            .map_err(|e| ChatError::Network(e.to_string()))?;
+/// Sending half of a peer connection
+pub struct ConnectionWriter {
+    stream: OwnedWriteHalf,
+}
 
-        // Parse message
-        ProtocolMessage::from_bytes(&data)
-            .map_err(|e| ChatError::Protocol(e.to_string()))
+impl ConnectionWriter {
+    /// Send a protocol message
+    pub async fn send_message(&mut self, message: &ProtocolMessage) -> Result<()> {
+        write_message(&mut self.stream, message).await
     }
 
-    /// Close the connection
+    /// Close the sending side of the connection
     pub async fn close(mut self) -> Result<()> {
         self.stream
             .shutdown()
@@ -85,3 +95,56 @@ impl PeerConnection {
             .map_err(|e| ChatError::Network(e.to_string()))
     }
 }
+
+/// Write a length-prefixed protocol message
+async fn write_message<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    message: &ProtocolMessage,
+) -> Result<()> {
+    let data = message
+        .to_bytes()
+        .map_err(|e| ChatError::Protocol(e.to_string()))?;
+
+    let frame = MessageFrame::new(data);
+    let frame_bytes = frame.to_bytes();
+
+    stream
+        .write_all(&frame_bytes)
+        .await
+        .map_err(|e| ChatError::Network(e.to_string()))?;
+
+    stream
+        .flush()
+        .await
+        .map_err(|e| ChatError::Network(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Read a length-prefixed protocol message
+async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<ProtocolMessage> {
+    // Read message length (4 bytes, big-endian)
+    let length = stream
+        .read_u32()
+        .await
+        .map_err(|e| ChatError::Network(e.to_string()))?;
+
+    // Validate length
+    if length > MAX_MESSAGE_SIZE {
+        return Err(ChatError::Protocol(format!(
+            "Message too large: {} bytes",
+            length
+        )));
+    }
+
+    // Read message data
+    let mut data = vec![0u8; length as usize];
+    stream
+        .read_exact(&mut data)
+        .await
+        .map_err(|e| ChatError::Network(e.to_string()))?;
+
+    // Parse message
+    ProtocolMessage::from_bytes(&data)
+        .map_err(|e| ChatError::Protocol(e.to_string()))
+}
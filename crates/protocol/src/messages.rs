@@ -1,9 +1,18 @@
-use lan_chat_core::{Message, ReadReceipt, SessionId, TypingIndicator, UserId};
+use lan_chat_core::{Group, Message, ReadReceipt, SessionId, TypingIndicator, UserId, UserProfile};
 use lan_chat_crypto::{EncryptedData, EncryptedSessionKey};
 use serde::{Deserialize, Serialize};
 
 /// Protocol version
-pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// `proof_payload` purpose of a probe response signature
+pub const PROBE_PURPOSE: &str = "lan-chat probe";
+
+/// `proof_payload` purpose of the signature in a handshake acknowledgment
+pub const HANDSHAKE_ACK_PURPOSE: &str = "lan-chat handshake ack";
+
+/// `proof_payload` purpose of the signature in a handshake proof
+pub const HANDSHAKE_PROOF_PURPOSE: &str = "lan-chat handshake proof";
 
 /// Protocol messages exchanged between peers
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,12 +22,27 @@ pub enum ProtocolMessage {
         version: u32,
         user_id: UserId,
         public_key: Vec<u8>,
+        #[serde(default)]
+        profile: Option<UserProfile>,
+        /// For the other side to sign in its acknowledgment
+        nonce: uuid::Uuid,
     },
 
-    /// Handshake acknowledgment
+    /// Handshake acknowledgment, signed over the handshake's nonce
     HandshakeAck {
         user_id: UserId,
         public_key: Vec<u8>,
+        #[serde(default)]
+        profile: Option<UserProfile>,
+        /// For the connecting side to sign in its proof
+        nonce: uuid::Uuid,
+        signature: Vec<u8>,
+    },
+
+    /// Last step of the handshake: the connecting side's signature over the
+    /// acknowledgment's nonce, proving it holds the key it claimed
+    HandshakeProof {
+        signature: Vec<u8>,
     },
 
     /// Text/media message (encrypted)
@@ -28,6 +52,20 @@ pub enum ProtocolMessage {
         encrypted_data: Option<EncryptedData>,
     },
 
+    /// New content for a message the sender sent earlier, encrypted like a
+    /// message
+    MessageEdit {
+        message_id: uuid::Uuid,
+        edited_at: chrono::DateTime<chrono::Utc>,
+        encrypted_key: EncryptedSessionKey,
+        encrypted_data: EncryptedData,
+    },
+
+    /// Delete a message the sender sent earlier, for everyone
+    MessageDelete {
+        message_id: uuid::Uuid,
+    },
+
     /// Message acknowledgment
     MessageAck {
         message_id: uuid::Uuid,
@@ -48,6 +86,23 @@ pub enum ProtocolMessage {
         indicator: TypingIndicator,
     },
 
+    /// Invitation to a group, sent by an admin to each new member, and to
+    /// everyone when the group is created
+    GroupInvite {
+        group: Group,
+    },
+
+    /// New name, members or admins of a group, sent by an admin to the members
+    /// and to anyone removed
+    GroupUpdate {
+        group: Group,
+    },
+
+    /// The sender is leaving the group
+    GroupLeave {
+        session_id: SessionId,
+    },
+
     /// Request message history
     HistoryRequest {
         session_id: SessionId,
@@ -61,12 +116,29 @@ pub enum ProtocolMessage {
         messages: Vec<Message>,
     },
 
+    /// Lightweight probe used by subnet scanning, answered without a handshake
+    Probe {
+        nonce: uuid::Uuid,
+    },
+
+    /// Response to a probe, signed over the probe's nonce
+    ProbeResponse {
+        profile: UserProfile,
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+    },
+
     /// Ping for keep-alive
     Ping,
 
     /// Pong response
     Pong,
 
+    /// Orderly connection close
+    Close {
+        reason: String,
+    },
+
     /// Error message
     Error {
         code: u32,
@@ -82,6 +154,47 @@ impl ProtocolMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(bytes)
     }
+
+    /// Bytes signed to prove holding the key of `user_id`, in answer to a
+    /// nonce the other side picked
+    ///
+    /// `purpose` keeps a signature made for one exchange from being replayed
+    /// in another.
+    pub fn proof_payload(purpose: &str, nonce: &uuid::Uuid, user_id: &UserId) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(purpose.len() + 32);
+        payload.extend_from_slice(purpose.as_bytes());
+        payload.extend_from_slice(nonce.as_bytes());
+        payload.extend_from_slice(user_id.as_bytes());
+        payload
+    }
+}
+
+/// The parts of a message that travel encrypted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedContent {
+    pub content: String,
+    #[serde(default)]
+    pub reply_to: Option<uuid::Uuid>,
+    #[serde(default)]
+    pub thread_root: Option<uuid::Uuid>,
+}
+
+impl SealedContent {
+    /// Take the parts to encrypt out of a message, leaving them blank
+    pub fn take(message: &mut Message) -> Self {
+        Self {
+            content: std::mem::take(&mut message.content),
+            reply_to: message.reply_to.take(),
+            thread_root: message.thread_root.take(),
+        }
+    }
+
+    /// Put decrypted parts back into a message
+    pub fn restore(self, message: &mut Message) {
+        message.content = self.content;
+        message.reply_to = self.reply_to;
+        message.thread_root = self.thread_root;
+    }
 }
 
 /// Message frame with length prefix
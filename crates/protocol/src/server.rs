@@ -1,22 +1,38 @@
-use crate::connection::PeerConnection;
-use crate::messages::ProtocolMessage;
+use crate::connection::{ConnectionReader, ConnectionWriter, PeerConnection};
+use crate::messages::{
+    ProtocolMessage, SealedContent, HANDSHAKE_ACK_PURPOSE, HANDSHAKE_PROOF_PURPOSE,
+    PROBE_PURPOSE,
+};
 use crate::MESSAGING_PORT;
-use lan_chat_core::{ChatEvent, Message, PeerRegistry, UserProfile};
-use lan_chat_crypto::{HybridEncryption, KeyPair};
+use chrono::{DateTime, Utc};
+use lan_chat_core::{
+    direct_session_id, ChatEvent, Group, GroupRegistry, Message, NetworkAddress, Peer,
+    PeerRegistry, SessionId, UserId, UserProfile,
+};
+use lan_chat_crypto::{
+    EncryptedData, EncryptedSessionKey, HybridEncryption, KeyPair, RsaSigner, Signature,
+};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, RwLock};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
+// How long to wait for each close frame during shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Messaging server
 pub struct MessagingServer {
     profile: UserProfile,
     keypair: KeyPair,
     peer_registry: PeerRegistry,
-    connections: Arc<RwLock<HashMap<uuid::Uuid, PeerConnection>>>,
+    groups: GroupRegistry,
+    connections: Arc<RwLock<HashMap<uuid::Uuid, ConnectionWriter>>>,
     event_tx: mpsc::UnboundedSender<ChatEvent>,
+    cancel_token: CancellationToken,
 }
 
 impl MessagingServer {
@@ -30,11 +46,19 @@ impl MessagingServer {
             profile,
             keypair,
             peer_registry,
+            groups: GroupRegistry::new(),
             connections: Arc::new(RwLock::new(HashMap::new())),
             event_tx,
+            cancel_token: CancellationToken::new(),
         }
     }
 
+    /// Use a shared group registry, e.g. one loaded from disk
+    pub fn with_groups(mut self, groups: GroupRegistry) -> Self {
+        self.groups = groups;
+        self
+    }
+
     /// Start the messaging server
     pub async fn start(self: Arc<Self>) -> lan_chat_core::Result<()> {
         let addr = SocketAddr::from(([0, 0, 0, 0], MESSAGING_PORT));
@@ -45,7 +69,15 @@ impl MessagingServer {
         info!("Messaging server listening on {}", addr);
 
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                _ = self.cancel_token.cancelled() => {
+                    info!("Messaging server stopped");
+                    return Ok(());
+                }
+                accepted = listener.accept() => accepted,
+            };
+
+            match accepted {
                 Ok((stream, addr)) => {
                     debug!("New connection from {}", addr);
                     let server = Arc::clone(&self);
@@ -66,14 +98,17 @@ impl MessagingServer {
     async fn handle_connection(&self, stream: TcpStream) -> lan_chat_core::Result<()> {
         let mut conn = PeerConnection::new(stream);
 
-        // Perform handshake
-        let peer_id = self.perform_handshake(&mut conn).await?;
+        // Perform handshake (probes are answered and closed right away)
+        let Some(peer_id) = self.perform_handshake(&mut conn).await? else {
+            return Ok(());
+        };
         conn.set_peer_id(peer_id);
 
-        // Store connection
+        // Store the sending half, keep reading on this task
+        let (reader, writer) = conn.into_split();
         {
             let mut connections = self.connections.write().await;
-            connections.insert(peer_id, conn);
+            connections.insert(peer_id, writer);
         }
 
         // Notify connection established
@@ -81,36 +116,47 @@ impl MessagingServer {
             let _ = self.event_tx.send(ChatEvent::PeerConnected(peer));
         }
 
-        // Handle messages
+        self.read_loop(peer_id, reader).await;
+
+        Ok(())
+    }
+
+    /// Handle messages from a peer until the connection closes or we shut down
+    async fn read_loop(&self, peer_id: uuid::Uuid, mut reader: ConnectionReader) {
         loop {
-            let mut connections = self.connections.write().await;
-            let conn = connections.get_mut(&peer_id).unwrap();
+            let message = tokio::select! {
+                _ = self.cancel_token.cancelled() => break,
+                message = reader.receive_message() => message,
+            };
 
-            match conn.receive_message().await {
+            match message {
+                Ok(ProtocolMessage::Close { reason }) => {
+                    info!("Peer {} closed the connection: {}", peer_id, reason);
+                    break;
+                }
                 Ok(message) => {
-                    drop(connections); // Release lock before handling
                     if let Err(e) = self.handle_message(peer_id, message).await {
                         error!("Error handling message from {}: {}", peer_id, e);
                     }
                 }
                 Err(e) => {
                     error!("Connection error with {}: {}", peer_id, e);
-                    drop(connections);
-                    self.remove_connection(&peer_id).await;
-                    let _ = self.event_tx.send(ChatEvent::PeerDisconnected(peer_id));
                     break;
                 }
             }
         }
 
-        Ok(())
+        self.remove_connection(&peer_id).await;
+        let _ = self.event_tx.send(ChatEvent::PeerDisconnected(peer_id));
     }
 
     /// Perform handshake with peer
+    ///
+    /// Returns `None` if the peer only sent a scan probe.
     async fn perform_handshake(
         &self,
         conn: &mut PeerConnection,
-    ) -> lan_chat_core::Result<uuid::Uuid> {
+    ) -> lan_chat_core::Result<Option<uuid::Uuid>> {
         // Wait for handshake from peer
         let message = conn.receive_message().await?;
 
@@ -119,6 +165,8 @@ impl MessagingServer {
                 version,
                 user_id,
                 public_key,
+                profile,
+                nonce,
             } => {
                 if version != crate::messages::PROTOCOL_VERSION {
                     return Err(lan_chat_core::ChatError::Protocol(format!(
@@ -127,22 +175,63 @@ impl MessagingServer {
                     )));
                 }
 
-                // Send handshake acknowledgment
+                self.check_pinned_key(&user_id, &public_key).await?;
+
+                // Send handshake acknowledgment, signed to prove who we are
+                let ack_nonce = uuid::Uuid::new_v4();
                 let ack = ProtocolMessage::HandshakeAck {
                     user_id: self.profile.user_id,
                     public_key: self.keypair.export_public_key_bytes()
                         .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?,
+                    profile: Some(self.profile.clone()),
+                    nonce: ack_nonce,
+                    signature: self.prove(HANDSHAKE_ACK_PURPOSE, &nonce)?,
                 };
 
                 conn.send_message(&ack).await?;
 
-                // Update peer's public key
+                // The peer is only who it claims to be once it has signed our
+                // nonce with that user's key
+                match conn.receive_message().await? {
+                    ProtocolMessage::HandshakeProof { signature } => verify_proof(
+                        &public_key,
+                        HANDSHAKE_PROOF_PURPOSE,
+                        &ack_nonce,
+                        &user_id,
+                        signature,
+                    )?,
+                    _ => {
+                        return Err(lan_chat_core::ChatError::Protocol(
+                            "Expected handshake proof".into(),
+                        ))
+                    }
+                }
+
+                // Update peer's public key, or register peers that discovery
+                // can't see (e.g. someone who added us by address)
                 if let Some(mut peer) = self.peer_registry.get_peer(&user_id).await {
                     peer.public_key = Some(public_key);
-                    self.peer_registry.add_peer(peer).await;
+                    self.register_peer(peer).await?;
+                } else if let Some(profile) = profile.filter(|p| p.user_id == user_id) {
+                    let address = NetworkAddress::new(conn.peer_addr()?.ip(), MESSAGING_PORT);
+                    let mut peer = Peer::new(profile, address);
+                    peer.public_key = Some(public_key);
+                    self.register_peer(peer.clone()).await?;
+                    let _ = self.event_tx.send(ChatEvent::PeerDiscovered(peer));
                 }
 
-                Ok(user_id)
+                Ok(Some(user_id))
+            }
+            ProtocolMessage::Probe { nonce } => {
+                let response = ProtocolMessage::ProbeResponse {
+                    profile: self.profile.clone(),
+                    public_key: self.keypair.export_public_key_bytes()
+                        .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?,
+                    signature: self.prove(PROBE_PURPOSE, &nonce)?,
+                };
+
+                conn.send_message(&response).await?;
+                Ok(None)
             }
             _ => Err(lan_chat_core::ChatError::Protocol(
                 "Expected handshake message".into(),
@@ -150,6 +239,39 @@ impl MessagingServer {
         }
     }
 
+    /// Sign another side's nonce, proving we hold our identity key
+    fn prove(&self, purpose: &str, nonce: &uuid::Uuid) -> lan_chat_core::Result<Vec<u8>> {
+        let payload = ProtocolMessage::proof_payload(purpose, nonce, &self.profile.user_id);
+        RsaSigner::sign(self.keypair.private_key(), &payload)
+            .map(|signature| signature.signature)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))
+    }
+
+    /// Refuse a handshake key that differs from the one on record for the user
+    async fn check_pinned_key(
+        &self,
+        user_id: &UserId,
+        public_key: &[u8],
+    ) -> lan_chat_core::Result<()> {
+        match self.peer_registry.pinned_key(user_id).await {
+            Some(pinned) if pinned != public_key => Err(lan_chat_core::ChatError::Crypto(
+                format!("Public key mismatch for {}", user_id),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn register_peer(&self, peer: Peer) -> lan_chat_core::Result<()> {
+        let user_id = peer.profile.user_id;
+        if !self.peer_registry.add_peer(peer).await {
+            return Err(lan_chat_core::ChatError::Crypto(format!(
+                "Public key mismatch for {}",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+
     /// Handle incoming protocol message
     async fn handle_message(
         &self,
@@ -162,13 +284,27 @@ impl MessagingServer {
                 encrypted_key,
                 encrypted_data,
             } => {
+                // Peers only ever speak for themselves
+                if message.sender_id != peer_id {
+                    return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                        "{} can't send messages as {}",
+                        peer_id, message.sender_id
+                    )));
+                }
+
+                // Only members may post to a group
+                if let Some(group) = self.groups.get(&message.session_id).await {
+                    if !group.is_member(&peer_id) {
+                        return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                            "{} is not a member of group {}",
+                            peer_id, group.session_id
+                        )));
+                    }
+                }
+
                 // Decrypt if encrypted
                 if let (Some(key), Some(data)) = (encrypted_key, encrypted_data) {
-                    let plaintext =
-                        HybridEncryption::decrypt(self.keypair.private_key(), &key, &data)
-                            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
-                    message.content = String::from_utf8(plaintext)
-                        .map_err(|e| lan_chat_core::ChatError::InvalidData(e.to_string()))?;
+                    self.open(&mut message, &key, &data)?;
                 }
 
                 // Send acknowledgment
@@ -184,6 +320,28 @@ impl MessagingServer {
                 let _ = self.event_tx.send(ChatEvent::MessageReceived(message));
             }
 
+            ProtocolMessage::MessageEdit {
+                message_id,
+                edited_at,
+                encrypted_key,
+                encrypted_data,
+            } => {
+                let content = self.decrypt_text(&encrypted_key, &encrypted_data)?;
+                let _ = self.event_tx.send(ChatEvent::MessageEdited {
+                    message_id,
+                    edited_by: peer_id,
+                    content,
+                    edited_at,
+                });
+            }
+
+            ProtocolMessage::MessageDelete { message_id } => {
+                let _ = self.event_tx.send(ChatEvent::MessageDeleted {
+                    message_id,
+                    deleted_by: peer_id,
+                });
+            }
+
             ProtocolMessage::MessageAck { message_id } => {
                 let _ = self
                     .event_tx
@@ -204,6 +362,23 @@ impl MessagingServer {
                 let _ = self.event_tx.send(ChatEvent::TypingIndicator(indicator));
             }
 
+            ProtocolMessage::GroupInvite { group } => {
+                self.handle_group_invite(peer_id, group).await?;
+            }
+
+            ProtocolMessage::GroupUpdate { group } => {
+                self.handle_group_update(peer_id, group).await?;
+            }
+
+            ProtocolMessage::GroupLeave { session_id } => {
+                if let Some(mut group) = self.groups.get(&session_id).await {
+                    if group.remove_member(&peer_id) {
+                        self.groups.insert(group.clone()).await;
+                        let _ = self.event_tx.send(ChatEvent::GroupUpdated(group));
+                    }
+                }
+            }
+
             ProtocolMessage::Ping => {
                 self.send_to_peer(&peer_id, &ProtocolMessage::Pong)
                     .await?;
@@ -221,6 +396,225 @@ impl MessagingServer {
         Ok(())
     }
 
+    /// Join a group an admin invited us to
+    async fn handle_group_invite(
+        &self,
+        peer_id: UserId,
+        group: Group,
+    ) -> lan_chat_core::Result<()> {
+        // An invite for a group we're already in mustn't let a non-admin
+        // take it over
+        let inviter_is_admin = match self.groups.get(&group.session_id).await {
+            Some(known) => known.is_admin(&peer_id),
+            None => group.is_admin(&peer_id),
+        };
+        if !inviter_is_admin || !group.is_member(&self.profile.user_id) {
+            return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                "Invalid invite to group {} from {}",
+                group.session_id, peer_id
+            )));
+        }
+
+        // Nor take over our direct chat with a member or any peer we know
+        let own_id = self.profile.user_id;
+        let known_peers = self.peer_registry.get_all_peers().await;
+        let is_direct_chat = group
+            .members
+            .iter()
+            .copied()
+            .chain(known_peers.iter().map(|p| p.profile.user_id))
+            .chain([peer_id])
+            .any(|id| direct_session_id(&own_id, &id) == group.session_id);
+        if is_direct_chat {
+            return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                "Invite from {} reuses a direct chat's session",
+                peer_id
+            )));
+        }
+
+        self.groups.insert(group.clone()).await;
+        let _ = self.event_tx.send(ChatEvent::GroupUpdated(group));
+        Ok(())
+    }
+
+    /// Apply an admin's change to a group we're in
+    async fn handle_group_update(
+        &self,
+        peer_id: UserId,
+        group: Group,
+    ) -> lan_chat_core::Result<()> {
+        let known = self.groups.get(&group.session_id).await;
+        if !known.is_some_and(|known| known.is_admin(&peer_id)) {
+            return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                "{} can't change group {}",
+                peer_id, group.session_id
+            )));
+        }
+
+        if group.is_member(&self.profile.user_id) {
+            self.groups.insert(group.clone()).await;
+            let _ = self.event_tx.send(ChatEvent::GroupUpdated(group));
+        } else {
+            self.groups.remove(&group.session_id).await;
+            let _ = self.event_tx.send(ChatEvent::GroupRemoved {
+                session_id: group.session_id,
+            });
+        }
+        Ok(())
+    }
+
+    /// Create a group with us as its admin and invite the members
+    pub async fn create_group(
+        &self,
+        name: String,
+        members: Vec<UserId>,
+    ) -> lan_chat_core::Result<Group> {
+        let group = Group::new(name, self.profile.user_id, members);
+        self.groups.insert(group.clone()).await;
+
+        let invite = ProtocolMessage::GroupInvite {
+            group: group.clone(),
+        };
+        self.send_to_members(group.other_members(&self.profile.user_id), &invite)
+            .await;
+
+        let _ = self.event_tx.send(ChatEvent::GroupUpdated(group.clone()));
+        Ok(group)
+    }
+
+    /// Add members to a group we're an admin of
+    pub async fn invite_to_group(
+        &self,
+        session_id: &SessionId,
+        members: Vec<UserId>,
+    ) -> lan_chat_core::Result<Group> {
+        let mut group = self.admin_group(session_id).await?;
+        let added: Vec<_> = members
+            .into_iter()
+            .filter(|m| group.add_member(*m))
+            .collect();
+        if added.is_empty() {
+            return Ok(group);
+        }
+        self.groups.insert(group.clone()).await;
+
+        let existing: Vec<_> = group
+            .other_members(&self.profile.user_id)
+            .filter(|m| !added.contains(m))
+            .collect();
+        let update = ProtocolMessage::GroupUpdate {
+            group: group.clone(),
+        };
+        self.send_to_members(existing, &update).await;
+        let invite = ProtocolMessage::GroupInvite {
+            group: group.clone(),
+        };
+        self.send_to_members(&added, &invite).await;
+
+        let _ = self.event_tx.send(ChatEvent::GroupUpdated(group.clone()));
+        Ok(group)
+    }
+
+    /// Remove a member from a group we're an admin of
+    pub async fn remove_from_group(
+        &self,
+        session_id: &SessionId,
+        user_id: &UserId,
+    ) -> lan_chat_core::Result<Group> {
+        let mut group = self.admin_group(session_id).await?;
+        if *user_id == self.profile.user_id || !group.remove_member(user_id) {
+            return Ok(group);
+        }
+        self.groups.insert(group.clone()).await;
+
+        // The removed member hears about it too, so they stop posting
+        let update = ProtocolMessage::GroupUpdate {
+            group: group.clone(),
+        };
+        let recipients = group
+            .other_members(&self.profile.user_id)
+            .chain(std::iter::once(user_id));
+        self.send_to_members(recipients, &update).await;
+
+        let _ = self.event_tx.send(ChatEvent::GroupUpdated(group.clone()));
+        Ok(group)
+    }
+
+    /// Leave a group, telling the other members
+    pub async fn leave_group(&self, session_id: &SessionId) -> lan_chat_core::Result<()> {
+        let group = self.groups.remove(session_id).await.ok_or_else(|| {
+            lan_chat_core::ChatError::InvalidData(format!("Unknown group {}", session_id))
+        })?;
+
+        let leave = ProtocolMessage::GroupLeave {
+            session_id: *session_id,
+        };
+        self.send_to_members(group.other_members(&self.profile.user_id), &leave)
+            .await;
+
+        let _ = self.event_tx.send(ChatEvent::GroupRemoved {
+            session_id: *session_id,
+        });
+        Ok(())
+    }
+
+    /// Send a message to every other member of its group, encrypted for each
+    ///
+    /// Each member's copy is addressed to them. Returns the members it
+    /// couldn't be delivered to, e.g. because they're offline.
+    pub async fn send_group_message(&self, message: Message) -> lan_chat_core::Result<Vec<UserId>> {
+        let group = self
+            .groups
+            .get(&message.session_id)
+            .await
+            .filter(|g| g.is_member(&self.profile.user_id))
+            .ok_or_else(|| {
+                lan_chat_core::ChatError::InvalidData(format!(
+                    "Not a member of group {}",
+                    message.session_id
+                ))
+            })?;
+
+        let mut undelivered = Vec::new();
+        for member in group.other_members(&self.profile.user_id) {
+            let mut copy = message.clone();
+            copy.recipient_id = *member;
+            if let Err(e) = self.send_encrypted_message(member, copy).await {
+                debug!("Failed to send group message to {}: {}", member, e);
+                undelivered.push(*member);
+            }
+        }
+
+        Ok(undelivered)
+    }
+
+    /// A group we're in and an admin of
+    async fn admin_group(&self, session_id: &SessionId) -> lan_chat_core::Result<Group> {
+        let group = self.groups.get(session_id).await.ok_or_else(|| {
+            lan_chat_core::ChatError::InvalidData(format!("Unknown group {}", session_id))
+        })?;
+        if !group.is_admin(&self.profile.user_id) {
+            return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                "Not an admin of group {}",
+                session_id
+            )));
+        }
+        Ok(group)
+    }
+
+    /// Send a group control message to members, skipping those we can't reach
+    async fn send_to_members<'a>(
+        &self,
+        members: impl IntoIterator<Item = &'a UserId>,
+        message: &ProtocolMessage,
+    ) {
+        for member in members {
+            if let Err(e) = self.send_to_peer(member, message).await {
+                warn!("Failed to send group update to {}: {}", member, e);
+            }
+        }
+    }
+
     /// Send a message to a specific peer
     pub async fn send_to_peer(
         &self,
@@ -244,7 +638,119 @@ impl MessagingServer {
         peer_id: &uuid::Uuid,
         message: Message,
     ) -> lan_chat_core::Result<()> {
-        // Get peer's public key
+        let protocol_message = self.seal(peer_id, message).await?;
+        self.send_to_peer(peer_id, &protocol_message).await
+    }
+
+    /// Encrypt a message's content and reply ids for a peer
+    async fn seal(
+        &self,
+        peer_id: &UserId,
+        mut message: Message,
+    ) -> lan_chat_core::Result<ProtocolMessage> {
+        let sealed = SealedContent::take(&mut message);
+        let (encrypted_key, encrypted_data) = self
+            .encrypt_for(peer_id, &serde_json::to_vec(&sealed)?)
+            .await?;
+
+        Ok(ProtocolMessage::Message {
+            message,
+            encrypted_key: Some(encrypted_key),
+            encrypted_data: Some(encrypted_data),
+        })
+    }
+
+    /// Decrypt the sealed parts of a message sent to us
+    fn open(
+        &self,
+        message: &mut Message,
+        key: &EncryptedSessionKey,
+        data: &EncryptedData,
+    ) -> lan_chat_core::Result<()> {
+        let plaintext = self.decrypt(key, data)?;
+        let sealed: SealedContent = serde_json::from_slice(&plaintext)?;
+        sealed.restore(message);
+        Ok(())
+    }
+
+    /// Send new content for a message we sent to everyone who got it
+    ///
+    /// Returns the recipients it couldn't be delivered to.
+    pub async fn send_edit(&self, message: &Message) -> lan_chat_core::Result<Vec<UserId>> {
+        let edited_at = message.edited_at.ok_or_else(|| {
+            lan_chat_core::ChatError::InvalidData("Message has not been edited".into())
+        })?;
+
+        let mut undelivered = Vec::new();
+        for recipient in self.recipients_of(message).await? {
+            if let Err(e) = self.send_edit_to(&recipient, message, edited_at).await {
+                debug!("Failed to send edit to {}: {}", recipient, e);
+                undelivered.push(recipient);
+            }
+        }
+        Ok(undelivered)
+    }
+
+    async fn send_edit_to(
+        &self,
+        recipient: &UserId,
+        message: &Message,
+        edited_at: DateTime<Utc>,
+    ) -> lan_chat_core::Result<()> {
+        let (encrypted_key, encrypted_data) = self
+            .encrypt_for(recipient, message.content.as_bytes())
+            .await?;
+        let edit = ProtocolMessage::MessageEdit {
+            message_id: message.id,
+            edited_at,
+            encrypted_key,
+            encrypted_data,
+        };
+        self.send_to_peer(recipient, &edit).await
+    }
+
+    /// Delete a message we sent for everyone who got it
+    ///
+    /// Returns the recipients it couldn't be delivered to.
+    pub async fn send_delete(&self, message: &Message) -> lan_chat_core::Result<Vec<UserId>> {
+        let delete = ProtocolMessage::MessageDelete {
+            message_id: message.id,
+        };
+
+        let mut undelivered = Vec::new();
+        for recipient in self.recipients_of(message).await? {
+            if let Err(e) = self.send_to_peer(&recipient, &delete).await {
+                debug!("Failed to send delete to {}: {}", recipient, e);
+                undelivered.push(recipient);
+            }
+        }
+        Ok(undelivered)
+    }
+
+    /// Who got a message we sent: the other group members, or its recipient
+    async fn recipients_of(&self, message: &Message) -> lan_chat_core::Result<Vec<UserId>> {
+        if message.sender_id != self.profile.user_id {
+            return Err(lan_chat_core::ChatError::NotAuthorized(format!(
+                "Message {} was sent by someone else",
+                message.id
+            )));
+        }
+
+        Ok(match self.groups.get(&message.session_id).await {
+            Some(group) => group
+                .other_members(&self.profile.user_id)
+                .copied()
+                .collect(),
+            None => vec![message.recipient_id],
+        })
+    }
+
+    /// Encrypt for a peer with their public key
+    async fn encrypt_for(
+        &self,
+        peer_id: &UserId,
+        plaintext: &[u8],
+    ) -> lan_chat_core::Result<(EncryptedSessionKey, EncryptedData)> {
         let peer = self
             .peer_registry
             .get_peer(peer_id)
@@ -259,18 +765,27 @@ impl MessagingServer {
         let public_key = KeyPair::import_public_key_bytes(peer_public_key)
             .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
 
-        // Encrypt message content
-        let (encrypted_key, encrypted_data) =
-            HybridEncryption::encrypt(&public_key, message.content.as_bytes())
-                .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+        HybridEncryption::encrypt(&public_key, plaintext)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))
+    }
 
-        let protocol_message = ProtocolMessage::Message {
-            message,
-            encrypted_key: Some(encrypted_key),
-            encrypted_data: Some(encrypted_data),
-        };
+    fn decrypt(
+        &self,
+        key: &EncryptedSessionKey,
+        data: &EncryptedData,
+    ) -> lan_chat_core::Result<Vec<u8>> {
+        HybridEncryption::decrypt(self.keypair.private_key(), key, data)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))
+    }
 
-        self.send_to_peer(peer_id, &protocol_message).await
+    /// Decrypt text sent to us
+    fn decrypt_text(
+        &self,
+        key: &EncryptedSessionKey,
+        data: &EncryptedData,
+    ) -> lan_chat_core::Result<String> {
+        String::from_utf8(self.decrypt(key, data)?)
+            .map_err(|e| lan_chat_core::ChatError::InvalidData(e.to_string()))
     }
 
     /// Remove a connection
@@ -279,9 +794,30 @@ impl MessagingServer {
         connections.remove(peer_id);
     }
 
+    /// Stop accepting connections and close existing ones with a close frame
+    pub async fn shutdown(&self) {
+        info!("Shutting down messaging server");
+        self.cancel_token.cancel();
+
+        let connections: Vec<_> = self.connections.write().await.drain().collect();
+        for (peer_id, mut writer) in connections {
+            let close = ProtocolMessage::Close {
+                reason: "Peer shutting down".into(),
+            };
+
+            match timeout(CLOSE_TIMEOUT, writer.send_message(&close)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Failed to send close frame to {}: {}", peer_id, e),
+                Err(_) => debug!("Timed out sending close frame to {}", peer_id),
+            }
+
+            let _ = writer.close().await;
+        }
+    }
+
     /// Connect to a peer
     pub async fn connect_to_peer(
-        &self,
+        self: &Arc<Self>,
         peer_id: &uuid::Uuid,
     ) -> lan_chat_core::Result<()> {
         // Get peer address
@@ -291,7 +827,17 @@ impl MessagingServer {
             .await
             .ok_or_else(|| lan_chat_core::ChatError::PeerNotFound(peer_id.to_string()))?;
 
-        let addr = peer.address.to_socket_addr();
+        self.connect_to_address(&peer.address).await?;
+
+        Ok(())
+    }
+
+    /// Connect to a peer by address, learning its profile from the handshake
+    pub async fn connect_to_address(
+        self: &Arc<Self>,
+        address: &NetworkAddress,
+    ) -> lan_chat_core::Result<Peer> {
+        let addr = address.to_socket_addr();
 
         // Connect
         let stream = TcpStream::connect(addr)
@@ -301,11 +847,14 @@ impl MessagingServer {
         let mut conn = PeerConnection::new(stream);
 
         // Send handshake
+        let nonce = uuid::Uuid::new_v4();
         let handshake = ProtocolMessage::Handshake {
             version: crate::messages::PROTOCOL_VERSION,
             user_id: self.profile.user_id,
             public_key: self.keypair.export_public_key_bytes()
                 .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?,
+            profile: Some(self.profile.clone()),
+            nonce,
         };
 
         conn.send_message(&handshake).await?;
@@ -315,24 +864,57 @@ impl MessagingServer {
             ProtocolMessage::HandshakeAck {
                 user_id,
                 public_key,
+                profile,
+                nonce: ack_nonce,
+                signature,
             } => {
                 conn.set_peer_id(user_id);
+                self.check_pinned_key(&user_id, &public_key).await?;
+                verify_proof(&public_key, HANDSHAKE_ACK_PURPOSE, &nonce, &user_id, signature)?;
 
-                // Update peer's public key
-                if let Some(mut peer) = self.peer_registry.get_peer(&user_id).await {
-                    peer.public_key = Some(public_key);
-                    self.peer_registry.add_peer(peer.clone()).await;
-                }
+                // Prove who we are in turn
+                let proof = ProtocolMessage::HandshakeProof {
+                    signature: self.prove(HANDSHAKE_PROOF_PURPOSE, &ack_nonce)?,
+                };
+                conn.send_message(&proof).await?;
 
-                // Store connection
+                // Update the known peer, or create it from the handshake profile
+                let peer = match (self.peer_registry.get_peer(&user_id).await, profile) {
+                    (Some(mut peer), _) => {
+                        peer.public_key = Some(public_key);
+                        peer.update_last_seen();
+                        self.register_peer(peer.clone()).await?;
+                        peer
+                    }
+                    (None, Some(profile)) if profile.user_id == user_id => {
+                        let mut peer = Peer::new(profile, address.clone());
+                        peer.public_key = Some(public_key);
+                        self.register_peer(peer.clone()).await?;
+                        let _ = self.event_tx.send(ChatEvent::PeerDiscovered(peer.clone()));
+                        peer
+                    }
+                    _ => {
+                        return Err(lan_chat_core::ChatError::Protocol(
+                            "Handshake did not include a peer profile".into(),
+                        ))
+                    }
+                };
+
+                // Store the sending half and read replies on a separate task
+                let (reader, writer) = conn.into_split();
                 {
                     let mut connections = self.connections.write().await;
-                    connections.insert(user_id, conn);
+                    connections.insert(user_id, writer);
                 }
 
-                let _ = self.event_tx.send(ChatEvent::PeerConnected(peer));
+                let server = Arc::clone(self);
+                tokio::spawn(async move {
+                    server.read_loop(user_id, reader).await;
+                });
+
+                let _ = self.event_tx.send(ChatEvent::PeerConnected(peer.clone()));
 
-                Ok(())
+                Ok(peer)
             }
             _ => Err(lan_chat_core::ChatError::Protocol(
                 "Expected handshake acknowledgment".into(),
@@ -340,3 +922,293 @@ impl MessagingServer {
         }
     }
 }
+
+/// Check that `user_id` signed our nonce with `public_key`
+fn verify_proof(
+    public_key: &[u8],
+    purpose: &str,
+    nonce: &uuid::Uuid,
+    user_id: &UserId,
+    signature: Vec<u8>,
+) -> lan_chat_core::Result<()> {
+    let key = KeyPair::import_public_key_bytes(public_key)
+        .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+    let payload = ProtocolMessage::proof_payload(purpose, nonce, user_id);
+    RsaSigner::verify(&key, &payload, &Signature { signature }).map_err(|_| {
+        lan_chat_core::ChatError::Crypto(format!("Invalid handshake signature from {}", user_id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> (MessagingServer, mpsc::UnboundedReceiver<ChatEvent>) {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let server = MessagingServer::new(
+            UserProfile::new("local".into(), "Local".into()),
+            KeyPair::generate().unwrap(),
+            PeerRegistry::new(),
+            event_tx,
+        );
+        (server, event_rx)
+    }
+
+    /// Accept one connection on a local port and hand it to `server`
+    async fn accept_one(
+        server: Arc<MessagingServer>,
+    ) -> (
+        NetworkAddress,
+        tokio::task::JoinHandle<lan_chat_core::Result<()>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.handle_connection(stream).await
+        });
+        (NetworkAddress::new(addr.ip(), addr.port()), handle)
+    }
+
+    /// A group made by `admin` with us and `member` in it
+    async fn join_group(server: &MessagingServer, admin: UserId, member: UserId) -> Group {
+        let group = Group::new("Team".into(), admin, [server.profile.user_id, member]);
+        server.groups.insert(group.clone()).await;
+        group
+    }
+
+    #[tokio::test]
+    async fn test_group_messages_need_membership() {
+        let (server, mut events) = server();
+        let (admin, member, outsider) = (UserId::new_v4(), UserId::new_v4(), UserId::new_v4());
+        let group = join_group(&server, admin, member).await;
+
+        let posted = |sender_id: UserId| ProtocolMessage::Message {
+            message: Message::new_text(
+                group.session_id,
+                sender_id,
+                server.profile.user_id,
+                "hi".into(),
+            ),
+            encrypted_key: None,
+            encrypted_data: None,
+        };
+
+        let result = server.handle_message(outsider, posted(outsider)).await;
+        assert!(matches!(
+            result,
+            Err(lan_chat_core::ChatError::NotAuthorized(_))
+        ));
+
+        // Members can't post in someone else's name either
+        let result = server.handle_message(member, posted(admin)).await;
+        assert!(matches!(
+            result,
+            Err(lan_chat_core::ChatError::NotAuthorized(_))
+        ));
+
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_messages_come_from_their_sender() {
+        let (server, mut events) = server();
+        let (peer, victim) = (UserId::new_v4(), UserId::new_v4());
+
+        // A direct message claiming to come from someone else
+        let session_id = direct_session_id(&server.profile.user_id, &victim);
+        let forged = ProtocolMessage::Message {
+            message: Message::new_text(session_id, victim, server.profile.user_id, "hi".into()),
+            encrypted_key: None,
+            encrypted_data: None,
+        };
+
+        let result = server.handle_message(peer, forged).await;
+        assert!(matches!(
+            result,
+            Err(lan_chat_core::ChatError::NotAuthorized(_))
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_updates_need_an_admin() {
+        let (server, mut events) = server();
+        let (admin, member) = (UserId::new_v4(), UserId::new_v4());
+        let group = join_group(&server, admin, member).await;
+
+        let mut takeover = group.clone();
+        takeover.name = "Mine".into();
+        takeover.admins = vec![member];
+        takeover.remove_member(&admin);
+
+        let update = ProtocolMessage::GroupUpdate {
+            group: takeover.clone(),
+        };
+        let result = server.handle_message(member, update).await;
+        assert!(matches!(
+            result,
+            Err(lan_chat_core::ChatError::NotAuthorized(_))
+        ));
+
+        // Re-inviting us to a known group doesn't work for non-admins either
+        let invite = ProtocolMessage::GroupInvite { group: takeover };
+        let result = server.handle_message(member, invite).await;
+        assert!(matches!(
+            result,
+            Err(lan_chat_core::ChatError::NotAuthorized(_))
+        ));
+
+        assert_eq!(server.groups.get(&group.session_id).await, Some(group));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invites_cant_take_over_direct_chats() {
+        let (server, mut events) = server();
+        let own_id = server.profile.user_id;
+        let (inviter, member, known) = (UserId::new_v4(), UserId::new_v4(), UserId::new_v4());
+
+        let mut profile = UserProfile::new("known".into(), "Known".into());
+        profile.user_id = known;
+        let address = NetworkAddress::new([127, 0, 0, 1].into(), MESSAGING_PORT);
+        server.peer_registry.add_peer(Peer::new(profile, address)).await;
+
+        // Our chat with a fellow member, and with a peer outside the group
+        for victim in [member, known] {
+            let mut group = Group::new("Team".into(), inviter, [own_id, member]);
+            group.session_id = direct_session_id(&own_id, &victim);
+
+            let invite = ProtocolMessage::GroupInvite { group };
+            let result = server.handle_message(inviter, invite).await;
+            assert!(matches!(
+                result,
+                Err(lan_chat_core::ChatError::NotAuthorized(_))
+            ));
+            assert!(server
+                .groups
+                .get(&direct_session_id(&own_id, &victim))
+                .await
+                .is_none());
+        }
+
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_leaving_member_gets_no_more_messages() {
+        let (server, _events) = server();
+        let (admin, member) = (UserId::new_v4(), UserId::new_v4());
+        let group = join_group(&server, admin, member).await;
+
+        let leave = ProtocolMessage::GroupLeave {
+            session_id: group.session_id,
+        };
+        server.handle_message(member, leave).await.unwrap();
+
+        let group = server.groups.get(&group.session_id).await.unwrap();
+        assert!(!group.is_member(&member));
+
+        // Nobody is connected, so every recipient comes back undelivered
+        let message =
+            Message::new_text(group.session_id, server.profile.user_id, admin, "hi".into());
+        let undelivered = server.send_group_message(message).await.unwrap();
+        assert_eq!(undelivered, vec![admin]);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_proves_both_sides() {
+        let (client, _client_events) = server();
+        let (host, mut host_events) = server();
+        let (client, host) = (Arc::new(client), Arc::new(host));
+        let (address, _handle) = accept_one(Arc::clone(&host)).await;
+
+        let peer = client.connect_to_address(&address).await.unwrap();
+        assert_eq!(peer.profile.user_id, host.profile.user_id);
+
+        // The host registers the client once its proof checks out
+        match host_events.recv().await {
+            Some(ChatEvent::PeerDiscovered(peer)) => {
+                assert_eq!(peer.profile.user_id, client.profile.user_id)
+            }
+            other => panic!("Expected the client to be discovered, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_needs_the_claimed_key() {
+        let (host, mut events) = server();
+        let host = Arc::new(host);
+        let victim = KeyPair::generate().unwrap();
+        let forger = KeyPair::generate().unwrap();
+        let mut profile = UserProfile::new("victim".into(), "Victim".into());
+        profile.user_id = UserId::new_v4();
+        let victim_id = profile.user_id;
+        let (address, handle) = accept_one(Arc::clone(&host)).await;
+
+        // Claim someone else's id and key, but sign with another key
+        let stream = TcpStream::connect(address.to_socket_addr()).await.unwrap();
+        let mut conn = PeerConnection::new(stream);
+        let handshake = ProtocolMessage::Handshake {
+            version: crate::messages::PROTOCOL_VERSION,
+            user_id: victim_id,
+            public_key: victim.export_public_key_bytes().unwrap(),
+            profile: Some(profile),
+            nonce: uuid::Uuid::new_v4(),
+        };
+        conn.send_message(&handshake).await.unwrap();
+
+        let ProtocolMessage::HandshakeAck { nonce, .. } = conn.receive_message().await.unwrap()
+        else {
+            panic!("Expected handshake acknowledgment");
+        };
+        let payload = ProtocolMessage::proof_payload(HANDSHAKE_PROOF_PURPOSE, &nonce, &victim_id);
+        let signature = RsaSigner::sign(forger.private_key(), &payload)
+            .unwrap()
+            .signature;
+        conn.send_message(&ProtocolMessage::HandshakeProof { signature })
+            .await
+            .unwrap();
+
+        assert!(handle.await.unwrap().is_err());
+        assert!(host.connections.read().await.is_empty());
+        assert!(host.peer_registry.pinned_key(&victim_id).await.is_none());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replies_travel_encrypted() {
+        let (client, _client_events) = server();
+        let (host, _host_events) = server();
+        let mut peer = Peer::new(
+            host.profile.clone(),
+            NetworkAddress::new([127, 0, 0, 1].into(), MESSAGING_PORT),
+        );
+        peer.public_key = Some(host.keypair.export_public_key_bytes().unwrap());
+        client.peer_registry.add_peer(peer).await;
+
+        let (client_id, host_id) = (client.profile.user_id, host.profile.user_id);
+        let session_id = uuid::Uuid::new_v4();
+        let root = Message::new_text(session_id, host_id, client_id, "root".into());
+        let parent =
+            Message::new_text(session_id, host_id, client_id, "parent".into()).with_reply_to(&root);
+        let reply = Message::new_text(session_id, client_id, host_id, "reply".into())
+            .with_reply_to(&parent);
+
+        let ProtocolMessage::Message {
+            mut message,
+            encrypted_key: Some(key),
+            encrypted_data: Some(data),
+        } = client.seal(&host_id, reply.clone()).await.unwrap()
+        else {
+            panic!("Expected an encrypted message");
+        };
+        assert!(message.content.is_empty());
+        assert_eq!((message.reply_to, message.thread_root), (None, None));
+
+        host.open(&mut message, &key, &data).unwrap();
+        assert_eq!(message.content, "reply");
+        assert_eq!(message.reply_to, Some(parent.id));
+        assert_eq!(message.thread_root, Some(root.id));
+    }
+}
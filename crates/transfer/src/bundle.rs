@@ -0,0 +1,456 @@
+use crate::manifest::{EntryKind, Manifest};
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::io::{Error, ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::warn;
+
+/// Reads chunks of an outgoing transfer's files, one run of chunks at a time
+pub struct BundleReader {
+    manifest: Manifest,
+    sources: Vec<PathBuf>,
+    chunk_size: usize,
+    ranges: VecDeque<Range<u64>>,
+    /// Open file, with its entry and read position
+    current: Option<(usize, u64, File)>,
+}
+
+impl BundleReader {
+    pub fn new(
+        manifest: Manifest,
+        sources: Vec<PathBuf>,
+        chunk_size: usize,
+        ranges: Vec<Range<u64>>,
+    ) -> Self {
+        Self {
+            manifest,
+            sources,
+            chunk_size,
+            ranges: ranges.into(),
+            current: None,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Read the next chunk into the front of `buffer`, returning its index and
+    /// length, or `None` once every range has been read
+    pub async fn next_chunk(&mut self, buffer: &mut [u8]) -> std::io::Result<Option<(u64, usize)>> {
+        let Some(chunk_index) = self.next_index() else {
+            return Ok(None);
+        };
+        let (entry, chunk) = self
+            .manifest
+            .locate(chunk_index, self.chunk_size)
+            .ok_or_else(|| invalid_data(format!("No chunk {}", chunk_index)))?;
+
+        let size = self.manifest.entries[entry].size;
+        let offset = chunk * self.chunk_size as u64;
+        let len = (size - offset).min(self.chunk_size as u64) as usize;
+        let source = &self.sources[entry];
+
+        // Runs are contiguous, so the file only needs seeking when one starts
+        let (_, position, file) = match &mut self.current {
+            Some((index, position, _)) if *index == entry && *position == offset => {
+                self.current.as_mut().expect("file is open")
+            }
+            current => current.insert((entry, offset, open_at(source, offset).await?)),
+        };
+
+        // A short read means the file changed after it was hashed
+        if read_chunk(file, &mut buffer[..len]).await? != len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{:?} changed while being sent", source),
+            ));
+        }
+        *position += len as u64;
+
+        Ok(Some((chunk_index, len)))
+    }
+
+    fn next_index(&mut self) -> Option<u64> {
+        while let Some(range) = self.ranges.front_mut() {
+            if let Some(index) = range.next() {
+                return Some(index);
+            }
+            self.ranges.pop_front();
+        }
+        None
+    }
+}
+
+/// Writes chunks of an incoming transfer into its staging directory, in any
+/// order
+///
+/// Clones share the staging directory, so each stream of a transfer can write
+/// through its own clone. Nothing is checked until `finish`.
+#[derive(Clone)]
+pub struct BundleWriter {
+    root: PathBuf,
+    manifest: Arc<Manifest>,
+    chunk_size: usize,
+    current: Option<(usize, Arc<std::fs::File>)>,
+}
+
+impl BundleWriter {
+    /// Open the staging directory, emptying it first if `fresh`
+    pub async fn open(
+        root: PathBuf,
+        manifest: Manifest,
+        chunk_size: usize,
+        fresh: bool,
+    ) -> std::io::Result<Self> {
+        if fresh {
+            match tokio::fs::remove_dir_all(&root).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        tokio::fs::create_dir_all(&root).await?;
+
+        // Folders and empty files never get a chunk. Other files get their
+        // full size up front, so a disk filling up fails here, not halfway.
+        for entry in &manifest.entries {
+            let path = root.join(&entry.path);
+            match entry.kind {
+                EntryKind::Directory => tokio::fs::create_dir_all(&path).await?,
+                EntryKind::File if entry.size == 0 => {
+                    create_parent(&path).await?;
+                    File::create(&path).await?;
+                }
+                EntryKind::File if fresh => {
+                    create_parent(&path).await?;
+                    preallocate(&path, entry.size).await?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            root,
+            manifest: Arc::new(manifest),
+            chunk_size,
+            current: None,
+        })
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Write a chunk at its place in its file
+    pub async fn write_chunk(&mut self, chunk_index: u64, data: Vec<u8>) -> std::io::Result<()> {
+        let (index, chunk) = self
+            .manifest
+            .locate(chunk_index, self.chunk_size)
+            .ok_or_else(|| invalid_data(format!("Unexpected chunk {}", chunk_index)))?;
+
+        let entry = &self.manifest.entries[index];
+        let offset = chunk * self.chunk_size as u64;
+        let expected = (entry.size - offset).min(self.chunk_size as u64);
+        if data.len() as u64 != expected {
+            return Err(invalid_data(format!(
+                "Chunk {} has {} bytes, expected {}",
+                chunk_index,
+                data.len(),
+                expected
+            )));
+        }
+
+        let file = match &self.current {
+            Some((current, file)) if *current == index => Arc::clone(file),
+            _ => {
+                let path = self.root.join(&entry.path);
+                create_parent(&path).await?;
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)
+                    .await?
+                    .into_std()
+                    .await;
+                let file = Arc::new(file);
+                self.current = Some((index, Arc::clone(&file)));
+                file
+            }
+        };
+
+        tokio::task::spawn_blocking(move || write_at(&file, &data, offset))
+            .await
+            .map_err(Error::other)?
+    }
+
+    /// Check every file and the whole transfer against their hashes, then
+    /// recreate links and permissions
+    ///
+    /// Call once every chunk has been written. `on_progress` gets the bytes
+    /// checked so far.
+    pub async fn finish(
+        self,
+        file_hash: &str,
+        mut on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        if !self.manifest.content_hash().eq_ignore_ascii_case(file_hash) {
+            return Err(invalid_data(
+                "File hash mismatch: the manifest doesn't match the transfer".into(),
+            ));
+        }
+
+        let mut verified = 0;
+
+        for entry in &self.manifest.entries {
+            if entry.kind != EntryKind::File {
+                continue;
+            }
+
+            let mut file = File::open(self.root.join(&entry.path)).await?;
+            let mut hasher = Sha256::new();
+            hash_into(&mut file, &mut hasher, |bytes| {
+                verified += bytes;
+                on_progress(verified);
+            })
+            .await?;
+
+            let actual = hex::encode(hasher.finalize());
+            if !actual.eq_ignore_ascii_case(&entry.hash) {
+                return Err(invalid_data(format!(
+                    "{} is corrupted: hash mismatch",
+                    entry.path
+                )));
+            }
+        }
+
+        let links: HashSet<&str> = self
+            .manifest
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry.kind, EntryKind::Symlink { .. }))
+            .map(|entry| entry.path.as_str())
+            .collect();
+        for entry in &self.manifest.entries {
+            if let EntryKind::Symlink { target } = &entry.kind {
+                create_symlink(&self.root, &entry.path, target, &links).await;
+            }
+        }
+
+        // Deepest first, so read-only folders don't block their contents
+        for entry in self.manifest.entries.iter().rev() {
+            if let Some(mode) = entry.mode {
+                set_mode(&self.root.join(&entry.path), mode).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Create a file taking up `size` bytes on disk
+///
+/// Filesystems that can't reserve space just get an empty file to fill in.
+async fn preallocate(path: &Path, size: u64) -> std::io::Result<()> {
+    let file = File::create(path).await?.into_std().await;
+    let allocated = tokio::task::spawn_blocking(move || fs4::FileExt::allocate(&file, size))
+        .await
+        .map_err(Error::other)?;
+
+    match allocated {
+        Err(e) if e.kind() == ErrorKind::Unsupported => {
+            warn!("Can't reserve space for {:?}: {}", path, e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Open a file for reading at `offset`
+async fn open_at(path: &Path, offset: u64) -> std::io::Result<File> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(file)
+}
+
+/// Read a full chunk unless the file ends first, so chunk offsets stay fixed
+async fn read_chunk(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        let bytes_read = file.read(&mut buffer[filled..]).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+
+    Ok(filled)
+}
+
+async fn hash_into(
+    file: &mut File,
+    hasher: &mut Sha256,
+    mut on_read: impl FnMut(u64),
+) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..bytes_read]);
+        on_read(bytes_read as u64);
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &std::fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &std::fs::File, mut data: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let written = file.seek_write(data, offset)?;
+        if written == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        data = &data[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+async fn create_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => tokio::fs::create_dir_all(parent).await,
+        None => Ok(()),
+    }
+}
+
+/// Whether a link at `path` pointing to `target` stays inside its top-level item
+///
+/// `links` holds the paths of every link in the transfer. A target may end at
+/// another link, but not pass through one: where that link leads isn't known
+/// here, so chained links could climb out one step at a time.
+fn link_stays_inside(path: &str, target: &str, links: &HashSet<&str>) -> bool {
+    // Start from the link's folder
+    let mut resolved: Vec<&str> = path.split('/').collect();
+    resolved.pop();
+    if resolved.is_empty() {
+        return false;
+    }
+
+    for component in Path::new(target).components() {
+        if links.contains(resolved.join("/").as_str()) {
+            return false;
+        }
+        match component {
+            Component::Normal(name) => match name.to_str() {
+                Some(name) => resolved.push(name),
+                None => return false,
+            },
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+                // Only the top-level item's contents are inside
+                if resolved.is_empty() {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+/// Recreate a preserved link, skipping any that could lead outside the tree
+async fn create_symlink(root: &Path, path: &str, target: &str, links: &HashSet<&str>) {
+    if !link_stays_inside(path, target, links) {
+        warn!(
+            "Skipping link {} pointing outside the transfer: {}",
+            path, target
+        );
+        return;
+    }
+
+    #[cfg(unix)]
+    {
+        let link = root.join(path);
+        if let Err(e) = create_parent(&link).await {
+            warn!("Failed to create link {}: {}", path, e);
+            return;
+        }
+        if let Err(e) = tokio::fs::symlink(target, &link).await {
+            warn!("Failed to create link {}: {}", path, e);
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = root;
+        warn!("Skipping link {}: links aren't supported here", path);
+    }
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Links get their target's permissions
+    if tokio::fs::symlink_metadata(path)
+        .await?
+        .file_type()
+        .is_symlink()
+    {
+        return Ok(());
+    }
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)).await
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_stays_inside() {
+        let links = HashSet::new();
+        assert!(link_stays_inside("p/a/link", "../b", &links));
+        assert!(link_stays_inside("p/link", "sub/file", &links));
+        assert!(!link_stays_inside("p/link", "../other", &links));
+        assert!(!link_stays_inside("p/a/link", "../../..", &links));
+        assert!(!link_stays_inside("p/link", "/etc/passwd", &links));
+        assert!(!link_stays_inside("link", "anything", &links));
+    }
+
+    #[test]
+    fn test_chained_links_stay_inside() {
+        let links = HashSet::from(["p/d/e/l", "p/l2", "p/l3"]);
+        // Inside on its own...
+        assert!(link_stays_inside("p/d/e/l", "../..", &links));
+        // ...but p/d/e/l/../.. really is p/../.., outside the transfer
+        assert!(!link_stays_inside("p/l2", "d/e/l/../..", &links));
+        assert!(!link_stays_inside("p/l2", "d/e/l/x", &links));
+        // Pointing at a link is fine, its own target is checked
+        assert!(link_stays_inside("p/l3", "d/e/l", &links));
+        assert!(link_stays_inside("p/l3", "./d/e/l", &links));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::manifest::hash_file;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// Files already received, stored under their SHA-256 so a later transfer of
+/// the same content can be completed without sending it again
+///
+/// Objects are hard links to the downloaded files where the filesystem allows
+/// it, so the cache costs no extra space until the download is deleted.
+#[derive(Debug, Clone)]
+pub struct ContentCache {
+    dir: PathBuf,
+}
+
+impl ContentCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Remember `path` as holding the content with this hash
+    ///
+    /// Keeps the first copy if the content is already cached. Only hard links,
+    /// since copying every download would double the disk space it takes.
+    pub async fn insert(&self, hash: &str, path: &Path) -> std::io::Result<()> {
+        let Some(object) = self.object_path(hash) else {
+            return Ok(());
+        };
+        if tokio::fs::try_exists(&object).await? {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(self.dir.join("objects")).await?;
+        match tokio::fs::hard_link(path, &object).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Whether content with this hash and size is cached
+    ///
+    /// Doesn't hash the object again; `link_to` still may find it stale.
+    pub async fn contains(&self, hash: &str, size: u64) -> bool {
+        let Some(object) = self.object_path(hash) else {
+            return false;
+        };
+        tokio::fs::metadata(&object)
+            .await
+            .is_ok_and(|metadata| metadata.len() == size)
+    }
+
+    /// Put the cached content with this hash at `dest`, returning whether it
+    /// was there to use
+    ///
+    /// The object is hashed again first, as the file it's linked to may have
+    /// been edited since; one that no longer matches is dropped.
+    pub async fn link_to(&self, hash: &str, size: u64, dest: &Path) -> std::io::Result<bool> {
+        let Some(object) = self.object_path(hash) else {
+            return Ok(false);
+        };
+        match tokio::fs::metadata(&object).await {
+            Ok(metadata) if metadata.len() == size => {}
+            Ok(_) => {
+                tokio::fs::remove_file(&object).await?;
+                return Ok(false);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        let (_, actual) = hash_file(&object, |_| {}).await?;
+        if !actual.eq_ignore_ascii_case(hash) {
+            tokio::fs::remove_file(&object).await?;
+            return Ok(false);
+        }
+
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        match tokio::fs::remove_file(dest).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        if tokio::fs::hard_link(&object, dest).await.is_err() {
+            tokio::fs::copy(&object, dest).await?;
+        }
+        Ok(true)
+    }
+
+    /// Where the content with this hash is kept, if the hash is well formed
+    ///
+    /// Hashes come from peers, so anything but 64 hex digits is refused
+    /// rather than used as a file name.
+    fn object_path(&self, hash: &str) -> Option<PathBuf> {
+        let valid = hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+        valid.then(|| self.dir.join("objects").join(hash.to_ascii_lowercase()))
+    }
+}
+
+/// Hashes of local files, reused while a file's size and modification time
+/// are unchanged so sending it again doesn't mean reading it again
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    // Never held across an await
+    entries: Mutex<HashMap<PathBuf, HashEntry>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HashEntry {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+impl HashCache {
+    /// Empty cache that saves to `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load the cache from a JSON file, starting empty if it doesn't exist
+    pub async fn load(path: &Path) -> lan_chat_core::Result<Self> {
+        let entries: Vec<HashEntry> = match tokio::fs::read(path).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries.into_iter().map(|e| (e.path.clone(), e)).collect()),
+        })
+    }
+
+    /// Hash of the file at `path`, if it hasn't changed since it was hashed
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<String> {
+        let modified = metadata.modified().ok()?;
+        self.lock()
+            .get(path)
+            .filter(|e| e.size == metadata.len() && e.modified == modified)
+            .map(|e| e.hash.clone())
+    }
+
+    pub fn insert(&self, path: &Path, metadata: &Metadata, hash: String) {
+        let Ok(modified) = metadata.modified() else {
+            return;
+        };
+        self.lock().insert(
+            path.to_path_buf(),
+            HashEntry {
+                path: path.to_path_buf(),
+                size: metadata.len(),
+                modified,
+                hash,
+            },
+        );
+    }
+
+    /// Save the cache as JSON, creating the parent directory if needed
+    pub async fn save(&self) -> lan_chat_core::Result<()> {
+        let data = {
+            let entries = self.lock();
+            serde_json::to_vec(&entries.values().collect::<Vec<_>>())?
+        };
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.path, data).await?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, HashEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_path_refuses_malformed_hashes() {
+        let cache = ContentCache::new("/cache");
+        let hash = "AB".repeat(32);
+        assert_eq!(
+            cache.object_path(&hash),
+            Some(PathBuf::from("/cache/objects").join("ab".repeat(32)))
+        );
+
+        assert_eq!(cache.object_path("../../etc/passwd"), None);
+        assert_eq!(cache.object_path(&"g".repeat(64)), None);
+        assert_eq!(cache.object_path(&"a".repeat(63)), None);
+    }
+
+    #[tokio::test]
+    async fn test_contains_checks_the_size() {
+        let dir = std::env::temp_dir().join(format!("lan-chat-cache-{}", uuid::Uuid::new_v4()));
+        let file = dir.join("download.txt");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(&file, b"hello").await.unwrap();
+
+        let cache = ContentCache::new(dir.join("cache"));
+        let hash = "ab".repeat(32);
+        assert!(!cache.contains(&hash, 5).await);
+
+        cache.insert(&hash, &file).await.unwrap();
+        let found = (
+            cache.contains(&hash, 5).await,
+            cache.contains(&hash, 6).await,
+        );
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        assert_eq!(found, (true, false));
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Set of chunk indexes, kept as sorted, non-overlapping ranges
+///
+/// Chunks sent over parallel streams arrive out of order, so the recipient
+/// tracks exactly which ones it has rather than how far it got.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSet {
+    ranges: Vec<Range<u64>>,
+}
+
+impl ChunkSet {
+    pub fn from_ranges(ranges: impl IntoIterator<Item = Range<u64>>) -> Self {
+        let mut sorted: Vec<_> = ranges.into_iter().filter(|r| !r.is_empty()).collect();
+        sorted.sort_by_key(|r| r.start);
+
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(sorted.len());
+        for range in sorted {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    /// Add a chunk, returning `false` if it was already in the set
+    pub fn insert(&mut self, index: u64) -> bool {
+        // First range ending at or after the index
+        let i = self.ranges.partition_point(|r| r.end < index);
+
+        match self.ranges.get(i) {
+            Some(range) if range.contains(&index) => return false,
+            Some(range) if range.end == index => {
+                self.ranges[i].end += 1;
+                if self
+                    .ranges
+                    .get(i + 1)
+                    .is_some_and(|next| next.start == index + 1)
+                {
+                    self.ranges[i].end = self.ranges.remove(i + 1).end;
+                }
+            }
+            Some(range) if range.start == index + 1 => self.ranges[i].start = index,
+            _ => self.ranges.insert(i, index..index + 1),
+        }
+
+        true
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        let i = self.ranges.partition_point(|r| r.end <= index);
+        self.ranges.get(i).is_some_and(|r| r.contains(&index))
+    }
+
+    /// Number of chunks in the set
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().map(|r| r.end - r.start).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Number of chunks in the set that fall within `range`
+    pub fn count_in(&self, range: Range<u64>) -> u64 {
+        self.ranges
+            .iter()
+            .map(|r| {
+                r.end
+                    .min(range.end)
+                    .saturating_sub(r.start.max(range.start))
+            })
+            .sum()
+    }
+
+    /// Chunks below `total` that aren't in the set
+    pub fn missing(&self, total: u64) -> Vec<Range<u64>> {
+        let mut missing = Vec::new();
+        let mut next = 0;
+
+        for range in &self.ranges {
+            if range.start >= total {
+                break;
+            }
+            if range.start > next {
+                missing.push(next..range.start);
+            }
+            next = next.max(range.end);
+        }
+        if next < total {
+            missing.push(next..total);
+        }
+
+        missing
+    }
+
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+}
+
+/// Split chunk ranges into at most `parts` contiguous runs of about equal size
+pub fn split_ranges(ranges: &[Range<u64>], parts: usize) -> Vec<Vec<Range<u64>>> {
+    let total: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+    if total == 0 || parts == 0 {
+        return Vec::new();
+    }
+    let per_part = total.div_ceil(parts as u64);
+
+    let mut split = Vec::new();
+    let mut current = Vec::new();
+    let mut current_len = 0;
+
+    for range in ranges {
+        let mut start = range.start;
+        while start < range.end {
+            let end = range.end.min(start + per_part - current_len);
+            current.push(start..end);
+            current_len += end - start;
+            start = end;
+
+            if current_len == per_part {
+                split.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+        }
+    }
+    if !current.is_empty() {
+        split.push(current);
+    }
+
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_merges_ranges() {
+        let mut set = ChunkSet::default();
+        for index in [0, 1, 5, 3, 2, 4] {
+            assert!(set.insert(index));
+        }
+        assert!(!set.insert(3));
+        set.insert(9);
+
+        assert_eq!(set.ranges(), &[0..6, 9..10]);
+        assert_eq!(set.len(), 7);
+        assert!(set.contains(9));
+        assert!(!set.contains(6));
+        assert_eq!(set.count_in(4..10), 3);
+        assert_eq!(set.missing(12), vec![6..9, 10..12]);
+    }
+
+    #[test]
+    fn test_split_ranges() {
+        let split = split_ranges(&[0..3, 10..17], 3);
+        assert_eq!(split, vec![vec![0..3, 10..11], vec![11..15], vec![15..17]]);
+
+        // Never more parts than chunks
+        assert_eq!(split_ranges(&[2..3, 5..6], 4), vec![vec![2..3], vec![5..6]]);
+        assert!(split_ranges(&[], 4).is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::manifest::Manifest;
+use std::collections::HashMap;
+
+/// zstd level for chunks; the fastest levels keep up with a LAN while still
+/// shrinking text several times over
+const COMPRESSION_LEVEL: i32 = 1;
+
+/// Chunks of each file looked at before deciding whether it compresses
+const SNIFF_CHUNKS: u32 = 2;
+
+/// Bits per byte above which data is taken to be compressed or encrypted
+/// already; such data sits just under 8
+const MAX_ENTROPY: f64 = 7.5;
+
+/// Compresses the chunks of an outgoing transfer, skipping files that won't
+/// get smaller
+///
+/// The first chunks of each file are sniffed: a file whose bytes look random,
+/// such as an archive, a photo or a video, or whose first chunks barely
+/// shrink, is sent as is from then on.
+#[derive(Debug)]
+pub struct ChunkCompressor {
+    manifest: Manifest,
+    chunk_size: usize,
+    files: HashMap<usize, FileSniff>,
+}
+
+#[derive(Debug, Default)]
+struct FileSniff {
+    chunks_seen: u32,
+    incompressible: bool,
+}
+
+impl ChunkCompressor {
+    pub fn new(manifest: Manifest, chunk_size: usize) -> Self {
+        Self {
+            manifest,
+            chunk_size,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Compressed form of a chunk, or `None` if it's better sent as is
+    pub fn compress(&mut self, chunk_index: u64, data: &[u8]) -> Option<Vec<u8>> {
+        let (entry, _) = self.manifest.locate(chunk_index, self.chunk_size)?;
+        let sniff = self.files.entry(entry).or_default();
+        if sniff.incompressible {
+            return None;
+        }
+
+        let sniffing = sniff.chunks_seen < SNIFF_CHUNKS;
+        sniff.chunks_seen += 1;
+        if sniffing && entropy(data) > MAX_ENTROPY {
+            sniff.incompressible = true;
+            return None;
+        }
+
+        // Not worth the recipient's time unless it saves a tenth
+        let compressed = zstd::bulk::compress(data, COMPRESSION_LEVEL).ok()?;
+        if compressed.len() > data.len() - data.len() / 10 {
+            sniff.incompressible |= sniffing;
+            return None;
+        }
+        Some(compressed)
+    }
+}
+
+/// Expand a compressed chunk, refusing to produce more than `max_size` bytes
+pub fn decompress(data: &[u8], max_size: usize) -> std::io::Result<Vec<u8>> {
+    zstd::bulk::decompress(data, max_size)
+}
+
+/// Shannon entropy of `data` in bits per byte, from 0 for a run of one byte
+/// to 8 for random bytes
+fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0u64; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{EntryKind, ManifestEntry};
+    use rand::RngCore;
+
+    const CHUNK: usize = 64 * 1024;
+
+    fn manifest(sizes: &[u64]) -> Manifest {
+        Manifest {
+            entries: sizes
+                .iter()
+                .enumerate()
+                .map(|(i, size)| ManifestEntry {
+                    path: format!("file{}", i),
+                    kind: EntryKind::File,
+                    size: *size,
+                    hash: String::new(),
+                    mode: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&[]), 0.0);
+        assert_eq!(entropy(&[7; 100]), 0.0);
+        assert!((entropy(&(0..=255).collect::<Vec<u8>>()) - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_compresses_text_and_skips_random_files() {
+        let text: Vec<u8> = b"2024-01-01 12:00:00 INFO request handled in 3ms\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(CHUNK)
+            .collect();
+        let mut noise = vec![0u8; CHUNK];
+        rand::thread_rng().fill_bytes(&mut noise);
+
+        // file0 is text, file1 is random
+        let mut compressor = ChunkCompressor::new(manifest(&[4 * CHUNK as u64; 2]), CHUNK);
+
+        let compressed = compressor.compress(0, &text).unwrap();
+        assert!(compressed.len() < CHUNK / 10);
+        assert_eq!(decompress(&compressed, CHUNK).unwrap(), text);
+
+        assert!(compressor.compress(4, &noise).is_none());
+        // Once a file is found to be incompressible, even text in it isn't tried
+        assert!(compressor.compress(5, &text).is_none());
+        assert!(compressor.compress(1, &text).is_some());
+
+        // Expanding past the limit fails rather than filling memory
+        assert!(decompress(&compressed, CHUNK / 2).is_err());
+    }
+}
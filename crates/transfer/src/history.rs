@@ -0,0 +1,74 @@
+use crate::protocol::{FileTransfer, TransferDirection, TransferStatus};
+use chrono::{DateTime, Utc};
+use lan_chat_core::UserId;
+use serde::{Deserialize, Serialize};
+
+/// Which transfers `TransferService::list_transfers` returns
+///
+/// Fields left unset match every transfer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferFilter {
+    /// Transfers with this peer, in either direction
+    pub peer_id: Option<UserId>,
+    /// Transfers in any of these states; empty for all
+    pub statuses: Vec<TransferStatus>,
+    pub direction: Option<TransferDirection>,
+    /// Requested at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Requested before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Most transfers to return, newest first
+    pub limit: Option<usize>,
+}
+
+impl TransferFilter {
+    /// Whether `transfer` passes the filter, seen from `local_user`'s side
+    pub fn matches(&self, transfer: &FileTransfer, local_user: UserId) -> bool {
+        self.peer_id
+            .is_none_or(|peer| transfer.peer_id(local_user) == peer)
+            && (self.statuses.is_empty() || self.statuses.contains(&transfer.status))
+            && self
+                .direction
+                .is_none_or(|direction| transfer.direction(local_user) == direction)
+            && self.since.is_none_or(|since| transfer.created_at >= since)
+            && self.until.is_none_or(|until| transfer.created_at < until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use chrono::Duration;
+
+    #[test]
+    fn test_filter_matches() {
+        let me = uuid::Uuid::new_v4();
+        let peer = uuid::Uuid::new_v4();
+        let mut received = FileTransfer::new(peer, me, Manifest::default(), String::new());
+        received.status = TransferStatus::Completed;
+        received.created_at = Utc::now() - Duration::days(1);
+
+        assert!(TransferFilter::default().matches(&received, me));
+
+        let yesterday = TransferFilter {
+            peer_id: Some(peer),
+            statuses: vec![TransferStatus::Completed, TransferStatus::Failed],
+            direction: Some(TransferDirection::Incoming),
+            since: Some(Utc::now() - Duration::days(2)),
+            until: Some(Utc::now() - Duration::hours(12)),
+            limit: None,
+        };
+        assert!(yesterday.matches(&received, me));
+
+        // Seen from the sender's side it went out
+        assert!(!yesterday.matches(&received, peer));
+
+        let today = TransferFilter {
+            since: Some(Utc::now() - Duration::hours(12)),
+            ..Default::default()
+        };
+        assert!(!today.matches(&received, me));
+    }
+}
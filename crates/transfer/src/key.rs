@@ -0,0 +1,160 @@
+use lan_chat_core::TransferId;
+use lan_chat_crypto::{AesEncryption, EncryptedData, EncryptedSessionKey, RsaEncryption};
+use rand::RngCore;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+
+const KEY_SIZE: usize = 32;
+
+/// Random bytes the listening side sends for the peer to prove key possession
+pub const CHALLENGE_SIZE: usize = 32;
+
+/// Per-transfer AEAD key, known only to the sender and the accepted recipient
+///
+/// Besides encrypting chunks it authenticates every connection that carries
+/// the transfer: a peer proves it holds the key by sealing a fresh challenge.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TransferKey([u8; KEY_SIZE]);
+
+impl TransferKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Wrap the key for the recipient's public key
+    pub fn wrap(&self, public_key: &RsaPublicKey) -> lan_chat_crypto::Result<EncryptedSessionKey> {
+        RsaEncryption::encrypt_session_key(public_key, &self.0)
+    }
+
+    /// Recover a key wrapped for us
+    pub fn unwrap(
+        private_key: &RsaPrivateKey,
+        wrapped: &EncryptedSessionKey,
+    ) -> lan_chat_crypto::Result<Self> {
+        RsaEncryption::decrypt_session_key(private_key, wrapped).map(Self)
+    }
+
+    /// Encrypt one chunk, binding it to its transfer and position, and to
+    /// whether it's compressed
+    pub fn seal_chunk(
+        &self,
+        transfer_id: TransferId,
+        chunk_index: u64,
+        compressed: bool,
+        data: &[u8],
+    ) -> lan_chat_crypto::Result<EncryptedData> {
+        self.cipher()
+            .encrypt_with_aad(data, &chunk_aad(transfer_id, chunk_index, compressed))
+    }
+
+    /// Decrypt a chunk; fails if it was altered, replayed or reordered, or
+    /// its compressed flag was flipped
+    pub fn open_chunk(
+        &self,
+        transfer_id: TransferId,
+        chunk_index: u64,
+        compressed: bool,
+        data: &EncryptedData,
+    ) -> lan_chat_crypto::Result<Vec<u8>> {
+        self.cipher()
+            .decrypt_with_aad(data, &chunk_aad(transfer_id, chunk_index, compressed))
+    }
+
+    /// Answer a connection challenge
+    pub fn prove(
+        &self,
+        transfer_id: TransferId,
+        challenge: &[u8],
+    ) -> lan_chat_crypto::Result<EncryptedData> {
+        self.cipher()
+            .encrypt_with_aad(&[], &proof_aad(transfer_id, challenge))
+    }
+
+    /// Check a peer's answer to our challenge
+    pub fn verify_proof(
+        &self,
+        transfer_id: TransferId,
+        challenge: &[u8],
+        proof: &EncryptedData,
+    ) -> lan_chat_crypto::Result<()> {
+        self.cipher()
+            .decrypt_with_aad(proof, &proof_aad(transfer_id, challenge))
+            .map(|_| ())
+    }
+
+    fn cipher(&self) -> AesEncryption {
+        AesEncryption::from_key(self.0)
+    }
+}
+
+// Keep key material out of logs
+impl std::fmt::Debug for TransferKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TransferKey(..)")
+    }
+}
+
+/// Fresh random challenge for `TransferKey::prove`
+pub fn new_challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; CHALLENGE_SIZE];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+fn chunk_aad(transfer_id: TransferId, chunk_index: u64, compressed: bool) -> Vec<u8> {
+    let mut aad = b"chunk".to_vec();
+    aad.extend_from_slice(transfer_id.as_bytes());
+    aad.extend_from_slice(&chunk_index.to_be_bytes());
+    // Left out for plain chunks so they seal as they did before compression
+    if compressed {
+        aad.extend_from_slice(b"zstd");
+    }
+    aad
+}
+
+fn proof_aad(transfer_id: TransferId, challenge: &[u8]) -> Vec<u8> {
+    let mut aad = b"proof".to_vec();
+    aad.extend_from_slice(transfer_id.as_bytes());
+    aad.extend_from_slice(challenge);
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_bound_to_index() {
+        let key = TransferKey::generate();
+        let transfer_id = uuid::Uuid::new_v4();
+
+        let sealed = key.seal_chunk(transfer_id, 3, false, b"data").unwrap();
+        assert_eq!(
+            key.open_chunk(transfer_id, 3, false, &sealed).unwrap(),
+            b"data"
+        );
+        assert!(key.open_chunk(transfer_id, 4, false, &sealed).is_err());
+        assert!(key
+            .open_chunk(uuid::Uuid::new_v4(), 3, false, &sealed)
+            .is_err());
+        assert!(key.open_chunk(transfer_id, 3, true, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_challenge_proof() {
+        let key = TransferKey::generate();
+        let transfer_id = uuid::Uuid::new_v4();
+        let challenge = new_challenge();
+
+        let proof = key.prove(transfer_id, &challenge).unwrap();
+        assert!(key.verify_proof(transfer_id, &challenge, &proof).is_ok());
+        assert!(key
+            .verify_proof(transfer_id, &new_challenge(), &proof)
+            .is_err());
+        assert!(TransferKey::generate()
+            .verify_proof(transfer_id, &challenge, &proof)
+            .is_err());
+    }
+}
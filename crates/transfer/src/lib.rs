@@ -1,8 +1,32 @@
+pub mod bundle;
+pub mod cache;
+pub mod chunks;
+pub mod compress;
+pub mod history;
+pub mod key;
+pub mod manifest;
+pub mod media;
+pub mod naming;
+pub mod policy;
 pub mod protocol;
+pub mod queue;
+pub mod rate;
 pub mod service;
+pub mod settings;
+pub mod space;
+pub mod store;
 
+pub use cache::{ContentCache, HashCache};
+pub use chunks::*;
+pub use history::*;
+pub use key::*;
+pub use manifest::*;
+pub use media::{ImagePreview, SharedMedia};
+pub use policy::*;
 pub use protocol::*;
 pub use service::*;
+pub use settings::*;
+pub use store::*;
 
 /// Default port for file transfers
 pub const TRANSFER_PORT: u16 = 37844;
@@ -0,0 +1,447 @@
+use crate::cache::HashCache;
+use crate::naming::sanitize_relative_path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// What to do with symbolic links found while building a manifest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymlinkPolicy {
+    /// Leave links out of the transfer
+    #[default]
+    Skip,
+    /// Send whatever the link points to as if it were a regular file or folder
+    Follow,
+    /// Send the link itself; the recipient only recreates links that stay
+    /// inside the transferred folder
+    Preserve,
+}
+
+/// Kind of a manifest entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink { target: String },
+}
+
+/// One file, folder or link in a transfer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the download directory, '/'-separated
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// SHA-256 of the contents; empty for folders and links
+    pub hash: String,
+    /// Unix permission bits, when the sender has them
+    pub mode: Option<u32>,
+}
+
+impl ManifestEntry {
+    /// Number of chunks this entry's data takes up
+    pub fn chunk_count(&self, chunk_size: usize) -> u64 {
+        match self.kind {
+            EntryKind::File => self.size.div_ceil(chunk_size as u64),
+            _ => 0,
+        }
+    }
+}
+
+/// Everything a transfer contains, in the order its data is sent
+///
+/// Chunks never span two files, so a chunk index maps to the same file and
+/// offset on both sides without any extra bookkeeping.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.size).sum()
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.kind == EntryKind::File)
+            .count()
+    }
+
+    pub fn chunk_count(&self, chunk_size: usize) -> u64 {
+        self.entries.iter().map(|e| e.chunk_count(chunk_size)).sum()
+    }
+
+    /// Names of the items that land directly in the download directory
+    pub fn top_level_names(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        self.entries
+            .iter()
+            .map(|e| e.path.split('/').next().unwrap_or_default())
+            .filter(|name| seen.insert(*name))
+            .collect()
+    }
+
+    /// "report.pdf", "project" or "3 items"
+    pub fn display_name(&self) -> String {
+        match self.top_level_names().as_slice() {
+            [name] => name.to_string(),
+            names => format!("{} items", names.len()),
+        }
+    }
+
+    /// Find the entry a chunk belongs to and the chunk's index within it
+    pub fn locate(&self, chunk_index: u64, chunk_size: usize) -> Option<(usize, u64)> {
+        let mut first = 0;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let count = entry.chunk_count(chunk_size);
+            if chunk_index < first + count {
+                return Some((i, chunk_index - first));
+            }
+            first += count;
+        }
+        None
+    }
+
+    /// Bytes of data in the chunks before `chunk_index`
+    pub fn bytes_before(&self, chunk_index: u64, chunk_size: usize) -> u64 {
+        let Some((index, chunk)) = self.locate(chunk_index, chunk_size) else {
+            return self.total_size();
+        };
+
+        let earlier: u64 = self.entries[..index].iter().map(|e| e.size).sum();
+        earlier + chunk * chunk_size as u64
+    }
+
+    /// Bytes of data in the given chunks
+    pub fn range_bytes(&self, chunks: Range<u64>, chunk_size: usize) -> u64 {
+        self.bytes_before(chunks.end, chunk_size) - self.bytes_before(chunks.start, chunk_size)
+    }
+
+    /// Chunks holding an entry's data
+    pub fn entry_chunks(&self, index: usize, chunk_size: usize) -> Range<u64> {
+        let first: u64 = self.entries[..index]
+            .iter()
+            .map(|e| e.chunk_count(chunk_size))
+            .sum();
+        first..first + self.entries[index].chunk_count(chunk_size)
+    }
+
+    /// Hash identifying everything the transfer contains: a single file's own
+    /// hash, or a hash over every file's hash in manifest order
+    ///
+    /// Only needs the per-file hashes, so unchanged files never have to be
+    /// read again to work it out.
+    pub fn content_hash(&self) -> String {
+        let mut files = self.entries.iter().filter(|e| e.kind == EntryKind::File);
+        match (files.next(), files.next()) {
+            (Some(file), None) => file.hash.to_ascii_lowercase(),
+            _ => {
+                let mut hasher = Sha256::new();
+                for entry in self.entries.iter().filter(|e| e.kind == EntryKind::File) {
+                    hasher.update(entry.hash.to_ascii_lowercase().as_bytes());
+                }
+                hex::encode(hasher.finalize())
+            }
+        }
+    }
+
+    /// Check an incoming manifest and make every path safe to create
+    ///
+    /// Returns `None` if any path can't be made safe, two entries end up with
+    /// the same path, or something would be created inside a file or link.
+    pub fn sanitized(&self) -> Option<Manifest> {
+        let mut entries = Vec::with_capacity(self.entries.len());
+        let mut paths = HashSet::new();
+
+        for entry in &self.entries {
+            let path = sanitize_relative_path(&entry.path)?;
+            if !paths.insert(path.clone()) {
+                return None;
+            }
+
+            let size = match entry.kind {
+                EntryKind::File => entry.size,
+                _ => 0,
+            };
+            entries.push(ManifestEntry {
+                path,
+                size,
+                ..entry.clone()
+            });
+        }
+
+        let leaves: HashSet<&str> = entries
+            .iter()
+            .filter(|e| e.kind != EntryKind::Directory)
+            .map(|e| e.path.as_str())
+            .collect();
+        let nested_in_leaf = entries.iter().any(|e| {
+            e.path
+                .match_indices('/')
+                .any(|(i, _)| leaves.contains(&e.path[..i]))
+        });
+        if nested_in_leaf {
+            return None;
+        }
+
+        Some(Manifest { entries })
+    }
+}
+
+/// Manifest of the given files and folders, with the local path of every entry
+///
+/// Everything is listed before anything is hashed, so `on_progress` gets the
+/// bytes hashed so far out of the total. Files found unchanged in `hash_cache`
+/// aren't read at all.
+pub async fn build_manifest(
+    paths: &[PathBuf],
+    policy: SymlinkPolicy,
+    hash_cache: Option<&HashCache>,
+    mut on_progress: impl FnMut(u64, u64),
+) -> std::io::Result<(Manifest, Vec<PathBuf>)> {
+    let mut entries = Vec::new();
+    let mut sources = Vec::new();
+    let mut top_level = HashSet::new();
+    let mut visited_dirs = HashSet::new();
+
+    for path in paths {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| invalid_input(format!("Invalid file name: {:?}", path)))?;
+        if !top_level.insert(name.to_string()) {
+            return Err(invalid_input(format!("Two items are named {:?}", name)));
+        }
+
+        // Depth-first, children in name order, so the manifest is deterministic
+        let mut stack = vec![(path.clone(), name.to_string())];
+        while let Some((source, relative)) = stack.pop() {
+            let mut metadata = tokio::fs::symlink_metadata(&source).await?;
+
+            if metadata.file_type().is_symlink() {
+                match policy {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Follow => metadata = tokio::fs::metadata(&source).await?,
+                    SymlinkPolicy::Preserve => {
+                        let target = tokio::fs::read_link(&source).await?;
+                        let target = target.to_str().ok_or_else(|| {
+                            invalid_input(format!("Invalid link target: {:?}", target))
+                        })?;
+                        entries.push(ManifestEntry {
+                            path: relative,
+                            kind: EntryKind::Symlink {
+                                target: target.replace('\\', "/"),
+                            },
+                            size: 0,
+                            hash: String::new(),
+                            mode: None,
+                        });
+                        sources.push(source);
+                        continue;
+                    }
+                }
+            }
+
+            if metadata.is_dir() {
+                // Followed links can point back up the tree
+                if !visited_dirs.insert(tokio::fs::canonicalize(&source).await?) {
+                    continue;
+                }
+
+                let mut children = Vec::new();
+                let mut dir = tokio::fs::read_dir(&source).await?;
+                while let Some(child) = dir.next_entry().await? {
+                    let child_name = child
+                        .file_name()
+                        .into_string()
+                        .map_err(|n| invalid_input(format!("Invalid file name: {:?}", n)))?;
+                    children.push(child_name);
+                }
+                children.sort();
+
+                for child in children.into_iter().rev() {
+                    stack.push((source.join(&child), format!("{}/{}", relative, child)));
+                }
+
+                entries.push(ManifestEntry {
+                    path: relative,
+                    kind: EntryKind::Directory,
+                    size: 0,
+                    hash: String::new(),
+                    mode: permission_bits(&metadata),
+                });
+                sources.push(source);
+            } else if metadata.is_file() {
+                entries.push(ManifestEntry {
+                    path: relative,
+                    kind: EntryKind::File,
+                    size: metadata.len(),
+                    hash: String::new(),
+                    mode: permission_bits(&metadata),
+                });
+                sources.push(source);
+            }
+        }
+    }
+
+    if entries.is_empty() {
+        return Err(invalid_input("Nothing to send".into()));
+    }
+
+    let total: u64 = entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::File)
+        .map(|e| e.size)
+        .sum();
+    let mut hashed = 0;
+    for (entry, source) in entries.iter_mut().zip(&sources) {
+        if entry.kind != EntryKind::File {
+            continue;
+        }
+
+        let metadata = tokio::fs::metadata(source).await?;
+        if let Some(hash) = hash_cache.and_then(|c| c.get(source, &metadata)) {
+            entry.size = metadata.len();
+            entry.hash = hash;
+            hashed += entry.size;
+            on_progress(hashed, total);
+            continue;
+        }
+
+        // Sizes come from what's actually read, in case a file changed since
+        let (size, hash) = hash_file(source, |bytes| {
+            hashed += bytes;
+            on_progress(hashed, total);
+        })
+        .await?;
+        if let Some(cache) = hash_cache {
+            if metadata.len() == size {
+                cache.insert(source, &metadata, hash.clone());
+            }
+        }
+        entry.size = size;
+        entry.hash = hash;
+    }
+
+    Ok((Manifest { entries }, sources))
+}
+
+/// Size and SHA-256 of a file, with `on_read` told about every block read
+pub(crate) async fn hash_file(
+    path: &Path,
+    mut on_read: impl FnMut(u64),
+) -> std::io::Result<(u64, String)> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..bytes_read]);
+        size += bytes_read as u64;
+        on_read(bytes_read as u64);
+    }
+
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(unix)]
+fn permission_bits(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn permission_bits(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: EntryKind, size: u64) -> ManifestEntry {
+        ManifestEntry {
+            path: path.into(),
+            kind,
+            size,
+            hash: String::new(),
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn test_locate_chunks() {
+        let manifest = Manifest {
+            entries: vec![
+                entry("p", EntryKind::Directory, 0),
+                entry("p/a", EntryKind::File, 25),
+                entry("p/empty", EntryKind::File, 0),
+                entry("p/b", EntryKind::File, 10),
+            ],
+        };
+
+        assert_eq!(manifest.chunk_count(10), 4);
+        assert_eq!(manifest.locate(0, 10), Some((1, 0)));
+        assert_eq!(manifest.locate(2, 10), Some((1, 2)));
+        assert_eq!(manifest.locate(3, 10), Some((3, 0)));
+        assert_eq!(manifest.locate(4, 10), None);
+        assert_eq!(manifest.bytes_before(3, 10), 25);
+        assert_eq!(manifest.range_bytes(2..4, 10), 15);
+        assert_eq!(manifest.entry_chunks(3, 10), 3..4);
+        assert_eq!(manifest.display_name(), "p");
+    }
+
+    #[test]
+    fn test_sanitized_rejects_unsafe_trees() {
+        let safe = Manifest {
+            entries: vec![
+                entry("p", EntryKind::Directory, 0),
+                entry("p/.env", EntryKind::File, 1),
+            ],
+        };
+        assert!(safe.sanitized().is_some());
+
+        let traversal = Manifest {
+            entries: vec![entry("p/../../x", EntryKind::File, 1)],
+        };
+        assert!(traversal.sanitized().is_none());
+
+        let duplicate = Manifest {
+            entries: vec![
+                entry("a", EntryKind::File, 1),
+                entry("a", EntryKind::File, 1),
+            ],
+        };
+        assert!(duplicate.sanitized().is_none());
+
+        let through_link = Manifest {
+            entries: vec![
+                entry(
+                    "p",
+                    EntryKind::Symlink {
+                        target: "/etc".into(),
+                    },
+                    0,
+                ),
+                entry("p/passwd", EntryKind::File, 1),
+            ],
+        };
+        assert!(through_link.sanitized().is_none());
+    }
+}
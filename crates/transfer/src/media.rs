@@ -0,0 +1,135 @@
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageReader};
+use lan_chat_core::{MessageType, UserId};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// Longest side of a thumbnail, in pixels
+pub const THUMBNAIL_SIZE: u32 = 320;
+
+/// JPEG quality of thumbnails; they only have to be recognizable
+const THUMBNAIL_QUALITY: u8 = 70;
+
+/// Images bigger than this get no thumbnail rather than tie up the sender
+const MAX_PREVIEW_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A file offered in a media message, which the peers it was sent to may fetch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedMedia {
+    pub file_hash: String,
+    pub path: PathBuf,
+    pub peers: Vec<UserId>,
+}
+
+/// Size and thumbnail of an image, sent inline with its message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImagePreview {
+    pub width: u32,
+    pub height: u32,
+    /// JPEG as a `data:` URL
+    pub thumbnail: String,
+}
+
+/// Kind of media message for a file, going by its extension
+pub fn media_type(path: &Path) -> Option<MessageType> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp" => Some(MessageType::Image),
+        "mp3" | "wav" | "ogg" | "oga" | "opus" | "m4a" | "aac" | "flac" => Some(MessageType::Audio),
+        "mp4" | "m4v" | "mov" | "webm" | "mkv" | "avi" => Some(MessageType::Video),
+        _ => None,
+    }
+}
+
+/// Read an image file and make its preview; `None` if it can't be decoded
+pub async fn image_preview(path: &Path) -> Option<ImagePreview> {
+    let size = tokio::fs::metadata(path).await.ok()?.len();
+    if size > MAX_PREVIEW_FILE_SIZE {
+        return None;
+    }
+    let data = tokio::fs::read(path).await.ok()?;
+
+    // Decoding and scaling are CPU-bound
+    tokio::task::spawn_blocking(move || preview_from_bytes(&data))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Decode an image in any supported format and make its preview
+///
+/// The decoder's default limits apply, so an image claiming huge dimensions
+/// fails instead of allocating them.
+pub fn preview_from_bytes(data: &[u8]) -> Option<ImagePreview> {
+    let image = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()?;
+    preview(&image)
+}
+
+fn preview(image: &DynamicImage) -> Option<ImagePreview> {
+    // JPEG has no alpha channel
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, THUMBNAIL_QUALITY)
+        .encode_image(&thumbnail)
+        .ok()?;
+
+    Some(ImagePreview {
+        width: image.width(),
+        height: image.height(),
+        thumbnail: format!(
+            "data:image/jpeg;base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(jpeg)
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbaImage};
+
+    #[test]
+    fn test_media_type() {
+        assert_eq!(
+            media_type(Path::new("Screenshot.PNG")),
+            Some(MessageType::Image)
+        );
+        assert_eq!(
+            media_type(Path::new("voice.opus")),
+            Some(MessageType::Audio)
+        );
+        assert_eq!(media_type(Path::new("clip.mp4")), Some(MessageType::Video));
+        assert_eq!(media_type(Path::new("report.pdf")), None);
+        assert_eq!(media_type(Path::new("png")), None);
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(1600, 400))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let preview = preview_from_bytes(&png).unwrap();
+        assert_eq!((preview.width, preview.height), (1600, 400));
+
+        let jpeg = preview
+            .thumbnail
+            .strip_prefix("data:image/jpeg;base64,")
+            .unwrap();
+        let jpeg = base64::engine::general_purpose::STANDARD
+            .decode(jpeg)
+            .unwrap();
+        let thumbnail = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (320, 80));
+
+        assert_eq!(preview_from_bytes(b"not an image"), None);
+    }
+}
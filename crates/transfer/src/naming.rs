@@ -0,0 +1,161 @@
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+
+/// Longest file name we'll create, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// Names Windows reserves regardless of extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Sanitize a '/'-separated path from a transfer manifest, component by component
+///
+/// Empty, `.` and `..` components are refused, so the result always stays
+/// below the directory it's joined to. Hidden names are fine below the top
+/// level since folders routinely contain them.
+pub fn sanitize_relative_path(path: &str) -> Option<String> {
+    let components = path
+        .split('/')
+        .enumerate()
+        .map(|(i, component)| clean_component(component, i > 0))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(components.join("/"))
+}
+
+fn clean_component(name: &str, allow_hidden: bool) -> Option<String> {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' | '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently drops trailing dots and spaces
+    let cleaned = cleaned.trim().trim_end_matches(['.', ' ']);
+
+    if cleaned.is_empty() || (cleaned.starts_with('.') && !allow_hidden) {
+        return None;
+    }
+
+    let stem = cleaned.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return None;
+    }
+
+    Some(truncate_name(cleaned))
+}
+
+/// Shorten a name to `MAX_NAME_LEN` bytes, keeping the extension
+fn truncate_name(name: &str) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name.to_string();
+    }
+
+    let (stem, ext) = split_extension(name);
+    let mut end = MAX_NAME_LEN.saturating_sub(ext.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &stem[..end], ext)
+}
+
+/// Split "report.pdf" into ("report", ".pdf")
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(idx) if idx > 0 => name.split_at(idx),
+        _ => (name, ""),
+    }
+}
+
+/// Candidate name for the nth collision: "report (1).pdf"
+fn numbered_name(name: &str, n: u32) -> String {
+    let (stem, ext) = split_extension(name);
+    format!("{} ({}){}", stem, n, ext)
+}
+
+/// Reserve a path in `dir` that doesn't clash with an existing file
+///
+/// The returned path exists as an empty file, so a concurrent download can't
+/// claim the same name before ours is renamed into place.
+pub async fn reserve_unique_path(dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    reserve(dir, name, false).await
+}
+
+/// Like `reserve_unique_path`, but reserves an empty directory
+pub async fn reserve_unique_dir(dir: &Path, name: &str) -> std::io::Result<PathBuf> {
+    reserve(dir, name, true).await
+}
+
+async fn reserve(dir: &Path, name: &str, is_dir: bool) -> std::io::Result<PathBuf> {
+    let mut n = 0;
+
+    loop {
+        let candidate = if n == 0 {
+            name.to_string()
+        } else {
+            numbered_name(name, n)
+        };
+        let path = dir.join(candidate);
+
+        let created = if is_dir {
+            tokio::fs::create_dir(&path).await
+        } else {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+                .map(|_| ())
+        };
+
+        match created {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_relative_path() {
+        assert_eq!(
+            sanitize_relative_path("project/src/.gitignore").as_deref(),
+            Some("project/src/.gitignore")
+        );
+        assert_eq!(
+            sanitize_relative_path("project/a\\b:c").as_deref(),
+            Some("project/a_b_c")
+        );
+        assert_eq!(sanitize_relative_path("project/../../etc/passwd"), None);
+        assert_eq!(sanitize_relative_path("/etc/passwd"), None);
+        assert_eq!(sanitize_relative_path("project//x"), None);
+        assert_eq!(sanitize_relative_path(".ssh/authorized_keys"), None);
+
+        // A single file's name is a path of one component
+        assert_eq!(
+            sanitize_relative_path("what?.txt").as_deref(),
+            Some("what_.txt")
+        );
+        assert_eq!(sanitize_relative_path("..\\..\\win.ini"), None);
+        assert_eq!(sanitize_relative_path(".."), None);
+        assert_eq!(sanitize_relative_path("dir/"), None);
+        assert_eq!(sanitize_relative_path("con.txt"), None);
+        assert_eq!(sanitize_relative_path("NUL"), None);
+    }
+
+    #[test]
+    fn test_numbered_name() {
+        assert_eq!(numbered_name("report.pdf", 1), "report (1).pdf");
+        assert_eq!(numbered_name("Makefile", 2), "Makefile (2)");
+    }
+}
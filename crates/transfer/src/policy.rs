@@ -0,0 +1,211 @@
+use crate::manifest::{EntryKind, Manifest};
+use crate::naming::sanitize_relative_path;
+use lan_chat_core::UserId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Rules for answering incoming transfers without asking the user
+///
+/// Only requests signed with the identity key we know for the sender get this
+/// far, so a peer on `allowed_peers` can't be impersonated by someone copying
+/// its user id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AcceptPolicy {
+    /// Peers whose transfers are accepted without asking
+    pub allowed_peers: Vec<UserId>,
+    /// Peers whose transfers are rejected without asking
+    pub blocked_peers: Vec<UserId>,
+    /// Largest transfer accepted without asking, in bytes; larger ones are
+    /// left to the user
+    pub max_auto_accept_size: Option<u64>,
+    /// If not empty, transfers with any other kind of file are left to the user
+    pub allowed_extensions: Vec<String>,
+    /// Transfers with any of these kinds of file are rejected, whoever sends them
+    pub denied_extensions: Vec<String>,
+    /// Folder inside the download directory for each peer's files
+    pub peer_folders: HashMap<UserId, String>,
+}
+
+/// What the policy says to do with a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Accept without asking, and why
+    Accept(String),
+    /// Reject without asking, with the reason given to the sender
+    Reject(String),
+    /// Leave it to the user
+    Ask,
+}
+
+impl AcceptPolicy {
+    /// Load the policy from a JSON file, returning the default (ask about
+    /// everything) if it doesn't exist
+    pub async fn load(path: &Path) -> lan_chat_core::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the policy as JSON, creating the parent directory if needed
+    pub async fn save(&self, path: &Path) -> lan_chat_core::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_vec_pretty(self)?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+
+    /// Decide on a request from `sender_id` for the files in `manifest`
+    pub fn evaluate(&self, sender_id: UserId, manifest: &Manifest) -> PolicyDecision {
+        if self.blocked_peers.contains(&sender_id) {
+            return PolicyDecision::Reject("Sender is blocked".into());
+        }
+
+        let extensions: Vec<String> = manifest
+            .entries
+            .iter()
+            .filter(|e| e.kind == EntryKind::File)
+            .map(|e| extension_of(&e.path))
+            .collect();
+
+        if let Some(denied) = extensions
+            .iter()
+            .find(|ext| contains_extension(&self.denied_extensions, ext))
+        {
+            return PolicyDecision::Reject(format!("Files of type .{} aren't accepted", denied));
+        }
+
+        if !self.allowed_peers.contains(&sender_id) {
+            return PolicyDecision::Ask;
+        }
+        if self
+            .max_auto_accept_size
+            .is_some_and(|max| manifest.total_size() > max)
+        {
+            return PolicyDecision::Ask;
+        }
+        if !self.allowed_extensions.is_empty()
+            && !extensions
+                .iter()
+                .all(|ext| contains_extension(&self.allowed_extensions, ext))
+        {
+            return PolicyDecision::Ask;
+        }
+
+        PolicyDecision::Accept("Sender is on the allow list".into())
+    }
+
+    /// Where files from `sender_id` go, given the download directory
+    ///
+    /// Folder names that would leave the download directory are ignored.
+    pub fn download_dir(&self, download_dir: &Path, sender_id: UserId) -> PathBuf {
+        match self
+            .peer_folders
+            .get(&sender_id)
+            .and_then(|folder| sanitize_relative_path(folder))
+        {
+            Some(folder) => download_dir.join(folder),
+            None => download_dir.to_path_buf(),
+        }
+    }
+}
+
+/// Lowercase extension of a '/'-separated path; empty if it has none
+fn extension_of(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.to_ascii_lowercase(),
+        _ => String::new(),
+    }
+}
+
+/// Whether `list` names `ext`, with or without a leading dot, in any case
+fn contains_extension(list: &[String], ext: &str) -> bool {
+    list.iter()
+        .any(|item| item.trim_start_matches('.').eq_ignore_ascii_case(ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+
+    fn manifest(files: &[(&str, u64)]) -> Manifest {
+        Manifest {
+            entries: files
+                .iter()
+                .map(|(path, size)| ManifestEntry {
+                    path: path.to_string(),
+                    kind: EntryKind::File,
+                    size: *size,
+                    hash: String::new(),
+                    mode: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let friend = uuid::Uuid::new_v4();
+        let stranger = uuid::Uuid::new_v4();
+        let pest = uuid::Uuid::new_v4();
+        let policy = AcceptPolicy {
+            allowed_peers: vec![friend],
+            blocked_peers: vec![pest],
+            max_auto_accept_size: Some(1000),
+            allowed_extensions: vec!["pdf".into(), ".PNG".into()],
+            denied_extensions: vec![".exe".into()],
+            ..Default::default()
+        };
+
+        let report = manifest(&[("docs/report.pdf", 500), ("docs/chart.png", 100)]);
+        assert!(matches!(
+            policy.evaluate(friend, &report),
+            PolicyDecision::Accept(_)
+        ));
+        assert_eq!(policy.evaluate(stranger, &report), PolicyDecision::Ask);
+        assert!(matches!(
+            policy.evaluate(pest, &report),
+            PolicyDecision::Reject(_)
+        ));
+
+        // Too big, or not on the extension list, is left to the user
+        let big = manifest(&[("report.pdf", 5000)]);
+        assert_eq!(policy.evaluate(friend, &big), PolicyDecision::Ask);
+        let archive = manifest(&[("report.pdf", 10), ("backup.tar.gz", 10)]);
+        assert_eq!(policy.evaluate(friend, &archive), PolicyDecision::Ask);
+
+        let setup = manifest(&[("tools/Setup.EXE", 10)]);
+        assert!(matches!(
+            policy.evaluate(stranger, &setup),
+            PolicyDecision::Reject(_)
+        ));
+    }
+
+    #[test]
+    fn test_download_dir() {
+        let friend = uuid::Uuid::new_v4();
+        let sneaky = uuid::Uuid::new_v4();
+        let policy = AcceptPolicy {
+            peer_folders: HashMap::from([(friend, "Alice".into()), (sneaky, "../..".into())]),
+            ..Default::default()
+        };
+
+        let downloads = Path::new("/downloads");
+        assert_eq!(
+            policy.download_dir(downloads, friend),
+            Path::new("/downloads/Alice")
+        );
+        assert_eq!(policy.download_dir(downloads, sneaky), downloads);
+        assert_eq!(
+            policy.download_dir(downloads, uuid::Uuid::new_v4()),
+            downloads
+        );
+    }
+}
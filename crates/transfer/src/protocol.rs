@@ -1,21 +1,49 @@
+use crate::manifest::Manifest;
+use chrono::{DateTime, Utc};
 use lan_chat_core::{TransferId, UserId};
+use lan_chat_crypto::{EncryptedData, EncryptedSessionKey};
 use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Smallest chunk size a transfer may use
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk size a transfer may use
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// File transfer protocol messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransferMessage {
-    /// Request to send a file
+    /// Request to send files and folders
     TransferRequest {
         transfer_id: TransferId,
         sender_id: UserId,
-        file_name: String,
-        file_size: u64,
+        manifest: Manifest,
+        /// `Manifest::content_hash`, which lets the recipient spot content it
+        /// already has
         file_hash: String,
+        /// Size of every chunk but the last of each file
+        chunk_size: usize,
+        /// Sender can compress chunks
+        #[serde(default)]
+        compression: bool,
+        /// Transfer key wrapped with the recipient's public key
+        encrypted_key: EncryptedSessionKey,
+        /// Sender's identity signature over the rest of the request
+        signature: Vec<u8>,
     },
 
     /// Accept file transfer
     TransferAccept {
         transfer_id: TransferId,
+        /// Sender must answer this in `StartTransfer`
+        challenge: Vec<u8>,
+        /// Chunks to send; the rest the recipient already had
+        missing: Vec<Range<u64>>,
+        /// Recipient takes compressed chunks; only set if the sender offered
+        #[serde(default)]
+        compression: bool,
     },
 
     /// Reject file transfer
@@ -27,19 +55,22 @@ pub enum TransferMessage {
     /// Start sending file data
     StartTransfer {
         transfer_id: TransferId,
+        /// Accept challenge sealed with the transfer key
+        proof: EncryptedData,
     },
 
-    /// File data chunk
+    /// File data chunk, encrypted with the chunk index as associated data
     DataChunk {
         transfer_id: TransferId,
         chunk_index: u64,
-        data: Vec<u8>,
+        /// `data` holds the chunk compressed with zstd
+        #[serde(default)]
+        compressed: bool,
+        data: EncryptedData,
     },
 
     /// Transfer complete
-    TransferComplete {
-        transfer_id: TransferId,
-    },
+    TransferComplete { transfer_id: TransferId },
 
     /// Transfer failed
     TransferFailed {
@@ -48,20 +79,49 @@ pub enum TransferMessage {
     },
 
     /// Request to pause transfer
-    Pause {
-        transfer_id: TransferId,
-    },
+    Pause { transfer_id: TransferId },
 
-    /// Resume transfer
+    /// Resume transfer, sending only the chunks the recipient is missing
     Resume {
         transfer_id: TransferId,
-        from_chunk: u64,
+        missing: Vec<Range<u64>>,
     },
 
     /// Cancel transfer
-    Cancel {
+    Cancel { transfer_id: TransferId },
+
+    /// Asks whoever opened a connection for an existing transfer to prove
+    /// they hold its key
+    Challenge {
+        transfer_id: TransferId,
+        challenge: Vec<u8>,
+    },
+
+    /// Challenge sealed with the transfer key
+    Proof {
         transfer_id: TransferId,
+        proof: EncryptedData,
     },
+
+    /// Opens an extra data connection for a running transfer
+    JoinStream { transfer_id: TransferId },
+
+    /// Recipient is ready for data on a joined connection
+    StreamReady { transfer_id: TransferId },
+
+    /// Asks the sender of a media message to send its file
+    FetchMedia {
+        requester_id: UserId,
+        file_hash: String,
+        /// Requester's identity signature over the rest of the request
+        signature: Vec<u8>,
+    },
+
+    /// Answer to `FetchMedia`: the transfer the file is coming in
+    FetchStarted { transfer_id: TransferId },
+
+    /// Answer to `FetchMedia`: the file won't be sent
+    FetchRefused { reason: String },
 }
 
 impl TransferMessage {
@@ -72,6 +132,17 @@ impl TransferMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
         serde_json::from_slice(bytes)
     }
+
+    /// Bytes covered by a request's signature: the request without it
+    pub fn signing_payload(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let mut unsigned = self.clone();
+        if let Self::TransferRequest { signature, .. } | Self::FetchMedia { signature, .. } =
+            &mut unsigned
+        {
+            signature.clear();
+        }
+        unsigned.to_bytes()
+    }
 }
 
 /// Transfer status
@@ -86,38 +157,99 @@ pub enum TransferStatus {
     Cancelled,
 }
 
+/// Which queued transfers get to send first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TransferPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Whether a transfer is coming in or going out, from where we stand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+}
+
 /// File transfer metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileTransfer {
     pub transfer_id: TransferId,
     pub sender_id: UserId,
     pub recipient_id: UserId,
+    /// Name of the single item sent, or a count like "3 items"
     pub file_name: String,
+    /// Total size of all files
     pub file_size: u64,
     pub file_hash: String,
+    pub manifest: Manifest,
+    pub chunk_size: usize,
+    /// Whether chunks may be sent compressed, as agreed when the transfer
+    /// was accepted
+    #[serde(default)]
+    pub compression: bool,
     pub bytes_transferred: u64,
     pub status: TransferStatus,
     pub error: Option<String>,
+    #[serde(default)]
+    pub priority: TransferPriority,
+    /// Bytes per second for this transfer, overriding the default limit
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// When the transfer was requested
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    /// When it completed, failed or was cancelled
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Where each top-level item of a completed incoming transfer was saved
+    #[serde(default)]
+    pub saved_paths: Vec<PathBuf>,
 }
 
 impl FileTransfer {
     pub fn new(
         sender_id: UserId,
         recipient_id: UserId,
-        file_name: String,
-        file_size: u64,
+        manifest: Manifest,
         file_hash: String,
     ) -> Self {
         Self {
             transfer_id: uuid::Uuid::new_v4(),
             sender_id,
             recipient_id,
-            file_name,
-            file_size,
+            file_name: manifest.display_name(),
+            file_size: manifest.total_size(),
             file_hash,
+            chunk_size: chunk_size_for(manifest.total_size()),
+            manifest,
+            compression: false,
             bytes_transferred: 0,
             status: TransferStatus::Pending,
             error: None,
+            priority: TransferPriority::default(),
+            rate_limit: None,
+            created_at: Utc::now(),
+            finished_at: None,
+            saved_paths: Vec::new(),
+        }
+    }
+
+    pub fn direction(&self, local_user: UserId) -> TransferDirection {
+        if self.sender_id == local_user {
+            TransferDirection::Outgoing
+        } else {
+            TransferDirection::Incoming
+        }
+    }
+
+    /// The other side of the transfer
+    pub fn peer_id(&self, local_user: UserId) -> UserId {
+        match self.direction(local_user) {
+            TransferDirection::Outgoing => self.recipient_id,
+            TransferDirection::Incoming => self.sender_id,
         }
     }
 
@@ -128,3 +260,17 @@ impl FileTransfer {
         (self.bytes_transferred as f64 / self.file_size as f64) * 100.0
     }
 }
+
+/// Chunk size for a transfer of `total_size` bytes
+///
+/// Aims for about a thousand chunks, so large transfers spend less time on
+/// per-chunk overhead while small ones still report progress smoothly.
+pub fn chunk_size_for(total_size: u64) -> usize {
+    let target = (total_size / 1024).max(1).next_power_of_two();
+    (target as usize).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+/// Whether a peer's chosen chunk size is one we'd pick ourselves
+pub fn is_valid_chunk_size(chunk_size: usize) -> bool {
+    chunk_size.is_power_of_two() && (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size)
+}
@@ -0,0 +1,190 @@
+use crate::protocol::TransferPriority;
+use lan_chat_core::TransferId;
+use std::cmp::Reverse;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::oneshot;
+
+/// Limits how many outgoing transfers send data at once
+///
+/// Transfers that have to wait get a slot highest priority first, then in the
+/// order they asked.
+#[derive(Debug, Clone)]
+pub struct TransferQueue {
+    // Never held across an await
+    state: Arc<Mutex<QueueState>>,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    max_running: usize,
+    running: usize,
+    waiting: Vec<Waiter>,
+    next_seq: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    transfer_id: TransferId,
+    priority: TransferPriority,
+    seq: u64,
+    slot_tx: oneshot::Sender<QueueSlot>,
+}
+
+/// Permission to send; the slot is handed on when this is dropped
+#[derive(Debug)]
+pub struct QueueSlot {
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl TransferQueue {
+    pub fn new(max_running: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(QueueState {
+                max_running: max_running.max(1),
+                running: 0,
+                waiting: Vec::new(),
+                next_seq: 0,
+            })),
+        }
+    }
+
+    pub fn set_max_running(&self, max_running: usize) {
+        lock(&self.state).max_running = max_running.max(1);
+        grant(&self.state);
+    }
+
+    /// Take a slot if one is free and nobody is waiting for it
+    pub fn try_acquire(&self) -> Option<QueueSlot> {
+        let mut state = lock(&self.state);
+        if state.running < state.max_running && state.waiting.is_empty() {
+            state.running += 1;
+            return Some(QueueSlot {
+                state: Arc::clone(&self.state),
+            });
+        }
+        None
+    }
+
+    /// Wait for a slot; dropping the future gives up the place in line
+    pub async fn acquire(&self, transfer_id: TransferId, priority: TransferPriority) -> QueueSlot {
+        self.enqueue(transfer_id, priority)
+            .await
+            .expect("waiters are only dropped once they've gone away")
+    }
+
+    /// Move a waiting transfer up or down the line
+    pub fn set_priority(&self, transfer_id: TransferId, priority: TransferPriority) {
+        let mut state = lock(&self.state);
+        for waiter in state
+            .waiting
+            .iter_mut()
+            .filter(|w| w.transfer_id == transfer_id)
+        {
+            waiter.priority = priority;
+        }
+    }
+
+    fn enqueue(
+        &self,
+        transfer_id: TransferId,
+        priority: TransferPriority,
+    ) -> oneshot::Receiver<QueueSlot> {
+        let (slot_tx, slot_rx) = oneshot::channel();
+        {
+            let mut state = lock(&self.state);
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Waiter {
+                transfer_id,
+                priority,
+                seq,
+                slot_tx,
+            });
+        }
+        grant(&self.state);
+        slot_rx
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        lock(&self.state).running -= 1;
+        grant(&self.state);
+    }
+}
+
+/// Hand free slots to the waiters first in line
+fn grant(state: &Arc<Mutex<QueueState>>) {
+    // Slots whose waiter gave up are released once the lock is let go
+    let mut unclaimed = Vec::new();
+    {
+        let mut guard = lock(state);
+        guard.waiting.retain(|w| !w.slot_tx.is_closed());
+
+        while guard.running < guard.max_running {
+            let Some(next) = guard
+                .waiting
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, w)| (w.priority, Reverse(w.seq)))
+                .map(|(i, _)| i)
+            else {
+                break;
+            };
+
+            let waiter = guard.waiting.remove(next);
+            guard.running += 1;
+            let slot = QueueSlot {
+                state: Arc::clone(state),
+            };
+            if let Err(slot) = waiter.slot_tx.send(slot) {
+                unclaimed.push(slot);
+            }
+        }
+    }
+    drop(unclaimed);
+}
+
+fn lock(state: &Mutex<QueueState>) -> MutexGuard<'_, QueueState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_go_by_priority() {
+        let queue = TransferQueue::new(1);
+        let first = queue.try_acquire().unwrap();
+        assert!(queue.try_acquire().is_none());
+
+        let mut low = queue.enqueue(uuid::Uuid::new_v4(), TransferPriority::Low);
+        let normal_id = uuid::Uuid::new_v4();
+        let mut normal = queue.enqueue(normal_id, TransferPriority::Normal);
+        let mut high = queue.enqueue(uuid::Uuid::new_v4(), TransferPriority::High);
+        queue.set_priority(normal_id, TransferPriority::Low);
+
+        drop(first);
+        let second = high.try_recv().unwrap();
+        assert!(normal.try_recv().is_err());
+
+        // Both are low now, so the one that asked first goes next
+        drop(second);
+        let _third = low.try_recv().unwrap();
+        assert!(normal.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_abandoned_slot_is_passed_on() {
+        let queue = TransferQueue::new(1);
+        let first = queue.try_acquire().unwrap();
+
+        let gave_up = queue.enqueue(uuid::Uuid::new_v4(), TransferPriority::High);
+        let mut waiting = queue.enqueue(uuid::Uuid::new_v4(), TransferPriority::Low);
+        drop(gave_up);
+
+        drop(first);
+        assert!(waiting.try_recv().is_ok());
+    }
+}
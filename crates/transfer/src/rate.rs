@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How far back `Throughput` looks when measuring speed
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(3);
+
+/// Token bucket limiting how many bytes per second pass through it
+///
+/// Holds up to one second's worth of tokens. A chunk bigger than what's left
+/// still goes through, putting the bucket in debt, and the next caller waits
+/// until it's paid off, so the average rate holds whatever the chunk size.
+#[derive(Debug)]
+pub struct RateLimiter {
+    // Never held across an await
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second; `None` means unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: tokio::time::Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|r| *r > 0);
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or_default() as f64,
+                refilled: tokio::time::Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.lock().rate
+    }
+
+    /// Change the limit; takes effect for the next `acquire`
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.lock();
+        bucket.refill();
+        bucket.rate = rate.filter(|r| *r > 0);
+        if let Some(rate) = bucket.rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        }
+    }
+
+    /// Wait until `bytes` may be sent
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.lock();
+            bucket.refill();
+            match bucket.rate {
+                Some(rate) => {
+                    bucket.tokens -= bytes as f64;
+                    (bucket.tokens < 0.0)
+                        .then(|| Duration::from_secs_f64(-bucket.tokens / rate as f64))
+                }
+                None => None,
+            }
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.bucket.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = tokio::time::Instant::now();
+        if let Some(rate) = self.rate {
+            let earned = now.duration_since(self.refilled).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + earned).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+}
+
+/// Recent transfer speed, measured over the last few seconds
+#[derive(Debug, Default)]
+pub struct Throughput {
+    samples: VecDeque<(Instant, u64)>,
+    started: Option<Instant>,
+}
+
+impl Throughput {
+    pub fn record(&mut self, bytes: u64) {
+        self.record_at(Instant::now(), bytes);
+    }
+
+    /// Bytes per second
+    pub fn bytes_per_second(&self) -> u64 {
+        self.rate_at(Instant::now())
+    }
+
+    /// Seconds until `remaining` more bytes are done at the current speed
+    pub fn eta_seconds(&self, remaining: u64) -> Option<u64> {
+        match self.bytes_per_second() {
+            0 => None,
+            rate => Some(remaining.div_ceil(rate)),
+        }
+    }
+
+    fn record_at(&mut self, now: Instant, bytes: u64) {
+        self.started.get_or_insert(now);
+        self.samples.push_back((now, bytes));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > THROUGHPUT_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    fn rate_at(&self, now: Instant) -> u64 {
+        let Some(started) = self.started else {
+            return 0;
+        };
+
+        // Right after starting, the window is only as long as we've been going
+        let window = now
+            .duration_since(started)
+            .min(THROUGHPUT_WINDOW)
+            .max(Duration::from_millis(100));
+        let bytes: u64 = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= window)
+            .map(|(_, bytes)| bytes)
+            .sum();
+
+        (bytes as f64 / window.as_secs_f64()) as u64
+    }
+}
+
+/// Speed of one phase of a transfer, and when it's next worth reporting
+#[derive(Debug)]
+pub struct ProgressMeter {
+    throughput: Throughput,
+    interval: Duration,
+    last_report: Option<Instant>,
+}
+
+impl ProgressMeter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            throughput: Throughput::default(),
+            interval,
+            last_report: None,
+        }
+    }
+
+    /// Count `bytes`, returning whether progress should be reported now
+    ///
+    /// Reports at most once per interval, except that the first and the
+    /// `finished` update always go out.
+    pub fn record(&mut self, bytes: u64, finished: bool) -> bool {
+        self.throughput.record(bytes);
+
+        let now = Instant::now();
+        let due = self
+            .last_report
+            .is_none_or(|last| now.duration_since(last) >= self.interval);
+        if due || finished {
+            self.last_report = Some(now);
+        }
+        due || finished
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.throughput.bytes_per_second()
+    }
+
+    pub fn eta_seconds(&self, remaining: u64) -> Option<u64> {
+        self.throughput.eta_seconds(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter_holds_average_rate() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let start = Instant::now();
+
+        // The first second's worth goes straight through, the rest is paced
+        for _ in 0..5 {
+            limiter.acquire(25_000).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(240));
+
+        limiter.set_rate(None);
+        let unlimited = Instant::now();
+        limiter.acquire(1_000_000).await;
+        assert!(unlimited.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_progress_meter_coalesces_reports() {
+        let mut meter = ProgressMeter::new(Duration::from_secs(60));
+        assert!(meter.record(100, false));
+        assert!(!meter.record(100, false));
+        assert!(meter.record(100, true));
+    }
+
+    #[test]
+    fn test_throughput() {
+        let start = Instant::now();
+        let mut throughput = Throughput::default();
+        throughput.record_at(start, 1000);
+        throughput.record_at(start + Duration::from_secs(1), 1000);
+        throughput.record_at(start + Duration::from_secs(2), 1000);
+
+        assert_eq!(throughput.rate_at(start + Duration::from_secs(2)), 1500);
+
+        // Old samples drop out of the window
+        throughput.record_at(start + Duration::from_secs(10), 3000);
+        assert_eq!(throughput.rate_at(start + Duration::from_secs(10)), 1000);
+    }
+}
@@ -1,47 +1,359 @@
-use crate::protocol::{FileTransfer, TransferMessage, TransferStatus};
+use crate::bundle::{BundleReader, BundleWriter};
+use crate::cache::{ContentCache, HashCache};
+use crate::chunks::{split_ranges, ChunkSet};
+use crate::compress::{self, ChunkCompressor};
+use crate::history::TransferFilter;
+use crate::key::{new_challenge, TransferKey};
+use crate::manifest::{build_manifest, EntryKind, Manifest, SymlinkPolicy};
+use crate::media::{image_preview, media_type, SharedMedia};
+use crate::naming::{reserve_unique_dir, reserve_unique_path};
+use crate::policy::{AcceptPolicy, PolicyDecision};
+use crate::protocol::{
+    is_valid_chunk_size, FileTransfer, TransferMessage, TransferPriority, TransferStatus,
+};
+use crate::queue::TransferQueue;
+use crate::rate::{ProgressMeter, RateLimiter};
+use crate::settings::TransferSettings;
+use crate::space::{available_space, check_incoming};
+use crate::store::{TransferRecord, TransferStore};
 use crate::TRANSFER_PORT;
-use lan_chat_core::{ChatEvent, PeerRegistry, TransferId, UserId};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use lan_chat_core::{
+    ChatEvent, MediaFile, Message, MessageType, PeerRegistry, SessionId, TransferId, TransferPhase,
+    UserId,
+};
+use lan_chat_crypto::{EncryptedData, EncryptedSessionKey, KeyPair, RsaSigner, Signature};
+use rsa::RsaPublicKey;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, oneshot, OnceCell, RwLock};
+use tokio::task::{JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-const CHUNK_SIZE: usize = 64 * 1024; // 64 KB chunks
+/// Connections a large transfer is spread over unless configured otherwise
+const DEFAULT_STREAMS: usize = 4;
+
+/// Most connections a recipient takes for one transfer
+const MAX_STREAMS: usize = 16;
+
+/// Each connection should have at least this much to send (8 MB)
+const MIN_STREAM_BYTES: u64 = 8 * 1024 * 1024;
+
+/// How long the sender waits for the recipient to accept or reject
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Save resume state every this many received bytes (16 MB)
+const PERSIST_INTERVAL: u64 = 16 * 1024 * 1024;
+
+/// Least time between two progress events of a transfer, unless configured
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long shutdown waits for running transfers to save their state
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Largest transfer message we read (32 MB); leaves room for a full chunk,
+/// which takes up to four bytes per byte once serialized, and large manifests
+const MAX_MESSAGE_SIZE: u32 = 32 * 1024 * 1024;
+
+/// Recipient's answer to an incoming transfer request
+enum TransferDecision {
+    Accept,
+    Reject(String),
+}
+
+/// Why a running transfer was asked to stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Pause,
+    Cancel,
+}
+
+/// Lets the user stop a running transfer
+///
+/// A cancelled token without a reason means the service is shutting down or a
+/// newer connection has taken over the transfer.
+#[derive(Clone)]
+struct TransferControl {
+    token: CancellationToken,
+    reason: Arc<OnceLock<StopReason>>,
+}
+
+impl TransferControl {
+    fn stop(&self, reason: StopReason) {
+        let _ = self.reason.set(reason);
+        self.token.cancel();
+    }
+
+    fn reason(&self) -> Option<StopReason> {
+        self.reason.get().copied()
+    }
+}
+
+/// Handle on a running transfer
+struct ActiveTransfer {
+    id: u64,
+    control: TransferControl,
+    /// Hands extra data connections to an incoming transfer's receiving loop
+    data_streams: Option<mpsc::UnboundedSender<TcpStream>>,
+}
+
+/// Staging directory opened for writing, with the chunks still to come
+struct PartialFile {
+    writer: BundleWriter,
+    missing: Vec<Range<u64>>,
+}
 
 /// File transfer service
 pub struct TransferService {
     user_id: UserId,
+    keypair: KeyPair,
     peer_registry: PeerRegistry,
     transfers: Arc<RwLock<HashMap<TransferId, FileTransfer>>>,
     event_tx: mpsc::UnboundedSender<ChatEvent>,
     download_dir: PathBuf,
+    /// Source path of each manifest entry of our outgoing transfers
+    outgoing_paths: Arc<RwLock<HashMap<TransferId, Vec<PathBuf>>>>,
+    /// Chunks sent or received so far
+    chunks_done: Arc<RwLock<HashMap<TransferId, ChunkSet>>>,
+    /// Keys of transfers that haven't finished yet
+    transfer_keys: Arc<RwLock<HashMap<TransferId, TransferKey>>>,
+    /// Incoming requests waiting on the user's decision
+    pending_decisions: Arc<RwLock<HashMap<TransferId, oneshot::Sender<TransferDecision>>>>,
+    /// Transfers currently sending or receiving data
+    active: Arc<RwLock<HashMap<TransferId, ActiveTransfer>>>,
+    next_active_id: AtomicU64,
+    store: Option<TransferStore>,
+    symlink_policy: SymlinkPolicy,
+    parallel_streams: usize,
+    settings: RwLock<TransferSettings>,
+    /// Which incoming transfers are answered without asking
+    accept_policy: RwLock<AcceptPolicy>,
+    /// Shared by every transfer, in both directions
+    global_limit: RateLimiter,
+    /// Each running transfer's own limit
+    transfer_limits: Arc<RwLock<HashMap<TransferId, Arc<RateLimiter>>>>,
+    /// Speed of each running transfer and when it last reported progress
+    progress: Arc<RwLock<HashMap<TransferId, ProgressMeter>>>,
+    progress_interval: Duration,
+    /// Outgoing transfers waiting for their turn to send
+    queue: TransferQueue,
+    /// Where received content and the hashes of sent files are cached
+    cache_dir: Option<PathBuf>,
+    /// Loaded the first time we send something
+    hash_cache: OnceCell<HashCache>,
+    /// Files offered in our media messages, by hash
+    shared_media: Arc<RwLock<HashMap<String, SharedMedia>>>,
+    /// Media we asked peers for, by sender and hash; accepted without asking
+    /// when it arrives
+    pending_fetches: Arc<RwLock<HashSet<(UserId, String)>>>,
+    /// Where we listen, and where peers' transfer services are reached
+    port: u16,
+    cancel_token: CancellationToken,
 }
 
 impl TransferService {
     pub fn new(
         user_id: UserId,
+        keypair: KeyPair,
         peer_registry: PeerRegistry,
         event_tx: mpsc::UnboundedSender<ChatEvent>,
         download_dir: PathBuf,
     ) -> Self {
         Self {
             user_id,
+            keypair,
             peer_registry,
             transfers: Arc::new(RwLock::new(HashMap::new())),
             event_tx,
             download_dir,
+            outgoing_paths: Arc::new(RwLock::new(HashMap::new())),
+            chunks_done: Arc::new(RwLock::new(HashMap::new())),
+            transfer_keys: Arc::new(RwLock::new(HashMap::new())),
+            pending_decisions: Arc::new(RwLock::new(HashMap::new())),
+            active: Arc::new(RwLock::new(HashMap::new())),
+            next_active_id: AtomicU64::new(0),
+            store: None,
+            symlink_policy: SymlinkPolicy::default(),
+            parallel_streams: DEFAULT_STREAMS,
+            settings: RwLock::new(TransferSettings::default()),
+            accept_policy: RwLock::new(AcceptPolicy::default()),
+            global_limit: RateLimiter::new(None),
+            transfer_limits: Arc::new(RwLock::new(HashMap::new())),
+            progress: Arc::new(RwLock::new(HashMap::new())),
+            progress_interval: PROGRESS_INTERVAL,
+            queue: TransferQueue::new(TransferSettings::default().max_concurrent_transfers),
+            cache_dir: None,
+            hash_cache: OnceCell::new(),
+            shared_media: Arc::new(RwLock::new(HashMap::new())),
+            pending_fetches: Arc::new(RwLock::new(HashSet::new())),
+            port: TRANSFER_PORT,
+            cancel_token: CancellationToken::new(),
+        }
+    }
+
+    /// Persist transfer state in `dir` so interrupted transfers survive a restart
+    pub fn with_state_dir(mut self, dir: PathBuf) -> Self {
+        self.store = Some(TransferStore::new(dir));
+        self
+    }
+
+    /// Cache received files and the hashes of sent ones in `dir`, so content
+    /// that was transferred before doesn't have to be sent or hashed again
+    pub fn with_cache_dir(mut self, dir: PathBuf) -> Self {
+        self.cache_dir = Some(dir);
+        self
+    }
+
+    /// How symbolic links inside folders we send are handled
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    /// How many connections large transfers we send are spread over; 1 keeps
+    /// everything on the request connection
+    pub fn with_parallel_streams(mut self, streams: usize) -> Self {
+        self.parallel_streams = streams.clamp(1, MAX_STREAMS);
+        self
+    }
+
+    /// Least time between two progress events of the same transfer
+    pub fn with_progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Port to listen on and to reach peers on instead of `TRANSFER_PORT`
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Rate limits and queue size to start with
+    pub fn with_settings(mut self, settings: TransferSettings) -> Self {
+        self.global_limit = RateLimiter::new(settings.global_rate_limit);
+        self.queue = TransferQueue::new(settings.max_concurrent_transfers);
+        self.settings = RwLock::new(settings);
+        self
+    }
+
+    /// Rules for accepting or rejecting incoming transfers without asking
+    pub fn with_accept_policy(mut self, policy: AcceptPolicy) -> Self {
+        self.accept_policy = RwLock::new(policy);
+        self
+    }
+
+    pub async fn accept_policy(&self) -> AcceptPolicy {
+        self.accept_policy.read().await.clone()
+    }
+
+    /// Replace the accept policy; applies to requests from now on
+    pub async fn set_accept_policy(&self, policy: AcceptPolicy) {
+        *self.accept_policy.write().await = policy;
+    }
+
+    pub async fn settings(&self) -> TransferSettings {
+        self.settings.read().await.clone()
+    }
+
+    /// Change rate limits and queue size; running transfers pick them up
+    /// right away
+    pub async fn update_settings(&self, settings: TransferSettings) {
+        self.global_limit.set_rate(settings.global_rate_limit);
+        self.queue
+            .set_max_running(settings.max_concurrent_transfers);
+
+        // Transfers with a limit of their own keep it
+        {
+            let transfers = self.transfers.read().await;
+            let limits = self.transfer_limits.read().await;
+            for (transfer_id, limit) in limits.iter() {
+                let own = transfers.get(transfer_id).and_then(|t| t.rate_limit);
+                limit.set_rate(own.or(settings.transfer_rate_limit));
+            }
+        }
+
+        *self.settings.write().await = settings;
+    }
+
+    pub async fn get_transfer(&self, transfer_id: TransferId) -> Option<FileTransfer> {
+        self.transfers.read().await.get(&transfer_id).cloned()
+    }
+
+    /// Transfers matching `filter`, running and finished, newest first
+    pub async fn list_transfers(&self, filter: &TransferFilter) -> Vec<FileTransfer> {
+        let mut transfers: Vec<_> = self
+            .transfers
+            .read()
+            .await
+            .values()
+            .filter(|t| filter.matches(t, self.user_id))
+            .cloned()
+            .collect();
+
+        transfers.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+        if let Some(limit) = filter.limit {
+            transfers.truncate(limit);
+        }
+        transfers
+    }
+
+    /// Move a transfer up or down the queue of outgoing transfers
+    pub async fn set_transfer_priority(
+        &self,
+        transfer_id: TransferId,
+        priority: TransferPriority,
+    ) -> lan_chat_core::Result<()> {
+        {
+            let mut transfers = self.transfers.write().await;
+            let transfer = transfers.get_mut(&transfer_id).ok_or_else(|| {
+                lan_chat_core::ChatError::FileTransfer("Transfer not found".into())
+            })?;
+            transfer.priority = priority;
+        }
+
+        self.queue.set_priority(transfer_id, priority);
+        self.persist(transfer_id).await;
+        Ok(())
+    }
+
+    /// Limit one transfer to `rate_limit` bytes per second; `None` goes back
+    /// to the default from the settings
+    pub async fn set_transfer_rate_limit(
+        &self,
+        transfer_id: TransferId,
+        rate_limit: Option<u64>,
+    ) -> lan_chat_core::Result<()> {
+        {
+            let mut transfers = self.transfers.write().await;
+            let transfer = transfers.get_mut(&transfer_id).ok_or_else(|| {
+                lan_chat_core::ChatError::FileTransfer("Transfer not found".into())
+            })?;
+            transfer.rate_limit = rate_limit;
         }
+
+        if let Some(limit) = self.transfer_limits.read().await.get(&transfer_id) {
+            let default = self.settings.read().await.transfer_rate_limit;
+            limit.set_rate(rate_limit.or(default));
+        }
+
+        self.persist(transfer_id).await;
+        Ok(())
     }
 
     /// Start the transfer service
     pub async fn start(self: Arc<Self>) -> lan_chat_core::Result<()> {
-        let addr = SocketAddr::from(([0, 0, 0, 0], TRANSFER_PORT));
+        self.restore_transfers().await;
+
+        let addr = SocketAddr::from(([0, 0, 0, 0], self.port));
         let listener = TcpListener::bind(addr)
             .await
             .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
@@ -49,7 +361,15 @@ impl TransferService {
         info!("Transfer service listening on {}", addr);
 
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                _ = self.cancel_token.cancelled() => {
+                    info!("Transfer service stopped");
+                    return Ok(());
+                }
+                accepted = listener.accept() => accepted,
+            };
+
+            match accepted {
                 Ok((stream, addr)) => {
                     debug!("New transfer connection from {}", addr);
                     let service = Arc::clone(&self);
@@ -66,59 +386,309 @@ impl TransferService {
         }
     }
 
-    /// Handle incoming transfer connection
-    async fn handle_connection(&self, mut stream: TcpStream) -> lan_chat_core::Result<()> {
-        // Read message length
-        let length = stream
-            .read_u32()
-            .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+    /// Reload finished transfers, and transfers interrupted in a previous run
+    /// as paused
+    async fn restore_transfers(&self) {
+        let Some(store) = &self.store else {
+            return;
+        };
 
-        // Read message
-        let mut buffer = vec![0u8; length as usize];
-        stream
-            .read_exact(&mut buffer)
-            .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+        match store.load_history().await {
+            Ok(history) => {
+                let mut transfers = self.transfers.write().await;
+                for transfer in history {
+                    transfers.insert(transfer.transfer_id, transfer);
+                }
+            }
+            Err(e) => warn!("Failed to load transfer history: {}", e),
+        }
 
-        let message = TransferMessage::from_bytes(&buffer)
-            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+        match store.load_shared_media().await {
+            Ok(media) => {
+                let mut shared = self.shared_media.write().await;
+                for media in media {
+                    shared.insert(media.file_hash.clone(), media);
+                }
+            }
+            Err(e) => warn!("Failed to load shared media: {}", e),
+        }
+
+        let records = match store.load_all().await {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("Failed to load saved transfers: {}", e);
+                return;
+            }
+        };
+
+        for mut record in records {
+            let transfer_id = record.transfer.transfer_id;
+            record.transfer.status = TransferStatus::Paused;
+
+            self.transfer_keys
+                .write()
+                .await
+                .insert(transfer_id, record.transfer_key);
+
+            match record.source_paths {
+                Some(paths) => {
+                    self.outgoing_paths.write().await.insert(transfer_id, paths);
+                }
+                None => {
+                    self.chunks_done
+                        .write()
+                        .await
+                        .insert(transfer_id, record.received_chunks);
+                }
+            }
+
+            self.transfers
+                .write()
+                .await
+                .insert(transfer_id, record.transfer);
+            info!("Restored paused transfer {}", transfer_id);
+        }
+    }
+
+    /// Handle incoming transfer connection
+    async fn handle_connection(
+        self: &Arc<Self>,
+        mut stream: TcpStream,
+    ) -> lan_chat_core::Result<()> {
+        let message = self.read_message(&mut stream).await?;
 
         match message {
             TransferMessage::TransferRequest {
                 transfer_id,
                 sender_id,
-                file_name,
-                file_size,
-                file_hash,
+                ref manifest,
+                ref file_hash,
+                chunk_size,
+                compression,
+                ref encrypted_key,
+                ref signature,
             } => {
-                // Store transfer info
-                let transfer = FileTransfer::new(
-                    sender_id,
-                    self.user_id,
-                    file_name.clone(),
-                    file_size,
-                    file_hash,
-                );
+                // Only take requests we can tie to a known peer's identity key
+                let key = match self
+                    .verify_request(&message, sender_id, encrypted_key, signature)
+                    .await
+                {
+                    Ok(key) => key,
+                    Err(e) => {
+                        warn!("Rejecting unverified transfer {}: {}", transfer_id, e);
+                        let reject = TransferMessage::TransferReject {
+                            transfer_id,
+                            reason: "Sender could not be verified".into(),
+                        };
+                        return self.send_message(&mut stream, &reject).await;
+                    }
+                };
+
+                // Never let the sender pick where files land
+                let Some(manifest) = manifest.sanitized() else {
+                    warn!("Rejecting transfer {} with unsafe file names", transfer_id);
+                    let reject = TransferMessage::TransferReject {
+                        transfer_id,
+                        reason: "Invalid file name".into(),
+                    };
+                    return self.send_message(&mut stream, &reject).await;
+                };
+
+                if !is_valid_chunk_size(chunk_size) {
+                    let reject = TransferMessage::TransferReject {
+                        transfer_id,
+                        reason: "Invalid chunk size".into(),
+                    };
+                    return self.send_message(&mut stream, &reject).await;
+                }
 
+                // Blocked peers are turned away before anything else is looked at
+                let policy_decision = self
+                    .accept_policy
+                    .read()
+                    .await
+                    .evaluate(sender_id, &manifest);
+                let fetched = self
+                    .pending_fetches
+                    .write()
+                    .await
+                    .remove(&(sender_id, file_hash.clone()));
+                // Content we already have is answered right away, as taking
+                // it costs neither time nor space
+                let cached = self.is_cached(&manifest).await;
+                let policy_decision = match policy_decision {
+                    PolicyDecision::Reject(reason) => PolicyDecision::Reject(reason),
+                    _ if fetched => PolicyDecision::Accept("You asked for this media".into()),
+                    _ if cached => PolicyDecision::Accept("Already have these files".into()),
+                    decision => decision,
+                };
+                let decision = match policy_decision {
+                    PolicyDecision::Reject(reason) => PolicyDecision::Reject(reason),
+                    decision if cached => decision,
+                    decision => match self.check_space(manifest.total_size()).await {
+                        Some(reason) => PolicyDecision::Reject(reason),
+                        None => decision,
+                    },
+                };
+
+                // Store transfer info under the sender's id
+                let mut transfer =
+                    FileTransfer::new(sender_id, self.user_id, manifest, file_hash.clone());
+                transfer.transfer_id = transfer_id;
+                transfer.chunk_size = chunk_size;
+                // We can always expand chunks, so whatever the sender offers goes
+                transfer.compression = compression;
+                let file_name = transfer.file_name.clone();
+                let file_size = transfer.file_size;
+                let file_count = transfer.manifest.file_count();
+
+                // A replayed request must not clobber the transfer it copies
                 {
                     let mut transfers = self.transfers.write().await;
+                    if transfers.contains_key(&transfer_id) {
+                        drop(transfers);
+                        warn!("Rejecting duplicate transfer request {}", transfer_id);
+                        let reject = TransferMessage::TransferReject {
+                            transfer_id,
+                            reason: "Duplicate transfer".into(),
+                        };
+                        return self.send_message(&mut stream, &reject).await;
+                    }
                     transfers.insert(transfer_id, transfer);
                 }
+                self.transfer_keys.write().await.insert(transfer_id, key);
+
+                if let PolicyDecision::Reject(reason) = &decision {
+                    info!("Rejecting transfer {}: {}", transfer_id, reason);
+                    {
+                        let mut transfers = self.transfers.write().await;
+                        if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                            transfer.status = TransferStatus::Cancelled;
+                            transfer.error = Some(reason.clone());
+                        }
+                    }
+                    self.forget(transfer_id).await;
+
+                    let _ = self.event_tx.send(ChatEvent::FileTransferAutoRejected {
+                        transfer_id,
+                        from: sender_id,
+                        file_name,
+                        reason: reason.clone(),
+                    });
+                    let reject = TransferMessage::TransferReject {
+                        transfer_id,
+                        reason: reason.clone(),
+                    };
+                    return self.send_message(&mut stream, &reject).await;
+                }
+
+                let (decision_tx, decision_rx) = oneshot::channel();
+                {
+                    let mut pending = self.pending_decisions.write().await;
+                    pending.insert(transfer_id, decision_tx);
+                }
 
                 // Notify user about incoming transfer
                 let _ = self.event_tx.send(ChatEvent::FileTransferRequested {
                     transfer_id,
                     from: sender_id,
-                    file_name,
+                    file_name: file_name.clone(),
                     file_size,
+                    file_count,
                 });
 
-                // Wait for user decision (handled separately)
+                if let PolicyDecision::Accept(reason) = decision {
+                    info!("Accepting transfer {}: {}", transfer_id, reason);
+                    self.accept_transfer(transfer_id).await?;
+                    let _ = self.event_tx.send(ChatEvent::FileTransferAutoAccepted {
+                        transfer_id,
+                        from: sender_id,
+                        file_name,
+                        reason,
+                    });
+                }
+
+                self.await_decision(stream, transfer_id, decision_rx)
+                    .await?;
+            }
+
+            TransferMessage::Resume {
+                transfer_id,
+                missing,
+            } => {
+                self.handle_resume(stream, transfer_id, missing).await?;
+            }
+
+            // The sender opened another connection for a running transfer
+            TransferMessage::JoinStream { transfer_id } => {
+                if let Err(e) = self.challenge_peer(&mut stream, transfer_id).await {
+                    warn!(
+                        "Refusing data connection for transfer {}: {}",
+                        transfer_id, e
+                    );
+                    return Ok(());
+                }
+
+                let data_streams = self
+                    .active
+                    .read()
+                    .await
+                    .get(&transfer_id)
+                    .and_then(|a| a.data_streams.clone());
+                let refused = match data_streams {
+                    Some(data_streams) => data_streams.send(stream).err().map(|e| e.0),
+                    None => Some(stream),
+                };
+
+                if let Some(mut stream) = refused {
+                    let failed = TransferMessage::TransferFailed {
+                        transfer_id,
+                        error: "Transfer isn't running".into(),
+                    };
+                    return self.send_message(&mut stream, &failed).await;
+                }
+            }
+
+            // The peer cancelled a transfer while it was paused
+            TransferMessage::Cancel { transfer_id } => {
+                let paused = self
+                    .transfers
+                    .read()
+                    .await
+                    .get(&transfer_id)
+                    .is_some_and(|t| t.status == TransferStatus::Paused);
+
+                if paused {
+                    if let Err(e) = self.challenge_peer(&mut stream, transfer_id).await {
+                        warn!("Ignoring cancel for transfer {}: {}", transfer_id, e);
+                        return Ok(());
+                    }
+
+                    info!("Transfer {} cancelled by peer", transfer_id);
+                    self.mark_cancelled(transfer_id).await;
+                }
             }
 
-            TransferMessage::StartTransfer { transfer_id } => {
-                self.receive_file(stream, transfer_id).await?;
+            TransferMessage::FetchMedia {
+                requester_id,
+                ref file_hash,
+                ref signature,
+            } => {
+                let reply = match self
+                    .verify_signature(&message, requester_id, signature)
+                    .await
+                {
+                    Ok(()) => self.start_fetch(requester_id, file_hash).await,
+                    Err(e) => {
+                        warn!("Refusing unverified media request: {}", e);
+                        Err("Requester could not be verified".into())
+                    }
+                };
+                let reply = match reply {
+                    Ok(transfer_id) => TransferMessage::FetchStarted { transfer_id },
+                    Err(reason) => TransferMessage::FetchRefused { reason },
+                };
+                return self.send_message(&mut stream, &reply).await;
             }
 
             _ => {
@@ -129,378 +699,3278 @@ impl TransferService {
         Ok(())
     }
 
-    /// Send a file to a peer
-    pub async fn send_file(
-        &self,
-        recipient_id: UserId,
-        file_path: &Path,
-    ) -> lan_chat_core::Result<TransferId> {
-        // Get file info
-        let file_name = file_path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .ok_or_else(|| lan_chat_core::ChatError::FileTransfer("Invalid file name".into()))?
-            .to_string();
+    /// Send a shared media file to a peer that asked for it
+    async fn start_fetch(
+        self: &Arc<Self>,
+        requester_id: UserId,
+        file_hash: &str,
+    ) -> Result<TransferId, String> {
+        let path = self
+            .shared_media
+            .read()
+            .await
+            .get(file_hash)
+            .filter(|media| media.peers.contains(&requester_id))
+            .map(|media| media.path.clone())
+            .ok_or_else(|| String::from("File isn't shared with you"))?;
 
-        let metadata = tokio::fs::metadata(file_path)
+        self.send_file(requester_id, &path)
             .await
-            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+            .map_err(|e| e.to_string())
+    }
 
-        let file_size = metadata.len();
+    /// Hold the request connection open until the user accepts or rejects
+    async fn await_decision(
+        self: &Arc<Self>,
+        mut stream: TcpStream,
+        transfer_id: TransferId,
+        decision_rx: oneshot::Receiver<TransferDecision>,
+    ) -> lan_chat_core::Result<()> {
+        let decision = tokio::select! {
+            _ = self.cancel_token.cancelled() => {
+                self.pending_decisions.write().await.remove(&transfer_id);
+                return Ok(());
+            }
+            decision = decision_rx => decision.ok(),
+            // The sender has nothing to say until we answer, so anything here
+            // means it cancelled or went away
+            _ = self.read_message(&mut stream) => None,
+        };
 
-        // Calculate file hash
-        let file_hash = self.calculate_file_hash(file_path).await?;
+        self.pending_decisions.write().await.remove(&transfer_id);
+
+        match decision {
+            Some(TransferDecision::Accept) => {
+                // Anything we already have is put in place before answering,
+                // so the sender only sends the rest
+                let mut partial = match self.open_partial(transfer_id).await {
+                    Ok(partial) => partial,
+                    // Most likely the disk filled up while reserving space
+                    Err(e) => {
+                        let reason = format!("Recipient can't save the files: {}", e);
+                        remove_staging(&self.partial_path(transfer_id)).await;
+                        self.mark_failed(transfer_id, TransferStatus::Failed, reason.clone())
+                            .await;
+                        let reject = TransferMessage::TransferReject {
+                            transfer_id,
+                            reason,
+                        };
+                        return self.send_message(&mut stream, &reject).await;
+                    }
+                };
+                self.fill_from_cache(transfer_id, &mut partial).await;
+                let staging_dir = self.partial_path(transfer_id);
+                let compression = self
+                    .transfers
+                    .read()
+                    .await
+                    .get(&transfer_id)
+                    .is_some_and(|t| t.compression);
+
+                let challenge = new_challenge();
+                let accept = TransferMessage::TransferAccept {
+                    transfer_id,
+                    challenge: challenge.clone(),
+                    missing: partial.missing.clone(),
+                    compression,
+                };
+                if let Err(e) = self.send_message(&mut stream, &accept).await {
+                    self.discard_download(transfer_id, &staging_dir, e.to_string())
+                        .await;
+                    return Ok(());
+                }
 
-        // Create transfer
-        let transfer = FileTransfer::new(
-            self.user_id,
-            recipient_id,
-            file_name.clone(),
-            file_size,
-            file_hash.clone(),
-        );
+                match self.read_message(&mut stream).await {
+                    Ok(TransferMessage::StartTransfer { proof, .. }) => {
+                        if let Err(e) = self.verify_proof(transfer_id, &challenge, &proof).await {
+                            self.discard_download(transfer_id, &staging_dir, e.to_string())
+                                .await;
+                            return Ok(());
+                        }
+                        self.run_receiving(stream, transfer_id, partial).await;
+                    }
+                    Ok(TransferMessage::TransferFailed { error, .. }) => {
+                        self.discard_download(transfer_id, &staging_dir, error)
+                            .await;
+                    }
+                    Ok(other) => {
+                        warn!("Unexpected transfer message: {:?}", other);
+                        self.discard_download(
+                            transfer_id,
+                            &staging_dir,
+                            "Sender didn't start the transfer".into(),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        self.discard_download(transfer_id, &staging_dir, e.to_string())
+                            .await;
+                    }
+                }
+            }
 
-        let transfer_id = transfer.transfer_id;
+            Some(TransferDecision::Reject(reason)) => {
+                let reject = TransferMessage::TransferReject {
+                    transfer_id,
+                    reason,
+                };
+                self.send_message(&mut stream, &reject).await?;
+            }
 
-        {
-            let mut transfers = self.transfers.write().await;
-            transfers.insert(transfer_id, transfer);
+            // Sender withdrew the request
+            None => {
+                self.mark_cancelled(transfer_id).await;
+            }
         }
 
-        // Get peer address
-        let peer = self
-            .peer_registry
-            .get_peer(&recipient_id)
-            .await
-            .ok_or_else(|| lan_chat_core::ChatError::PeerNotFound(recipient_id.to_string()))?;
+        Ok(())
+    }
 
-        let addr = peer.address.to_socket_addr();
-        let transfer_addr = SocketAddr::new(addr.ip(), TRANSFER_PORT);
+    /// Peer asked to continue a paused transfer
+    async fn handle_resume(
+        self: &Arc<Self>,
+        mut stream: TcpStream,
+        transfer_id: TransferId,
+        missing: Vec<Range<u64>>,
+    ) -> lan_chat_core::Result<()> {
+        // Resuming hands out or accepts data, so only the key holder may ask
+        if let Err(e) = self.challenge_peer(&mut stream, transfer_id).await {
+            warn!("Refusing to resume transfer {}: {}", transfer_id, e);
+            let failed = TransferMessage::TransferFailed {
+                transfer_id,
+                error: "Transfer can't be resumed".into(),
+            };
+            return self.send_message(&mut stream, &failed).await;
+        }
 
-        // Connect and send request
-        let mut stream = TcpStream::connect(transfer_addr)
+        let resumable = self
+            .transfers
+            .read()
             .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
-
-        let request = TransferMessage::TransferRequest {
-            transfer_id,
-            sender_id: self.user_id,
-            file_name,
-            file_size,
-            file_hash,
-        };
+            .get(&transfer_id)
+            .is_some_and(|t| is_resumable(t.status));
 
-        self.send_message(&mut stream, &request).await?;
-
-        // Wait for acceptance (handled separately)
+        if !resumable {
+            let failed = TransferMessage::TransferFailed {
+                transfer_id,
+                error: "Transfer can't be resumed".into(),
+            };
+            return self.send_message(&mut stream, &failed).await;
+        }
 
-        Ok(transfer_id)
-    }
+        let source_paths = self.outgoing_paths.read().await.get(&transfer_id).cloned();
 
-    /// Accept a file transfer
-    pub async fn accept_transfer(&self, transfer_id: TransferId) -> lan_chat_core::Result<()> {
-        // Update transfer status
-        {
-            let mut transfers = self.transfers.write().await;
-            if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                transfer.status = TransferStatus::Accepted;
-            } else {
-                return Err(lan_chat_core::ChatError::FileTransfer(
-                    "Transfer not found".into(),
-                ));
+        match source_paths {
+            // We're the sender: send whatever the recipient is missing
+            Some(paths) => {
+                self.run_sending(stream, transfer_id, paths, missing).await;
             }
-        }
 
-        let _ = self.event_tx.send(ChatEvent::FileTransferAccepted { transfer_id });
+            // We're the recipient: tell the sender what we still need
+            None => {
+                let partial = self
+                    .open_partial(transfer_id)
+                    .await
+                    .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
 
-        Ok(())
-    }
+                let resume = TransferMessage::Resume {
+                    transfer_id,
+                    missing: partial.missing.clone(),
+                };
+                self.send_message(&mut stream, &resume).await?;
 
-    /// Reject a file transfer
-    pub async fn reject_transfer(
-        &self,
-        transfer_id: TransferId,
-        reason: String,
-    ) -> lan_chat_core::Result<()> {
-        {
-            let mut transfers = self.transfers.write().await;
-            if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                transfer.status = TransferStatus::Cancelled;
-                transfer.error = Some(reason);
+                self.run_receiving(stream, transfer_id, partial).await;
             }
         }
 
         Ok(())
     }
 
-    /// Start sending file after acceptance
-    pub async fn start_sending(
-        &self,
-        transfer_id: TransferId,
+    /// Send a file or folder to a peer
+    pub async fn send_file(
+        self: &Arc<Self>,
+        recipient_id: UserId,
         file_path: &Path,
-    ) -> lan_chat_core::Result<()> {
-        let transfer = {
-            let transfers = self.transfers.read().await;
-            transfers
-                .get(&transfer_id)
-                .cloned()
-                .ok_or_else(|| lan_chat_core::ChatError::FileTransfer("Transfer not found".into()))?
-        };
-
-        // Get recipient address
-        let peer = self
-            .peer_registry
-            .get_peer(&transfer.recipient_id)
+    ) -> lan_chat_core::Result<TransferId> {
+        self.send_files(recipient_id, &[file_path.to_path_buf()])
             .await
-            .ok_or_else(|| {
-                lan_chat_core::ChatError::PeerNotFound(transfer.recipient_id.to_string())
-            })?;
-
-        let addr = peer.address.to_socket_addr();
-        let transfer_addr = SocketAddr::new(addr.ip(), TRANSFER_PORT);
+    }
 
-        // Connect
-        let mut stream = TcpStream::connect(transfer_addr)
+    /// Send several files and folders to a peer as one transfer
+    pub async fn send_files(
+        self: &Arc<Self>,
+        recipient_id: UserId,
+        paths: &[PathBuf],
+    ) -> lan_chat_core::Result<TransferId> {
+        // Known before hashing starts, so hashing progress can be reported
+        let transfer_id = uuid::Uuid::new_v4();
+
+        // List and hash everything up front so the recipient can see what's coming
+        let mut meter = ProgressMeter::new(self.progress_interval);
+        let mut previous = 0;
+        let hash_cache = self.hash_cache().await;
+        let (manifest, source_paths) =
+            build_manifest(paths, self.symlink_policy, hash_cache, |hashed, total| {
+                let bytes = hashed - std::mem::replace(&mut previous, hashed);
+                if meter.record(bytes, hashed == total) {
+                    self.emit_progress(transfer_id, TransferPhase::Hashing, hashed, total, &meter);
+                }
+            })
             .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        if let Some(cache) = hash_cache {
+            if let Err(e) = cache.save().await {
+                warn!("Failed to save file hashes: {}", e);
+            }
+        }
+        let file_hash = manifest.content_hash();
 
-        // Send start message
-        let start_msg = TransferMessage::StartTransfer { transfer_id };
-        self.send_message(&mut stream, &start_msg).await?;
+        // Only the recipient can unwrap the key, so only it can read the data
+        let key = TransferKey::generate();
+        let encrypted_key = key
+            .wrap(&self.peer_public_key(&recipient_id).await?)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+
+        // Create transfer
+        let mut transfer = FileTransfer::new(
+            self.user_id,
+            recipient_id,
+            manifest.clone(),
+            file_hash.clone(),
+        );
+        transfer.transfer_id = transfer_id;
+        transfer.compression = self.settings.read().await.compress_transfers;
+        let chunk_size = transfer.chunk_size;
+        let compression = transfer.compression;
+        let file_size = transfer.file_size;
 
-        // Update status
         {
             let mut transfers = self.transfers.write().await;
-            if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                transfer.status = TransferStatus::InProgress;
-            }
+            transfers.insert(transfer_id, transfer);
+        }
+        {
+            let mut paths = self.outgoing_paths.write().await;
+            paths.insert(transfer_id, source_paths);
         }
+        self.transfer_keys.write().await.insert(transfer_id, key);
 
-        // Open file
-        let mut file = File::open(file_path)
-            .await
-            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        // Connect and send request; the transfer is registered by now, so a
+        // failure must end it rather than leave it pending
+        let sent = async {
+            let mut stream = self.connect_to_peer(&recipient_id).await?;
+
+            let request = self.sign_request(TransferMessage::TransferRequest {
+                transfer_id,
+                sender_id: self.user_id,
+                manifest,
+                file_hash,
+                chunk_size,
+                compression,
+                encrypted_key,
+                signature: Vec::new(),
+            })?;
 
-        // Send file in chunks
-        let mut chunk_index = 0u64;
-        let mut buffer = vec![0u8; CHUNK_SIZE];
+            self.send_message(&mut stream, &request).await?;
+            Ok::<_, lan_chat_core::ChatError>(stream)
+        }
+        .await;
+        let stream = match sent {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.mark_failed(transfer_id, TransferStatus::Failed, e.to_string())
+                    .await;
+                return Err(e);
+            }
+        };
 
-        loop {
-            let bytes_read = file
-                .read(&mut buffer)
-                .await
-                .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        // Registered before returning so the request can be cancelled right away
+        let (id, control) = self.activate(transfer_id).await;
 
-            if bytes_read == 0 {
-                break;
+        let _ = self.event_tx.send(ChatEvent::FileTransferProgress {
+            transfer_id,
+            phase: TransferPhase::WaitingForAccept,
+            bytes_transferred: 0,
+            total_bytes: file_size,
+            bytes_per_second: 0,
+            eta_seconds: None,
+        });
+
+        // Wait for the recipient's decision in the background
+        let service = Arc::clone(self);
+        tokio::spawn(async move {
+            let response = service
+                .await_response(stream, transfer_id, id, control)
+                .await;
+            if let Err(e) = response {
+                service
+                    .mark_failed(transfer_id, TransferStatus::Failed, e.to_string())
+                    .await;
             }
+        });
 
-            let chunk = TransferMessage::DataChunk {
-                transfer_id,
-                chunk_index,
-                data: buffer[..bytes_read].to_vec(),
-            };
+        Ok(transfer_id)
+    }
 
-            self.send_message(&mut stream, &chunk).await?;
+    /// Wait for the recipient to answer our request, then send the file if accepted
+    async fn await_response(
+        self: &Arc<Self>,
+        mut stream: TcpStream,
+        transfer_id: TransferId,
+        id: u64,
+        control: TransferControl,
+    ) -> lan_chat_core::Result<()> {
+        let response = tokio::select! {
+            _ = control.token.cancelled() => None,
+            response = tokio::time::timeout(RESPONSE_TIMEOUT, self.read_message(&mut stream)) => {
+                Some(response)
+            }
+        };
+        self.deactivate(transfer_id, id).await;
+
+        let Some(response) = response else {
+            if control.reason() == Some(StopReason::Cancel) {
+                // Withdraw the request before the recipient answers
+                let cancel = TransferMessage::Cancel { transfer_id };
+                let _ = self.send_message(&mut stream, &cancel).await;
+                self.mark_cancelled(transfer_id).await;
+            } else {
+                self.abort_transfer(transfer_id).await;
+            }
+            return Ok(());
+        };
 
-            // Update progress
-            {
-                let mut transfers = self.transfers.write().await;
-                if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                    transfer.bytes_transferred += bytes_read as u64;
+        let response = response.map_err(|_| {
+            lan_chat_core::ChatError::FileTransfer("No response from recipient".into())
+        })??;
 
-                    let _ = self.event_tx.send(ChatEvent::FileTransferProgress {
-                        transfer_id,
-                        bytes_transferred: transfer.bytes_transferred,
-                        total_bytes: transfer.file_size,
-                    });
+        match response {
+            TransferMessage::TransferAccept {
+                challenge,
+                missing,
+                compression,
+                ..
+            } => {
+                {
+                    let mut transfers = self.transfers.write().await;
+                    if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                        transfer.status = TransferStatus::Accepted;
+                        // Older recipients don't answer, so they get plain chunks
+                        transfer.compression &= compression;
+                    }
+                }
+
+                // Accepted transfers can be resumed, so remember them
+                self.persist(transfer_id).await;
+
+                let _ = self
+                    .event_tx
+                    .send(ChatEvent::FileTransferAccepted { transfer_id });
+
+                let source_paths = self
+                    .outgoing_paths
+                    .read()
+                    .await
+                    .get(&transfer_id)
+                    .cloned()
+                    .ok_or_else(|| {
+                        lan_chat_core::ChatError::FileTransfer("Transfer not found".into())
+                    })?;
+
+                // Prove we're the peer that made the request
+                let proof = self
+                    .transfer_key(transfer_id)
+                    .await?
+                    .prove(transfer_id, &challenge)
+                    .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+
+                // Send start message
+                let start_msg = TransferMessage::StartTransfer { transfer_id, proof };
+                if let Err(e) = self.send_message(&mut stream, &start_msg).await {
+                    warn!("Transfer {} interrupted: {}", transfer_id, e);
+                    self.mark_paused(transfer_id).await;
+                    return Ok(());
                 }
+
+                self.run_sending(stream, transfer_id, source_paths, missing)
+                    .await;
+                Ok(())
+            }
+
+            TransferMessage::TransferReject { reason, .. } => {
+                {
+                    let mut transfers = self.transfers.write().await;
+                    if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                        transfer.status = TransferStatus::Cancelled;
+                        transfer.error = Some(reason.clone());
+                    }
+                }
+
+                self.forget(transfer_id).await;
+
+                let _ = self.event_tx.send(ChatEvent::FileTransferRejected {
+                    transfer_id,
+                    reason,
+                });
+
+                Ok(())
             }
 
-            chunk_index += 1;
+            other => Err(lan_chat_core::ChatError::Protocol(format!(
+                "Unexpected response to transfer request: {:?}",
+                other
+            ))),
         }
+    }
 
-        // Send completion
-        let complete = TransferMessage::TransferComplete { transfer_id };
-        self.send_message(&mut stream, &complete).await?;
+    /// Make an image, audio or video message for the file at `path` and let
+    /// `recipient_id` fetch the file later with `fetch_media`
+    ///
+    /// Nothing is sent yet. Images get their dimensions and a thumbnail, so the
+    /// message can be shown inline as soon as it arrives.
+    pub async fn share_media(
+        &self,
+        session_id: SessionId,
+        recipient_id: UserId,
+        path: &Path,
+    ) -> lan_chat_core::Result<Message> {
+        let message_type = media_type(path).ok_or_else(|| {
+            lan_chat_core::ChatError::FileTransfer("Not an image, audio or video file".into())
+        })?;
+
+        let hash_cache = self.hash_cache().await;
+        let (manifest, source_paths) = build_manifest(
+            &[path.to_path_buf()],
+            SymlinkPolicy::Follow,
+            hash_cache,
+            |_, _| {},
+        )
+        .await
+        .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        if let Some(cache) = hash_cache {
+            if let Err(e) = cache.save().await {
+                warn!("Failed to save file hashes: {}", e);
+            }
+        }
+        let source = match (source_paths.as_slice(), manifest.entries.as_slice()) {
+            ([source], [entry]) if entry.kind == EntryKind::File => source,
+            _ => {
+                return Err(lan_chat_core::ChatError::FileTransfer(
+                    "Only single files can be sent as media".into(),
+                ))
+            }
+        };
 
-        // Update status
+        let file = MediaFile {
+            file_name: manifest.display_name(),
+            file_size: manifest.total_size(),
+            file_hash: manifest.content_hash(),
+        };
+        let message = match message_type {
+            MessageType::Image => {
+                let preview = image_preview(source).await;
+                let (width, height) = preview.as_ref().map_or((0, 0), |p| (p.width, p.height));
+                Message::new_image(
+                    session_id,
+                    self.user_id,
+                    recipient_id,
+                    file.clone(),
+                    width,
+                    height,
+                    preview.map(|p| p.thumbnail),
+                )
+            }
+            MessageType::Audio => {
+                Message::new_audio(session_id, self.user_id, recipient_id, file.clone(), 0)
+            }
+            _ => Message::new_video(
+                session_id,
+                self.user_id,
+                recipient_id,
+                file.clone(),
+                0,
+                None,
+            ),
+        };
+
+        let shared = {
+            let mut shared = self.shared_media.write().await;
+            let media = shared
+                .entry(file.file_hash.clone())
+                .or_insert_with(|| SharedMedia {
+                    file_hash: file.file_hash.clone(),
+                    path: source.clone(),
+                    peers: Vec::new(),
+                });
+            // The latest copy of the content is the one most likely to still be there
+            media.path = source.clone();
+            if !media.peers.contains(&recipient_id) {
+                media.peers.push(recipient_id);
+            }
+            shared.values().cloned().collect::<Vec<_>>()
+        };
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_shared_media(&shared).await {
+                warn!("Failed to save shared media: {}", e);
+            }
+        }
+
+        Ok(message)
+    }
+
+    /// Ask the sender of a media message for its file
+    ///
+    /// Returns the transfer the file comes in, which is accepted without
+    /// asking and reports progress like any other.
+    pub async fn fetch_media(&self, message: &Message) -> lan_chat_core::Result<TransferId> {
+        let file = message.media_file().ok_or_else(|| {
+            lan_chat_core::ChatError::FileTransfer("Message has no media to fetch".into())
+        })?;
+        let sender_id = message.sender_id;
+        if sender_id == self.user_id {
+            return Err(lan_chat_core::ChatError::FileTransfer(
+                "Media sent by us is already here".into(),
+            ));
+        }
+
+        // Registered first, as the transfer request can beat the answer here
+        let fetch = (sender_id, file.file_hash.clone());
+        self.pending_fetches.write().await.insert(fetch.clone());
+
+        let result = async {
+            let mut stream = self.connect_to_peer(&sender_id).await?;
+            let request = self.sign_request(TransferMessage::FetchMedia {
+                requester_id: self.user_id,
+                file_hash: file.file_hash.clone(),
+                signature: Vec::new(),
+            })?;
+            self.send_message(&mut stream, &request).await?;
+
+            let response = tokio::time::timeout(RESPONSE_TIMEOUT, self.read_message(&mut stream))
+                .await
+                .map_err(|_| {
+                    lan_chat_core::ChatError::FileTransfer("No response from sender".into())
+                })??;
+            match response {
+                TransferMessage::FetchStarted { transfer_id } => Ok(transfer_id),
+                TransferMessage::FetchRefused { reason } => {
+                    Err(lan_chat_core::ChatError::FileTransfer(reason))
+                }
+                other => Err(lan_chat_core::ChatError::Protocol(format!(
+                    "Unexpected response to media request: {:?}",
+                    other
+                ))),
+            }
+        }
+        .await;
+
+        if result.is_err() {
+            self.pending_fetches.write().await.remove(&fetch);
+        }
+        result
+    }
+
+    /// Accept a file transfer
+    ///
+    /// Disk space is checked again, as it may have run low since the request
+    /// came in; without room the transfer is rejected instead.
+    pub async fn accept_transfer(&self, transfer_id: TransferId) -> lan_chat_core::Result<()> {
+        let size = self
+            .transfers
+            .read()
+            .await
+            .get(&transfer_id)
+            .map(|t| t.file_size)
+            .ok_or_else(|| lan_chat_core::ChatError::FileTransfer("Transfer not found".into()))?;
+        if let Some(reason) = self.check_space(size).await {
+            self.reject_transfer(transfer_id, reason.clone()).await?;
+            return Err(lan_chat_core::ChatError::FileTransfer(reason));
+        }
+
+        self.decide(transfer_id, TransferDecision::Accept).await?;
+
+        // Update transfer status
         {
             let mut transfers = self.transfers.write().await;
             if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                transfer.status = TransferStatus::Completed;
+                transfer.status = TransferStatus::Accepted;
             }
         }
 
         let _ = self
             .event_tx
-            .send(ChatEvent::FileTransferCompleted { transfer_id });
+            .send(ChatEvent::FileTransferAccepted { transfer_id });
 
         Ok(())
     }
 
-    /// Receive a file
-    async fn receive_file(
+    /// Reject a file transfer
+    pub async fn reject_transfer(
         &self,
-        mut stream: TcpStream,
         transfer_id: TransferId,
+        reason: String,
     ) -> lan_chat_core::Result<()> {
-        let file_name = {
-            let transfers = self.transfers.read().await;
-            let transfer = transfers.get(&transfer_id).ok_or_else(|| {
-                lan_chat_core::ChatError::FileTransfer("Transfer not found".into())
-            })?;
-
-            transfer.file_name.clone()
-        };
-
-        // Create file
-        let file_path = self.download_dir.join(&file_name);
-        let mut file = File::create(&file_path)
-            .await
-            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        self.decide(transfer_id, TransferDecision::Reject(reason.clone()))
+            .await?;
 
-        // Update status
         {
             let mut transfers = self.transfers.write().await;
             if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                transfer.status = TransferStatus::InProgress;
+                transfer.status = TransferStatus::Cancelled;
+                transfer.error = Some(reason);
             }
         }
 
-        // Receive chunks
-        loop {
-            let length = stream
-                .read_u32()
-                .await
-                .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
-
-            let mut buffer = vec![0u8; length as usize];
-            stream
-                .read_exact(&mut buffer)
-                .await
-                .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
-
-            let message = TransferMessage::from_bytes(&buffer)
-                .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+        self.forget(transfer_id).await;
 
-            match message {
-                TransferMessage::DataChunk { data, .. } => {
-                    file.write_all(&data)
-                        .await
-                        .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        Ok(())
+    }
 
-                    // Update progress
-                    {
-                        let mut transfers = self.transfers.write().await;
-                        if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                            transfer.bytes_transferred += data.len() as u64;
+    /// Why an incoming transfer of `size` bytes can't be taken, if it's over
+    /// the size limit or doesn't fit on the download disk
+    async fn check_space(&self, size: u64) -> Option<String> {
+        let max_size = self.settings.read().await.max_incoming_size;
+        check_incoming(size, max_size, available_space(&self.download_dir).await)
+    }
 
-                            let _ = self.event_tx.send(ChatEvent::FileTransferProgress {
-                                transfer_id,
-                                bytes_transferred: transfer.bytes_transferred,
-                                total_bytes: transfer.file_size,
-                            });
-                        }
-                    }
-                }
+    /// Hand the user's decision to the connection waiting on it
+    async fn decide(
+        &self,
+        transfer_id: TransferId,
+        decision: TransferDecision,
+    ) -> lan_chat_core::Result<()> {
+        let decision_tx = self
+            .pending_decisions
+            .write()
+            .await
+            .remove(&transfer_id)
+            .ok_or_else(|| {
+                lan_chat_core::ChatError::FileTransfer("Transfer is not awaiting a decision".into())
+            })?;
 
-                TransferMessage::TransferComplete { .. } => {
-                    file.flush()
-                        .await
-                        .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        decision_tx.send(decision).map_err(|_| {
+            lan_chat_core::ChatError::FileTransfer("Sender is no longer connected".into())
+        })
+    }
 
-                    // Update status
-                    {
-                        let mut transfers = self.transfers.write().await;
-                        if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                            transfer.status = TransferStatus::Completed;
-                        }
-                    }
+    /// Resume a paused transfer, sending only what the recipient is missing
+    ///
+    /// Works from either side: the recipient always decides which chunks are
+    /// still needed, based on what it has on disk.
+    pub async fn resume_transfer(
+        self: &Arc<Self>,
+        transfer_id: TransferId,
+    ) -> lan_chat_core::Result<()> {
+        let transfer = self
+            .transfers
+            .read()
+            .await
+            .get(&transfer_id)
+            .cloned()
+            .ok_or_else(|| lan_chat_core::ChatError::FileTransfer("Transfer not found".into()))?;
+
+        if transfer.status != TransferStatus::Paused {
+            return Err(lan_chat_core::ChatError::FileTransfer(
+                "Transfer is not paused".into(),
+            ));
+        }
 
-                    let _ = self
-                        .event_tx
-                        .send(ChatEvent::FileTransferCompleted { transfer_id });
+        let key = self.transfer_key(transfer_id).await?;
+        let source_paths = self.outgoing_paths.read().await.get(&transfer_id).cloned();
 
-                    break;
-                }
+        match source_paths {
+            Some(paths) => {
+                let mut stream = self.connect_to_peer(&transfer.recipient_id).await?;
 
-                TransferMessage::TransferFailed { error, .. } => {
-                    {
-                        let mut transfers = self.transfers.write().await;
-                        if let Some(transfer) = transfers.get_mut(&transfer_id) {
-                            transfer.status = TransferStatus::Failed;
-                            transfer.error = Some(error.clone());
-                        }
+                // Ask the recipient what it still needs
+                let resume = TransferMessage::Resume {
+                    transfer_id,
+                    missing: Vec::new(),
+                };
+                self.send_message(&mut stream, &resume).await?;
+                self.answer_challenge(&mut stream, transfer_id, &key)
+                    .await?;
+
+                let missing = match self.read_message(&mut stream).await? {
+                    TransferMessage::Resume { missing, .. } => missing,
+                    TransferMessage::TransferFailed { error, .. } => {
+                        self.mark_failed(transfer_id, TransferStatus::Failed, error.clone())
+                            .await;
+                        return Err(lan_chat_core::ChatError::FileTransfer(error));
                     }
+                    other => {
+                        return Err(lan_chat_core::ChatError::Protocol(format!(
+                            "Unexpected response to resume: {:?}",
+                            other
+                        )));
+                    }
+                };
 
-                    let _ = self.event_tx.send(ChatEvent::FileTransferFailed {
-                        transfer_id,
-                        error,
-                    });
+                let service = Arc::clone(self);
+                tokio::spawn(async move {
+                    service
+                        .run_sending(stream, transfer_id, paths, missing)
+                        .await;
+                });
+            }
 
-                    break;
-                }
+            None => {
+                let mut stream = self.connect_to_peer(&transfer.sender_id).await?;
 
-                _ => {}
+                let partial = self
+                    .open_partial(transfer_id)
+                    .await
+                    .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+
+                let resume = TransferMessage::Resume {
+                    transfer_id,
+                    missing: partial.missing.clone(),
+                };
+                self.send_message(&mut stream, &resume).await?;
+                self.answer_challenge(&mut stream, transfer_id, &key)
+                    .await?;
+
+                let service = Arc::clone(self);
+                tokio::spawn(async move {
+                    service.run_receiving(stream, transfer_id, partial).await;
+                });
             }
         }
 
         Ok(())
     }
 
-    /// Calculate SHA-256 hash of a file
-    async fn calculate_file_hash(&self, file_path: &Path) -> lan_chat_core::Result<String> {
-        let mut file = File::open(file_path)
+    /// Pause a running transfer; the peer keeps its progress for `resume_transfer`
+    pub async fn pause_transfer(&self, transfer_id: TransferId) -> lan_chat_core::Result<()> {
+        let status = self
+            .transfers
+            .read()
             .await
-            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+            .get(&transfer_id)
+            .map(|t| t.status);
+        if !matches!(
+            status,
+            Some(TransferStatus::Accepted | TransferStatus::InProgress)
+        ) {
+            return Err(lan_chat_core::ChatError::FileTransfer(
+                "Transfer is not in progress".into(),
+            ));
+        }
 
-        let mut hasher = Sha256::new();
-        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let control = self
+            .active
+            .read()
+            .await
+            .get(&transfer_id)
+            .map(|a| a.control.clone())
+            .ok_or_else(|| {
+                lan_chat_core::ChatError::FileTransfer("Transfer is not running".into())
+            })?;
 
-        loop {
-            let bytes_read = file
-                .read(&mut buffer)
-                .await
-                .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+        control.stop(StopReason::Pause);
+        Ok(())
+    }
 
-            if bytes_read == 0 {
-                break;
-            }
+    /// Cancel a transfer in any unfinished state, telling the peer
+    pub async fn cancel_transfer(
+        self: &Arc<Self>,
+        transfer_id: TransferId,
+    ) -> lan_chat_core::Result<()> {
+        // Requests we haven't answered yet are simply declined
+        if self
+            .pending_decisions
+            .read()
+            .await
+            .contains_key(&transfer_id)
+        {
+            return self
+                .reject_transfer(transfer_id, "Cancelled by recipient".into())
+                .await;
+        }
+
+        let transfer = self
+            .transfers
+            .read()
+            .await
+            .get(&transfer_id)
+            .cloned()
+            .ok_or_else(|| lan_chat_core::ChatError::FileTransfer("Transfer not found".into()))?;
+
+        // A run that just paused may not have let go yet, but it's done either way
+        let running = self
+            .active
+            .read()
+            .await
+            .get(&transfer_id)
+            .map(|a| a.control.clone())
+            .filter(|_| transfer.status != TransferStatus::Paused);
+        if let Some(control) = running {
+            control.stop(StopReason::Cancel);
+            return Ok(());
+        }
 
-            hasher.update(&buffer[..bytes_read]);
+        if transfer.status != TransferStatus::Paused {
+            return Err(lan_chat_core::ChatError::FileTransfer(
+                "Transfer is not running or paused".into(),
+            ));
         }
 
-        Ok(hex::encode(hasher.finalize()))
-    }
+        let peer_id = if transfer.sender_id == self.user_id {
+            transfer.recipient_id
+        } else {
+            transfer.sender_id
+        };
 
-    /// Send a transfer message
-    async fn send_message(
-        &self,
-        stream: &mut TcpStream,
-        message: &TransferMessage,
-    ) -> lan_chat_core::Result<()> {
-        let data = message
-            .to_bytes()
-            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+        // Grab the key before cancelling forgets it; the peer will ask for proof
+        let key = self.transfer_key(transfer_id).await?;
+        self.mark_cancelled(transfer_id).await;
+
+        // Nothing is connected while paused, so tell the peer on a new connection
+        let service = Arc::clone(self);
+        tokio::spawn(async move {
+            let notified = async {
+                let mut stream = service.connect_to_peer(&peer_id).await?;
+                let cancel = TransferMessage::Cancel { transfer_id };
+                service.send_message(&mut stream, &cancel).await?;
+                service
+                    .answer_challenge(&mut stream, transfer_id, &key)
+                    .await
+            };
 
-        let length = data.len() as u32;
+            if let Err(e) = notified.await {
+                debug!(
+                    "Couldn't tell peer about cancelled transfer {}: {}",
+                    transfer_id, e
+                );
+            }
+        });
 
-        stream
-            .write_u32(length)
-            .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+        Ok(())
+    }
 
-        stream
-            .write_all(&data)
+    /// Open a connection to a peer's transfer port
+    async fn connect_to_peer(&self, peer_id: &UserId) -> lan_chat_core::Result<TcpStream> {
+        let peer = self
+            .peer_registry
+            .get_peer(peer_id)
             .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+            .ok_or_else(|| lan_chat_core::ChatError::PeerNotFound(peer_id.to_string()))?;
 
-        stream
-            .flush()
+        let addr = peer.address.to_socket_addr();
+        let transfer_addr = SocketAddr::new(addr.ip(), self.port);
+
+        TcpStream::connect(transfer_addr)
             .await
-            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))
+    }
+
+    /// Look up a peer's identity key, as learned through discovery
+    async fn peer_public_key(&self, peer_id: &UserId) -> lan_chat_core::Result<RsaPublicKey> {
+        let key = self
+            .peer_registry
+            .get_peer(peer_id)
+            .await
+            .ok_or_else(|| lan_chat_core::ChatError::PeerNotFound(peer_id.to_string()))?
+            .public_key
+            .ok_or_else(|| {
+                lan_chat_core::ChatError::Crypto(format!("No public key for {}", peer_id))
+            })?;
+
+        KeyPair::import_public_key_bytes(&key)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))
+    }
+
+    /// Sign an outgoing transfer request with our identity key
+    fn sign_request(&self, mut request: TransferMessage) -> lan_chat_core::Result<TransferMessage> {
+        let payload = request
+            .signing_payload()
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+        let signed = RsaSigner::sign(self.keypair.private_key(), &payload)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+
+        if let TransferMessage::TransferRequest { signature, .. }
+        | TransferMessage::FetchMedia { signature, .. } = &mut request
+        {
+            *signature = signed.signature;
+        }
+        Ok(request)
+    }
+
+    /// Check a request's signature against the claimed sender and unwrap its key
+    async fn verify_request(
+        &self,
+        request: &TransferMessage,
+        sender_id: UserId,
+        encrypted_key: &EncryptedSessionKey,
+        signature: &[u8],
+    ) -> lan_chat_core::Result<TransferKey> {
+        self.verify_signature(request, sender_id, signature).await?;
+
+        TransferKey::unwrap(self.keypair.private_key(), encrypted_key)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))
+    }
+
+    /// Check a request's signature against the identity key we know for `signer_id`
+    async fn verify_signature(
+        &self,
+        request: &TransferMessage,
+        signer_id: UserId,
+        signature: &[u8],
+    ) -> lan_chat_core::Result<()> {
+        let public_key = self.peer_public_key(&signer_id).await?;
+        let payload = request
+            .signing_payload()
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+
+        RsaSigner::verify(
+            &public_key,
+            &payload,
+            &Signature {
+                signature: signature.to_vec(),
+            },
+        )
+        .map_err(|e| lan_chat_core::ChatError::Crypto(format!("{} from {}", e, signer_id)))
+    }
+
+    async fn transfer_key(&self, transfer_id: TransferId) -> lan_chat_core::Result<TransferKey> {
+        self.transfer_keys
+            .read()
+            .await
+            .get(&transfer_id)
+            .cloned()
+            .ok_or_else(|| lan_chat_core::ChatError::Crypto("Unknown transfer key".into()))
+    }
+
+    /// Check that the other end of a connection holds the transfer key
+    async fn verify_proof(
+        &self,
+        transfer_id: TransferId,
+        challenge: &[u8],
+        proof: &EncryptedData,
+    ) -> lan_chat_core::Result<()> {
+        self.transfer_key(transfer_id)
+            .await?
+            .verify_proof(transfer_id, challenge, proof)
+            .map_err(|_| {
+                lan_chat_core::ChatError::Crypto(
+                    "Transfer connection could not be authenticated".into(),
+                )
+            })
+    }
+
+    /// Make whoever opened this connection prove they hold the transfer key
+    async fn challenge_peer<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        transfer_id: TransferId,
+    ) -> lan_chat_core::Result<()> {
+        // Don't bother the peer for a transfer we know nothing about
+        self.transfer_key(transfer_id).await?;
+
+        let challenge = new_challenge();
+        let request = TransferMessage::Challenge {
+            transfer_id,
+            challenge: challenge.clone(),
+        };
+        self.send_message(stream, &request).await?;
+
+        match self.read_message(stream).await? {
+            TransferMessage::Proof { proof, .. } => {
+                self.verify_proof(transfer_id, &challenge, &proof).await
+            }
+            other => Err(lan_chat_core::ChatError::Protocol(format!(
+                "Expected proof, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Prove we hold the transfer key on a connection we opened
+    async fn answer_challenge<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        transfer_id: TransferId,
+        key: &TransferKey,
+    ) -> lan_chat_core::Result<()> {
+        let challenge = match self.read_message(stream).await? {
+            TransferMessage::Challenge { challenge, .. } => challenge,
+            TransferMessage::TransferFailed { error, .. } => {
+                return Err(lan_chat_core::ChatError::FileTransfer(error));
+            }
+            other => {
+                return Err(lan_chat_core::ChatError::Protocol(format!(
+                    "Expected challenge, got {:?}",
+                    other
+                )));
+            }
+        };
+
+        let proof = key
+            .prove(transfer_id, &challenge)
+            .map_err(|e| lan_chat_core::ChatError::Crypto(e.to_string()))?;
+        self.send_message(stream, &TransferMessage::Proof { transfer_id, proof })
+            .await
+    }
+
+    /// Register the data phase of a transfer, superseding any earlier one
+    async fn activate(&self, transfer_id: TransferId) -> (u64, TransferControl) {
+        let id = self.next_active_id.fetch_add(1, Ordering::Relaxed);
+        let control = TransferControl {
+            token: self.cancel_token.child_token(),
+            reason: Arc::new(OnceLock::new()),
+        };
+
+        let previous = self.active.write().await.insert(
+            transfer_id,
+            ActiveTransfer {
+                id,
+                control: control.clone(),
+                data_streams: None,
+            },
+        );
+
+        // A stale connection for the same transfer must stop writing
+        if let Some(previous) = previous {
+            previous.control.token.cancel();
+        }
+
+        (id, control)
+    }
+
+    async fn deactivate(&self, transfer_id: TransferId, id: u64) {
+        let mut active = self.active.write().await;
+        if active.get(&transfer_id).is_some_and(|a| a.id == id) {
+            active.remove(&transfer_id);
+        }
+    }
+
+    async fn run_sending(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        transfer_id: TransferId,
+        source_paths: Vec<PathBuf>,
+        missing: Vec<Range<u64>>,
+    ) {
+        let (id, control) = self.activate(transfer_id).await;
+        self.start_sending(stream, transfer_id, source_paths, missing, &control)
+            .await;
+        self.deactivate(transfer_id, id).await;
+    }
+
+    async fn run_receiving(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        transfer_id: TransferId,
+        partial: PartialFile,
+    ) {
+        let (id, control) = self.activate(transfer_id).await;
+        let data_streams = self.accept_streams(transfer_id, id).await;
+        self.receive_file(stream, transfer_id, partial, data_streams, &control)
+            .await;
+        self.deactivate(transfer_id, id).await;
+    }
+
+    /// Let extra data connections through to this run of an incoming transfer
+    async fn accept_streams(
+        &self,
+        transfer_id: TransferId,
+        id: u64,
+    ) -> mpsc::UnboundedReceiver<TcpStream> {
+        let (streams_tx, streams_rx) = mpsc::unbounded_channel();
+
+        let mut active = self.active.write().await;
+        if let Some(active) = active.get_mut(&transfer_id).filter(|a| a.id == id) {
+            active.data_streams = Some(streams_tx);
+        }
+
+        streams_rx
+    }
+
+    /// Mark a transfer in progress, announcing it if it was paused
+    async fn mark_in_progress(&self, transfer_id: TransferId, bytes_transferred: u64) {
+        let was_paused = {
+            let mut transfers = self.transfers.write().await;
+            match transfers.get_mut(&transfer_id) {
+                Some(transfer) => {
+                    let was_paused = transfer.status == TransferStatus::Paused;
+                    transfer.status = TransferStatus::InProgress;
+                    transfer.bytes_transferred = bytes_transferred.min(transfer.file_size);
+                    was_paused
+                }
+                None => false,
+            }
+        };
+
+        // Speed is measured afresh for each run
+        self.progress
+            .write()
+            .await
+            .insert(transfer_id, ProgressMeter::new(self.progress_interval));
+
+        if was_paused {
+            let _ = self
+                .event_tx
+                .send(ChatEvent::FileTransferResumed { transfer_id });
+        }
+    }
+
+    /// Send the chunks the recipient is missing, spreading large transfers
+    /// over extra connections
+    ///
+    /// This connection carries the first share of the chunks along with any
+    /// pause or cancel; each extra connection carries one contiguous share.
+    async fn start_sending(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        transfer_id: TransferId,
+        source_paths: Vec<PathBuf>,
+        missing: Vec<Range<u64>>,
+        control: &TransferControl,
+    ) {
+        let (mut reader, mut writer) = stream.into_split();
+
+        let Ok(key) = self.transfer_key(transfer_id).await else {
+            return;
+        };
+        let Some(transfer) = self.transfers.read().await.get(&transfer_id).cloned() else {
+            return;
+        };
+        let manifest = transfer.manifest;
+        let chunk_size = transfer.chunk_size;
+
+        // The recipient only speaks up to pause or cancel
+        let peer_message = self.read_message(&mut reader);
+        tokio::pin!(peer_message);
+
+        // Whatever the recipient asks for, only send chunks that exist
+        let total_chunks = manifest.chunk_count(chunk_size);
+        let done = ChunkSet::from_ranges(ChunkSet::from_ranges(missing).missing(total_chunks));
+        let wanted = done.missing(total_chunks);
+
+        // Wait for our turn, still listening for a pause or cancel. A recipient
+        // that already has everything doesn't need to wait.
+        let _slot = match self.queue.try_acquire() {
+            Some(slot) => Some(slot),
+            None if wanted.is_empty() => None,
+            None => {
+                let _ = self
+                    .event_tx
+                    .send(ChatEvent::FileTransferQueued { transfer_id });
+                tokio::select! {
+                    _ = control.token.cancelled() => {
+                        self.stop_sending(&mut writer, transfer_id, control).await;
+                        return;
+                    }
+                    message = &mut peer_message => {
+                        self.handle_peer_stop(transfer_id, message).await;
+                        return;
+                    }
+                    slot = self.queue.acquire(transfer_id, transfer.priority) => Some(slot),
+                }
+            }
+        };
+
+        let wanted_bytes: u64 = wanted
+            .iter()
+            .map(|r| manifest.range_bytes(r.clone(), chunk_size))
+            .sum();
+
+        self.chunks_done.write().await.insert(transfer_id, done);
+        self.mark_in_progress(transfer_id, manifest.total_size() - wanted_bytes)
+            .await;
+
+        // Small transfers aren't worth the extra connections
+        let stream_count =
+            (wanted_bytes / MIN_STREAM_BYTES).clamp(1, self.parallel_streams as u64) as usize;
+        let mut shares = split_ranges(&wanted, stream_count).into_iter();
+        let mut files = BundleReader::new(
+            manifest.clone(),
+            source_paths.clone(),
+            chunk_size,
+            shares.next().unwrap_or_default(),
+        );
+        let new_compressor = || {
+            transfer
+                .compression
+                .then(|| ChunkCompressor::new(manifest.clone(), chunk_size))
+        };
+        let mut compressor = new_compressor();
+
+        let run = control.token.child_token();
+        let mut streams = JoinSet::new();
+        for share in shares {
+            let files =
+                BundleReader::new(manifest.clone(), source_paths.clone(), chunk_size, share);
+            streams.spawn(Arc::clone(self).send_stream(
+                transfer_id,
+                transfer.recipient_id,
+                key.clone(),
+                files,
+                new_compressor(),
+                run.clone(),
+            ));
+        }
+
+        let mut buffer = vec![0u8; chunk_size];
+        let mut own_share_done = false;
+
+        loop {
+            // Checked between chunks so a frame is never cut short
+            if control.token.is_cancelled() {
+                stop_streams(&run, &mut streams).await;
+                self.stop_sending(&mut writer, transfer_id, control).await;
+                return;
+            }
+
+            // Our share is sent; wait for the other connections to finish theirs
+            if own_share_done {
+                let joined = tokio::select! {
+                    _ = control.token.cancelled() => continue,
+                    message = &mut peer_message => {
+                        stop_streams(&run, &mut streams).await;
+                        self.handle_peer_stop(transfer_id, message).await;
+                        return;
+                    }
+                    joined = streams.join_next() => joined,
+                };
+
+                match joined.map(stream_error) {
+                    Some(None) => continue,
+                    Some(Some(e)) => {
+                        stop_streams(&run, &mut streams).await;
+                        self.interrupt_sending(&mut writer, transfer_id, e).await;
+                        return;
+                    }
+                    // Stopped streams end early too, so make sure nobody stopped them
+                    None if control.token.is_cancelled() => continue,
+                    None => break,
+                }
+            }
+
+            if let Some(e) = std::iter::from_fn(|| streams.try_join_next()).find_map(stream_error) {
+                stop_streams(&run, &mut streams).await;
+                self.interrupt_sending(&mut writer, transfer_id, e).await;
+                return;
+            }
+
+            let (chunk_index, bytes_read) = match files.next_chunk(&mut buffer).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => {
+                    own_share_done = true;
+                    continue;
+                }
+                Err(e) => {
+                    stop_streams(&run, &mut streams).await;
+                    self.fail_sending(&mut writer, transfer_id, e.to_string())
+                        .await;
+                    return;
+                }
+            };
+
+            let chunk = &buffer[..bytes_read];
+            let compressed = compressor
+                .as_mut()
+                .and_then(|c| c.compress(chunk_index, chunk));
+            let payload = compressed.as_deref().unwrap_or(chunk);
+
+            tokio::select! {
+                _ = control.token.cancelled() => continue,
+                _ = self.throttle(transfer_id, payload.len()) => {}
+            }
+
+            let data = match key.seal_chunk(transfer_id, chunk_index, compressed.is_some(), payload)
+            {
+                Ok(data) => data,
+                Err(_) => {
+                    stop_streams(&run, &mut streams).await;
+                    self.fail_sending(
+                        &mut writer,
+                        transfer_id,
+                        "Sender failed to encrypt the file".into(),
+                    )
+                    .await;
+                    return;
+                }
+            };
+
+            let chunk = TransferMessage::DataChunk {
+                transfer_id,
+                chunk_index,
+                compressed: compressed.is_some(),
+                data,
+            };
+
+            let sent = tokio::select! {
+                message = &mut peer_message => {
+                    stop_streams(&run, &mut streams).await;
+                    self.handle_peer_stop(transfer_id, message).await;
+                    return;
+                }
+                sent = self.send_message(&mut writer, &chunk) => sent,
+            };
+
+            if let Err(e) = sent {
+                stop_streams(&run, &mut streams).await;
+                warn!("Transfer {} interrupted: {}", transfer_id, e);
+                self.mark_paused(transfer_id).await;
+                return;
+            }
+
+            self.report_progress(transfer_id, chunk_index, bytes_read)
+                .await;
+        }
+
+        // Send completion
+        let complete = TransferMessage::TransferComplete { transfer_id };
+        if let Err(e) = self.send_message(&mut writer, &complete).await {
+            warn!("Transfer {} interrupted: {}", transfer_id, e);
+            self.mark_paused(transfer_id).await;
+            return;
+        }
+
+        // Update status
+        {
+            let mut transfers = self.transfers.write().await;
+            if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                transfer.status = TransferStatus::Completed;
+            }
+        }
+
+        self.forget(transfer_id).await;
+
+        let _ = self
+            .event_tx
+            .send(ChatEvent::FileTransferCompleted { transfer_id });
+    }
+
+    /// Send one share of a transfer's chunks over an extra connection
+    async fn send_stream(
+        self: Arc<Self>,
+        transfer_id: TransferId,
+        recipient_id: UserId,
+        key: TransferKey,
+        mut files: BundleReader,
+        mut compressor: Option<ChunkCompressor>,
+        token: CancellationToken,
+    ) -> lan_chat_core::Result<()> {
+        // A connection that can't join is a network problem, not a broken transfer
+        let joined = async {
+            let mut stream = self.connect_to_peer(&recipient_id).await?;
+            self.send_message(&mut stream, &TransferMessage::JoinStream { transfer_id })
+                .await?;
+            self.answer_challenge(&mut stream, transfer_id, &key)
+                .await?;
+
+            match self.read_message(&mut stream).await? {
+                TransferMessage::StreamReady { .. } => Ok(stream),
+                TransferMessage::TransferFailed { error, .. } => {
+                    Err(lan_chat_core::ChatError::FileTransfer(error))
+                }
+                other => Err(lan_chat_core::ChatError::Protocol(format!(
+                    "Unexpected response to joining stream: {:?}",
+                    other
+                ))),
+            }
+        };
+        let mut stream = joined
+            .await
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        let mut buffer = vec![0u8; files.chunk_size()];
+
+        loop {
+            if token.is_cancelled() {
+                return Ok(());
+            }
+
+            let Some((chunk_index, bytes_read)) = files
+                .next_chunk(&mut buffer)
+                .await
+                .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?
+            else {
+                break;
+            };
+
+            let chunk = &buffer[..bytes_read];
+            let compressed = compressor
+                .as_mut()
+                .and_then(|c| c.compress(chunk_index, chunk));
+            let payload = compressed.as_deref().unwrap_or(chunk);
+
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                _ = self.throttle(transfer_id, payload.len()) => {}
+            }
+
+            let data = key
+                .seal_chunk(transfer_id, chunk_index, compressed.is_some(), payload)
+                .map_err(|_| {
+                    lan_chat_core::ChatError::FileTransfer(
+                        "Sender failed to encrypt the file".into(),
+                    )
+                })?;
+
+            let chunk = TransferMessage::DataChunk {
+                transfer_id,
+                chunk_index,
+                compressed: compressed.is_some(),
+                data,
+            };
+
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                sent = self.send_message(&mut stream, &chunk) => sent?,
+            }
+
+            self.report_progress(transfer_id, chunk_index, bytes_read)
+                .await;
+        }
+
+        self.send_message(
+            &mut stream,
+            &TransferMessage::TransferComplete { transfer_id },
+        )
+        .await
+    }
+
+    /// Tell the recipient why we stopped sending and record it
+    async fn stop_sending<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        transfer_id: TransferId,
+        control: &TransferControl,
+    ) {
+        match self.stop_reason(control) {
+            Some(StopReason::Cancel) => {
+                let cancel = TransferMessage::Cancel { transfer_id };
+                let _ = self.send_message(writer, &cancel).await;
+                self.mark_cancelled(transfer_id).await;
+            }
+            // Let the recipient keep what it has
+            Some(StopReason::Pause) => {
+                let pause = TransferMessage::Pause { transfer_id };
+                let _ = self.send_message(writer, &pause).await;
+                self.mark_paused(transfer_id).await;
+            }
+            // Superseded by a newer connection
+            None => {}
+        }
+    }
+
+    /// An extra connection failed: a problem with the files ends the transfer,
+    /// anything else pauses it so it can be resumed
+    async fn interrupt_sending<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        transfer_id: TransferId,
+        error: lan_chat_core::ChatError,
+    ) {
+        match error {
+            lan_chat_core::ChatError::FileTransfer(error) => {
+                self.fail_sending(writer, transfer_id, error).await;
+            }
+            error => {
+                warn!("Transfer {} interrupted: {}", transfer_id, error);
+                let pause = TransferMessage::Pause { transfer_id };
+                let _ = self.send_message(writer, &pause).await;
+                self.mark_paused(transfer_id).await;
+            }
+        }
+    }
+
+    /// Give up on an outgoing transfer, telling the recipient why
+    async fn fail_sending<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        transfer_id: TransferId,
+        error: String,
+    ) {
+        let failed = TransferMessage::TransferFailed {
+            transfer_id,
+            error: error.clone(),
+        };
+        let _ = self.send_message(writer, &failed).await;
+        self.mark_failed(transfer_id, TransferStatus::Failed, error)
+            .await;
+    }
+
+    /// Count a chunk towards the transfer's and its file's progress, returning
+    /// the bytes transferred so far
+    async fn report_progress(
+        &self,
+        transfer_id: TransferId,
+        chunk_index: u64,
+        bytes: usize,
+    ) -> u64 {
+        let mut transfers = self.transfers.write().await;
+        let Some(transfer) = transfers.get_mut(&transfer_id) else {
+            return 0;
+        };
+
+        let mut chunks_done = self.chunks_done.write().await;
+        let done = chunks_done.entry(transfer_id).or_default();
+        if !done.insert(chunk_index) {
+            return transfer.bytes_transferred;
+        }
+
+        transfer.bytes_transferred += bytes as u64;
+        let finished = transfer.bytes_transferred >= transfer.file_size;
+
+        let mut progress = self.progress.write().await;
+        let meter = progress
+            .entry(transfer_id)
+            .or_insert_with(|| ProgressMeter::new(self.progress_interval));
+        let due = meter.record(bytes as u64, finished);
+        if due {
+            self.emit_progress(
+                transfer_id,
+                TransferPhase::Transferring,
+                transfer.bytes_transferred,
+                transfer.file_size,
+                meter,
+            );
+        }
+
+        // Per-file progress only means something when there's more than one
+        if transfer.manifest.file_count() > 1 {
+            let chunk_size = transfer.chunk_size;
+            if let Some((index, _)) = transfer.manifest.locate(chunk_index, chunk_size) {
+                let entry = &transfer.manifest.entries[index];
+                let chunks = transfer.manifest.entry_chunks(index, chunk_size);
+
+                // Only a file's last chunk is short, so this is never off by more than one
+                let entry_bytes = (done.count_in(chunks) * chunk_size as u64).min(entry.size);
+
+                // Along with the overall progress, and whenever a file is done
+                if due || entry_bytes == entry.size {
+                    let _ = self.event_tx.send(ChatEvent::FileTransferItemProgress {
+                        transfer_id,
+                        path: entry.path.clone(),
+                        bytes_transferred: entry_bytes,
+                        total_bytes: entry.size,
+                    });
+                }
+            }
+        }
+
+        transfer.bytes_transferred
+    }
+
+    fn emit_progress(
+        &self,
+        transfer_id: TransferId,
+        phase: TransferPhase,
+        bytes_transferred: u64,
+        total_bytes: u64,
+        meter: &ProgressMeter,
+    ) {
+        let _ = self.event_tx.send(ChatEvent::FileTransferProgress {
+            transfer_id,
+            phase,
+            bytes_transferred,
+            total_bytes,
+            bytes_per_second: meter.bytes_per_second(),
+            eta_seconds: meter.eta_seconds(total_bytes.saturating_sub(bytes_transferred)),
+        });
+    }
+
+    /// Wait until `bytes` more fit within this transfer's limit and the global one
+    async fn throttle(&self, transfer_id: TransferId, bytes: usize) {
+        self.transfer_limit(transfer_id)
+            .await
+            .acquire(bytes as u64)
+            .await;
+        self.global_limit.acquire(bytes as u64).await;
+    }
+
+    /// This transfer's own rate limiter, shared by all its connections
+    async fn transfer_limit(&self, transfer_id: TransferId) -> Arc<RateLimiter> {
+        if let Some(limit) = self.transfer_limits.read().await.get(&transfer_id) {
+            return Arc::clone(limit);
+        }
+
+        let own = self
+            .transfers
+            .read()
+            .await
+            .get(&transfer_id)
+            .and_then(|t| t.rate_limit);
+        let rate = own.or(self.settings.read().await.transfer_rate_limit);
+
+        let mut limits = self.transfer_limits.write().await;
+        Arc::clone(
+            limits
+                .entry(transfer_id)
+                .or_insert_with(|| Arc::new(RateLimiter::new(rate))),
+        )
+    }
+
+    /// Why a stopped transfer stopped; shutting down counts as a pause, and
+    /// `None` means a newer connection took over
+    fn stop_reason(&self, control: &TransferControl) -> Option<StopReason> {
+        match control.reason() {
+            None if self.cancel_token.is_cancelled() => Some(StopReason::Pause),
+            reason => reason,
+        }
+    }
+
+    /// The recipient interrupted the data stream
+    async fn handle_peer_stop(
+        &self,
+        transfer_id: TransferId,
+        message: lan_chat_core::Result<TransferMessage>,
+    ) {
+        match message {
+            Ok(TransferMessage::Cancel { .. }) => {
+                info!("Transfer {} cancelled by peer", transfer_id);
+                self.mark_cancelled(transfer_id).await;
+            }
+            Ok(TransferMessage::Pause { .. }) => {
+                info!("Transfer {} paused by peer", transfer_id);
+                self.mark_paused(transfer_id).await;
+            }
+            Ok(other) => {
+                warn!("Unexpected transfer message: {:?}", other);
+                self.mark_paused(transfer_id).await;
+            }
+            Err(e) => {
+                warn!("Transfer {} interrupted: {}", transfer_id, e);
+                self.mark_paused(transfer_id).await;
+            }
+        }
+    }
+
+    /// Staging directory an incoming transfer is written to until it's verified
+    fn partial_path(&self, transfer_id: TransferId) -> PathBuf {
+        self.download_dir.join(format!(".{}.part", transfer_id))
+    }
+
+    /// Open the staging directory, keeping the chunks already written to it
+    ///
+    /// Starts over if the staging directory has gone missing.
+    async fn open_partial(&self, transfer_id: TransferId) -> std::io::Result<PartialFile> {
+        let path = self.partial_path(transfer_id);
+        let (manifest, chunk_size) = self
+            .transfers
+            .read()
+            .await
+            .get(&transfer_id)
+            .map(|t| (t.manifest.clone(), t.chunk_size))
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "Transfer not found")
+            })?;
+
+        let mut received = self
+            .chunks_done
+            .read()
+            .await
+            .get(&transfer_id)
+            .cloned()
+            .unwrap_or_default();
+        if !received.is_empty() && !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            warn!("Can't resume from {:?}, starting over", path);
+            received = ChunkSet::default();
+        }
+
+        let missing = received.missing(manifest.chunk_count(chunk_size));
+        let writer = BundleWriter::open(path, manifest, chunk_size, received.is_empty()).await?;
+        self.chunks_done.write().await.insert(transfer_id, received);
+
+        Ok(PartialFile { writer, missing })
+    }
+
+    /// Put files we've received before into the staging directory and count
+    /// their chunks as received
+    ///
+    /// A file that can't be taken from the cache is simply sent.
+    async fn fill_from_cache(&self, transfer_id: TransferId, partial: &mut PartialFile) {
+        let Some(cache) = self.cache_dir.clone().map(ContentCache::new) else {
+            return;
+        };
+        let Some((manifest, chunk_size)) = self
+            .transfers
+            .read()
+            .await
+            .get(&transfer_id)
+            .map(|t| (t.manifest.clone(), t.chunk_size))
+        else {
+            return;
+        };
+        let staging_dir = self.partial_path(transfer_id);
+        let mut received = self
+            .chunks_done
+            .read()
+            .await
+            .get(&transfer_id)
+            .cloned()
+            .unwrap_or_default();
+
+        let mut cached = Vec::new();
+        for (index, entry) in manifest.entries.iter().enumerate() {
+            let chunks = manifest.entry_chunks(index, chunk_size);
+            if entry.kind != EntryKind::File
+                || chunks.is_empty()
+                || received.count_in(chunks.clone()) == chunks.end - chunks.start
+            {
+                continue;
+            }
+
+            let dest = staging_dir.join(&entry.path);
+            match cache.link_to(&entry.hash, entry.size, &dest).await {
+                Ok(true) => {
+                    debug!("Took {} from the cache", entry.path);
+                    cached.push(chunks);
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Failed to use cached copy of {}: {}", entry.path, e),
+            }
+        }
+        if cached.is_empty() {
+            return;
+        }
+
+        received = ChunkSet::from_ranges(received.ranges().iter().cloned().chain(cached));
+        partial.missing = received.missing(manifest.chunk_count(chunk_size));
+        self.chunks_done.write().await.insert(transfer_id, received);
+    }
+
+    /// Whether the content cache holds every file of `manifest`
+    async fn is_cached(&self, manifest: &Manifest) -> bool {
+        let Some(cache) = self.cache_dir.clone().map(ContentCache::new) else {
+            return false;
+        };
+        let files: Vec<_> = manifest
+            .entries
+            .iter()
+            .filter(|e| e.kind == EntryKind::File && e.size > 0)
+            .collect();
+        if files.is_empty() {
+            return false;
+        }
+
+        for entry in files {
+            if !cache.contains(&entry.hash, entry.size).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Keep the files of a verified download in the content cache
+    async fn cache_download(&self, staging_dir: &Path, manifest: &Manifest) {
+        let Some(cache) = self.cache_dir.clone().map(ContentCache::new) else {
+            return;
+        };
+        for entry in &manifest.entries {
+            if entry.kind != EntryKind::File || entry.size == 0 {
+                continue;
+            }
+            if let Err(e) = cache
+                .insert(&entry.hash, &staging_dir.join(&entry.path))
+                .await
+            {
+                warn!("Failed to cache {}: {}", entry.path, e);
+            }
+        }
+    }
+
+    /// Hashes of files sent before, if there's a cache directory
+    async fn hash_cache(&self) -> Option<&HashCache> {
+        let path = self.cache_dir.as_ref()?.join("hashes.json");
+        let cache = self
+            .hash_cache
+            .get_or_init(|| async {
+                HashCache::load(&path).await.unwrap_or_else(|e| {
+                    warn!("Failed to load file hashes: {}", e);
+                    HashCache::new(path.clone())
+                })
+            })
+            .await;
+        Some(cache)
+    }
+
+    /// Receive the files of a transfer, over this connection and any extra
+    /// ones the sender opens
+    async fn receive_file(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        transfer_id: TransferId,
+        partial: PartialFile,
+        mut data_streams: mpsc::UnboundedReceiver<TcpStream>,
+        control: &TransferControl,
+    ) {
+        let Some((manifest, file_hash, chunk_size, sender_id)) =
+            self.transfers.read().await.get(&transfer_id).map(|t| {
+                (
+                    t.manifest.clone(),
+                    t.file_hash.clone(),
+                    t.chunk_size,
+                    t.sender_id,
+                )
+            })
+        else {
+            return;
+        };
+        let Ok(key) = self.transfer_key(transfer_id).await else {
+            return;
+        };
+
+        let PartialFile {
+            writer: mut files,
+            missing,
+        } = partial;
+        let staging_dir = self.partial_path(transfer_id);
+        let missing_bytes: u64 = missing
+            .iter()
+            .map(|r| manifest.range_bytes(r.clone(), chunk_size))
+            .sum();
+
+        self.mark_in_progress(transfer_id, manifest.total_size() - missing_bytes)
+            .await;
+        self.persist(transfer_id).await;
+
+        let run = control.token.child_token();
+        let mut streams = JoinSet::new();
+
+        // The pending read owns its half of the stream, so racing it against
+        // other events never drops part of a message
+        let (reader, mut writer) = stream.into_split();
+        let read_next = |mut reader: OwnedReadHalf| async move {
+            let message = self.read_message(&mut reader).await;
+            (reader, message)
+        };
+        let mut next_message = Box::pin(read_next(reader));
+
+        // Receive chunks
+        loop {
+            let message = tokio::select! {
+                _ = control.token.cancelled() => {
+                    stop_streams(&run, &mut streams).await;
+                    match self.stop_reason(control) {
+                        Some(StopReason::Cancel) => {
+                            let cancel = TransferMessage::Cancel { transfer_id };
+                            let _ = self.send_message(&mut writer, &cancel).await;
+                            self.mark_cancelled(transfer_id).await;
+                        }
+                        // Keep what we have so it can be resumed
+                        Some(StopReason::Pause) => {
+                            let pause = TransferMessage::Pause { transfer_id };
+                            let _ = self.send_message(&mut writer, &pause).await;
+                            self.mark_paused(transfer_id).await;
+                        }
+                        None => {}
+                    }
+                    return;
+                }
+                Some(data_stream) = data_streams.recv() => {
+                    if streams.len() < MAX_STREAMS {
+                        streams.spawn(Arc::clone(self).receive_stream(
+                            data_stream,
+                            transfer_id,
+                            key.clone(),
+                            files.clone(),
+                            run.clone(),
+                        ));
+                    } else {
+                        warn!("Too many connections for transfer {}", transfer_id);
+                    }
+                    continue;
+                }
+                Some(joined) = streams.join_next() => {
+                    match stream_error(joined) {
+                        // The sender pauses or cancels on this connection, so a
+                        // dropped extra connection alone changes nothing
+                        Some(lan_chat_core::ChatError::Network(e)) => {
+                            debug!("Data connection for transfer {} closed: {}", transfer_id, e);
+                        }
+                        Some(e) => {
+                            stop_streams(&run, &mut streams).await;
+                            self.discard_download(transfer_id, &staging_dir, e.to_string())
+                                .await;
+                            return;
+                        }
+                        None => {}
+                    }
+                    continue;
+                }
+                (reader, message) = &mut next_message => {
+                    next_message = Box::pin(read_next(reader));
+                    message
+                }
+            };
+
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    stop_streams(&run, &mut streams).await;
+                    warn!("Transfer {} interrupted: {}", transfer_id, e);
+                    self.mark_paused(transfer_id).await;
+                    return;
+                }
+            };
+
+            match message {
+                TransferMessage::DataChunk {
+                    chunk_index,
+                    compressed,
+                    data,
+                    ..
+                } => {
+                    let received = self
+                        .receive_chunk(transfer_id, &key, &mut files, chunk_index, compressed, data)
+                        .await;
+                    let bytes = match received {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            stop_streams(&run, &mut streams).await;
+                            self.discard_download(transfer_id, &staging_dir, e.to_string())
+                                .await;
+                            return;
+                        }
+                    };
+
+                    // Reading slower holds the sender back too
+                    tokio::select! {
+                        _ = control.token.cancelled() => {}
+                        _ = self.throttle(transfer_id, bytes) => {}
+                    }
+                }
+
+                TransferMessage::TransferComplete { .. } => {
+                    // The sender finishes every connection before this one
+                    while let Some(joined) = streams.join_next().await {
+                        match stream_error(joined) {
+                            Some(lan_chat_core::ChatError::Network(_)) | None => {}
+                            Some(e) => {
+                                stop_streams(&run, &mut streams).await;
+                                self.discard_download(transfer_id, &staging_dir, e.to_string())
+                                    .await;
+                                return;
+                            }
+                        }
+                    }
+
+                    let complete = self
+                        .chunks_done
+                        .read()
+                        .await
+                        .get(&transfer_id)
+                        .is_some_and(|c| c.len() == manifest.chunk_count(chunk_size));
+                    if !complete {
+                        self.discard_download(
+                            transfer_id,
+                            &staging_dir,
+                            "Transfer ended before all data arrived".into(),
+                        )
+                        .await;
+                        return;
+                    }
+
+                    // Chunks came in any order, so hashes are checked once they're all in
+                    let total = manifest.total_size();
+                    let mut meter = ProgressMeter::new(self.progress_interval);
+                    let mut previous = 0;
+                    let verified = files
+                        .finish(&file_hash, |verified| {
+                            let bytes = verified - std::mem::replace(&mut previous, verified);
+                            if meter.record(bytes, verified == total) {
+                                self.emit_progress(
+                                    transfer_id,
+                                    TransferPhase::Verifying,
+                                    verified,
+                                    total,
+                                    &meter,
+                                );
+                            }
+                        })
+                        .await;
+                    if let Err(e) = verified {
+                        warn!("Verifying transfer {} failed: {}", transfer_id, e);
+                        self.discard_download(transfer_id, &staging_dir, e.to_string())
+                            .await;
+                        return;
+                    }
+
+                    // Moving the files keeps the links the cache holds to them
+                    self.cache_download(&staging_dir, &manifest).await;
+                    let dest_dir = self
+                        .accept_policy
+                        .read()
+                        .await
+                        .download_dir(&self.download_dir, sender_id);
+                    let saved_paths = match self
+                        .finalize_download(&staging_dir, &dest_dir, &manifest)
+                        .await
+                    {
+                        Ok(saved_paths) => saved_paths,
+                        Err(e) => {
+                            self.discard_download(transfer_id, &staging_dir, e.to_string())
+                                .await;
+                            return;
+                        }
+                    };
+
+                    // Update status
+                    {
+                        let mut transfers = self.transfers.write().await;
+                        if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                            transfer.status = TransferStatus::Completed;
+                            transfer.saved_paths = saved_paths;
+                        }
+                    }
+
+                    self.forget(transfer_id).await;
+
+                    let _ = self
+                        .event_tx
+                        .send(ChatEvent::FileTransferCompleted { transfer_id });
+
+                    return;
+                }
+
+                TransferMessage::Pause { .. } => {
+                    info!("Transfer {} paused by peer", transfer_id);
+                    stop_streams(&run, &mut streams).await;
+                    self.mark_paused(transfer_id).await;
+                    return;
+                }
+
+                TransferMessage::Cancel { .. } => {
+                    info!("Transfer {} cancelled by peer", transfer_id);
+                    stop_streams(&run, &mut streams).await;
+                    self.mark_cancelled(transfer_id).await;
+                    return;
+                }
+
+                TransferMessage::TransferFailed { error, .. } => {
+                    stop_streams(&run, &mut streams).await;
+                    self.discard_download(transfer_id, &staging_dir, error)
+                        .await;
+                    return;
+                }
+
+                _ => {}
+            }
+        }
+    }
+
+    /// Receive chunks over an extra connection until the sender is done with it
+    async fn receive_stream(
+        self: Arc<Self>,
+        mut stream: TcpStream,
+        transfer_id: TransferId,
+        key: TransferKey,
+        mut files: BundleWriter,
+        token: CancellationToken,
+    ) -> lan_chat_core::Result<()> {
+        self.send_message(&mut stream, &TransferMessage::StreamReady { transfer_id })
+            .await?;
+
+        loop {
+            let message = tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                message = self.read_message(&mut stream) => message?,
+            };
+
+            match message {
+                TransferMessage::DataChunk {
+                    chunk_index,
+                    compressed,
+                    data,
+                    ..
+                } => {
+                    let bytes = self
+                        .receive_chunk(transfer_id, &key, &mut files, chunk_index, compressed, data)
+                        .await?;
+                    tokio::select! {
+                        _ = token.cancelled() => return Ok(()),
+                        _ = self.throttle(transfer_id, bytes) => {}
+                    }
+                }
+                TransferMessage::TransferComplete { .. } => return Ok(()),
+                other => {
+                    return Err(lan_chat_core::ChatError::Protocol(format!(
+                        "Unexpected message on data connection: {:?}",
+                        other
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Decrypt a chunk, write it in place and count it, returning how many
+    /// bytes it took on the wire
+    ///
+    /// Progress counts the chunk's bytes once expanded, as the sender does.
+    async fn receive_chunk(
+        &self,
+        transfer_id: TransferId,
+        key: &TransferKey,
+        files: &mut BundleWriter,
+        chunk_index: u64,
+        compressed: bool,
+        data: EncryptedData,
+    ) -> lan_chat_core::Result<usize> {
+        // The index is in the associated data, so a chunk that was tampered
+        // with or moved fails here
+        let data = key
+            .open_chunk(transfer_id, chunk_index, compressed, &data)
+            .map_err(|_| {
+                lan_chat_core::ChatError::Crypto(format!(
+                    "Chunk {} failed authentication",
+                    chunk_index
+                ))
+            })?;
+        let wire_bytes = data.len();
+
+        // No chunk is bigger than the chunk size, so neither may its expansion be
+        let data = if compressed {
+            compress::decompress(&data, files.chunk_size()).map_err(|e| {
+                lan_chat_core::ChatError::FileTransfer(format!(
+                    "Chunk {} could not be decompressed: {}",
+                    chunk_index, e
+                ))
+            })?
+        } else {
+            data
+        };
+
+        let bytes = data.len() as u64;
+        files
+            .write_chunk(chunk_index, data)
+            .await
+            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+
+        // Checkpoint now and then; the chunk is already written, so the saved
+        // set never runs ahead of what's on disk
+        let total = self
+            .report_progress(transfer_id, chunk_index, bytes as usize)
+            .await;
+        if total / PERSIST_INTERVAL != total.saturating_sub(bytes) / PERSIST_INTERVAL {
+            self.persist(transfer_id).await;
+        }
+
+        Ok(wire_bytes)
+    }
+
+    /// Stop accepting transfers and pause the ones in flight
+    pub async fn shutdown(&self) {
+        info!("Shutting down transfer service");
+        self.cancel_token.cancel();
+
+        // Give running transfers a moment to save their resume state
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+        while !self.active.read().await.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Mark a transfer as cancelled because the service is shutting down
+    async fn abort_transfer(&self, transfer_id: TransferId) {
+        self.mark_failed(
+            transfer_id,
+            TransferStatus::Cancelled,
+            "Transfer aborted: shutting down".into(),
+        )
+        .await;
+    }
+
+    /// Move a verified download into `dest_dir` under non-clashing names
+    async fn finalize_download(
+        &self,
+        staging_dir: &Path,
+        dest_dir: &Path,
+        manifest: &Manifest,
+    ) -> lan_chat_core::Result<Vec<PathBuf>> {
+        let mut saved = Vec::new();
+        tokio::fs::create_dir_all(dest_dir)
+            .await
+            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+
+        for name in manifest.top_level_names() {
+            let staged = staging_dir.join(name);
+
+            // Top-level links are never created, so there's nothing to move
+            if tokio::fs::symlink_metadata(&staged).await.is_err() {
+                continue;
+            }
+
+            let is_dir = !manifest
+                .entries
+                .iter()
+                .any(|e| e.path == name && e.kind != EntryKind::Directory);
+            let final_path = if is_dir {
+                reserve_unique_dir(dest_dir, name).await
+            } else {
+                reserve_unique_path(dest_dir, name).await
+            }
+            .map_err(|e| lan_chat_core::ChatError::FileTransfer(e.to_string()))?;
+
+            // Windows won't rename over the placeholder, even an empty one
+            if is_dir {
+                let _ = tokio::fs::remove_dir(&final_path).await;
+            }
+
+            if let Err(e) = tokio::fs::rename(&staged, &final_path).await {
+                let _ = if is_dir {
+                    tokio::fs::remove_dir(&final_path).await
+                } else {
+                    tokio::fs::remove_file(&final_path).await
+                };
+                return Err(lan_chat_core::ChatError::FileTransfer(e.to_string()));
+            }
+
+            info!("Saved received file to {:?}", final_path);
+            saved.push(final_path);
+        }
+
+        remove_staging(staging_dir).await;
+
+        Ok(saved)
+    }
+
+    /// Remove a partial or corrupted download and mark the transfer failed
+    async fn discard_download(&self, transfer_id: TransferId, staging_dir: &Path, error: String) {
+        remove_staging(staging_dir).await;
+
+        self.mark_failed(transfer_id, TransferStatus::Failed, error)
+            .await;
+    }
+
+    /// Record why a transfer ended early and notify the user
+    async fn mark_failed(&self, transfer_id: TransferId, status: TransferStatus, error: String) {
+        {
+            let mut transfers = self.transfers.write().await;
+            if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                transfer.status = status;
+                transfer.error = Some(error.clone());
+            }
+        }
+
+        self.forget(transfer_id).await;
+
+        let _ = self
+            .event_tx
+            .send(ChatEvent::FileTransferFailed { transfer_id, error });
+    }
+
+    /// Keep an interrupted transfer so it can be resumed later
+    async fn mark_paused(&self, transfer_id: TransferId) {
+        {
+            let mut transfers = self.transfers.write().await;
+            if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                transfer.status = TransferStatus::Paused;
+            }
+        }
+
+        self.persist(transfer_id).await;
+
+        let _ = self
+            .event_tx
+            .send(ChatEvent::FileTransferPaused { transfer_id });
+    }
+
+    /// Stop a transfer for good, removing any partial download
+    async fn mark_cancelled(&self, transfer_id: TransferId) {
+        {
+            let mut transfers = self.transfers.write().await;
+            if let Some(transfer) = transfers.get_mut(&transfer_id) {
+                transfer.status = TransferStatus::Cancelled;
+            }
+        }
+
+        // Only incoming transfers have one
+        remove_staging(&self.partial_path(transfer_id)).await;
+
+        self.forget(transfer_id).await;
+
+        let _ = self
+            .event_tx
+            .send(ChatEvent::FileTransferCancelled { transfer_id });
+    }
+
+    /// Save a transfer's resume state, if persistence is enabled
+    async fn persist(&self, transfer_id: TransferId) {
+        let Some(store) = &self.store else {
+            return;
+        };
+
+        let Some(transfer) = self.transfers.read().await.get(&transfer_id).cloned() else {
+            return;
+        };
+        let Ok(transfer_key) = self.transfer_key(transfer_id).await else {
+            return;
+        };
+
+        let record = TransferRecord {
+            transfer,
+            source_paths: self.outgoing_paths.read().await.get(&transfer_id).cloned(),
+            received_chunks: self
+                .chunks_done
+                .read()
+                .await
+                .get(&transfer_id)
+                .cloned()
+                .unwrap_or_default(),
+            transfer_key,
+        };
+
+        if let Err(e) = store.save(&record).await {
+            warn!("Failed to save state of transfer {}: {}", transfer_id, e);
+        }
+    }
+
+    /// Drop resume state once a transfer has finished one way or another,
+    /// moving it to the history
+    async fn forget(&self, transfer_id: TransferId) {
+        let finished = {
+            let mut transfers = self.transfers.write().await;
+            transfers.get_mut(&transfer_id).map(|transfer| {
+                transfer.finished_at = Some(chrono::Utc::now());
+                transfer.clone()
+            })
+        };
+
+        self.outgoing_paths.write().await.remove(&transfer_id);
+        self.chunks_done.write().await.remove(&transfer_id);
+        self.transfer_keys.write().await.remove(&transfer_id);
+        self.transfer_limits.write().await.remove(&transfer_id);
+        self.progress.write().await.remove(&transfer_id);
+
+        if let Some(store) = &self.store {
+            if let Err(e) = store.remove(&transfer_id).await {
+                warn!("Failed to remove state of transfer {}: {}", transfer_id, e);
+            }
+            if let Some(transfer) = finished {
+                if let Err(e) = store.save_finished(&transfer).await {
+                    warn!("Failed to save transfer {} to history: {}", transfer_id, e);
+                }
+            }
+        }
+    }
+
+    /// Read a transfer message
+    async fn read_message<R: AsyncRead + Unpin>(
+        &self,
+        stream: &mut R,
+    ) -> lan_chat_core::Result<TransferMessage> {
+        let length = stream
+            .read_u32()
+            .await
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        // Checked before anything is allocated, since the peer picks the length
+        if length > MAX_MESSAGE_SIZE {
+            return Err(lan_chat_core::ChatError::Protocol(format!(
+                "Message too large: {} bytes",
+                length
+            )));
+        }
+
+        let mut buffer = vec![0u8; length as usize];
+        stream
+            .read_exact(&mut buffer)
+            .await
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        TransferMessage::from_bytes(&buffer)
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))
+    }
+
+    /// Send a transfer message
+    async fn send_message<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        message: &TransferMessage,
+    ) -> lan_chat_core::Result<()> {
+        let data = message
+            .to_bytes()
+            .map_err(|e| lan_chat_core::ChatError::Protocol(e.to_string()))?;
+
+        let length = data.len() as u32;
+
+        stream
+            .write_u32(length)
+            .await
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        stream
+            .write_all(&data)
+            .await
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
+
+        stream
+            .flush()
+            .await
+            .map_err(|e| lan_chat_core::ChatError::Network(e.to_string()))?;
 
         Ok(())
     }
 }
+
+/// Whether a transfer has been accepted and not yet finished
+fn is_resumable(status: TransferStatus) -> bool {
+    matches!(
+        status,
+        TransferStatus::Accepted | TransferStatus::InProgress | TransferStatus::Paused
+    )
+}
+
+/// Stop a transfer's extra connections and wait until they've let go of it
+async fn stop_streams(run: &CancellationToken, streams: &mut JoinSet<lan_chat_core::Result<()>>) {
+    run.cancel();
+    while streams.join_next().await.is_some() {}
+}
+
+/// What an extra connection ended with, if it wasn't a clean finish
+fn stream_error(
+    joined: Result<lan_chat_core::Result<()>, JoinError>,
+) -> Option<lan_chat_core::ChatError> {
+    match joined {
+        Ok(result) => result.err(),
+        Err(e) => Some(lan_chat_core::ChatError::FileTransfer(e.to_string())),
+    }
+}
+
+/// Remove an incoming transfer's staging directory, if there is one
+async fn remove_staging(staging_dir: &Path) {
+    if let Err(e) = tokio::fs::remove_dir_all(staging_dir).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!(
+                "Failed to remove staging directory {:?}: {}",
+                staging_dir, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MIN_CHUNK_SIZE;
+    use lan_chat_core::{NetworkAddress, Peer, UserProfile};
+    use rand::RngCore;
+    use tokio::task::JoinHandle;
+
+    /// How long a test waits for an event before giving up
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Slow enough that a few chunks take seconds to send
+    const SLOW_RATE: u64 = MIN_CHUNK_SIZE as u64;
+
+    /// Identity keys shared by every test, as generating them is slow
+    fn keypairs() -> &'static [KeyPair; 3] {
+        static KEYPAIRS: OnceLock<[KeyPair; 3]> = OnceLock::new();
+        KEYPAIRS.get_or_init(|| std::array::from_fn(|_| KeyPair::generate().unwrap()))
+    }
+
+    /// Who a test service runs as, kept so it can be started again
+    struct Identity {
+        user_id: UserId,
+        keypair: KeyPair,
+        address: SocketAddr,
+        dir: PathBuf,
+    }
+
+    impl Identity {
+        fn downloads(&self) -> PathBuf {
+            self.dir.join("downloads")
+        }
+
+        fn peer(&self) -> Peer {
+            let mut profile = UserProfile::new("peer".into(), "Peer".into());
+            profile.user_id = self.user_id;
+            let mut peer = Peer::new(
+                profile,
+                NetworkAddress::new(self.address.ip(), self.address.port()),
+            );
+            peer.public_key = Some(self.keypair.export_public_key_bytes().unwrap());
+            peer
+        }
+    }
+
+    /// A running service and its events
+    struct Side {
+        service: Arc<TransferService>,
+        events: mpsc::UnboundedReceiver<ChatEvent>,
+        server: JoinHandle<()>,
+    }
+
+    /// Two identities on loopback addresses of their own that share a port,
+    /// with their listeners
+    async fn identities() -> ((Identity, TcpListener), (Identity, TcpListener)) {
+        let dir = std::env::temp_dir().join(format!("lan-chat-transfer-{}", uuid::Uuid::new_v4()));
+        let [sender_keys, recipient_keys, _] = keypairs().clone();
+
+        let (sender, recipient) = loop {
+            let sender = TcpListener::bind("127.0.0.2:0").await.unwrap();
+            let port = sender.local_addr().unwrap().port();
+            if let Ok(recipient) = TcpListener::bind(("127.0.0.3", port)).await {
+                break (sender, recipient);
+            }
+        };
+
+        let identity = |listener: &TcpListener, keypair, name| Identity {
+            user_id: uuid::Uuid::new_v4(),
+            keypair,
+            address: listener.local_addr().unwrap(),
+            dir: dir.join(name),
+        };
+        (
+            (identity(&sender, sender_keys, "sender"), sender),
+            (identity(&recipient, recipient_keys, "recipient"), recipient),
+        )
+    }
+
+    /// Run a service as `identity`, knowing `other`, on `listener`
+    ///
+    /// Connections are handed over as `start` does; `configure` adds to the
+    /// settings every test service has.
+    async fn start(
+        identity: &Identity,
+        other: &Identity,
+        listener: TcpListener,
+        configure: impl FnOnce(TransferService) -> TransferService,
+    ) -> Side {
+        let (event_tx, events) = mpsc::unbounded_channel();
+        let peer_registry = PeerRegistry::new();
+        peer_registry.add_peer(other.peer()).await;
+
+        let service = TransferService::new(
+            identity.user_id,
+            identity.keypair.clone(),
+            peer_registry,
+            event_tx,
+            identity.downloads(),
+        )
+        .with_port(identity.address.port())
+        .with_progress_interval(Duration::ZERO);
+        let service = Arc::new(configure(service));
+
+        service.restore_transfers().await;
+        let server = tokio::spawn({
+            let service = Arc::clone(&service);
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let service = Arc::clone(&service);
+                    tokio::spawn(async move {
+                        let _ = service.handle_connection(stream).await;
+                    });
+                }
+            }
+        });
+
+        Side {
+            service,
+            events,
+            server,
+        }
+    }
+
+    fn slow(service: TransferService) -> TransferService {
+        service.with_settings(TransferSettings {
+            transfer_rate_limit: Some(SLOW_RATE),
+            ..Default::default()
+        })
+    }
+
+    /// A sender and a recipient with nothing but the test settings
+    async fn pair() -> ((Identity, Side), (Identity, Side)) {
+        let ((sender, sender_listener), (recipient, recipient_listener)) = identities().await;
+        let sender_side = start(&sender, &recipient, sender_listener, |s| s).await;
+        let recipient_side = start(&recipient, &sender, recipient_listener, |s| s).await;
+        ((sender, sender_side), (recipient, recipient_side))
+    }
+
+    /// Remove what both sides of a test wrote
+    async fn clean_up(identity: &Identity) {
+        let _ = tokio::fs::remove_dir_all(identity.dir.parent().unwrap()).await;
+    }
+
+    /// Wait for the first event `pick` returns something for
+    async fn wait_for<T>(
+        events: &mut mpsc::UnboundedReceiver<ChatEvent>,
+        mut pick: impl FnMut(&ChatEvent) -> Option<T>,
+    ) -> T {
+        let found = async {
+            loop {
+                let event = events.recv().await.expect("Service is gone");
+                if let Some(found) = pick(&event) {
+                    return found;
+                }
+            }
+        };
+        tokio::time::timeout(EVENT_TIMEOUT, found)
+            .await
+            .expect("Timed out waiting for an event")
+    }
+
+    async fn requested(side: &mut Side) -> TransferId {
+        wait_for(&mut side.events, |event| match event {
+            ChatEvent::FileTransferRequested { transfer_id, .. } => Some(*transfer_id),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn completed(side: &mut Side) -> TransferId {
+        wait_for(&mut side.events, |event| match event {
+            ChatEvent::FileTransferCompleted { transfer_id } => Some(*transfer_id),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Wait until some data has arrived or gone out
+    async fn progressed(side: &mut Side) {
+        wait_for(&mut side.events, |event| match event {
+            ChatEvent::FileTransferProgress {
+                phase: TransferPhase::Transferring,
+                bytes_transferred,
+                ..
+            } => (*bytes_transferred > 0).then_some(()),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn paused(side: &mut Side) -> TransferId {
+        wait_for(&mut side.events, |event| match event {
+            ChatEvent::FileTransferPaused { transfer_id } => Some(*transfer_id),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn cancelled(side: &mut Side) -> TransferId {
+        wait_for(&mut side.events, |event| match event {
+            ChatEvent::FileTransferCancelled { transfer_id } => Some(*transfer_id),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn failed(side: &mut Side) -> String {
+        wait_for(&mut side.events, |event| match event {
+            ChatEvent::FileTransferFailed { error, .. } => Some(error.clone()),
+            _ => None,
+        })
+        .await
+    }
+
+    /// A file of random bytes, which compression can't shrink
+    async fn random_file(dir: &Path, name: &str, size: usize) -> PathBuf {
+        let mut data = vec![0u8; size];
+        rand::thread_rng().fill_bytes(&mut data);
+        tokio::fs::create_dir_all(dir).await.unwrap();
+        let path = dir.join(name);
+        tokio::fs::write(&path, data).await.unwrap();
+        path
+    }
+
+    /// Everything in a download directory, staging directories included
+    async fn downloads(identity: &Identity) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Ok(mut entries) = tokio::fs::read_dir(identity.downloads()).await {
+            while let Some(entry) = entries.next_entry().await.unwrap() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        names
+    }
+
+    /// Write a message framed the way peers frame them
+    async fn send(stream: &mut TcpStream, message: &TransferMessage) {
+        let data = message.to_bytes().unwrap();
+        stream.write_u32(data.len() as u32).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+    }
+
+    async fn receive(stream: &mut TcpStream) -> TransferMessage {
+        let length = stream.read_u32().await.unwrap();
+        let mut data = vec![0u8; length as usize];
+        stream.read_exact(&mut data).await.unwrap();
+        TransferMessage::from_bytes(&data).unwrap()
+    }
+
+    /// A request from `from` for `path`, put together by hand and signed with
+    /// `signer`
+    struct RawRequest {
+        stream: TcpStream,
+        transfer_id: TransferId,
+        key: TransferKey,
+    }
+
+    async fn raw_request(
+        from: &Identity,
+        to: &Identity,
+        path: &Path,
+        signer: &KeyPair,
+    ) -> RawRequest {
+        let (manifest, _) = build_manifest(
+            &[path.to_path_buf()],
+            SymlinkPolicy::default(),
+            None,
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+        let transfer_id = uuid::Uuid::new_v4();
+        let key = TransferKey::generate();
+
+        let mut request = TransferMessage::TransferRequest {
+            transfer_id,
+            sender_id: from.user_id,
+            manifest: manifest.clone(),
+            file_hash: manifest.content_hash(),
+            chunk_size: MIN_CHUNK_SIZE,
+            compression: false,
+            encrypted_key: key.wrap(to.keypair.public_key()).unwrap(),
+            signature: Vec::new(),
+        };
+        let payload = request.signing_payload().unwrap();
+        let signed = RsaSigner::sign(signer.private_key(), &payload).unwrap();
+        if let TransferMessage::TransferRequest { signature, .. } = &mut request {
+            *signature = signed.signature;
+        }
+
+        let mut stream = TcpStream::connect(to.address).await.unwrap();
+        send(&mut stream, &request).await;
+        RawRequest {
+            stream,
+            transfer_id,
+            key,
+        }
+    }
+
+    /// Read the recipient's acceptance of a raw request
+    async fn raw_accepted(request: &mut RawRequest) -> (Vec<u8>, Vec<Range<u64>>) {
+        match receive(&mut request.stream).await {
+            TransferMessage::TransferAccept {
+                challenge, missing, ..
+            } => (challenge, missing),
+            other => panic!("Expected the transfer to be accepted, got {:?}", other),
+        }
+    }
+
+    /// Start an accepted raw request and send the chunks of `data` in the
+    /// order given, then finish
+    async fn raw_send(request: &mut RawRequest, data: &[u8], order: &[u64]) {
+        let (challenge, _) = raw_accepted(request).await;
+        let transfer_id = request.transfer_id;
+        let proof = request.key.prove(transfer_id, &challenge).unwrap();
+        send(
+            &mut request.stream,
+            &TransferMessage::StartTransfer { transfer_id, proof },
+        )
+        .await;
+
+        for &chunk_index in order {
+            let start = chunk_index as usize * MIN_CHUNK_SIZE;
+            let chunk = &data[start..data.len().min(start + MIN_CHUNK_SIZE)];
+            let chunk = TransferMessage::DataChunk {
+                transfer_id,
+                chunk_index,
+                compressed: false,
+                data: request
+                    .key
+                    .seal_chunk(transfer_id, chunk_index, false, chunk)
+                    .unwrap(),
+            };
+            send(&mut request.stream, &chunk).await;
+        }
+        send(
+            &mut request.stream,
+            &TransferMessage::TransferComplete { transfer_id },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_accepted_transfer_arrives() {
+        let ((sender, mut sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 3 * MIN_CHUNK_SIZE + 10).await;
+
+        let transfer_id = sender_side
+            .service
+            .send_file(recipient.user_id, &path)
+            .await
+            .unwrap();
+        assert_eq!(requested(&mut recipient_side).await, transfer_id);
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+
+        assert_eq!(completed(&mut recipient_side).await, transfer_id);
+        assert_eq!(completed(&mut sender_side).await, transfer_id);
+
+        let received = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(received.status, TransferStatus::Completed);
+        assert_eq!(
+            received.saved_paths,
+            vec![recipient.downloads().join("report.bin")]
+        );
+        assert_eq!(
+            tokio::fs::read(&received.saved_paths[0]).await.unwrap(),
+            tokio::fs::read(&path).await.unwrap()
+        );
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_rejected_transfer_reaches_sender() {
+        let ((sender, mut sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 100).await;
+
+        let transfer_id = sender_side
+            .service
+            .send_file(recipient.user_id, &path)
+            .await
+            .unwrap();
+        requested(&mut recipient_side).await;
+        recipient_side
+            .service
+            .reject_transfer(transfer_id, "Not now".into())
+            .await
+            .unwrap();
+
+        let reason = wait_for(&mut sender_side.events, |event| match event {
+            ChatEvent::FileTransferRejected { reason, .. } => Some(reason.clone()),
+            _ => None,
+        })
+        .await;
+        assert_eq!(reason, "Not now");
+
+        let sent = sender_side.service.get_transfer(transfer_id).await.unwrap();
+        assert_eq!(sent.status, TransferStatus::Cancelled);
+        assert!(downloads(&recipient).await.is_empty());
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_accept_must_be_answered_with_start() {
+        let ((sender, _sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 100).await;
+
+        let mut request = raw_request(&sender, &recipient, &path, &sender.keypair).await;
+        let transfer_id = requested(&mut recipient_side).await;
+        assert_eq!(transfer_id, request.transfer_id);
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+        raw_accepted(&mut request).await;
+
+        // Skip straight to the end without ever starting
+        send(
+            &mut request.stream,
+            &TransferMessage::TransferComplete { transfer_id },
+        )
+        .await;
+
+        failed(&mut recipient_side).await;
+        let received = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(received.status, TransferStatus::Failed);
+        assert!(downloads(&recipient).await.is_empty());
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_chunks_arrive_in_any_order() {
+        let ((sender, _sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 3 * MIN_CHUNK_SIZE + 10).await;
+        let data = tokio::fs::read(&path).await.unwrap();
+
+        let mut request = raw_request(&sender, &recipient, &path, &sender.keypair).await;
+        let transfer_id = requested(&mut recipient_side).await;
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+        raw_send(&mut request, &data, &[3, 1, 0, 2]).await;
+
+        completed(&mut recipient_side).await;
+        let saved = recipient.downloads().join("report.bin");
+        assert_eq!(tokio::fs::read(&saved).await.unwrap(), data);
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_content_not_matching_its_hash_is_discarded() {
+        let ((sender, _sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 2 * MIN_CHUNK_SIZE).await;
+        let mut data = tokio::fs::read(&path).await.unwrap();
+
+        let mut request = raw_request(&sender, &recipient, &path, &sender.keypair).await;
+        let transfer_id = requested(&mut recipient_side).await;
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+
+        // Properly sealed, but not what the manifest promised
+        data[MIN_CHUNK_SIZE] ^= 0xff;
+        raw_send(&mut request, &data, &[0, 1]).await;
+
+        failed(&mut recipient_side).await;
+        let received = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(received.status, TransferStatus::Failed);
+        assert!(downloads(&recipient).await.is_empty());
+        clean_up(&sender).await;
+    }
+
+    /// A slow transfer the recipient has accepted and started receiving
+    async fn running_transfer() -> ((Identity, Side), (Identity, Side), TransferId) {
+        let ((sender, sender_listener), (recipient, recipient_listener)) = identities().await;
+        let sender_side = start(&sender, &recipient, sender_listener, slow).await;
+        let mut recipient_side = start(&recipient, &sender, recipient_listener, |s| s).await;
+        let path = random_file(&sender.dir, "report.bin", 6 * MIN_CHUNK_SIZE).await;
+
+        let transfer_id = sender_side
+            .service
+            .send_file(recipient.user_id, &path)
+            .await
+            .unwrap();
+        requested(&mut recipient_side).await;
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+        progressed(&mut recipient_side).await;
+
+        (
+            (sender, sender_side),
+            (recipient, recipient_side),
+            transfer_id,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_paused_transfer_resumes() {
+        let ((sender, mut sender_side), (recipient, mut recipient_side), transfer_id) =
+            running_transfer().await;
+
+        // Pausing on one side pauses both
+        recipient_side
+            .service
+            .pause_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(paused(&mut recipient_side).await, transfer_id);
+        assert_eq!(paused(&mut sender_side).await, transfer_id);
+        let received = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(received.status, TransferStatus::Paused);
+        assert!(received.bytes_transferred < received.file_size);
+
+        sender_side
+            .service
+            .update_settings(TransferSettings::default())
+            .await;
+        recipient_side
+            .service
+            .resume_transfer(transfer_id)
+            .await
+            .unwrap();
+
+        completed(&mut recipient_side).await;
+        let saved = recipient.downloads().join("report.bin");
+        assert_eq!(
+            tokio::fs::read(&saved).await.unwrap(),
+            tokio::fs::read(sender.dir.join("report.bin"))
+                .await
+                .unwrap()
+        );
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_resumes_after_restart() {
+        let ((sender, sender_listener), (recipient, recipient_listener)) = identities().await;
+        let state_dir = recipient.dir.join("state");
+        let with_state = |s: TransferService| s.with_state_dir(state_dir.clone());
+        let mut sender_side = start(&sender, &recipient, sender_listener, slow).await;
+        let mut recipient_side = start(&recipient, &sender, recipient_listener, with_state).await;
+        let path = random_file(&sender.dir, "report.bin", 6 * MIN_CHUNK_SIZE).await;
+
+        let transfer_id = sender_side
+            .service
+            .send_file(recipient.user_id, &path)
+            .await
+            .unwrap();
+        requested(&mut recipient_side).await;
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+        progressed(&mut recipient_side).await;
+
+        // Shutting down keeps what has arrived for the next run
+        recipient_side.server.abort();
+        let _ = recipient_side.server.await;
+        recipient_side.service.shutdown().await;
+        paused(&mut sender_side).await;
+
+        let listener = TcpListener::bind(recipient.address).await.unwrap();
+        let mut recipient_side = start(&recipient, &sender, listener, with_state).await;
+        let restored = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(restored.status, TransferStatus::Paused);
+
+        // The sender picks it up this time
+        sender_side
+            .service
+            .update_settings(TransferSettings::default())
+            .await;
+        sender_side
+            .service
+            .resume_transfer(transfer_id)
+            .await
+            .unwrap();
+
+        completed(&mut recipient_side).await;
+        let saved = recipient.downloads().join("report.bin");
+        assert_eq!(
+            tokio::fs::read(&saved).await.unwrap(),
+            tokio::fs::read(&path).await.unwrap()
+        );
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_cancel_reaches_recipient() {
+        let ((sender, mut sender_side), (recipient, mut recipient_side), transfer_id) =
+            running_transfer().await;
+
+        sender_side
+            .service
+            .cancel_transfer(transfer_id)
+            .await
+            .unwrap();
+
+        assert_eq!(cancelled(&mut sender_side).await, transfer_id);
+        assert_eq!(cancelled(&mut recipient_side).await, transfer_id);
+        let received = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(received.status, TransferStatus::Cancelled);
+        assert!(downloads(&recipient).await.is_empty());
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_cancel_while_paused_reaches_peer() {
+        let ((sender, mut sender_side), (recipient, mut recipient_side), transfer_id) =
+            running_transfer().await;
+
+        recipient_side
+            .service
+            .pause_transfer(transfer_id)
+            .await
+            .unwrap();
+        paused(&mut recipient_side).await;
+        paused(&mut sender_side).await;
+
+        // Nothing is connected any more, so the peer hears on a new connection
+        recipient_side
+            .service
+            .cancel_transfer(transfer_id)
+            .await
+            .unwrap();
+
+        assert_eq!(cancelled(&mut recipient_side).await, transfer_id);
+        assert_eq!(cancelled(&mut sender_side).await, transfer_id);
+        let sent = sender_side.service.get_transfer(transfer_id).await.unwrap();
+        assert_eq!(sent.status, TransferStatus::Cancelled);
+        assert!(downloads(&recipient).await.is_empty());
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_cached_content_is_accepted_without_asking() {
+        let ((sender, sender_listener), (recipient, recipient_listener)) = identities().await;
+        let cache = |identity: &Identity| {
+            let dir = identity.dir.join("cache");
+            move |service: TransferService| service.with_cache_dir(dir)
+        };
+        let mut sender_side = start(&sender, &recipient, sender_listener, cache(&sender)).await;
+        let mut recipient_side =
+            start(&recipient, &sender, recipient_listener, cache(&recipient)).await;
+        let path = random_file(&sender.dir, "report.bin", 2 * MIN_CHUNK_SIZE).await;
+
+        let first = sender_side
+            .service
+            .send_file(recipient.user_id, &path)
+            .await
+            .unwrap();
+        requested(&mut recipient_side).await;
+        recipient_side.service.accept_transfer(first).await.unwrap();
+        completed(&mut recipient_side).await;
+        completed(&mut sender_side).await;
+
+        // The same content again doesn't need anyone to accept it
+        let second = sender_side
+            .service
+            .send_file(recipient.user_id, &path)
+            .await
+            .unwrap();
+        let reason = wait_for(&mut recipient_side.events, |event| match event {
+            ChatEvent::FileTransferAutoAccepted {
+                transfer_id,
+                reason,
+                ..
+            } if *transfer_id == second => Some(reason.clone()),
+            _ => None,
+        })
+        .await;
+        assert_eq!(reason, "Already have these files");
+        assert_eq!(completed(&mut recipient_side).await, second);
+
+        assert_eq!(
+            downloads(&recipient).await,
+            ["report (1).bin", "report.bin"]
+        );
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_unverified_requests_are_rejected() {
+        let ((sender, _sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 100).await;
+
+        // Claims to come from the sender, but signed with another key
+        let forger = &keypairs()[2];
+        let mut request = raw_request(&sender, &recipient, &path, forger).await;
+
+        match receive(&mut request.stream).await {
+            TransferMessage::TransferReject { reason, .. } => {
+                assert_eq!(reason, "Sender could not be verified")
+            }
+            other => panic!("Expected the request to be rejected, got {:?}", other),
+        }
+        assert!(recipient_side.events.try_recv().is_err());
+        assert!(recipient_side
+            .service
+            .get_transfer(request.transfer_id)
+            .await
+            .is_none());
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_start_transfer_needs_proof() {
+        let ((sender, _sender_side), (recipient, mut recipient_side)) = pair().await;
+        let path = random_file(&sender.dir, "report.bin", 100).await;
+
+        let mut request = raw_request(&sender, &recipient, &path, &sender.keypair).await;
+        let transfer_id = requested(&mut recipient_side).await;
+        recipient_side
+            .service
+            .accept_transfer(transfer_id)
+            .await
+            .unwrap();
+        let (challenge, _) = raw_accepted(&mut request).await;
+
+        // Answered with a key that isn't the transfer's
+        let proof = TransferKey::generate()
+            .prove(transfer_id, &challenge)
+            .unwrap();
+        send(
+            &mut request.stream,
+            &TransferMessage::StartTransfer { transfer_id, proof },
+        )
+        .await;
+
+        failed(&mut recipient_side).await;
+        let received = recipient_side
+            .service
+            .get_transfer(transfer_id)
+            .await
+            .unwrap();
+        assert_eq!(received.status, TransferStatus::Failed);
+        assert!(downloads(&recipient).await.is_empty());
+        clean_up(&sender).await;
+    }
+
+    #[tokio::test]
+    async fn test_resume_needs_the_key() {
+        let ((sender, mut sender_side), (recipient, recipient_side), transfer_id) =
+            running_transfer().await;
+        recipient_side
+            .service
+            .pause_transfer(transfer_id)
+            .await
+            .unwrap();
+        paused(&mut sender_side).await;
+
+        // Someone else asks the sender for everything
+        let mut stream = TcpStream::connect(sender.address).await.unwrap();
+        let resume = TransferMessage::Resume {
+            transfer_id,
+            missing: vec![Range { start: 0, end: 6 }],
+        };
+        send(&mut stream, &resume).await;
+        let TransferMessage::Challenge { challenge, .. } = receive(&mut stream).await else {
+            panic!("Expected a challenge");
+        };
+        let proof = TransferKey::generate()
+            .prove(transfer_id, &challenge)
+            .unwrap();
+        send(&mut stream, &TransferMessage::Proof { transfer_id, proof }).await;
+
+        assert!(matches!(
+            receive(&mut stream).await,
+            TransferMessage::TransferFailed { .. }
+        ));
+        let sent = sender_side.service.get_transfer(transfer_id).await.unwrap();
+        assert_eq!(sent.status, TransferStatus::Paused);
+        clean_up(&recipient).await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_messages_are_refused() {
+        let (event_tx, _events) = mpsc::unbounded_channel();
+        let service = TransferService::new(
+            uuid::Uuid::new_v4(),
+            keypairs()[0].clone(),
+            PeerRegistry::new(),
+            event_tx,
+            std::env::temp_dir(),
+        );
+
+        // Refused on the length alone, before anything is allocated
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_u32(MAX_MESSAGE_SIZE + 1).await.unwrap();
+        assert!(matches!(
+            service.read_message(&mut server).await,
+            Err(lan_chat_core::ChatError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Outgoing transfers sending at once unless configured otherwise
+pub const DEFAULT_MAX_CONCURRENT: usize = 3;
+
+/// User-adjustable limits on how transfers use the network and the disk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferSettings {
+    /// Bytes per second for all transfers together, in both directions;
+    /// `None` for no limit
+    pub global_rate_limit: Option<u64>,
+    /// Bytes per second for each transfer without a limit of its own
+    pub transfer_rate_limit: Option<u64>,
+    /// Outgoing transfers sending at once; the rest wait in the queue
+    pub max_concurrent_transfers: usize,
+    /// Largest incoming transfer in bytes; bigger requests are rejected
+    pub max_incoming_size: Option<u64>,
+    /// Offer to compress outgoing transfers; files that won't get smaller
+    /// are sent as is either way
+    pub compress_transfers: bool,
+}
+
+impl Default for TransferSettings {
+    fn default() -> Self {
+        Self {
+            global_rate_limit: None,
+            transfer_rate_limit: None,
+            max_concurrent_transfers: DEFAULT_MAX_CONCURRENT,
+            max_incoming_size: None,
+            compress_transfers: true,
+        }
+    }
+}
+
+impl TransferSettings {
+    /// Load settings from a JSON file, returning the defaults if it doesn't exist
+    pub async fn load(path: &Path) -> lan_chat_core::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save settings as JSON, creating the parent directory if needed
+    pub async fn save(&self, path: &Path) -> lan_chat_core::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let data = serde_json::to_vec_pretty(self)?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Space left free on the download disk after an incoming transfer
+pub const FREE_SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// Bytes available to us on the disk holding `dir`
+///
+/// Looks at the nearest folder that exists, as the download directory may not
+/// have been created yet. `None` if the disk can't be asked, in which case the
+/// transfer isn't held up.
+pub async fn available_space(dir: &Path) -> Option<u64> {
+    let dir: PathBuf = dir.to_path_buf();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut last_error = None;
+        for candidate in dir.ancestors() {
+            match fs4::available_space(candidate) {
+                Ok(space) => return Ok(space),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::ErrorKind::NotFound.into()))
+    })
+    .await
+    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+
+    match result {
+        Ok(space) => Some(space),
+        Err(e) => {
+            warn!("Can't tell how much disk space is free: {}", e);
+            None
+        }
+    }
+}
+
+/// Why an incoming transfer of `size` bytes can't be taken, if it can't
+///
+/// The reason is sent to the sender as is, so it says what the limit was.
+pub fn check_incoming(size: u64, max_size: Option<u64>, available: Option<u64>) -> Option<String> {
+    if let Some(max_size) = max_size.filter(|max| size > *max) {
+        return Some(format!(
+            "Transfer is too large: {} is over the recipient's limit of {}",
+            format_size(size),
+            format_size(max_size)
+        ));
+    }
+
+    let available = available?;
+    if size.saturating_add(FREE_SPACE_MARGIN) > available {
+        return Some(format!(
+            "Not enough disk space: {} needed, {} free",
+            format_size(size),
+            format_size(available.saturating_sub(FREE_SPACE_MARGIN))
+        ));
+    }
+
+    None
+}
+
+/// Size for people to read, such as "1.5 GB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_incoming() {
+        const GB: u64 = 1024 * 1024 * 1024;
+
+        assert_eq!(check_incoming(GB, None, None), None);
+        assert_eq!(check_incoming(GB, Some(2 * GB), Some(10 * GB)), None);
+
+        assert_eq!(
+            check_incoming(200 * GB, None, Some(12 * GB + FREE_SPACE_MARGIN)).as_deref(),
+            Some("Not enough disk space: 200.0 GB needed, 12.0 GB free")
+        );
+        assert_eq!(
+            check_incoming(3 * GB, Some(2 * GB), Some(100 * GB)).as_deref(),
+            Some("Transfer is too large: 3.0 GB is over the recipient's limit of 2.0 GB")
+        );
+
+        // The margin is kept free too
+        assert!(check_incoming(GB, None, Some(GB)).is_some());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 bytes");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+    }
+}
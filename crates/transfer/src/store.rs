@@ -0,0 +1,220 @@
+use crate::chunks::ChunkSet;
+use crate::key::TransferKey;
+use crate::media::SharedMedia;
+use crate::protocol::FileTransfer;
+use lan_chat_core::TransferId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Everything needed to pick an interrupted transfer back up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    pub transfer: FileTransfer,
+    /// Local path of each manifest entry being sent; `None` for incoming transfers
+    pub source_paths: Option<Vec<PathBuf>>,
+    /// Chunks already written to the staging directory of an incoming transfer
+    pub received_chunks: ChunkSet,
+    /// Needed to authenticate the resumed connection and its chunks
+    pub transfer_key: TransferKey,
+}
+
+/// Saves transfer records as one JSON file per transfer
+///
+/// Finished transfers are kept apart in a `history` folder, without the key
+/// or anything else needed to resume them. Files offered in media messages
+/// are listed in `media/shared.json`.
+#[derive(Debug, Clone)]
+pub struct TransferStore {
+    dir: PathBuf,
+}
+
+impl TransferStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn record_path(&self, transfer_id: &TransferId) -> PathBuf {
+        self.dir.join(format!("{}.json", transfer_id))
+    }
+
+    fn history_dir(&self) -> PathBuf {
+        self.dir.join("history")
+    }
+
+    fn shared_media_path(&self) -> PathBuf {
+        self.dir.join("media").join("shared.json")
+    }
+
+    pub async fn save(&self, record: &TransferRecord) -> lan_chat_core::Result<()> {
+        write_json(&self.record_path(&record.transfer.transfer_id), record).await
+    }
+
+    pub async fn remove(&self, transfer_id: &TransferId) -> lan_chat_core::Result<()> {
+        match tokio::fs::remove_file(self.record_path(transfer_id)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load every saved record, skipping files that can't be parsed
+    pub async fn load_all(&self) -> lan_chat_core::Result<Vec<TransferRecord>> {
+        read_json_dir(&self.dir).await
+    }
+
+    /// Keep a finished transfer in the history
+    pub async fn save_finished(&self, transfer: &FileTransfer) -> lan_chat_core::Result<()> {
+        let path = self
+            .history_dir()
+            .join(format!("{}.json", transfer.transfer_id));
+        write_json(&path, transfer).await
+    }
+
+    /// Load every finished transfer, skipping files that can't be parsed
+    pub async fn load_history(&self) -> lan_chat_core::Result<Vec<FileTransfer>> {
+        read_json_dir(&self.history_dir()).await
+    }
+
+    pub async fn save_shared_media(&self, media: &[SharedMedia]) -> lan_chat_core::Result<()> {
+        write_json(&self.shared_media_path(), &media).await
+    }
+
+    /// Files offered in media messages; none if nothing was saved
+    pub async fn load_shared_media(&self) -> lan_chat_core::Result<Vec<SharedMedia>> {
+        match read_json(&self.shared_media_path()).await {
+            Err(lan_chat_core::ChatError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Vec::new())
+            }
+            result => result,
+        }
+    }
+}
+
+async fn write_json(path: &Path, value: &impl Serialize) -> lan_chat_core::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let data = serde_json::to_vec_pretty(value)?;
+
+    // Write then rename so a crash never leaves a truncated record
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+async fn read_json_dir<T: DeserializeOwned>(dir: &Path) -> lan_chat_core::Result<Vec<T>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        match read_json(&path).await {
+            Ok(record) => records.push(record),
+            Err(e) => warn!("Skipping unreadable transfer record {:?}: {}", path, e),
+        }
+    }
+
+    Ok(records)
+}
+
+async fn read_json<T: DeserializeOwned>(path: &Path) -> lan_chat_core::Result<T> {
+    let data = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+
+    fn record(source_paths: Option<Vec<PathBuf>>) -> TransferRecord {
+        let (sender, recipient) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        TransferRecord {
+            transfer: FileTransfer::new(sender, recipient, Manifest::default(), String::new()),
+            source_paths,
+            received_chunks: ChunkSet::from_ranges([0..4, 6..8]),
+            transfer_key: TransferKey::generate(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_records_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lan-chat-store-{}", uuid::Uuid::new_v4()));
+        let store = TransferStore::new(dir.clone());
+
+        // Nothing saved yet
+        assert!(store.load_all().await.unwrap().is_empty());
+        assert!(store.load_history().await.unwrap().is_empty());
+        assert!(store.load_shared_media().await.unwrap().is_empty());
+
+        let incoming = record(None);
+        let outgoing = record(Some(vec![PathBuf::from("/tmp/a.txt")]));
+        store.save(&incoming).await.unwrap();
+        store.save(&outgoing).await.unwrap();
+
+        // Leftovers that aren't records are skipped
+        tokio::fs::write(dir.join("broken.json"), b"{")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("notes.txt"), b"hi")
+            .await
+            .unwrap();
+
+        let mut loaded = store.load_all().await.unwrap();
+        loaded.sort_by_key(|r| r.source_paths.is_some());
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(
+            loaded[0].transfer.transfer_id,
+            incoming.transfer.transfer_id
+        );
+        assert_eq!(loaded[0].received_chunks, incoming.received_chunks);
+        assert_eq!(loaded[1].source_paths, outgoing.source_paths);
+
+        // A finished transfer moves to the history
+        store.remove(&incoming.transfer.transfer_id).await.unwrap();
+        store.save_finished(&incoming.transfer).await.unwrap();
+
+        // Removing a record that's already gone is fine
+        store.remove(&incoming.transfer.transfer_id).await.unwrap();
+
+        let remaining = store.load_all().await.unwrap();
+        let history = store.load_history().await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(
+            remaining[0].transfer.transfer_id,
+            outgoing.transfer.transfer_id
+        );
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].transfer_id, incoming.transfer.transfer_id);
+    }
+
+    #[tokio::test]
+    async fn test_shared_media_round_trip() {
+        let dir = std::env::temp_dir().join(format!("lan-chat-store-{}", uuid::Uuid::new_v4()));
+        let store = TransferStore::new(dir.clone());
+
+        let media = vec![SharedMedia {
+            file_hash: "abc".into(),
+            path: PathBuf::from("/tmp/photo.png"),
+            peers: vec![uuid::Uuid::new_v4()],
+        }];
+        store.save_shared_media(&media).await.unwrap();
+        let loaded = store.load_shared_media().await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+
+        assert_eq!(loaded, media);
+    }
+}
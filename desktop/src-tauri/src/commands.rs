@@ -1,7 +1,15 @@
 use crate::state::AppState;
-use lan_chat_core::{Message, Peer, UserProfile};
-use lan_chat_transfer::FileTransfer;
+use chrono::{DateTime, Utc};
+use lan_chat_core::{
+    direct_session_id, thread, Conversation, Group, Message, MessageStatus, NetworkAddress, Peer,
+    UserProfile,
+};
+use lan_chat_discovery::{ScanReport, SubnetScanner};
+use lan_chat_transfer::{
+    AcceptPolicy, FileTransfer, TransferFilter, TransferPriority, TransferSettings,
+};
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tauri::State;
 use uuid::Uuid;
@@ -17,8 +25,17 @@ pub async fn initialize_app(
     state: State<'_, Arc<AppState>>,
     request: InitializeRequest,
 ) -> Result<UserProfile, String> {
-    let profile = UserProfile::new(request.username, request.display_name);
+    let mut profile = UserProfile::new(request.username, request.display_name);
+
+    // Peers and saved state know us by our user id, so it stays
+    if let Some(current) = state.user_profile.read().await.as_ref() {
+        profile.user_id = current.user_id;
+    }
     *state.user_profile.write().await = Some(profile.clone());
+    state
+        .save_identity()
+        .await
+        .map_err(|e| format!("Failed to save profile: {}", e))?;
     Ok(profile)
 }
 
@@ -32,10 +49,16 @@ pub async fn get_user_profile(
 #[tauri::command]
 pub async fn update_user_profile(
     state: State<'_, Arc<AppState>>,
-    profile: UserProfile,
+    mut profile: UserProfile,
 ) -> Result<(), String> {
+    if let Some(current) = state.user_profile.read().await.as_ref() {
+        profile.user_id = current.user_id;
+    }
     *state.user_profile.write().await = Some(profile);
-    Ok(())
+    state
+        .save_identity()
+        .await
+        .map_err(|e| format!("Failed to save profile: {}", e))
 }
 
 #[tauri::command]
@@ -43,10 +66,91 @@ pub async fn get_peers(state: State<'_, Arc<AppState>>) -> Result<Vec<Peer>, Str
     Ok(state.peer_registry.get_all_peers().await)
 }
 
+/// Add a peer by `ip:port` (or bare IP) that discovery can't see
+///
+/// Returns the peer if the messaging handshake succeeded right away; otherwise
+/// the address is kept in the static peer list and queried in the background.
+#[tauri::command]
+pub async fn add_peer_by_address(
+    state: State<'_, Arc<AppState>>,
+    address: String,
+) -> Result<Option<Peer>, String> {
+    let address = NetworkAddress::parse(&address, lan_chat_protocol::MESSAGING_PORT)
+        .ok_or_else(|| format!("Invalid peer address: {}", address))?;
+
+    state.peer_registry.add_static_address(address.clone()).await;
+    state
+        .save_static_peers()
+        .await
+        .map_err(|e| format!("Failed to save static peers: {}", e))?;
+
+    if let Some(discovery) = state.discovery.read().await.clone() {
+        if let Err(e) = discovery.query_address(address.ip).await {
+            tracing::warn!("Discovery request to {} failed: {}", address.ip, e);
+        }
+    }
+
+    let messaging = state.messaging.read().await.clone();
+    match messaging {
+        Some(messaging) => match messaging.connect_to_address(&address).await {
+            Ok(peer) => Ok(Some(peer)),
+            Err(e) => {
+                tracing::warn!("Could not connect to {}: {}", address.to_socket_addr(), e);
+                Ok(None)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+#[tauri::command]
+pub async fn remove_static_peer(
+    state: State<'_, Arc<AppState>>,
+    address: String,
+) -> Result<(), String> {
+    let address = NetworkAddress::parse(&address, lan_chat_protocol::MESSAGING_PORT)
+        .ok_or_else(|| format!("Invalid peer address: {}", address))?;
+
+    state.peer_registry.remove_static_address(&address).await;
+    state
+        .save_static_peers()
+        .await
+        .map_err(|e| format!("Failed to save static peers: {}", e))
+}
+
+#[tauri::command]
+pub async fn get_static_peers(
+    state: State<'_, Arc<AppState>>,
+) -> Result<Vec<NetworkAddress>, String> {
+    Ok(state.peer_registry.get_static_addresses().await)
+}
+
+/// Probe the local subnets for peers on the messaging port
+#[tauri::command]
+pub async fn scan_for_peers(state: State<'_, Arc<AppState>>) -> Result<ScanReport, String> {
+    let profile = state
+        .user_profile
+        .read()
+        .await
+        .clone()
+        .ok_or("Not initialized")?;
+
+    let scanner = SubnetScanner::new(
+        profile.user_id,
+        state.peer_registry.clone(),
+        state.event_tx.clone(),
+    );
+
+    Ok(scanner.scan().await)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SendMessageRequest {
     pub recipient_id: String,
     pub content: String,
+    /// Id of the message this replies to
+    #[serde(default)]
+    pub reply_to: Option<String>,
 }
 
 #[tauri::command]
@@ -64,18 +168,25 @@ pub async fn send_message(
     let recipient_id = Uuid::parse_str(&request.recipient_id)
         .map_err(|e| format!("Invalid recipient ID: {}", e))?;
 
-    let message = Message::new_text(
-        Uuid::new_v4(),
-        profile.user_id,
-        recipient_id,
-        request.content,
-    );
+    let session_id = direct_session_id(&profile.user_id, &recipient_id);
+    let mut message = Message::new_text(session_id, profile.user_id, recipient_id, request.content);
+    if let Some(parent) = find_parent(&state, request.reply_to.as_deref(), &session_id).await? {
+        message = message.with_reply_to(&parent);
+    }
 
-    // TODO: Send via messaging server
-    let mut messages = state.messages.write().await;
-    messages.push(message.clone());
+    let messaging = state.messaging.read().await.clone().ok_or("Not initialized")?;
+    message.status = match messaging
+        .send_encrypted_message(&recipient_id, message.clone())
+        .await
+    {
+        Ok(()) => MessageStatus::Sent,
+        Err(e) => {
+            tracing::warn!("Failed to send message: {}", e);
+            MessageStatus::Failed
+        }
+    };
 
-    Ok(message)
+    Ok(state.store_message(message).await)
 }
 
 #[tauri::command]
@@ -83,6 +194,391 @@ pub async fn get_messages(state: State<'_, Arc<AppState>>) -> Result<Vec<Message
     Ok(state.messages.read().await.clone())
 }
 
+/// The thread a message starts or belongs to: its first message and every
+/// reply, oldest first
+#[tauri::command]
+pub async fn get_thread(
+    state: State<'_, Arc<AppState>>,
+    message_id: String,
+) -> Result<Vec<Message>, String> {
+    let message_id = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let messages = state.messages.read().await;
+    thread(&messages, &message_id).ok_or_else(|| "Message not found".into())
+}
+
+/// The stored message a new one replies to, which must be in the same
+/// conversation
+async fn find_parent(
+    state: &AppState,
+    reply_to: Option<&str>,
+    session_id: &Uuid,
+) -> Result<Option<Message>, String> {
+    let Some(reply_to) = reply_to else {
+        return Ok(None);
+    };
+    let reply_to =
+        Uuid::parse_str(reply_to).map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let messages = state.messages.read().await;
+    let parent = messages
+        .iter()
+        .find(|m| m.id == reply_to && m.session_id == *session_id)
+        .ok_or("Message to reply to not found in this conversation")?;
+    Ok(Some(parent.clone()))
+}
+
+/// Edit a message we sent, for everyone who got it
+///
+/// Earlier versions stay in the message's `edit_history`, on this device only.
+#[tauri::command]
+pub async fn edit_message(
+    state: State<'_, Arc<AppState>>,
+    message_id: String,
+    content: String,
+) -> Result<Message, String> {
+    let profile = state
+        .user_profile
+        .read()
+        .await
+        .clone()
+        .ok_or("Not initialized")?;
+    let message_id = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let message = state
+        .apply_edit(&message_id, &profile.user_id, content, Utc::now())
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("The message has a newer edit")?;
+
+    if let Some(messaging) = state.messaging.read().await.clone() {
+        match messaging.send_edit(&message).await {
+            Ok(undelivered) if !undelivered.is_empty() => {
+                tracing::warn!("Edit didn't reach {} recipient(s)", undelivered.len())
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Failed to send edit: {}", e),
+        }
+    }
+
+    Ok(message)
+}
+
+/// Delete a message we sent, for everyone who got it
+///
+/// The content is erased from the store; only a marker that there was a
+/// message remains.
+#[tauri::command]
+pub async fn delete_message(
+    state: State<'_, Arc<AppState>>,
+    message_id: String,
+) -> Result<Message, String> {
+    let profile = state
+        .user_profile
+        .read()
+        .await
+        .clone()
+        .ok_or("Not initialized")?;
+    let message_id = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let message = state
+        .apply_delete(&message_id, &profile.user_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let Some(messaging) = state.messaging.read().await.clone() {
+        match messaging.send_delete(&message).await {
+            Ok(undelivered) if !undelivered.is_empty() => {
+                tracing::warn!("Deletion didn't reach {} recipient(s)", undelivered.len())
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Failed to send deletion: {}", e),
+        }
+    }
+
+    Ok(message)
+}
+
+/// Conversations, most recently active first
+#[tauri::command]
+pub async fn get_conversations(
+    state: State<'_, Arc<AppState>>,
+) -> Result<Vec<Conversation>, String> {
+    Ok(state.conversations.read().await.by_activity())
+}
+
+/// A page of a conversation's messages, oldest first
+///
+/// Returns up to `limit` messages sent before `before`, or the latest ones
+/// without it; pass the first message's timestamp to get the page before.
+#[tauri::command]
+pub async fn get_conversation_messages(
+    state: State<'_, Arc<AppState>>,
+    session_id: String,
+    before: Option<DateTime<Utc>>,
+    limit: usize,
+) -> Result<Vec<Message>, String> {
+    let session_id = Uuid::parse_str(&session_id)
+        .map_err(|e| format!("Invalid session ID: {}", e))?;
+
+    let messages = state.messages.read().await;
+    let mut page: Vec<_> = messages
+        .iter()
+        .filter(|m| m.session_id == session_id)
+        .filter(|m| before.is_none_or(|before| m.timestamp < before))
+        .cloned()
+        .collect();
+    page.sort_by_key(|m| m.timestamp);
+    let start = page.len().saturating_sub(limit);
+    Ok(page.split_off(start))
+}
+
+#[tauri::command]
+pub async fn mark_conversation_read(
+    state: State<'_, Arc<AppState>>,
+    session_id: String,
+) -> Result<(), String> {
+    let session_id = Uuid::parse_str(&session_id)
+        .map_err(|e| format!("Invalid session ID: {}", e))?;
+    if !state.conversations.write().await.mark_read(&session_id) {
+        return Err("Conversation not found".into());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_conversation_muted(
+    state: State<'_, Arc<AppState>>,
+    session_id: String,
+    muted: bool,
+) -> Result<(), String> {
+    let session_id = Uuid::parse_str(&session_id)
+        .map_err(|e| format!("Invalid session ID: {}", e))?;
+    if !state
+        .conversations
+        .write()
+        .await
+        .set_muted(&session_id, muted)
+    {
+        return Err("Conversation not found".into());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_groups(state: State<'_, Arc<AppState>>) -> Result<Vec<Group>, String> {
+    Ok(state.groups.get_all().await)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+/// Create a group with us as its admin and invite the members
+#[tauri::command]
+pub async fn create_group(
+    state: State<'_, Arc<AppState>>,
+    request: CreateGroupRequest,
+) -> Result<Group, String> {
+    let members = parse_user_ids(&request.members)?;
+    let messaging = state.messaging.read().await.clone().ok_or("Not initialized")?;
+    messaging
+        .create_group(request.name, members)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteToGroupRequest {
+    pub session_id: String,
+    pub members: Vec<String>,
+}
+
+#[tauri::command]
+pub async fn invite_to_group(
+    state: State<'_, Arc<AppState>>,
+    request: InviteToGroupRequest,
+) -> Result<Group, String> {
+    let session_id = Uuid::parse_str(&request.session_id)
+        .map_err(|e| format!("Invalid group ID: {}", e))?;
+    let members = parse_user_ids(&request.members)?;
+    let messaging = state.messaging.read().await.clone().ok_or("Not initialized")?;
+    messaging
+        .invite_to_group(&session_id, members)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn remove_from_group(
+    state: State<'_, Arc<AppState>>,
+    session_id: String,
+    user_id: String,
+) -> Result<Group, String> {
+    let session_id =
+        Uuid::parse_str(&session_id).map_err(|e| format!("Invalid group ID: {}", e))?;
+    let user_id = Uuid::parse_str(&user_id).map_err(|e| format!("Invalid user ID: {}", e))?;
+    let messaging = state.messaging.read().await.clone().ok_or("Not initialized")?;
+    messaging
+        .remove_from_group(&session_id, &user_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn leave_group(
+    state: State<'_, Arc<AppState>>,
+    session_id: String,
+) -> Result<(), String> {
+    let session_id =
+        Uuid::parse_str(&session_id).map_err(|e| format!("Invalid group ID: {}", e))?;
+    let messaging = state.messaging.read().await.clone().ok_or("Not initialized")?;
+    messaging
+        .leave_group(&session_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendGroupMessageRequest {
+    pub session_id: String,
+    pub content: String,
+    /// Id of the message this replies to
+    #[serde(default)]
+    pub reply_to: Option<String>,
+}
+
+/// Send a text message to every member of a group
+///
+/// Our copy is addressed to the group's session id. It's marked sent if it
+/// reached at least one member.
+#[tauri::command]
+pub async fn send_group_message(
+    state: State<'_, Arc<AppState>>,
+    request: SendGroupMessageRequest,
+) -> Result<Message, String> {
+    let profile = state
+        .user_profile
+        .read()
+        .await
+        .clone()
+        .ok_or("Not initialized")?;
+    let session_id = Uuid::parse_str(&request.session_id)
+        .map_err(|e| format!("Invalid group ID: {}", e))?;
+    let group = state.groups.get(&session_id).await.ok_or("Unknown group")?;
+
+    let mut message =
+        Message::new_text(session_id, profile.user_id, session_id, request.content);
+    if let Some(parent) = find_parent(&state, request.reply_to.as_deref(), &session_id).await? {
+        message = message.with_reply_to(&parent);
+    }
+
+    let messaging = state.messaging.read().await.clone().ok_or("Not initialized")?;
+    let undelivered = messaging
+        .send_group_message(message.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    let recipients = group.other_members(&profile.user_id).count();
+    message.status = if recipients > 0 && undelivered.len() == recipients {
+        MessageStatus::Failed
+    } else {
+        MessageStatus::Sent
+    };
+
+    // The group's event may not have been handled yet
+    state.conversations.write().await.add_group(&group);
+    Ok(state.store_message(message).await)
+}
+
+fn parse_user_ids(ids: &[String]) -> Result<Vec<Uuid>, String> {
+    ids.iter()
+        .map(|id| Uuid::parse_str(id).map_err(|e| format!("Invalid user ID: {}", e)))
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendMediaRequest {
+    pub recipient_id: String,
+    pub file_path: String,
+}
+
+/// Send an image, audio or video file as a message
+///
+/// The message goes out straight away, with a thumbnail for images; the
+/// recipient fetches the file itself with `fetch_media`.
+#[tauri::command]
+pub async fn send_media(
+    state: State<'_, Arc<AppState>>,
+    request: SendMediaRequest,
+) -> Result<Message, String> {
+    let profile = state
+        .user_profile
+        .read()
+        .await
+        .clone()
+        .ok_or("Not initialized")?;
+    let recipient_id = Uuid::parse_str(&request.recipient_id)
+        .map_err(|e| format!("Invalid recipient ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    let mut message = transfer
+        .share_media(
+            direct_session_id(&profile.user_id, &recipient_id),
+            recipient_id,
+            Path::new(&request.file_path),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if let Some(messaging) = state.messaging.read().await.clone() {
+        message.status = match messaging
+            .send_encrypted_message(&recipient_id, message.clone())
+            .await
+        {
+            Ok(()) => MessageStatus::Sent,
+            Err(e) => {
+                tracing::warn!("Failed to send media message: {}", e);
+                MessageStatus::Failed
+            }
+        };
+    }
+
+    Ok(state.store_message(message).await)
+}
+
+/// Fetch the file of a received media message, returning the transfer it
+/// comes in
+#[tauri::command]
+pub async fn fetch_media(
+    state: State<'_, Arc<AppState>>,
+    message_id: String,
+) -> Result<String, String> {
+    let message_id = Uuid::parse_str(&message_id)
+        .map_err(|e| format!("Invalid message ID: {}", e))?;
+
+    let message = state
+        .messages
+        .read()
+        .await
+        .iter()
+        .find(|m| m.id == message_id)
+        .cloned()
+        .ok_or("Message not found")?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    let transfer_id = transfer
+        .fetch_media(&message)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(transfer_id.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SendFileRequest {
     pub recipient_id: String,
@@ -91,38 +587,208 @@ pub struct SendFileRequest {
 
 #[tauri::command]
 pub async fn send_file(
-    _state: State<'_, Arc<AppState>>,
+    state: State<'_, Arc<AppState>>,
     request: SendFileRequest,
 ) -> Result<String, String> {
-    let _recipient_id = Uuid::parse_str(&request.recipient_id)
+    let recipient_id = Uuid::parse_str(&request.recipient_id)
+        .map_err(|e| format!("Invalid recipient ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    let transfer_id = transfer
+        .send_file(recipient_id, Path::new(&request.file_path))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(transfer_id.to_string())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendFilesRequest {
+    pub recipient_id: String,
+    pub file_paths: Vec<String>,
+}
+
+#[tauri::command]
+pub async fn send_files(
+    state: State<'_, Arc<AppState>>,
+    request: SendFilesRequest,
+) -> Result<String, String> {
+    let recipient_id = Uuid::parse_str(&request.recipient_id)
         .map_err(|e| format!("Invalid recipient ID: {}", e))?;
+    let paths: Vec<PathBuf> = request.file_paths.iter().map(PathBuf::from).collect();
 
-    // TODO: Implement via transfer service
-    Ok("transfer-id".to_string())
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    let transfer_id = transfer
+        .send_files(recipient_id, &paths)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(transfer_id.to_string())
 }
 
 #[tauri::command]
 pub async fn accept_file_transfer(
-    _state: State<'_, Arc<AppState>>,
-    _transfer_id: String,
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
 ) -> Result<(), String> {
-    // TODO: Implement
-    Ok(())
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .accept_transfer(transfer_id)
+        .await
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
 pub async fn reject_file_transfer(
-    _state: State<'_, Arc<AppState>>,
-    _transfer_id: String,
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
 ) -> Result<(), String> {
-    // TODO: Implement
-    Ok(())
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .reject_transfer(transfer_id, "Rejected by recipient".into())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn pause_file_transfer(
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
+) -> Result<(), String> {
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .pause_transfer(transfer_id)
+        .await
+        .map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+pub async fn resume_file_transfer(
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
+) -> Result<(), String> {
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .resume_transfer(transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn cancel_file_transfer(
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
+) -> Result<(), String> {
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .cancel_transfer(transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_file_transfer_priority(
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
+    priority: TransferPriority,
+) -> Result<(), String> {
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .set_transfer_priority(transfer_id, priority)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Limit one transfer to `rate_limit` bytes per second, or back to the default
+#[tauri::command]
+pub async fn set_file_transfer_rate_limit(
+    state: State<'_, Arc<AppState>>,
+    transfer_id: String,
+    rate_limit: Option<u64>,
+) -> Result<(), String> {
+    let transfer_id = Uuid::parse_str(&transfer_id)
+        .map_err(|e| format!("Invalid transfer ID: {}", e))?;
+
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer
+        .set_transfer_rate_limit(transfer_id, rate_limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_transfer_settings(
+    state: State<'_, Arc<AppState>>,
+) -> Result<TransferSettings, String> {
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    Ok(transfer.settings().await)
+}
+
+/// Apply new bandwidth limits and queue size, and keep them for next time
+#[tauri::command]
+pub async fn update_transfer_settings(
+    state: State<'_, Arc<AppState>>,
+    settings: TransferSettings,
+) -> Result<(), String> {
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer.update_settings(settings.clone()).await;
+
+    state
+        .save_transfer_settings(&settings)
+        .await
+        .map_err(|e| format!("Failed to save transfer settings: {}", e))
+}
+
+#[tauri::command]
+pub async fn get_accept_policy(state: State<'_, Arc<AppState>>) -> Result<AcceptPolicy, String> {
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    Ok(transfer.accept_policy().await)
+}
+
+/// Change which incoming transfers are accepted or rejected without asking,
+/// and keep the rules for next time
+#[tauri::command]
+pub async fn update_accept_policy(
+    state: State<'_, Arc<AppState>>,
+    policy: AcceptPolicy,
+) -> Result<(), String> {
+    let transfer = state.transfer.read().await.clone().ok_or("Not initialized")?;
+    transfer.set_accept_policy(policy.clone()).await;
+
+    state
+        .save_accept_policy(&policy)
+        .await
+        .map_err(|e| format!("Failed to save accept policy: {}", e))
+}
+
+/// Running and past transfers matching `filter`, newest first
 #[tauri::command]
 pub async fn get_file_transfers(
-    _state: State<'_, Arc<AppState>>,
+    state: State<'_, Arc<AppState>>,
+    filter: Option<TransferFilter>,
 ) -> Result<Vec<FileTransfer>, String> {
-    // TODO: Implement
-    Ok(Vec::new())
+    let Some(transfer) = state.transfer.read().await.clone() else {
+        return Ok(Vec::new());
+    };
+    Ok(transfer
+        .list_transfers(&filter.unwrap_or_default())
+        .await)
 }
@@ -5,9 +5,9 @@ mod commands;
 mod state;
 
 use commands::*;
-use state::AppState;
+use state::{AppState, SHUTDOWN_TIMEOUT};
 use std::sync::Arc;
-use tauri::Manager;
+use tauri::{Manager, WindowEvent};
 use tracing_subscriber;
 
 #[tokio::main]
@@ -25,13 +25,60 @@ async fn main() {
             get_user_profile,
             update_user_profile,
             get_peers,
+            add_peer_by_address,
+            remove_static_peer,
+            get_static_peers,
+            scan_for_peers,
             send_message,
             get_messages,
+            get_thread,
+            edit_message,
+            delete_message,
+            get_conversations,
+            get_conversation_messages,
+            mark_conversation_read,
+            set_conversation_muted,
+            get_groups,
+            create_group,
+            invite_to_group,
+            remove_from_group,
+            leave_group,
+            send_group_message,
+            send_media,
+            fetch_media,
             send_file,
+            send_files,
             accept_file_transfer,
             reject_file_transfer,
+            pause_file_transfer,
+            resume_file_transfer,
+            cancel_file_transfer,
             get_file_transfers,
+            set_file_transfer_priority,
+            set_file_transfer_rate_limit,
+            get_transfer_settings,
+            update_transfer_settings,
+            get_accept_policy,
+            update_accept_policy,
         ])
+        .on_window_event(|event| {
+            if let WindowEvent::CloseRequested { api, .. } = event.event() {
+                let state = event.window().state::<Arc<AppState>>().inner().clone();
+                if state.is_shut_down() {
+                    return;
+                }
+
+                // Hold the window open until peers have been told we're leaving
+                api.prevent_close();
+                let window = event.window().clone();
+                tokio::spawn(async move {
+                    if tokio::time::timeout(SHUTDOWN_TIMEOUT, state.shutdown()).await.is_err() {
+                        tracing::warn!("Shutdown timed out, closing anyway");
+                    }
+                    let _ = window.close();
+                });
+            }
+        })
         .setup(move |app| {
             let window = app.get_window("main").unwrap();
             let state = app_state.clone();
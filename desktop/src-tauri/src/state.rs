@@ -1,15 +1,21 @@
+use chrono::{DateTime, Utc};
 use lan_chat_core::{
-    ChatEvent, Message, NetworkAddress, PeerRegistry, UserProfile,
+    ChatEvent, ConversationList, GroupList, GroupRegistry, Message, NetworkAddress, PeerRegistry,
+    StaticPeerList, UserId, UserProfile,
 };
 use lan_chat_crypto::KeyPair;
 use lan_chat_discovery::DiscoveryService;
 use lan_chat_protocol::MessagingServer;
-use lan_chat_transfer::TransferService;
+use lan_chat_transfer::{AcceptPolicy, TransferService, TransferSettings};
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, RwLock};
 use tauri::Window;
+use uuid::Uuid;
 
 extern crate dirs;
 
@@ -17,9 +23,64 @@ pub struct AppState {
     pub user_profile: Arc<RwLock<Option<UserProfile>>>,
     pub keypair: Arc<RwLock<Option<KeyPair>>>,
     pub peer_registry: PeerRegistry,
+    pub groups: GroupRegistry,
     pub messages: Arc<RwLock<Vec<Message>>>,
+    pub conversations: Arc<RwLock<ConversationList>>,
     pub event_tx: mpsc::UnboundedSender<ChatEvent>,
     pub event_rx: Arc<RwLock<mpsc::UnboundedReceiver<ChatEvent>>>,
+    pub discovery: Arc<RwLock<Option<Arc<DiscoveryService>>>>,
+    pub messaging: Arc<RwLock<Option<Arc<MessagingServer>>>>,
+    pub transfer: Arc<RwLock<Option<Arc<TransferService>>>>,
+    shut_down: AtomicBool,
+}
+
+/// Upper bound on how long closing the window waits for services to stop
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Our profile and key pair as saved between runs
+///
+/// Peers pin our key to our user id, and groups and interrupted transfers
+/// are saved under that id, so both have to stay the same after a restart.
+#[derive(Serialize, Deserialize)]
+struct Identity {
+    profile: UserProfile,
+    private_key_pem: String,
+}
+
+impl Identity {
+    fn new(profile: UserProfile, keypair: &KeyPair) -> anyhow::Result<Self> {
+        Ok(Self {
+            profile,
+            private_key_pem: keypair.export_private_key_pem()?,
+        })
+    }
+
+    /// Load the saved identity, or `None` if there isn't one yet
+    async fn load(path: &Path) -> anyhow::Result<Option<(UserProfile, KeyPair)>> {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let identity: Self = serde_json::from_slice(&data)?;
+        let keypair = KeyPair::from_private_key_pem(&identity.private_key_pem)?;
+        Ok(Some((identity.profile, keypair)))
+    }
+
+    /// Save the identity, readable only by us where the platform allows
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+        Ok(())
+    }
 }
 
 impl AppState {
@@ -30,27 +91,155 @@ impl AppState {
             user_profile: Arc::new(RwLock::new(None)),
             keypair: Arc::new(RwLock::new(None)),
             peer_registry: PeerRegistry::new(),
+            groups: GroupRegistry::new(),
             messages: Arc::new(RwLock::new(Vec::new())),
+            conversations: Arc::new(RwLock::new(ConversationList::new())),
             event_tx,
             event_rx: Arc::new(RwLock::new(event_rx)),
+            discovery: Arc::new(RwLock::new(None)),
+            messaging: Arc::new(RwLock::new(None)),
+            transfer: Arc::new(RwLock::new(None)),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Save the manually added peer addresses
+    pub async fn save_static_peers(&self) -> anyhow::Result<()> {
+        let list = StaticPeerList {
+            addresses: self.peer_registry.get_static_addresses().await,
+        };
+        list.save(&static_peers_path()).await?;
+        Ok(())
+    }
+
+    /// Store a sent or received message and update its conversation
+    ///
+    /// One-to-one messages are stored under the conversation's session id,
+    /// whatever the sender used.
+    pub async fn store_message(&self, mut message: Message) -> Message {
+        if let Some(profile) = self.user_profile.read().await.as_ref() {
+            self.conversations
+                .write()
+                .await
+                .record(&mut message, &profile.user_id);
         }
+        self.messages.write().await.push(message.clone());
+        message
+    }
+
+    /// Edit a stored message, if `editor` sent it
+    ///
+    /// Returns `None` if a newer edit is already in place.
+    pub async fn apply_edit(
+        &self,
+        message_id: &Uuid,
+        editor: &UserId,
+        content: String,
+        edited_at: DateTime<Utc>,
+    ) -> lan_chat_core::Result<Option<Message>> {
+        let mut messages = self.messages.write().await;
+        let message = find_message(&mut messages, message_id)?;
+        if !message.edit(editor, content, edited_at)? {
+            return Ok(None);
+        }
+        let message = message.clone();
+        self.conversations.write().await.update_message(&message);
+        Ok(Some(message))
+    }
+
+    /// Delete a stored message's content, if `deleter` sent it
+    pub async fn apply_delete(
+        &self,
+        message_id: &Uuid,
+        deleter: &UserId,
+    ) -> lan_chat_core::Result<Message> {
+        let mut messages = self.messages.write().await;
+        let message = find_message(&mut messages, message_id)?;
+        message.delete(deleter)?;
+        let message = message.clone();
+        self.conversations.write().await.update_message(&message);
+        Ok(message)
+    }
+
+    /// Save our profile and key pair, once both exist
+    pub async fn save_identity(&self) -> anyhow::Result<()> {
+        let profile = self.user_profile.read().await.clone();
+        let keypair = self.keypair.read().await.clone();
+        if let (Some(profile), Some(keypair)) = (profile, keypair) {
+            Identity::new(profile, &keypair)?.save(&identity_path()).await?;
+        }
+        Ok(())
+    }
+
+    /// Save the groups we're in
+    pub async fn save_groups(&self) -> anyhow::Result<()> {
+        let list = GroupList {
+            groups: self.groups.get_all().await,
+        };
+        list.save(&groups_path()).await?;
+        Ok(())
+    }
+
+    /// Save the transfer bandwidth and queue settings
+    pub async fn save_transfer_settings(&self, settings: &TransferSettings) -> anyhow::Result<()> {
+        settings.save(&transfer_settings_path()).await?;
+        Ok(())
+    }
+
+    /// Save the rules for answering incoming transfers without asking
+    pub async fn save_accept_policy(&self, policy: &AcceptPolicy) -> anyhow::Result<()> {
+        policy.save(&accept_policy_path()).await?;
+        Ok(())
+    }
+
+    /// Whether `shutdown` has already run
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Stop all services: say goodbye to peers, close connections and abort transfers
+    pub async fn shutdown(&self) {
+        if self.shut_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        tracing::info!("Shutting down chat services...");
+
+        if let Some(discovery) = self.discovery.read().await.clone() {
+            if let Err(e) = discovery.shutdown().await {
+                tracing::error!("Failed to announce goodbye: {}", e);
+            }
+        }
+
+        if let Some(messaging) = self.messaging.read().await.clone() {
+            messaging.shutdown().await;
+        }
+
+        if let Some(transfer) = self.transfer.read().await.clone() {
+            transfer.shutdown().await;
+        }
+
+        tracing::info!("All services stopped");
     }
 
     pub async fn start_services(&self) -> anyhow::Result<()> {
         tracing::info!("Starting chat services...");
         
-        // Generate keypair
-        let keypair = KeyPair::generate()?;
-        let public_key = keypair.export_public_key_bytes()?;
+        // Reuse the identity of earlier runs, or make one on the first
+        let (saved_profile, keypair) = match Identity::load(&identity_path()).await? {
+            Some(identity) => identity,
+            None => (UserProfile::new("user".into(), "User".into()), KeyPair::generate()?),
+        };
         *self.keypair.write().await = Some(keypair.clone());
 
-        // Get profile
-        let profile = self
-            .user_profile
-            .read()
-            .await
-            .clone()
-            .unwrap_or_else(|| UserProfile::new("user".into(), "User".into()));
+        // Get profile; a name set in the meantime keeps the saved user id
+        let profile = {
+            let mut current = self.user_profile.write().await;
+            let profile = current.get_or_insert_with(|| saved_profile.clone());
+            profile.user_id = saved_profile.user_id;
+            profile.clone()
+        };
+        self.save_identity().await?;
 
         tracing::info!("User profile: {} ({})", profile.display_name, profile.user_id);
 
@@ -66,6 +255,24 @@ impl AppState {
             }
         };
 
+        // Load manually added peers so discovery can reach them directly
+        match StaticPeerList::load(&static_peers_path()).await {
+            Ok(list) => list.apply_to(&self.peer_registry).await,
+            Err(e) => tracing::warn!("Failed to load static peers: {}", e),
+        }
+
+        // Load the groups we're in, so their messages are accepted
+        match GroupList::load(&groups_path()).await {
+            Ok(list) => {
+                list.apply_to(&self.groups).await;
+                let mut conversations = self.conversations.write().await;
+                for group in &list.groups {
+                    conversations.add_group(group);
+                }
+            }
+            Err(e) => tracing::warn!("Failed to load groups: {}", e),
+        }
+
         // Start discovery service
         tracing::info!("Starting peer discovery service...");
         let discovery = Arc::new(
@@ -75,8 +282,9 @@ impl AppState {
                 self.peer_registry.clone(),
                 self.event_tx.clone(),
             )
-            .with_public_key(public_key),
+            .with_keypair(keypair.clone()),
         );
+        *self.discovery.write().await = Some(discovery.clone());
 
         tokio::spawn(async move {
             if let Err(e) = discovery.start().await {
@@ -86,12 +294,16 @@ impl AppState {
 
         // Start messaging server
         tracing::info!("Starting messaging server on port {}...", lan_chat_protocol::MESSAGING_PORT);
-        let messaging = Arc::new(MessagingServer::new(
-            profile.clone(),
-            keypair,
-            self.peer_registry.clone(),
-            self.event_tx.clone(),
-        ));
+        let messaging = Arc::new(
+            MessagingServer::new(
+                profile.clone(),
+                keypair.clone(),
+                self.peer_registry.clone(),
+                self.event_tx.clone(),
+            )
+            .with_groups(self.groups.clone()),
+        );
+        *self.messaging.write().await = Some(messaging.clone());
 
         tokio::spawn(async move {
             if let Err(e) = messaging.start().await {
@@ -100,14 +312,34 @@ impl AppState {
         });
 
         // Start transfer service
+        let transfer_settings = TransferSettings::load(&transfer_settings_path())
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to load transfer settings: {}", e);
+                TransferSettings::default()
+            });
+        let accept_policy = AcceptPolicy::load(&accept_policy_path())
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to load accept policy: {}", e);
+                AcceptPolicy::default()
+            });
         let download_dir = dirs::download_dir().unwrap_or_else(|| PathBuf::from("."));
         tracing::info!("Starting file transfer service (downloads: {:?})...", download_dir);
-        let transfer = Arc::new(TransferService::new(
-            profile.user_id,
-            self.peer_registry.clone(),
-            self.event_tx.clone(),
-            download_dir,
-        ));
+        let transfer = Arc::new(
+            TransferService::new(
+                profile.user_id,
+                keypair,
+                self.peer_registry.clone(),
+                self.event_tx.clone(),
+                download_dir,
+            )
+            .with_state_dir(data_dir().join("transfers"))
+            .with_cache_dir(data_dir().join("cache"))
+            .with_settings(transfer_settings)
+            .with_accept_policy(accept_policy),
+        );
+        *self.transfer.write().await = Some(transfer.clone());
 
         tokio::spawn(async move {
             if let Err(e) = transfer.start().await {
@@ -135,27 +367,116 @@ impl AppState {
                     let _ = window.emit("peer-disconnected", user_id);
                 }
                 ChatEvent::MessageReceived(msg) => {
-                    // Store message
-                    let mut messages = self.messages.write().await;
-                    messages.push(msg.clone());
-                    let _ = window.emit("message-received", msg);
+                    let msg = self.store_message(msg.clone()).await;
+                    let _ = window.emit("message-received", &msg);
+                }
+                ChatEvent::MessageEdited { message_id, edited_by, content, edited_at } => {
+                    match self.apply_edit(message_id, edited_by, content.clone(), *edited_at).await {
+                        Ok(Some(msg)) => {
+                            let _ = window.emit("message-edited", &msg);
+                        }
+                        Ok(None) => tracing::debug!("Ignoring stale edit of {}", message_id),
+                        Err(e) => tracing::warn!("Ignoring edit of {}: {}", message_id, e),
+                    }
+                }
+                ChatEvent::MessageDeleted { message_id, deleted_by } => {
+                    match self.apply_delete(message_id, deleted_by).await {
+                        Ok(msg) => {
+                            let _ = window.emit("message-deleted", &msg);
+                        }
+                        Err(e) => tracing::warn!("Ignoring deletion of {}: {}", message_id, e),
+                    }
                 }
                 ChatEvent::MessageSent(msg) => {
                     let _ = window.emit("message-sent", msg);
                 }
-                ChatEvent::FileTransferRequested { transfer_id, from, file_name, file_size } => {
+                ChatEvent::GroupUpdated(group) => {
+                    self.conversations.write().await.add_group(group);
+                    if let Err(e) = self.save_groups().await {
+                        tracing::warn!("Failed to save groups: {}", e);
+                    }
+                    let _ = window.emit("group-updated", group);
+                }
+                ChatEvent::GroupRemoved { session_id } => {
+                    if let Err(e) = self.save_groups().await {
+                        tracing::warn!("Failed to save groups: {}", e);
+                    }
+                    let _ = window.emit("group-removed", serde_json::json!({
+                        "session_id": session_id,
+                    }));
+                }
+                ChatEvent::FileTransferRequested { transfer_id, from, file_name, file_size, file_count } => {
                     let _ = window.emit("file-transfer-requested", serde_json::json!({
                         "transfer_id": transfer_id,
                         "from": from,
                         "file_name": file_name,
                         "file_size": file_size,
+                        "file_count": file_count,
                     }));
                 }
-                ChatEvent::FileTransferProgress { transfer_id, bytes_transferred, total_bytes } => {
+                ChatEvent::FileTransferProgress { transfer_id, phase, bytes_transferred, total_bytes, bytes_per_second, eta_seconds } => {
                     let _ = window.emit("file-transfer-progress", serde_json::json!({
                         "transfer_id": transfer_id,
+                        "phase": phase,
                         "bytes_transferred": bytes_transferred,
                         "total_bytes": total_bytes,
+                        "bytes_per_second": bytes_per_second,
+                        "eta_seconds": eta_seconds,
+                    }));
+                }
+                ChatEvent::FileTransferItemProgress { transfer_id, path, bytes_transferred, total_bytes } => {
+                    let _ = window.emit("file-transfer-item-progress", serde_json::json!({
+                        "transfer_id": transfer_id,
+                        "path": path,
+                        "bytes_transferred": bytes_transferred,
+                        "total_bytes": total_bytes,
+                    }));
+                }
+                ChatEvent::FileTransferAccepted { transfer_id } => {
+                    let _ = window.emit("file-transfer-accepted", serde_json::json!({
+                        "transfer_id": transfer_id,
+                    }));
+                }
+                ChatEvent::FileTransferRejected { transfer_id, reason } => {
+                    let _ = window.emit("file-transfer-rejected", serde_json::json!({
+                        "transfer_id": transfer_id,
+                        "reason": reason,
+                    }));
+                }
+                ChatEvent::FileTransferAutoAccepted { transfer_id, from, file_name, reason } => {
+                    let _ = window.emit("file-transfer-auto-accepted", serde_json::json!({
+                        "transfer_id": transfer_id,
+                        "from": from,
+                        "file_name": file_name,
+                        "reason": reason,
+                    }));
+                }
+                ChatEvent::FileTransferAutoRejected { transfer_id, from, file_name, reason } => {
+                    let _ = window.emit("file-transfer-auto-rejected", serde_json::json!({
+                        "transfer_id": transfer_id,
+                        "from": from,
+                        "file_name": file_name,
+                        "reason": reason,
+                    }));
+                }
+                ChatEvent::FileTransferQueued { transfer_id } => {
+                    let _ = window.emit("file-transfer-queued", serde_json::json!({
+                        "transfer_id": transfer_id,
+                    }));
+                }
+                ChatEvent::FileTransferPaused { transfer_id } => {
+                    let _ = window.emit("file-transfer-paused", serde_json::json!({
+                        "transfer_id": transfer_id,
+                    }));
+                }
+                ChatEvent::FileTransferResumed { transfer_id } => {
+                    let _ = window.emit("file-transfer-resumed", serde_json::json!({
+                        "transfer_id": transfer_id,
+                    }));
+                }
+                ChatEvent::FileTransferCancelled { transfer_id } => {
+                    let _ = window.emit("file-transfer-cancelled", serde_json::json!({
+                        "transfer_id": transfer_id,
                     }));
                 }
                 ChatEvent::FileTransferCompleted { transfer_id } => {
@@ -163,6 +484,12 @@ impl AppState {
                         "transfer_id": transfer_id,
                     }));
                 }
+                ChatEvent::FileTransferFailed { transfer_id, error } => {
+                    let _ = window.emit("file-transfer-failed", serde_json::json!({
+                        "transfer_id": transfer_id,
+                        "error": error,
+                    }));
+                }
                 _ => {
                     // Fallback for other events
                     let _ = window.emit("chat-event", &event);
@@ -172,6 +499,47 @@ impl AppState {
     }
 }
 
+fn find_message<'a>(
+    messages: &'a mut [Message],
+    message_id: &Uuid,
+) -> lan_chat_core::Result<&'a mut Message> {
+    messages
+        .iter_mut()
+        .find(|m| m.id == *message_id)
+        .ok_or_else(|| lan_chat_core::ChatError::InvalidData(format!("Unknown message {}", message_id)))
+}
+
+/// Directory for the app's persisted state
+fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("lan-chat")
+}
+
+/// Location of the persisted static peer list
+fn static_peers_path() -> PathBuf {
+    data_dir().join("static_peers.json")
+}
+
+/// Location of the persisted transfer settings
+fn transfer_settings_path() -> PathBuf {
+    data_dir().join("transfer_settings.json")
+}
+
+/// Location of our persisted profile and key pair
+fn identity_path() -> PathBuf {
+    data_dir().join("identity.json")
+}
+
+/// Location of the persisted groups
+fn groups_path() -> PathBuf {
+    data_dir().join("groups.json")
+}
+
+fn accept_policy_path() -> PathBuf {
+    data_dir().join("accept_policy.json")
+}
+
 fn get_local_ip() -> Option<IpAddr> {
     use local_ip_address::local_ip;
 
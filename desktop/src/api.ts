@@ -3,8 +3,18 @@ import { listen } from "@tauri-apps/api/event";
 import {
   UserProfile,
   Peer,
+  NetworkAddress,
+  ScanReport,
   Message,
+  Conversation,
+  Group,
   FileTransfer,
+  TransferFilter,
+  TransferPriority,
+  TransferSettings,
+  AcceptPolicy,
+  FileTransferProgress,
+  FileTransferAutoDecision,
 } from "./types";
 
 export async function initializeApp(
@@ -33,14 +43,34 @@ export async function getPeers(): Promise<Peer[]> {
   return await invoke("get_peers");
 }
 
+export async function addPeerByAddress(
+  address: string
+): Promise<Peer | null> {
+  return await invoke("add_peer_by_address", { address });
+}
+
+export async function removeStaticPeer(address: string): Promise<void> {
+  await invoke("remove_static_peer", { address });
+}
+
+export async function getStaticPeers(): Promise<NetworkAddress[]> {
+  return await invoke("get_static_peers");
+}
+
+export async function scanForPeers(): Promise<ScanReport> {
+  return await invoke("scan_for_peers");
+}
+
 export async function sendMessage(
   recipientId: string,
-  content: string
+  content: string,
+  replyTo?: string
 ): Promise<Message> {
   return await invoke("send_message", {
     request: {
       recipient_id: recipientId,
       content,
+      reply_to: replyTo ?? null,
     },
   });
 }
@@ -49,6 +79,110 @@ export async function getMessages(): Promise<Message[]> {
   return await invoke("get_messages");
 }
 
+export async function getThread(messageId: string): Promise<Message[]> {
+  return await invoke("get_thread", { messageId });
+}
+
+export async function editMessage(
+  messageId: string,
+  content: string
+): Promise<Message> {
+  return await invoke("edit_message", { messageId, content });
+}
+
+export async function deleteMessage(messageId: string): Promise<Message> {
+  return await invoke("delete_message", { messageId });
+}
+
+export async function getConversations(): Promise<Conversation[]> {
+  return await invoke("get_conversations");
+}
+
+// Up to `limit` messages before `before` (an ISO timestamp), oldest first
+export async function getConversationMessages(
+  sessionId: string,
+  limit: number,
+  before?: string
+): Promise<Message[]> {
+  return await invoke("get_conversation_messages", {
+    sessionId,
+    before: before ?? null,
+    limit,
+  });
+}
+
+export async function markConversationRead(sessionId: string): Promise<void> {
+  await invoke("mark_conversation_read", { sessionId });
+}
+
+export async function setConversationMuted(
+  sessionId: string,
+  muted: boolean
+): Promise<void> {
+  await invoke("set_conversation_muted", { sessionId, muted });
+}
+
+export async function getGroups(): Promise<Group[]> {
+  return await invoke("get_groups");
+}
+
+export async function createGroup(
+  name: string,
+  members: string[]
+): Promise<Group> {
+  return await invoke("create_group", { request: { name, members } });
+}
+
+export async function inviteToGroup(
+  sessionId: string,
+  members: string[]
+): Promise<Group> {
+  return await invoke("invite_to_group", {
+    request: { session_id: sessionId, members },
+  });
+}
+
+export async function removeFromGroup(
+  sessionId: string,
+  userId: string
+): Promise<Group> {
+  return await invoke("remove_from_group", { sessionId, userId });
+}
+
+export async function leaveGroup(sessionId: string): Promise<void> {
+  await invoke("leave_group", { sessionId });
+}
+
+export async function sendGroupMessage(
+  sessionId: string,
+  content: string,
+  replyTo?: string
+): Promise<Message> {
+  return await invoke("send_group_message", {
+    request: {
+      session_id: sessionId,
+      content,
+      reply_to: replyTo ?? null,
+    },
+  });
+}
+
+export async function sendMedia(
+  recipientId: string,
+  filePath: string
+): Promise<Message> {
+  return await invoke("send_media", {
+    request: {
+      recipient_id: recipientId,
+      file_path: filePath,
+    },
+  });
+}
+
+export async function fetchMedia(messageId: string): Promise<string> {
+  return await invoke("fetch_media", { messageId });
+}
+
 export async function sendFile(
   recipientId: string,
   filePath: string
@@ -61,6 +195,18 @@ export async function sendFile(
   });
 }
 
+export async function sendFiles(
+  recipientId: string,
+  filePaths: string[]
+): Promise<string> {
+  return await invoke("send_files", {
+    request: {
+      recipient_id: recipientId,
+      file_paths: filePaths,
+    },
+  });
+}
+
 export async function acceptFileTransfer(transferId: string): Promise<void> {
   await invoke("accept_file_transfer", { transferId });
 }
@@ -69,8 +215,54 @@ export async function rejectFileTransfer(transferId: string): Promise<void> {
   await invoke("reject_file_transfer", { transferId });
 }
 
-export async function getFileTransfers(): Promise<FileTransfer[]> {
-  return await invoke("get_file_transfers");
+export async function pauseFileTransfer(transferId: string): Promise<void> {
+  await invoke("pause_file_transfer", { transferId });
+}
+
+export async function resumeFileTransfer(transferId: string): Promise<void> {
+  await invoke("resume_file_transfer", { transferId });
+}
+
+export async function cancelFileTransfer(transferId: string): Promise<void> {
+  await invoke("cancel_file_transfer", { transferId });
+}
+
+export async function setFileTransferPriority(
+  transferId: string,
+  priority: TransferPriority
+): Promise<void> {
+  await invoke("set_file_transfer_priority", { transferId, priority });
+}
+
+export async function setFileTransferRateLimit(
+  transferId: string,
+  rateLimit: number | null
+): Promise<void> {
+  await invoke("set_file_transfer_rate_limit", { transferId, rateLimit });
+}
+
+export async function getTransferSettings(): Promise<TransferSettings> {
+  return await invoke("get_transfer_settings");
+}
+
+export async function updateTransferSettings(
+  settings: TransferSettings
+): Promise<void> {
+  await invoke("update_transfer_settings", { settings });
+}
+
+export async function getAcceptPolicy(): Promise<AcceptPolicy> {
+  return await invoke("get_accept_policy");
+}
+
+export async function updateAcceptPolicy(policy: AcceptPolicy): Promise<void> {
+  await invoke("update_accept_policy", { policy });
+}
+
+export async function getFileTransfers(
+  filter?: TransferFilter
+): Promise<FileTransfer[]> {
+  return await invoke("get_file_transfers", { filter: filter ?? null });
 }
 
 // Event listeners
@@ -90,6 +282,38 @@ export function listenToMessageReceived(
   });
 }
 
+export function listenToMessageEdited(
+  callback: (message: Message) => void
+): Promise<() => void> {
+  return listen("message-edited", (event) => {
+    callback(event.payload as Message);
+  });
+}
+
+export function listenToMessageDeleted(
+  callback: (message: Message) => void
+): Promise<() => void> {
+  return listen("message-deleted", (event) => {
+    callback(event.payload as Message);
+  });
+}
+
+export function listenToGroupUpdated(
+  callback: (group: Group) => void
+): Promise<() => void> {
+  return listen("group-updated", (event) => {
+    callback(event.payload as Group);
+  });
+}
+
+export function listenToGroupRemoved(
+  callback: (data: { session_id: string }) => void
+): Promise<() => void> {
+  return listen("group-removed", (event) => {
+    callback(event.payload as { session_id: string });
+  });
+}
+
 export function listenToFileTransferRequested(
   callback: (data: any) => void
 ): Promise<() => void> {
@@ -97,3 +321,75 @@ export function listenToFileTransferRequested(
     callback(event.payload);
   });
 }
+
+export function listenToFileTransferAccepted(
+  callback: (data: { transfer_id: string }) => void
+): Promise<() => void> {
+  return listen("file-transfer-accepted", (event) => {
+    callback(event.payload as { transfer_id: string });
+  });
+}
+
+export function listenToFileTransferRejected(
+  callback: (data: { transfer_id: string; reason: string }) => void
+): Promise<() => void> {
+  return listen("file-transfer-rejected", (event) => {
+    callback(event.payload as { transfer_id: string; reason: string });
+  });
+}
+
+export function listenToFileTransferProgress(
+  callback: (data: FileTransferProgress) => void
+): Promise<() => void> {
+  return listen("file-transfer-progress", (event) => {
+    callback(event.payload as FileTransferProgress);
+  });
+}
+
+export function listenToFileTransferAutoAccepted(
+  callback: (data: FileTransferAutoDecision) => void
+): Promise<() => void> {
+  return listen("file-transfer-auto-accepted", (event) => {
+    callback(event.payload as FileTransferAutoDecision);
+  });
+}
+
+export function listenToFileTransferAutoRejected(
+  callback: (data: FileTransferAutoDecision) => void
+): Promise<() => void> {
+  return listen("file-transfer-auto-rejected", (event) => {
+    callback(event.payload as FileTransferAutoDecision);
+  });
+}
+
+export function listenToFileTransferQueued(
+  callback: (data: { transfer_id: string }) => void
+): Promise<() => void> {
+  return listen("file-transfer-queued", (event) => {
+    callback(event.payload as { transfer_id: string });
+  });
+}
+
+export function listenToFileTransferPaused(
+  callback: (data: { transfer_id: string }) => void
+): Promise<() => void> {
+  return listen("file-transfer-paused", (event) => {
+    callback(event.payload as { transfer_id: string });
+  });
+}
+
+export function listenToFileTransferResumed(
+  callback: (data: { transfer_id: string }) => void
+): Promise<() => void> {
+  return listen("file-transfer-resumed", (event) => {
+    callback(event.payload as { transfer_id: string });
+  });
+}
+
+export function listenToFileTransferCancelled(
+  callback: (data: { transfer_id: string }) => void
+): Promise<() => void> {
+  return listen("file-transfer-cancelled", (event) => {
+    callback(event.payload as { transfer_id: string });
+  });
+}
@@ -14,16 +14,25 @@ export enum UserStatus {
   Offline = "Offline",
 }
 
+export interface NetworkAddress {
+  ip: string;
+  port: number;
+}
+
 export interface Peer {
   profile: UserProfile;
-  address: {
-    ip: string;
-    port: number;
-  };
+  address: NetworkAddress;
   last_seen: string;
   public_key?: number[];
 }
 
+export interface ScanReport {
+  subnets: string[];
+  hosts_probed: number;
+  unresponsive_hosts: string[];
+  peers: Peer[];
+}
+
 export interface Message {
   id: string;
   session_id: string;
@@ -31,11 +40,67 @@ export interface Message {
   recipient_id: string;
   message_type: MessageType;
   content: string;
+  metadata?: MessageMetadata | null;
   timestamp: string;
   status: MessageStatus;
   encrypted: boolean;
+  reply_to?: string | null;
+  thread_root?: string | null;
+  edited_at?: string | null;
+  edit_history?: MessageEdit[];
+  deleted?: boolean;
+}
+
+export interface MessageEdit {
+  content: string;
+  edited_at: string;
+}
+
+export interface Group {
+  session_id: string;
+  name: string;
+  members: string[];
+  admins: string[];
+  created_by: string;
+  created_at: string;
 }
 
+export type ConversationKind = { Direct: { peer_id: string } } | "Group";
+
+export interface Conversation {
+  session_id: string;
+  kind: ConversationKind;
+  last_message?: Message | null;
+  unread_count: number;
+  muted: boolean;
+  last_activity: string;
+}
+
+export interface MediaFile {
+  file_name: string;
+  file_size: number;
+  file_hash: string;
+}
+
+export type MessageMetadata =
+  | { File: { file_name: string; file_size: number; file_hash: string } }
+  | {
+      Image: {
+        width: number;
+        height: number;
+        thumbnail?: string | null;
+        file?: MediaFile | null;
+      };
+    }
+  | { Audio: { duration: number; file?: MediaFile | null } }
+  | {
+      Video: {
+        duration: number;
+        thumbnail?: string | null;
+        file?: MediaFile | null;
+      };
+    };
+
 export enum MessageType {
   Text = "Text",
   Image = "Image",
@@ -60,9 +125,31 @@ export interface FileTransfer {
   file_name: string;
   file_size: number;
   file_hash: string;
+  manifest: Manifest;
+  chunk_size: number;
+  compression: boolean;
   bytes_transferred: number;
   status: TransferStatus;
   error?: string;
+  priority: TransferPriority;
+  rate_limit?: number;
+  created_at: string;
+  finished_at?: string | null;
+  saved_paths: string[];
+}
+
+export type EntryKind = "File" | "Directory" | { Symlink: { target: string } };
+
+export interface ManifestEntry {
+  path: string;
+  kind: EntryKind;
+  size: number;
+  hash: string;
+  mode?: number;
+}
+
+export interface Manifest {
+  entries: ManifestEntry[];
 }
 
 export enum TransferStatus {
@@ -75,6 +162,68 @@ export enum TransferStatus {
   Cancelled = "Cancelled",
 }
 
+export enum TransferDirection {
+  Incoming = "Incoming",
+  Outgoing = "Outgoing",
+}
+
+export interface TransferFilter {
+  peer_id?: string | null;
+  statuses?: TransferStatus[];
+  direction?: TransferDirection | null;
+  since?: string | null;
+  until?: string | null;
+  limit?: number | null;
+}
+
+export enum TransferPriority {
+  Low = "Low",
+  Normal = "Normal",
+  High = "High",
+}
+
+export interface TransferSettings {
+  global_rate_limit?: number | null;
+  transfer_rate_limit?: number | null;
+  max_concurrent_transfers: number;
+  max_incoming_size?: number | null;
+  compress_transfers: boolean;
+}
+
+export interface AcceptPolicy {
+  allowed_peers: string[];
+  blocked_peers: string[];
+  max_auto_accept_size?: number | null;
+  allowed_extensions: string[];
+  denied_extensions: string[];
+  /** Folder inside the download directory, by peer user id */
+  peer_folders: Record<string, string>;
+}
+
+/** Payload of the auto-accepted and auto-rejected events */
+export interface FileTransferAutoDecision {
+  transfer_id: string;
+  from: string;
+  file_name: string;
+  reason: string;
+}
+
+export enum TransferPhase {
+  Hashing = "Hashing",
+  WaitingForAccept = "WaitingForAccept",
+  Transferring = "Transferring",
+  Verifying = "Verifying",
+}
+
+export interface FileTransferProgress {
+  transfer_id: string;
+  phase: TransferPhase;
+  bytes_transferred: number;
+  total_bytes: number;
+  bytes_per_second: number;
+  eta_seconds?: number | null;
+}
+
 export interface ChatEvent {
   type: string;
   data: any;